@@ -1,21 +1,429 @@
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use clap::Parser;
+use fuser::MountOption;
+use regex::Regex;
+use thiserror::Error;
+use crate::options::{parse_duration, parse_glob, parse_size, resolve_path, AtimePolicy, CachePolicy, CompressionAlgorithm, FsOptions, MetadataCompression, SyncOnClose, DEFAULT_STORAGE_HIGH_WATER};
+
+/// Command line arguments that couldn't be parsed or don't make sense together.
+#[derive(Debug, Error)]
+pub(crate) enum ArgsError {
+    #[error("Invalid --min-interval: {0}")]
+    MinInterval(String),
+    #[error("Invalid --exclude pattern {0:?}: {1}")]
+    Exclude(String, regex::Error),
+    #[error("Invalid path {0:?}: {1}")]
+    Path(PathBuf, std::io::Error),
+    #[error("Storage path {0:?} and mount path {1:?} must not be inside one another")]
+    OverlappingPaths(PathBuf, PathBuf),
+    #[error("Mount path can't be created, {0:?} is not a directory")]
+    NotDirectory(PathBuf),
+    #[error("Invalid --metadata-compression-level {1} for {0:?}")]
+    CompressionLevel(CompressionAlgorithm, i32),
+}
+
+/// The free-form [`Args`] parsed into typed values.
+#[derive(Debug)]
+pub(crate) struct ParsedArgs {
+    pub(crate) min_interval: Duration,
+    pub(crate) storage_limit: u64,
+    pub(crate) exclude: Vec<Regex>,
+}
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 pub(crate) struct Args {
     storage_path: PathBuf,
     mount_path: PathBuf,
+    /// Record a version of a file before writes change it
     #[clap(long)]
     auto_version: bool,
+    /// Versions kept per file before the oldest are dropped, `0` for no limit
     #[clap(long)]
     max_version: u16,
+    /// Bytes the versions of a file may hold beyond its current contents before the oldest are
+    /// dropped, e.g. `1G`
+    #[clap(long, value_parser = parse_size)]
+    max_version_bytes: Option<u64>,
+    /// Comma separated globs of paths never versioned automatically, e.g. `*.tmp,**/.git/**`
     #[clap(long)]
     exclude: String,
+    /// Minimum time between two automatic versions of a file, e.g. `30s` or `5m`
     #[clap(long)]
     min_interval: String,
+    /// Block storage size past which the oldest trashed files are purged, e.g. `10G`
+    #[clap(long, value_parser = parse_size)]
+    storage_limit: u64,
+    /// Percentage of the storage limit past which writes are increasingly delayed
+    #[clap(long, default_value_t = DEFAULT_STORAGE_HIGH_WATER, value_parser = clap::value_parser!(u8).range(1..=100))]
+    storage_high_water: u8,
+    /// File of `uid limit` lines, e.g. `1000 10G`, capping the bytes the files of each uid may
+    /// take up, past which writes fail with `EDQUOT`
     #[clap(long)]
-    storage_limit: String,
+    quota_file: Option<PathBuf>,
+    /// File of inode ids or absolute paths, one per line, whose blocks are read into the cache in
+    /// the background after mounting
+    #[clap(long, value_name = "HINT_FILE")]
+    warm_cache: Option<PathBuf>,
     #[clap(long)]
     max_cache: u32,
-}
\ No newline at end of file
+    #[clap(long)]
+    read_only: bool,
+    /// Encrypt blocks at rest with the 32 byte key stored in this file
+    #[clap(long)]
+    key_file: Option<PathBuf>,
+    /// When reads update the access time of files
+    #[clap(long, value_enum, default_value_t = AtimePolicy::Relatime)]
+    atime: AtimePolicy,
+    /// Which blocks the block cache evicts once it's full
+    #[clap(long, value_enum, default_value_t = CachePolicy::TinyLfu)]
+    cache_policy: CachePolicy,
+    /// What closing a file writes to disk before returning
+    #[clap(long, value_enum, default_value_t = SyncOnClose::None)]
+    sync_on_close: SyncOnClose,
+    /// Number of background threads flushing dirty blocks [default: number of CPUs]
+    #[clap(long)]
+    flush_threads: Option<NonZeroUsize>,
+    /// Open files past which opening another fails with `EMFILE` [default: unlimited]
+    #[clap(long)]
+    max_open_files: Option<usize>,
+    /// Size past which files can't grow, e.g. `10G`, writes beyond failing with `EFBIG` [default: unlimited]
+    #[clap(long, value_parser = parse_size)]
+    max_file_size: Option<u64>,
+    /// Largest write the kernel sends in one request, e.g. `1M`, lowered to what it supports
+    /// [default: the kernel's]
+    #[clap(long, value_parser = parse_size)]
+    max_write: Option<u64>,
+    /// How far the kernel reads ahead of sequential reads, e.g. `1M`, lowered to what it supports
+    /// [default: the kernel's]
+    #[clap(long, value_parser = parse_size)]
+    max_readahead: Option<u64>,
+    /// Move deleted files to a `.trash` directory they can be restored from
+    #[clap(long)]
+    trash: bool,
+    /// How long deleted files stay in the trash, e.g. `12h` or `30d` [default: 7d]
+    #[clap(long, value_parser = parse_duration)]
+    trash_retention: Option<Duration>,
+    /// Unflushed data in the block cache that starts flushing early, e.g. `64M` [default: 2M]
+    #[clap(long, value_parser = parse_size)]
+    dirty_high_water: Option<u64>,
+    /// How long blocks stay dirty in the cache before being flushed, `0` to only flush them when
+    /// evicted, synced or on unmount, which may lose more on a crash [default: 30s]
+    #[clap(long, value_parser = parse_duration)]
+    flush_interval: Option<Duration>,
+    /// Keep everything in memory, nothing is written to the storage path
+    #[clap(long)]
+    in_memory: bool,
+    /// Ignore case when looking up names, while keeping the case files were created with
+    #[clap(long)]
+    case_insensitive: bool,
+    /// Don't fsync metadata after writing it, faster but recent changes may be lost on power failure
+    #[clap(long)]
+    no_metadata_sync: bool,
+    /// Compress blocks with zstd, only for new filesystems, which must then always be mounted with it
+    #[clap(long)]
+    compress_blocks: bool,
+    /// Read every block back from disk after writing it, failing the write unless it matches.
+    /// Catches storage acknowledging writes it didn't persist correctly, at the cost of a read per write
+    #[clap(long)]
+    verify_writes: bool,
+    /// Give new blocks their ids only when their file is flushed, or every --flush-interval at the
+    /// latest, in a row in file order, so files written out of order are laid out for sequential reads
+    #[clap(long)]
+    delayed_allocation: bool,
+    /// Train a zstd dictionary on a sample of the existing blocks before mounting, compressing new
+    /// blocks with it
+    #[clap(long, requires = "compress_blocks")]
+    train_dictionary: bool,
+    /// Write changed file inodes out together at this interval rather than on every change,
+    /// `fsync` still writing a file's inode right away
+    #[clap(long, value_parser = parse_duration)]
+    inode_flush_interval: Option<Duration>,
+    /// Gather the fsyncs arriving within this window, e.g. `2ms`, into one commit writing out every
+    /// dirty block, for workloads syncing often from many threads
+    #[clap(long, value_parser = parse_duration)]
+    fsync_batch_window: Option<Duration>,
+    /// How long the kernel caches file attributes, `0` to always ask again [default: 1s]
+    #[clap(long, value_parser = parse_duration)]
+    attr_ttl: Option<Duration>,
+    /// How long the kernel caches name lookups, `0` to always ask again [default: 1s]
+    #[clap(long, value_parser = parse_duration)]
+    entry_ttl: Option<Duration>,
+    /// Verify the checksum of every block before mounting
+    #[clap(long)]
+    scrub: bool,
+    /// Before mounting, delete the leftovers of block writes cut short by a crash and move blocks
+    /// failing verification to `lost+found` in the storage path
+    #[clap(long)]
+    repair_blocks: bool,
+    /// Before mounting, turn blocks of zeros into holes, cut off stale tails and pad blocks left
+    /// short by an earlier end of a file to full ones
+    #[clap(long)]
+    compact: bool,
+    /// Check that directory entries and inodes agree before mounting
+    #[clap(long)]
+    fsck: bool,
+    /// Like `--fsck`, also fixing the inconsistencies found
+    #[clap(long)]
+    fsck_repair: bool,
+    /// Load every inode on disk and recompute the superblock counters from them before mounting
+    #[clap(long)]
+    rebuild: bool,
+    /// Algorithm compressed metadata is written with, files written with any of them stay readable
+    #[clap(long, value_enum, default_value_t = CompressionAlgorithm::Zlib)]
+    metadata_compression: CompressionAlgorithm,
+    /// Compression level, 0-9 for zlib and 1-22 for zstd [default: 9 for zlib, 3 for zstd]
+    #[clap(long)]
+    metadata_compression_level: Option<i32>,
+    /// Also compress inode and superblock files, which stay readable either way
+    #[clap(long)]
+    compress_metadata: bool,
+    /// Write every inode and the blocks written since `--export-since` to this file, then exit
+    /// without mounting
+    #[clap(long, value_name = "FILE")]
+    export: Option<PathBuf>,
+    /// Seconds since the epoch, only blocks written after it are exported [default: 0, every block]
+    #[clap(long, value_name = "SECS", requires = "export")]
+    export_since: Option<u64>,
+    /// Apply a stream written by `--export` before mounting, the full one first and the
+    /// incremental ones after it in order
+    #[clap(long, value_name = "FILE", conflicts_with = "export")]
+    import: Option<PathBuf>,
+    /// Validate the arguments and exit without mounting
+    #[clap(long)]
+    check: bool,
+    /// Print the superblock and every inode with its blocks, versions and xattrs, then exit
+    /// without mounting. Reports what it can of damaged storage
+    #[clap(long)]
+    dump: bool,
+    /// Extra mount options passed to FUSE, e.g. `-o ro`
+    #[clap(short = 'o', value_delimiter = ',')]
+    options: Vec<String>,
+}
+
+impl Args {
+    pub(crate) fn storage_path(&self) -> &Path {
+        &self.storage_path
+    }
+
+    pub(crate) fn mount_path(&self) -> &Path {
+        &self.mount_path
+    }
+
+    pub(crate) fn check(&self) -> bool {
+        self.check
+    }
+
+    pub(crate) fn dump(&self) -> bool {
+        self.dump
+    }
+
+    /// File to export to, and the time blocks must have been written after to be included.
+    pub(crate) fn export(&self) -> Option<(&Path, SystemTime)> {
+        let since = SystemTime::UNIX_EPOCH + Duration::from_secs(self.export_since.unwrap_or(0));
+        self.export.as_deref().map(|path| (path, since))
+    }
+
+    pub(crate) fn import(&self) -> Option<&Path> {
+        self.import.as_deref()
+    }
+
+    /// Parses the free-form arguments and checks the paths, so mistakes are reported before mounting.
+    pub(crate) fn validate(&self) -> Result<ParsedArgs, ArgsError> {
+        let min_interval = parse_duration(&self.min_interval).map_err(ArgsError::MinInterval)?;
+
+        let exclude = self.exclude
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| parse_glob(pattern).map_err(|e| ArgsError::Exclude(pattern.to_string(), e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Mounting over the storage would hide it from TimeFS itself, and storing inside the mount
+        // would feed the filesystem into itself, symlinks included.
+        let storage_path = resolve_path(&self.storage_path).map_err(|e| ArgsError::Path(self.storage_path.clone(), e))?;
+        let mount_path = resolve_path(&self.mount_path).map_err(|e| ArgsError::Path(self.mount_path.clone(), e))?;
+        if storage_path.starts_with(&mount_path) || mount_path.starts_with(&storage_path) {
+            return Err(ArgsError::OverlappingPaths(storage_path, mount_path));
+        }
+
+        if let Some(level) = self.metadata_compression_level {
+            let algorithm = self.metadata_compression;
+            if !algorithm.levels().is_some_and(|levels| levels.contains(&level)) {
+                return Err(ArgsError::CompressionLevel(algorithm, level));
+            }
+        }
+
+        // The mount point must be a directory, or be creatable inside the closest existing one.
+        let existing = mount_path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("/"));
+        if !existing.is_dir() {
+            return Err(ArgsError::NotDirectory(existing.to_path_buf()));
+        }
+
+        Ok(ParsedArgs {
+            min_interval,
+            storage_limit: self.storage_limit,
+            exclude,
+        })
+    }
+
+    pub(crate) fn scrub(&self) -> bool {
+        self.scrub
+    }
+
+    pub(crate) fn repair_blocks(&self) -> bool {
+        self.repair_blocks
+    }
+
+    pub(crate) fn compact(&self) -> bool {
+        self.compact
+    }
+
+    pub(crate) fn fsck(&self) -> bool {
+        self.fsck || self.fsck_repair
+    }
+
+    pub(crate) fn fsck_repair(&self) -> bool {
+        self.fsck_repair
+    }
+
+    pub(crate) fn rebuild(&self) -> bool {
+        self.rebuild
+    }
+
+    pub(crate) fn train_dictionary(&self) -> bool {
+        self.train_dictionary
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only || self.options.iter().any(|o| o == "ro")
+    }
+
+    pub(crate) fn fs_options(&self, parsed: &ParsedArgs) -> FsOptions {
+        FsOptions {
+            read_only: self.read_only(),
+            in_memory: self.in_memory,
+            key_file: self.key_file.clone(),
+            atime: self.atime,
+            cache_policy: self.cache_policy,
+            sync_on_close: self.sync_on_close,
+            flush_threads: self.flush_threads,
+            max_open_files: self.max_open_files,
+            max_file_size: self.max_file_size,
+            max_write: self.max_write,
+            max_readahead: self.max_readahead,
+            dirty_high_water: self.dirty_high_water.map(|bytes| bytes as usize),
+            flush_interval: self.flush_interval,
+            trash: self.trash,
+            trash_retention: self.trash_retention,
+            storage_limit: Some(parsed.storage_limit),
+            quota_file: self.quota_file.clone(),
+            warm_cache: self.warm_cache.clone(),
+            storage_high_water: Some(self.storage_high_water),
+            metadata_compression: MetadataCompression {
+                algorithm: self.metadata_compression,
+                level: self.metadata_compression_level,
+            },
+            compress_metadata: self.compress_metadata,
+            case_insensitive: self.case_insensitive,
+            no_metadata_sync: self.no_metadata_sync,
+            inode_flush_interval: self.inode_flush_interval,
+            fsync_batch_window: self.fsync_batch_window,
+            block_compression: self.compress_blocks,
+            verify_writes: self.verify_writes,
+            attr_ttl: self.attr_ttl,
+            entry_ttl: self.entry_ttl,
+            auto_version: self.auto_version,
+            min_version_interval: Some(parsed.min_interval),
+            version_exclude: parsed.exclude.clone(),
+            max_versions: (self.max_version > 0).then_some(self.max_version),
+            max_version_bytes: self.max_version_bytes,
+            delayed_allocation: self.delayed_allocation,
+        }
+    }
+
+    pub(crate) fn mount_options(&self) -> Vec<MountOption> {
+        let mut options = vec![MountOption::FSName("timefs".to_string())];
+        if self.read_only() {
+            options.push(MountOption::RO);
+        }
+
+        options.extend(self.options
+            .iter()
+            .filter(|o| o.as_str() != "ro")
+            .map(|o| MountOption::CUSTOM(o.clone())));
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn parse(dir: &Path, extra: &[&str]) -> Args {
+        let storage = dir.join("storage");
+        let mount = dir.join("mnt");
+        let mut argv = vec![
+            "timefs".to_string(),
+            storage.to_string_lossy().into_owned(),
+            mount.to_string_lossy().into_owned(),
+            "--max-version=10".to_string(),
+            "--max-cache=100".to_string(),
+        ];
+        let defaults = [("--exclude", "*.tmp,**/.git/**"), ("--min-interval", "30s"), ("--storage-limit", "10G")];
+        for (flag, value) in defaults {
+            if !extra.iter().any(|e| e.starts_with(flag)) {
+                argv.push(format!("{}={}", flag, value));
+            }
+        }
+        argv.extend(extra.iter().map(|e| e.to_string()));
+        Args::try_parse_from(argv).expect("Failed to parse args")
+    }
+
+    #[test]
+    fn test_validate_valid_args() {
+        let temp_dir = tempdir().unwrap();
+        let parsed = parse(temp_dir.path(), &["--check"]).validate().expect("args should be valid");
+
+        assert_eq!(parsed.min_interval, Duration::from_secs(30));
+        assert_eq!(parsed.storage_limit, 10 << 30);
+        assert_eq!(parsed.exclude.len(), 2);
+        assert!(parsed.exclude[0].is_match("draft.tmp"));
+    }
+
+    #[test]
+    fn test_validate_malformed_args() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+
+        let err = parse(dir, &["--min-interval=5 parsecs"]).validate().unwrap_err();
+        assert!(matches!(err, ArgsError::MinInterval(_)), "{}", err);
+
+        let err = parse(dir, &["--exclude=*.tmp,[z-a]"]).validate().unwrap_err();
+        assert!(matches!(err, ArgsError::Exclude(ref p, _) if p == "[z-a]"), "{}", err);
+
+        let err = parse(dir, &["--metadata-compression=zlib", "--metadata-compression-level=12"]).validate().unwrap_err();
+        assert!(matches!(err, ArgsError::CompressionLevel(CompressionAlgorithm::Zlib, 12)), "{}", err);
+        let err = parse(dir, &["--metadata-compression=none", "--metadata-compression-level=1"]).validate().unwrap_err();
+        assert!(matches!(err, ArgsError::CompressionLevel(CompressionAlgorithm::None, 1)), "{}", err);
+
+        let same = dir.join("same").to_string_lossy().into_owned();
+        let args = Args::try_parse_from(["timefs", &same, &same, "--max-version=1", "--exclude=", "--min-interval=1", "--storage-limit=1G", "--max-cache=1"]).unwrap();
+        assert!(matches!(args.validate(), Err(ArgsError::OverlappingPaths(..))));
+        let nested = dir.join("same").join("mnt").to_string_lossy().into_owned();
+        let args = Args::try_parse_from(["timefs", &same, &nested, "--max-version=1", "--exclude=", "--min-interval=1", "--storage-limit=1G", "--max-cache=1"]).unwrap();
+        assert!(matches!(args.validate(), Err(ArgsError::OverlappingPaths(..))));
+
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let mount = dir.join("file").join("mnt").to_string_lossy().into_owned();
+        let storage = dir.join("storage").to_string_lossy().into_owned();
+        let args = Args::try_parse_from(["timefs", &storage, &mount, "--max-version=1", "--exclude=", "--min-interval=1", "--storage-limit=1G", "--max-cache=1"]).unwrap();
+        assert!(matches!(args.validate(), Err(ArgsError::NotDirectory(_))));
+
+        let err = Args::try_parse_from(["timefs", &storage, "mnt", "--max-version=1", "--exclude=", "--min-interval=1", "--storage-limit=10GG", "--max-cache=1"]);
+        assert!(err.is_err(), "malformed sizes are rejected while parsing");
+    }
+}
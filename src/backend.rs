@@ -0,0 +1,208 @@
+//! Where block files are stored. The block cache reads and writes whole blocks, already encoded
+//! and checksummed, through a [`BlockBackend`], so storing them elsewhere than in a local
+//! directory doesn't touch the caching and flushing logic.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::warn;
+use tokio::io::AsyncWriteExt;
+use crate::Result;
+
+/// Suffix of the next temporary file a block is written to before being renamed into place.
+static NEXT_TMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Stores blocks by id. Blocks are written concurrently, also the same block by an eviction
+/// and a flush, so a write must replace the block as a whole.
+pub(crate) trait BlockBackend: Send + Sync + 'static {
+    /// Contents of block `block_id` as last written, `None` if it never was.
+    fn read(&self, block_id: u64) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    /// Replaces block `block_id` with `raw`, which must survive a crash once this returns.
+    fn write(&self, block_id: u64, raw: &[u8]) -> impl Future<Output = Result<()>> + Send;
+
+    /// Removes block `block_id`, reading back as never written afterwards.
+    fn delete(&self, block_id: u64) -> impl Future<Output = Result<()>> + Send;
+
+    /// Ids of every block stored.
+    #[cfg_attr(not(feature = "zstd"), allow(dead_code, reason = "only dictionary training lists blocks"))]
+    fn list(&self) -> impl Future<Output = Result<Vec<u64>>> + Send;
+}
+
+/// Blocks stored as files in a local directory, sharded by id into subdirectories of a
+/// thousand blocks each.
+pub(crate) struct LocalFsBackend {
+    blocks_dir: PathBuf,
+}
+
+impl LocalFsBackend {
+    /// The directory is only created along with the first shard written to, so opening
+    /// storage that's never written to leaves no trace.
+    pub fn new(blocks_dir: &Path) -> Self {
+        Self { blocks_dir: blocks_dir.to_path_buf() }
+    }
+
+    pub fn block_path(blocks_dir: &Path, block_id: u64) -> PathBuf {
+        let dir_id = block_id / 1000;
+        let dir_path = blocks_dir.join(format!("{:03}", dir_id));
+
+        let _ = std::fs::create_dir_all(&dir_path);
+
+        dir_path.join(format!("block_{}.bin", block_id))
+    }
+
+    /// Writes `raw` to a temporary file of its own next to the file of block `block_id`,
+    /// returning the paths of both.
+    async fn write_tmp_file(&self, block_id: u64, raw: &[u8]) -> Result<(PathBuf, PathBuf)> {
+        let path = Self::block_path(&self.blocks_dir, block_id);
+        // Each write has a file of its own, as an eviction may write a block while it's flushed.
+        // It sits next to the block so that renaming it into place stays on the same device.
+        let tmp_path = path.with_extension(format!("tmp{}", NEXT_TMP_FILE.fetch_add(1, Ordering::Relaxed)));
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(raw).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        Ok((tmp_path, path))
+    }
+
+    /// Finishes putting the block file at `tmp_path` in place given how renaming it to `path`
+    /// went, copying it over where that failed across devices.
+    async fn finish_rename(tmp_path: &Path, path: &Path, renamed: std::io::Result<()>) -> Result<()> {
+        match renamed {
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => Self::copy_block_file(tmp_path, path).await?,
+            renamed => renamed?,
+        }
+        crate::sync_parent_dir(path)?;
+        Ok(())
+    }
+
+    /// Puts a block file in place where renaming it can't, e.g. when the blocks directory was
+    /// swapped for a symlink to another device while it was written. Not atomic, but a block
+    /// torn by a crash fails its checksum rather than being read back wrong.
+    async fn copy_block_file(tmp_path: &Path, path: &Path) -> Result<()> {
+        warn!("Renaming {:?} failed across devices, copying it to {:?} instead", tmp_path, path);
+        tokio::fs::copy(tmp_path, path).await?;
+        tokio::fs::File::open(path).await?.sync_all().await?;
+        tokio::fs::remove_file(tmp_path).await?;
+        Ok(())
+    }
+}
+
+impl BlockBackend for LocalFsBackend {
+    async fn read(&self, block_id: u64) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(Self::block_path(&self.blocks_dir, block_id)).await {
+            Ok(raw) => Ok(Some(raw)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write(&self, block_id: u64, raw: &[u8]) -> Result<()> {
+        let (tmp_path, path) = self.write_tmp_file(block_id, raw).await?;
+        let renamed = tokio::fs::rename(&tmp_path, &path).await;
+        Self::finish_rename(&tmp_path, &path, renamed).await
+    }
+
+    async fn delete(&self, block_id: u64) -> Result<()> {
+        let path = Self::block_path(&self.blocks_dir, block_id);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(crate::sync_parent_dir(&path)?),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<u64>> {
+        let blocks_dir = self.blocks_dir.clone();
+        let ids = tokio::task::spawn_blocking(move || crate::block::block_file_ids(&blocks_dir)).await
+            .map_err(|e| std::io::Error::other(e.to_string()))??;
+        Ok(ids.into_iter().collect())
+    }
+}
+
+/// Blocks kept in a map, for testing the cache against a backend other than the local one.
+/// Clones share their blocks, like caches opened on the same directory do.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct MemoryBackend {
+    pub(crate) blocks: std::sync::Arc<dashmap::DashMap<u64, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl BlockBackend for MemoryBackend {
+    async fn read(&self, block_id: u64) -> Result<Option<Vec<u8>>> {
+        Ok(self.blocks.get(&block_id).map(|raw| raw.clone()))
+    }
+
+    async fn write(&self, block_id: u64, raw: &[u8]) -> Result<()> {
+        self.blocks.insert(block_id, raw.to_vec());
+        Ok(())
+    }
+
+    async fn delete(&self, block_id: u64) -> Result<()> {
+        self.blocks.remove(&block_id);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<u64>> {
+        Ok(self.blocks.iter().map(|entry| *entry.key()).collect())
+    }
+}
+
+/// Blocks stored in a local directory, with writes failing or corrupted on demand, for testing
+/// how the cache copes with storage misbehaving. Clones share what they inject.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct FaultyBackend {
+    inner: std::sync::Arc<LocalFsBackend>,
+    /// Block ids whose next writes fail with `EAGAIN`, and how many times.
+    pub(crate) write_failures: std::sync::Arc<dashmap::DashMap<u64, u32>>,
+    /// Block ids whose files get a byte flipped when written, as by storage corrupting them.
+    pub(crate) corrupt_writes: std::sync::Arc<dashmap::DashSet<u64>>,
+    /// Block ids whose files fail to be renamed into place with `EXDEV`, as across devices.
+    pub(crate) cross_device: std::sync::Arc<dashmap::DashSet<u64>>,
+}
+
+#[cfg(test)]
+impl FaultyBackend {
+    pub fn new(blocks_dir: &Path) -> Self {
+        Self {
+            inner: std::sync::Arc::new(LocalFsBackend::new(blocks_dir)),
+            write_failures: Default::default(),
+            corrupt_writes: Default::default(),
+            cross_device: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl BlockBackend for FaultyBackend {
+    async fn read(&self, block_id: u64) -> Result<Option<Vec<u8>>> {
+        self.inner.read(block_id).await
+    }
+
+    async fn write(&self, block_id: u64, raw: &[u8]) -> Result<()> {
+        if let Some(mut failures) = self.write_failures.get_mut(&block_id).filter(|f| **f > 0) {
+            *failures -= 1;
+            return Err(std::io::Error::from_raw_os_error(libc::EAGAIN).into());
+        }
+        if self.corrupt_writes.contains(&block_id) {
+            let mut corrupted = raw.to_vec();
+            corrupted[0] ^= 0xff;
+            return self.inner.write(block_id, &corrupted).await;
+        }
+        if self.cross_device.contains(&block_id) {
+            let (tmp_path, path) = self.inner.write_tmp_file(block_id, raw).await?;
+            let renamed = Err(std::io::Error::from_raw_os_error(libc::EXDEV));
+            return LocalFsBackend::finish_rename(&tmp_path, &path, renamed).await;
+        }
+        self.inner.write(block_id, raw).await
+    }
+
+    async fn delete(&self, block_id: u64) -> Result<()> {
+        self.inner.delete(block_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<u64>> {
+        self.inner.list().await
+    }
+}
@@ -1,20 +1,33 @@
 use crate::Result;
-use crossbeam::channel::{unbounded, Receiver, Sender};
 use dashmap::DashMap;
 use moka::future::{Cache, FutureExt};
+use moka::notification::RemovalCause;
+use moka::ops::compute::Op;
+use moka::policy::EvictionPolicy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::Write;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
 use tokio::runtime;
 use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::task::JoinHandle;
 use tokio::time::MissedTickBehavior;
+use tracing::Instrument;
+use crate::backend::{BlockBackend, LocalFsBackend};
+use crate::crypto::BlockCipher;
+#[cfg(feature = "zstd")]
+use crate::compress::BlockCompressor;
+use crate::error::TimeFSError;
 use crate::fs::BLOCK_SIZE;
+use crate::options::CachePolicy;
+use log::{error, warn};
 
 #[derive(Error, Debug)]
 pub enum BlockCacheError {
@@ -24,12 +37,25 @@ pub enum BlockCacheError {
     NotFound(u64),
     #[error("Failed to flush block: {0}")]
     FlushFailed(String),
+    #[error("Failed to authenticate block {0}, wrong key or corrupted data")]
+    Crypto(u64),
+    #[error("Bad encryption key: {0}")]
+    BadKey(String),
+    #[error("Checksum mismatch in block {0}")]
+    Checksum(u64),
+    #[error("Block cache is already frozen")]
+    AlreadyFrozen,
+    #[error("Block compression: {0}")]
+    Compression(String),
+    #[error("Block {0} read back differently than it was written")]
+    VerifyFailed(u64),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct BlockRef {
     block_id: u64,
     size: u32,
+    written_at: SystemTime,
 }
 
 #[derive(Clone)]
@@ -37,63 +63,321 @@ pub(crate) struct CacheEntry {
     data: Vec<u8>,
     dirty: bool,
     last_modified: Instant,
+    /// Pinned entries weigh nothing, so the cache never evicts them to make room.
+    pinned: bool,
+}
+
+impl CacheEntry {
+    pub fn last_modified(&self) -> Instant {
+        self.last_modified
+    }
+
+    fn weight(&self) -> u32 {
+        if self.pinned { 0 } else { 1 }
+    }
+}
+
+/// Decides which dirty blocks the periodic flush writes out, consulted for every dirty block on each tick.
+pub(crate) trait FlushPolicy: Send + Sync {
+    /// Whether to write `entry` out now, `dirty_bytes` being the total of all unflushed blocks.
+    fn should_flush(&self, entry: &CacheEntry, now: Instant, dirty_bytes: u64) -> bool;
+}
+
+/// The default policy, flushing blocks once they've been left unmodified for `max_age`.
+pub(crate) struct AgePolicy {
+    max_age: Duration,
+}
+
+impl AgePolicy {
+    pub fn new(max_age: Duration) -> Self {
+        Self { max_age }
+    }
+}
+
+impl FlushPolicy for AgePolicy {
+    fn should_flush(&self, entry: &CacheEntry, now: Instant, _dirty_bytes: u64) -> bool {
+        now.duration_since(entry.last_modified()) >= self.max_age
+    }
 }
 
 enum BlockOperation {
-    MarkDirty(u64, Instant),
-    Flush(u64),
+    /// Flush every dirty block now, sent once dirty bytes pass the high-water mark.
+    FlushDirty,
     ShutDown,
 }
 
+/// Operations the background thread may have queued before senders wait for it to catch up,
+/// so a burst of writes can't pile them up without limit.
+const OPERATION_QUEUE_CAPACITY: usize = 64;
+
+/// Dirty bytes past which flushing starts without waiting for the flush interval.
+pub(crate) const DEFAULT_DIRTY_HIGH_WATER: usize = 512 * BLOCK_SIZE as usize;
+
+/// Attempts at writing a block file before a transient error is given up on.
+const WRITE_ATTEMPTS: u32 = 4;
+/// Delay before retrying a failed block write, doubled on every further attempt.
+const WRITE_BACKOFF: Duration = Duration::from_millis(20);
+
+/// Blocks modified in the cache but not yet written out, with when they were dirtied and how big they are.
+#[derive(Default)]
+struct DirtyBlocks {
+    blocks: DashMap<u64, (Instant, usize)>,
+    bytes: AtomicUsize,
+    /// Block writes that failed even after retrying.
+    flush_errors: AtomicU64,
+    /// Block writes started but not finished, which a block may already have left `blocks` for.
+    in_flight: AtomicUsize,
+    idle: tokio::sync::Notify,
+    /// When a block was last written out, in milliseconds since the epoch, 0 before the first.
+    last_flush: AtomicU64,
+}
+
+/// Counts a block write as in flight until dropped.
+struct WriteInFlight(DirtyTracer);
+
+impl Drop for WriteInFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl DirtyBlocks {
+    /// Marks a block dirty, returning the dirty byte count before and after.
+    fn mark(&self, block_id: u64, last_modified: Instant, size: usize) -> (usize, usize) {
+        let old_size = self.blocks
+            .insert(block_id, (last_modified, size))
+            .map(|(_, size)| size)
+            .unwrap_or(0);
+        let before = self.bytes.fetch_add(size, Ordering::SeqCst);
+        self.bytes.fetch_sub(old_size, Ordering::SeqCst);
+        (before, (before + size).saturating_sub(old_size))
+    }
+
+    fn clear(&self, block_id: u64) {
+        if let Some((_, (_, size))) = self.blocks.remove(&block_id) {
+            self.bytes.fetch_sub(size, Ordering::SeqCst);
+        }
+    }
+
+    /// Clears a block written out as last modified at `written`, unless it was modified again since.
+    fn clear_written(&self, block_id: u64, written: Instant) {
+        if let Some((_, (_, size))) = self.blocks.remove_if(&block_id, |_, (last_modified, _)| *last_modified == written) {
+            self.bytes.fetch_sub(size, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether the contents of `block_id` last modified at `last_modified` wait for a flush.
+    fn is_tracked(&self, block_id: u64, last_modified: Instant) -> bool {
+        self.blocks.get(&block_id).is_some_and(|entry| entry.0 == last_modified)
+    }
+
+    fn ids(&self) -> Vec<u64> {
+        self.blocks.iter().map(|e| *e.key()).collect()
+    }
+
+    /// Records a failed write, keeping the block dirty so a later flush retries it.
+    fn flush_failed(&self, block_id: u64, last_modified: Instant, size: usize) {
+        self.flush_errors.fetch_add(1, Ordering::SeqCst);
+        self.mark(block_id, last_modified, size);
+    }
+
+    fn flushed(&self) {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        self.last_flush.store(now.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    fn start_write(self: &Arc<Self>) -> WriteInFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        WriteInFlight(self.clone())
+    }
+
+    /// Waits until no block write is in flight anymore.
+    async fn writes_finished(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
 
 type Blocks = Arc<Cache<u64, CacheEntry>>;
-type DirtyTracer = Arc<DashMap<u64, Instant>>;
+type DirtyTracer = Arc<DirtyBlocks>;
 type BGHandle = Arc<Mutex<Option<std::thread::JoinHandle<()>>>>;
+type Codec = Arc<BlockCodec>;
+pub(crate) type Policy = Arc<dyn FlushPolicy>;
+
+/// How blocks are transformed on their way to disk, compressed first and then encrypted.
+#[derive(Default)]
+pub(crate) struct BlockCodec {
+    cipher: Option<BlockCipher>,
+    #[cfg(feature = "zstd")]
+    compressor: Option<BlockCompressor>,
+    /// Read every block file back once written, failing the write unless it holds what was written.
+    verify_writes: bool,
+}
+
+impl BlockCodec {
+    pub fn new(cipher: Option<BlockCipher>) -> Self {
+        Self { cipher, ..Self::default() }
+    }
+
+    #[cfg(feature = "zstd")]
+    pub fn with_compressor(mut self, compressor: BlockCompressor) -> Self {
+        self.compressor = Some(compressor);
+        self
+    }
 
-pub(crate) struct BlockCache {
+    pub fn with_verify_writes(mut self, verify_writes: bool) -> Self {
+        self.verify_writes = verify_writes;
+        self
+    }
+
+    fn encode<'a>(&self, block_id: u64, data: &'a [u8]) -> std::result::Result<Cow<'a, [u8]>, BlockCacheError> {
+        let mut data = Cow::Borrowed(data);
+        #[cfg(feature = "zstd")]
+        if let Some(ref compressor) = self.compressor {
+            data = Cow::Owned(compressor.compress(block_id, &data)?);
+        }
+        if let Some(ref cipher) = self.cipher {
+            data = Cow::Owned(cipher.encrypt(block_id, &data)?);
+        }
+        Ok(data)
+    }
+
+    fn decode(&self, block_id: u64, payload: &[u8]) -> std::result::Result<Vec<u8>, BlockCacheError> {
+        let data = match self.cipher {
+            Some(ref cipher) => cipher.decrypt(block_id, payload)?,
+            None => payload.to_vec(),
+        };
+        #[cfg(feature = "zstd")]
+        if let Some(ref compressor) = self.compressor {
+            return compressor.decompress(block_id, &data);
+        }
+        Ok(data)
+    }
+}
+
+pub(crate) struct BlockCache<B: BlockBackend = LocalFsBackend> {
     blocks: Blocks,
     dirty_tracer: DirtyTracer,
     operation_sender: Sender<BlockOperation>,
-    blocks_dir: PathBuf,
-    runtime: tokio::runtime::Handle,
+    /// Where blocks are persisted, `None` for a cache living purely in memory.
+    backend: Option<Arc<B>>,
     bg_handle: BGHandle,
+    codec: Codec,
+    /// Dirty bytes past which flushing starts early, `None` to leave dirty blocks until evicted.
+    dirty_high_water: Option<usize>,
+    /// Pin count of every pinned block.
+    pins: DashMap<u64, usize>,
+    /// Number of block updates so far, i.e. how much churn writes cause in the cache.
+    updates: AtomicU64,
+    /// Side log of the writes made while the cache is frozen, see [`BlockCache::freeze`].
+    frozen: parking_lot::Mutex<Option<HashMap<u64, Vec<u8>>>>,
 }
 
-impl BlockCache {
-    pub fn new(max_capacity: u64, blocks_dir: &Path, flush_interval_secs: u64) -> Self {
-        std::fs::create_dir_all(blocks_dir).expect("Failed to create block dir");
+impl BlockCache<LocalFsBackend> {
+    /// Creates a cache whose periodic flush writes out the dirty blocks `policy` picks. Without a
+    /// policy there is no periodic flush, and dirty blocks are only written out when evicted,
+    /// flushed explicitly or on shutdown.
+    ///
+    /// Whatever `cache_policy` evicts is written to disk first, so it only decides which blocks
+    /// have to be read back later.
+    pub fn with_flush_policy(
+        max_capacity: u64,
+        blocks_dir: &Path,
+        flush_threads: NonZeroUsize,
+        codec: BlockCodec,
+        policy: Option<Policy>,
+        cache_policy: CachePolicy,
+    ) -> Self {
+        let backend = LocalFsBackend::new(blocks_dir);
+        Self::with_backend(max_capacity, backend, flush_threads, codec, policy, cache_policy)
+    }
 
-        let blocks_dir = blocks_dir.to_path_buf();
-        let blocks_dir_cloned = blocks_dir.clone();
-        let flush_blocks_dir = blocks_dir.to_path_buf();
+    /// Creates a cache that never touches disk. Blocks evicted to make room are dropped, so
+    /// `max_capacity` bounds how many blocks it can hold.
+    pub fn in_memory(max_capacity: u64) -> Self {
+        // Nothing ever needs flushing, so there's no background thread receiving operations.
+        let (operation_sender, _) = mpsc::channel::<BlockOperation>(1);
 
+        Self {
+            blocks: Arc::new(Cache::builder().max_capacity(max_capacity).weigher(|_, entry: &CacheEntry| entry.weight()).build()),
+            dirty_tracer: Arc::new(DirtyBlocks::default()),
+            operation_sender,
+            backend: None,
+            bg_handle: Arc::new(Mutex::new(None)),
+            codec: Arc::default(),
+            dirty_high_water: Some(DEFAULT_DIRTY_HIGH_WATER),
+            pins: DashMap::new(),
+            updates: AtomicU64::new(0),
+            frozen: parking_lot::Mutex::new(None),
+        }
+    }
+
+}
+
+impl<B: BlockBackend> BlockCache<B> {
+    /// Creates a cache storing its blocks in `backend`, flushing them like
+    /// [`BlockCache::with_flush_policy`] does.
+    pub fn with_backend(
+        max_capacity: u64,
+        backend: B,
+        flush_threads: NonZeroUsize,
+        codec: BlockCodec,
+        policy: Option<Policy>,
+        cache_policy: CachePolicy,
+    ) -> Self {
+        let backend = Arc::new(backend);
+        let evict_backend = backend.clone();
+        let flush_backend = backend.clone();
+        let codec = Arc::new(codec);
+        let evict_codec = codec.clone();
+        let flush_codec = codec.clone();
+
+        let eviction_policy = match cache_policy {
+            CachePolicy::TinyLfu => EvictionPolicy::tiny_lfu(),
+            CachePolicy::Lru => EvictionPolicy::lru(),
+        };
         let cache = Cache::builder()
             .max_capacity(max_capacity)
-            .async_eviction_listener(move |key: Arc<u64>, entry: CacheEntry, _cause| {
-                let blocks_dir_cloned = blocks_dir.clone();
+            .eviction_policy(eviction_policy)
+            .weigher(|_, entry: &CacheEntry| entry.weight())
+            .async_eviction_listener(move |key: Arc<u64>, entry: CacheEntry, cause: RemovalCause| {
+                let backend = evict_backend.clone();
+                let codec = evict_codec.clone();
                 async move {
-                    let path = Self::get_block_path_static(&blocks_dir_cloned, *key);
-                    Self::write_block_to_disk(&path, &entry.data).await.expect("Failed to write block to disk");
+                    // Replaced entries are stale, and clean ones are on disk already.
+                    if !cause.was_evicted() || !entry.dirty {
+                        return;
+                    }
+                    Self::write_block_with_retry(&backend, *key, &entry.data, &codec).await.expect("Failed to write block to disk");
                 }.boxed()
             })
             .build();
 
-        let (operation_sender, operation_receiver) = unbounded::<BlockOperation>();
-        let runtime = tokio::runtime::Handle::current();
+        let (operation_sender, operation_receiver) = mpsc::channel::<BlockOperation>(OPERATION_QUEUE_CAPACITY);
 
         let cache = Arc::new(cache);
         let flush_blocks = cache.clone();
-        let dirty_tracer = Arc::new(DashMap::new());
+        let dirty_tracer = Arc::new(DirtyBlocks::default());
 
         let dirty_tracer_cloned = dirty_tracer.clone();
 
         let handle = std::thread::spawn(move || {
             Self::background_thread(
                 flush_blocks,
-                flush_blocks_dir,
+                flush_backend,
                 dirty_tracer_cloned,
                 operation_receiver,
-                flush_interval_secs,
+                policy,
+                flush_threads,
+                flush_codec,
             )
         });
 
@@ -101,120 +385,333 @@ impl BlockCache {
             blocks: cache,
             dirty_tracer,
             operation_sender,
-            blocks_dir: blocks_dir_cloned,
-            runtime,
+            backend: Some(backend),
             bg_handle: Arc::new(Mutex::new(Some(handle))),
+            codec,
+            dirty_high_water: Some(DEFAULT_DIRTY_HIGH_WATER),
+            pins: DashMap::new(),
+            updates: AtomicU64::new(0),
+            frozen: parking_lot::Mutex::new(None),
         }
     }
 
-    fn get_block_path(&self, block_id: u64) -> PathBuf {
-        let dir_id = block_id / 1000;
-        let dir_path = self.blocks_dir.join(format!("{:03}", dir_id));
-
-        let _ = std::fs::create_dir_all(&dir_path);
-        dir_path.join(format!("block_{}.bin", block_id))
+    /// Sets how many dirty bytes may pile up before flushing starts early. Past twice that,
+    /// writers flush synchronously until the backlog is on disk. With `None` any number may.
+    pub fn with_dirty_high_water(mut self, bytes: Option<usize>) -> Self {
+        self.dirty_high_water = bytes;
+        self
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn get_block(&self, block_id: u64) -> Result<Vec<u8>> {
+        if let Some(data) = self.frozen.lock().as_ref().and_then(|side_log| side_log.get(&block_id).cloned()) {
+            return Ok(data);
+        }
         if let Some(entry) = self.blocks.get(&block_id).await {
             return Ok(entry.data.clone());
         }
 
-        let path = self.get_block_path(block_id);
-        match tokio::fs::read(&path).await {
-            Ok(data) => {
-                self.blocks.insert(block_id, CacheEntry {
-                    data: data.clone(),
-                    dirty: false,
-                    last_modified: Instant::now(),
-                }).await;
-                Ok(data)
-            }
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    let empty_data = Vec::new();
-                    self.blocks.insert(block_id, CacheEntry {
-                        data: empty_data,
-                        dirty: false,
-                        last_modified: Instant::now(),
-                    }).await;
-                    Ok(vec![])
-                } else { Err(BlockCacheError::Io(e).into()) }
+        let Some(ref backend) = self.backend else {
+            return Ok(Vec::new());
+        };
+        match backend.read(block_id).instrument(tracing::debug_span!("block_fault")).await? {
+            Some(raw) => {
+                let payload = verify_checksum(block_id, &raw)?;
+                let data = self.codec.decode(block_id, payload)?;
+                Ok(self.insert_clean(block_id, data).await)
             }
+            None => Ok(self.insert_clean(block_id, Vec::new()).await),
         }
     }
 
+    /// Caches `data` read from disk, unless the block was written while it was being read, in
+    /// which case the newer contents are returned. A read thus never replaces a dirty block with
+    /// the older contents on disk.
+    async fn insert_clean(&self, block_id: u64, data: Vec<u8>) -> Vec<u8> {
+        let entry = self.blocks.entry(block_id).or_insert(CacheEntry {
+            data,
+            dirty: false,
+            last_modified: Instant::now(),
+            pinned: self.is_pinned(block_id),
+        }).await;
+        entry.into_value().data
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, data))]
     pub async fn update_block(&self, block_id: u64, data: Vec<u8>) -> Result<()> {
-        let now = Instant::now();
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref mut side_log) = *self.frozen.lock() {
+            side_log.insert(block_id, data);
+            return Ok(());
+        }
+
+        let Some((before, after)) = self.store_block(block_id, data).await else {
+            return Ok(());
+        };
+        let Some(high_water) = self.dirty_high_water else {
+            return Ok(());
+        };
+        if after > 2 * high_water {
+            // The flusher can't keep up, make the writer wait for the backlog to reach disk.
+            for block_id in self.dirty_tracer.ids() {
+                self.flush_block(block_id, true).await?;
+            }
+        } else if before <= high_water && after > high_water {
+            self.operation_sender.send(BlockOperation::FlushDirty).await
+                .map_err(|e| BlockCacheError::FlushFailed(e.to_string()))?;
+        }
+
+        Ok(())
+    }
 
+    /// Drops a block nothing uses anymore and deletes its file, once the writes in flight are
+    /// done so none of them brings the file back. While frozen the deletion waits in the side log,
+    /// an empty block being deleted rather than written when it's flushed.
+    pub async fn delete_block(&self, block_id: u64) -> Result<()> {
+        if let Some(ref mut side_log) = *self.frozen.lock() {
+            side_log.insert(block_id, Vec::new());
+            return Ok(());
+        }
+
+        self.dirty_tracer.clear(block_id);
+        self.blocks.invalidate(&block_id).await;
+        let Some(ref backend) = self.backend else {
+            return Ok(());
+        };
+        self.dirty_tracer.writes_finished().await;
+        backend.delete(block_id).await
+    }
+
+    /// Inserts a modified block, returning the dirty byte count before and after unless the
+    /// cache lives in memory only.
+    async fn store_block(&self, block_id: u64, data: Vec<u8>) -> Option<(usize, usize)> {
+        let now = Instant::now();
+        let size = data.len();
         self.blocks.insert(block_id, CacheEntry {
             data,
-            dirty: true,
+            dirty: self.backend.is_some(),
             last_modified: now,
+            pinned: self.is_pinned(block_id),
         }).await;
+        self.backend.as_ref()?;
+        Some(self.dirty_tracer.mark(block_id, now, size))
+    }
 
-        self.operation_sender.send(BlockOperation::MarkDirty(block_id, now))
-            .map_err(|e| BlockCacheError::FlushFailed(e.to_string()))?;
+    /// Flushes every dirty block and keeps the block files untouched until the returned guard is
+    /// dropped, so the block directory can be copied as a consistent whole. Writes made in the
+    /// meantime are held in a side log, read back from there, and applied once the guard goes.
+    pub async fn freeze(self: &Arc<Self>) -> Result<FrozenCache<B>> {
+        {
+            let mut frozen = self.frozen.lock();
+            if frozen.is_some() {
+                return Err(BlockCacheError::AlreadyFrozen.into());
+            }
+            *frozen = Some(HashMap::new());
+        }
+        let guard = FrozenCache { cache: self.clone() };
+
+        for block_id in self.dirty_tracer.ids() {
+            self.flush_block(block_id, true).await?;
+        }
+        Ok(guard)
+    }
+
+    /// Applies the writes held back while frozen. The side log stays locked until they're all in
+    /// the cache, so neither reads nor newer writes can slip in between.
+    fn thaw(&self) {
+        let mut frozen = self.frozen.lock();
+        let Some(side_log) = frozen.take() else {
+            return;
+        };
+
+        let mut dirty_bytes = 0;
+        futures::executor::block_on(async {
+            for (block_id, data) in side_log {
+                if let Some((_, after)) = self.store_block(block_id, data).await {
+                    dirty_bytes = after;
+                }
+            }
+        });
+        // A full queue holds a flush of every dirty block already, which covers these too.
+        if self.dirty_high_water.is_some_and(|high_water| dirty_bytes > high_water)
+            && let Err(TrySendError::Closed(_)) = self.operation_sender.try_send(BlockOperation::FlushDirty)
+        {
+            warn!("Failed to start flushing blocks written while frozen: the flush thread is gone");
+        }
+    }
+
+    /// Trains a compression dictionary on the blocks `sample_ids`, which every block written from
+    /// now on is compressed with, returning its id.
+    #[cfg(feature = "zstd")]
+    pub async fn train_dictionary(&self, sample_ids: &[u64]) -> Result<u32> {
+        let Some(ref compressor) = self.codec.compressor else {
+            return Err(BlockCacheError::Compression("blocks aren't compressed".into()).into());
+        };
+        let mut samples = Vec::with_capacity(sample_ids.len());
+        for &block_id in sample_ids {
+            samples.push(self.get_block(block_id).await?);
+        }
+        compressor.train(&samples)
+    }
+
+    /// Ids of every block stored by the backend, none when the cache lives in memory.
+    #[cfg_attr(not(feature = "zstd"), allow(dead_code, reason = "only dictionary training lists blocks"))]
+    pub async fn block_ids(&self) -> Result<Vec<u64>> {
+        match self.backend {
+            Some(ref backend) => backend.list().await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Keeps a block resident until it's unpinned as often as it was pinned, so a mapped file
+    /// never has its blocks evicted and read back in between accesses.
+    pub async fn pin_block(&self, block_id: u64) -> Result<()> {
+        let first = {
+            let mut count = self.pins.entry(block_id).or_default();
+            *count += 1;
+            *count == 1
+        };
+        if first {
+            self.set_pinned(block_id, true).await?;
+        }
+        Ok(())
+    }
 
+    pub async fn unpin_block(&self, block_id: u64) -> Result<()> {
+        let last = match self.pins.get_mut(&block_id) {
+            Some(mut count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => return Ok(()),
+        };
+        if last {
+            self.pins.remove_if(&block_id, |_, count| *count == 0);
+            self.set_pinned(block_id, false).await?;
+        }
         Ok(())
     }
 
+    pub fn is_pinned(&self, block_id: u64) -> bool {
+        self.pins.contains_key(&block_id)
+    }
+
+    /// Whether the block is resident, i.e. reading it won't go to disk.
+    pub fn is_cached(&self, block_id: u64) -> bool {
+        self.blocks.contains_key(&block_id)
+    }
+
+    /// Reinserts a block so the cache weighs it again, loading it first if it isn't cached.
+    async fn set_pinned(&self, block_id: u64, pinned: bool) -> Result<()> {
+        if self.blocks.get(&block_id).await.is_none() {
+            self.get_block(block_id).await?;
+        }
+        if let Some(mut entry) = self.blocks.get(&block_id).await {
+            entry.pinned = pinned;
+            self.blocks.insert(block_id, entry).await;
+        }
+        Ok(())
+    }
+
+    pub fn updates(&self) -> u64 {
+        self.updates.load(Ordering::Relaxed)
+    }
+
+    /// Number of block writes that kept failing after being retried.
+    pub fn flush_errors(&self) -> u64 {
+        self.dirty_tracer.flush_errors.load(Ordering::SeqCst)
+    }
+
+    /// Number of blocks written to the cache but not yet to disk.
+    pub fn dirty_blocks(&self) -> usize {
+        self.dirty_tracer.blocks.len()
+    }
+
+    /// When a block was last written out, `None` before the first.
+    pub fn last_flush(&self) -> Option<SystemTime> {
+        match self.dirty_tracer.last_flush.load(Ordering::SeqCst) {
+            0 => None,
+            millis => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// Bytes of blocks written to the cache but not yet to disk.
+    #[cfg(test)]
+    fn dirty_bytes(&self) -> usize {
+        self.dirty_tracer.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Operations sent to the background thread it hasn't picked up yet.
+    #[cfg(test)]
+    fn queued_operations(&self) -> usize {
+        self.operation_sender.max_capacity() - self.operation_sender.capacity()
+    }
+
     fn background_thread(
         blocks: Blocks,
-        blocks_dir: PathBuf,
+        backend: Arc<B>,
         dirty_tracer: DirtyTracer,
-        operation_receiver: Receiver<BlockOperation>,
-        flush_interval_secs: u64,
+        mut operation_receiver: Receiver<BlockOperation>,
+        policy: Option<Policy>,
+        flush_threads: NonZeroUsize,
+        codec: Codec,
     ) {
         let runtime = runtime::Builder::new_multi_thread()
-            .worker_threads(12)
+            .worker_threads(flush_threads.get())
             .enable_all()
             .build()
             .expect("Failed to build Tokio runtime");
 
         runtime.block_on(async move {
             let dirty_cloned = dirty_tracer.clone();
-            let blocks_dir_cloned = blocks_dir.clone();
+            let backend_cloned = backend.clone();
             let blocks_cloned = blocks.clone();
+            let codec_cloned = codec.clone();
+
+            if let Some(policy) = policy {
+                tokio::spawn(async move {
+                    Self::periodic_flush_task(
+                        blocks_cloned,
+                        backend_cloned,
+                        dirty_cloned,
+                        policy,
+                        codec_cloned,
+                    ).await;
+                });
+            }
 
-            tokio::spawn(async move {
-                Self::periodic_flush_task(
-                    blocks_cloned,
-                    blocks_dir_cloned,
-                    dirty_cloned,
-                    flush_interval_secs,
-                ).await;
-            });
-
-            while let Ok(operation) = operation_receiver.recv() {
+            while let Some(operation) = operation_receiver.recv().await {
                 match operation {
-                    BlockOperation::MarkDirty(block_id, last_modified) => {
-                        let dirty = dirty_tracer.clone();
-                        dirty.insert(block_id, last_modified);
-                    }
-                    BlockOperation::Flush(block_id) => {
-                        Self::flush_block_static(
-                            block_id,
-                            &blocks_dir,
-                            blocks.clone(),
-                            dirty_tracer.clone(),
-                            false
-                        ).await.expect("Failed to flush block");
+                    BlockOperation::FlushDirty => {
+                        for block_id in dirty_tracer.ids() {
+                            Self::flush_block_static(
+                                block_id,
+                                &backend,
+                                blocks.clone(),
+                                dirty_tracer.clone(),
+                                codec.clone(),
+                                false
+                            ).await.expect("Failed to flush block");
+                        }
                     }
                     BlockOperation::ShutDown => {
-                        let dirty_block_ids = dirty_tracer
-                                .iter()
-                                .map(|e| *e.key())
-                                .collect::<Vec<_>>();
-
-                        for block_id in dirty_block_ids {
-                            Self::flush_block_static(
+                        // Anything queued behind is covered by flushing every dirty block below.
+                        while operation_receiver.try_recv().is_ok() {}
+                        // Blocks the periodic flush is writing aren't tracked as dirty anymore,
+                        // and their writes would be cut off once this runtime goes away.
+                        blocks.run_pending_tasks().await;
+                        dirty_tracer.writes_finished().await;
+
+                        for block_id in Self::unflushed_ids(&blocks, &dirty_tracer) {
+                            // Already logged and counted, the other blocks still get their chance.
+                            let _ = Self::flush_block_static(
                                 block_id,
-                                &blocks_dir,
+                                &backend,
                                 blocks.clone(),
                                 dirty_tracer.clone(),
+                                codec.clone(),
                                 true
-                            ).await.expect("Failed to shut down cache!");
+                            ).await;
                         }
 
                         break;
@@ -224,16 +721,36 @@ impl BlockCache {
         })
     }
 
+    /// Writes out every dirty block at once, returning when they're all on disk.
+    pub async fn flush_dirty(&self) -> Result<()> {
+        let flushes = self.dirty_tracer.ids().into_iter().map(|block_id| self.flush_block(block_id, true));
+        futures::future::try_join_all(flushes).await?;
+        Ok(())
+    }
+
+    /// Ids of the blocks not on disk yet, whether still tracked as dirty or not.
+    fn unflushed_ids(blocks: &Blocks, dirty_tracer: &DirtyBlocks) -> HashSet<u64> {
+        let mut ids = dirty_tracer.ids().into_iter().collect::<HashSet<_>>();
+        ids.extend(blocks.iter().filter(|(_, entry)| entry.dirty).map(|(id, _)| *id));
+        ids
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn flush_block(
         &self,
         block_id: u64,
         wait: bool,
     ) -> Result<bool> {
+        let Some(ref backend) = self.backend else {
+            return Ok(false);
+        };
+
         Self::flush_block_static(
             block_id,
-            &self.blocks_dir,
+            backend,
             self.blocks.clone(),
             self.dirty_tracer.clone(),
+            self.codec.clone(),
             wait,
         ).await
     }
@@ -241,41 +758,55 @@ impl BlockCache {
     /// Flush a single block to disk.
     async fn flush_block_static(
         block_id: u64,
-        blocks_dir: &Path,
+        backend: &Arc<B>,
         blocks: Blocks,
         dirty_blocks: DirtyTracer,
+        codec: Codec,
         wait: bool,
     ) -> Result<bool> {
         match blocks.get(&block_id).await {
             Some(entry) => {
                 if entry.dirty {
-                    let path = Self::get_block_path_static(blocks_dir, block_id);
+                    let backend = backend.clone();
+                    let in_flight = dirty_blocks.start_write();
                     let handle: JoinHandle<Result<()>> = tokio::spawn(async move {
-                        if let Err(e) = Self::write_block_to_disk(&path, &entry.data).await {
-                            return Err(e.into());
+                        let _in_flight = in_flight;
+                        if let Err(e) = Self::write_block_with_retry(&backend, block_id, &entry.data, &codec).await {
+                            error!("Failed to write block {} to disk: {}", block_id, e);
+                            dirty_blocks.flush_failed(block_id, entry.last_modified, entry.data.len());
+                            return Err(e);
                         }
-                        if let Some(mut entry) = blocks.get(&block_id).await {
-                            entry.dirty = false;
-                            blocks.insert(block_id, entry).await;
-                        }
-
-                        dirty_blocks.remove(&block_id);
+                        // Untracked first, so a clean entry is never found tracked in between.
+                        dirty_blocks.clear_written(block_id, entry.last_modified);
+                        Self::mark_clean(&blocks, block_id, entry.last_modified).await;
+                        dirty_blocks.flushed();
                         Ok(())
                     });
 
                     if wait {
                         match handle.await {
-                            Ok(_) => {},
+                            Ok(result) => result?,
                             Err(e) => {
-                                return Err(BlockCacheError::FlushFailed("Failed to flush block!".into()).into());
+                                return Err(BlockCacheError::FlushFailed(e.to_string()).into());
                             }
                         }
                     }
 
                     Ok(true)
-                } else { Ok(false) }
+                } else {
+                    debug_assert!(
+                        !dirty_blocks.is_tracked(block_id, entry.last_modified),
+                        "clean block {} is tracked as dirty",
+                        block_id,
+                    );
+                    // Tracked from before being evicted and read back, already written out then.
+                    dirty_blocks.clear(block_id);
+                    Ok(false)
+                }
             }
             None => {
+                // Evicted entries are written out by the eviction listener.
+                dirty_blocks.clear(block_id);
                 Ok(false)
             }
         }
@@ -283,9 +814,10 @@ impl BlockCache {
 
     async fn periodic_flush_task(
         blocks: Blocks,
-        blocks_dir: PathBuf,
+        backend: Arc<B>,
         dirty_tracer: DirtyTracer,
-        flush_interval_secs: u64,
+        policy: Policy,
+        codec: Codec,
     ) {
         let mut interval = tokio::time::interval(Duration::from_secs(5));
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
@@ -294,121 +826,482 @@ impl BlockCache {
             interval.tick().await;
 
             let now = Instant::now();
-            let flush_threshold = now - Duration::from_secs(flush_interval_secs);
+            let dirty_bytes = dirty_tracer.bytes.load(Ordering::SeqCst) as u64;
+            for block_id in dirty_tracer.ids() {
+                let Some(entry) = blocks.get(&block_id).await.filter(|entry| entry.dirty) else {
+                    // Evicted entries are written out by the eviction listener.
+                    dirty_tracer.clear(block_id);
+                    continue;
+                };
+                if !policy.should_flush(&entry, now, dirty_bytes) {
+                    continue;
+                }
+
+                dirty_tracer.clear(block_id);
+                let backend = backend.clone();
+                let blocks_ref = blocks.clone();
+                let codec = codec.clone();
+                let in_flight = dirty_tracer.start_write();
+                let dirty_tracer = dirty_tracer.clone();
+
+                tokio::spawn(async move {
+                    let _in_flight = in_flight;
+                    if let Err(e) = Self::write_block_with_retry(&backend, block_id, &entry.data, &codec).await {
+                        // Still dirty with its original timestamp, so the next tick picks it up again.
+                        error!("Failed to write block {} to disk: {}", block_id, e);
+                        dirty_tracer.flush_failed(block_id, entry.last_modified, entry.data.len());
+                        return;
+                    }
+                    dirty_tracer.flushed();
+                    Self::mark_clean(&blocks_ref, block_id, entry.last_modified).await;
+                });
+            }
+        }
+    }
 
-            let blocks_to_flush = {
-                let expired = dirty_tracer.iter()
-                    .filter(|pair| *pair.value() <= flush_threshold)
-                    .map(|pair| *pair.key())
-                    .collect::<Vec<_>>();
+    /// Marks `block_id` clean once written out as last modified at `written`. Left dirty when
+    /// modified again in the meantime, as the newer contents still have to be written.
+    async fn mark_clean(blocks: &Blocks, block_id: u64, written: Instant) {
+        blocks.entry(block_id).and_compute_with(|entry| async move {
+            match entry.map(|entry| entry.into_value()) {
+                Some(entry) if entry.dirty && entry.last_modified == written => Op::Put(CacheEntry { dirty: false, ..entry }),
+                _ => Op::Nop,
+            }
+        }).await;
+    }
 
-                for id in &expired {
-                    dirty_tracer.remove(id);
+    /// Writes a block, retrying with exponential backoff as long as the error may go away by itself.
+    async fn write_block_with_retry(backend: &B, block_id: u64, data: &[u8], codec: &BlockCodec) -> Result<()> {
+        let mut backoff = WRITE_BACKOFF;
+        for attempt in 1.. {
+            match Self::write_block_to_disk(backend, block_id, data, codec).await {
+                Err(e) if attempt < WRITE_ATTEMPTS && is_transient(&e) => {
+                    warn!("Retrying write of block {} in {:?}: {}", block_id, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
                 }
+                result => return result,
+            }
+        }
+        unreachable!()
+    }
 
-                expired
-            };
+    async fn write_block_to_disk(backend: &B, block_id: u64, data: &[u8], codec: &BlockCodec) -> Result<()> {
+        // An empty block reads back the same as a missing one, there's nothing to store.
+        if data.is_empty() {
+            return backend.delete(block_id).await;
+        }
 
-            for block_id in blocks_to_flush {
-                if let Some(entry) = blocks.get(&block_id).await {
-                    if entry.dirty {
-                        let path = Self::get_block_path_static(&blocks_dir, block_id);
-                        let data = entry.data.clone();
+        let mut raw = codec.encode(block_id, data)?.into_owned();
+        let checksum = crc32fast::hash(&raw).to_le_bytes();
+        raw.extend_from_slice(&checksum);
+        backend.write(block_id, &raw).await?;
 
-                        let blocks_ref = blocks.clone();
+        if codec.verify_writes && backend.read(block_id).await?.is_none_or(|read| read != raw) {
+            error!("Block {} read back doesn't match what was written", block_id);
+            return Err(BlockCacheError::VerifyFailed(block_id).into());
+        }
+        Ok(())
+    }
 
-                        tokio::spawn(async move {
-                            if let Err(e) = Self::write_block_to_disk(&path, &data).await {
-                                panic!("Failed to write block to disk: {:?}", e);
-                            }
-                            if let Some(mut entry) = blocks_ref.get(&block_id).await {
-                                entry.dirty = false;
-                                blocks_ref.insert(block_id, entry).await;
-                            }
-                        });
-                    }
-                }
+    /// Flushes every dirty block and stops the background thread, after which nothing is written anymore.
+    pub async fn shutdown(&self) -> Result<()> {
+        if self.backend.is_none() {
+            return Ok(());
+        }
+
+        self.operation_sender.send(BlockOperation::ShutDown).await
+            .map_err(|e| BlockCacheError::FlushFailed(e.to_string()))?;
+
+        let mut handle_lock = self.bg_handle.lock().await;
+
+        if let Some(handle) = (*handle_lock).take() {
+            // Joined off this runtime, whose flush tasks the background thread may be waiting for.
+            let joined = tokio::task::spawn_blocking(move || handle.join()).await
+                .map_err(|e| BlockCacheError::FlushFailed(e.to_string()))?;
+            if let Err(e) = joined {
+                return Err(BlockCacheError::FlushFailed(format!("Failed to join background thread: {:?}", e)).into());
             }
         }
 
+        Ok(())
+    }
+}
+
+/// Keeps a [`BlockCache`] frozen while held, applying the writes made in the meantime when dropped.
+pub(crate) struct FrozenCache<B: BlockBackend = LocalFsBackend> {
+    cache: Arc<BlockCache<B>>,
+}
 
+impl<B: BlockBackend> Drop for FrozenCache<B> {
+    fn drop(&mut self) {
+        self.cache.thaw();
     }
+}
 
-    async fn write_block_to_disk(path: &Path, data: &[u8]) -> Result<()> {
-        let tmp_path = path.with_extension("tmp");
-        let mut file = tokio::fs::File::create(&tmp_path).await?;
+/// Whether a failed write is worth retrying: interrupted, would block, or out of space
+/// until trashed blocks are purged.
+fn is_transient(e: &TimeFSError) -> bool {
+    let (TimeFSError::Io(e) | TimeFSError::BlockCacheError(BlockCacheError::Io(e))) = e else {
+        return false;
+    };
+    matches!(e.raw_os_error(), Some(libc::EINTR | libc::EAGAIN | libc::ENOSPC))
+}
 
-        file.write_all(data).await?;
-        file.flush().await?;
-        file.sync_all().await?;
+/// Number of background flush workers used unless configured otherwise, one per CPU.
+pub(crate) fn default_flush_threads() -> NonZeroUsize {
+    std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+}
 
-        drop(file);
+/// Size of the CRC32 trailer appended to every block file.
+const CHECKSUM_SIZE: usize = 4;
 
-        tokio::fs::rename(tmp_path, path).await?;
-        Ok(())
+/// Checks the CRC32 trailer of a raw block file, returning the payload in front of it.
+fn verify_checksum(block_id: u64, raw: &[u8]) -> std::result::Result<&[u8], BlockCacheError> {
+    if raw.len() < CHECKSUM_SIZE {
+        return Err(BlockCacheError::Checksum(block_id));
     }
 
-    fn get_block_path_static(blocks_dir: &Path, block_id: u64) -> PathBuf {
-        let dir_id = block_id / 1000;
-        let dir_path = blocks_dir.join(format!("{:03}", dir_id));
+    let (payload, trailer) = raw.split_at(raw.len() - CHECKSUM_SIZE);
+    if crc32fast::hash(payload).to_le_bytes() != trailer {
+        return Err(BlockCacheError::Checksum(block_id));
+    }
+    Ok(payload)
+}
 
-        let _ = std::fs::create_dir_all(&dir_path);
+/// Makes sure blocks are read back the way they were written.
+///
+/// The first writable open with compression on leaves a marker at `path`, which only a
+/// filesystem without any blocks yet may get, and a filesystem with the marker can't be opened
+/// without compression.
+pub(crate) fn check_compression(enabled: bool, path: &Path, blocks_dir: &Path, writable: bool) -> Result<()> {
+    match (enabled, path.exists()) {
+        (true, false) if max_block_file_id(blocks_dir)? > 0 => {
+            Err(BlockCacheError::Compression("existing blocks were written uncompressed".into()).into())
+        }
+        (true, false) if writable => {
+            std::fs::write(path, [])?;
+            Ok(())
+        }
+        (false, true) => Err(BlockCacheError::Compression("filesystem compresses its blocks, compression must be enabled".into()).into()),
+        _ => Ok(()),
+    }
+}
+
+/// Parses the block id out of a `block_{id}.bin` file name.
+fn parse_block_file_name(name: &str) -> Option<u64> {
+    name.strip_prefix("block_")?.strip_suffix(".bin")?.parse().ok()
+}
+
+/// Outcome of verifying the checksum of every block file on disk.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ScrubReport {
+    pub(crate) good: u64,
+    pub(crate) corrupt: u64,
+    pub(crate) corrupt_blocks: Vec<u64>,
+}
 
-        dir_path.join(format!("block_{}.bin", block_id))
+/// Outcome of cleaning up the block files a crash may have left behind.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct BlockRepairReport {
+    /// Temporary files of writes that never got renamed into place.
+    pub(crate) removed_tmp_files: usize,
+    /// Blocks failing verification, moved out of the way.
+    pub(crate) quarantined: Vec<u64>,
+    /// Inodes referencing a quarantined block, with their path when it can be resolved.
+    pub(crate) affected_inodes: Vec<(u64, Option<PathBuf>)>,
+}
+
+/// Ids and paths of every block file under `blocks_dir`.
+fn block_files(blocks_dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    Ok(shard_files(blocks_dir)?
+        .into_iter()
+        .filter_map(|path| Some((path.file_name()?.to_str().and_then(parse_block_file_name)?, path)))
+        .collect())
+}
+
+/// Paths of every file in the shard directories under `blocks_dir`.
+fn shard_files(blocks_dir: &Path) -> Result<Vec<PathBuf>> {
+    let shards = match std::fs::read_dir(blocks_dir) {
+        Ok(shards) => shards,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for shard in shards {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+
+        for file in std::fs::read_dir(shard.path())? {
+            files.push(file?.path());
+        }
     }
+    Ok(files)
+}
 
-    async fn shutdown(&self) -> Result<()> {
-        self.operation_sender.send(BlockOperation::ShutDown)
-            .map_err(|e| BlockCacheError::FlushFailed(e.to_string()))?;
+/// Ids of every block file on disk.
+pub(crate) fn block_file_ids(blocks_dir: &Path) -> Result<HashSet<u64>> {
+    Ok(block_files(blocks_dir)?.into_iter().map(|(id, _)| id).collect())
+}
 
-        let mut handle_lock = self.bg_handle.lock().await;
+/// Highest id of any block file on disk, 0 when there are none.
+pub(crate) fn max_block_file_id(blocks_dir: &Path) -> Result<u64> {
+    Ok(block_files(blocks_dir)?.into_iter().map(|(id, _)| id).max().unwrap_or(0))
+}
 
-        if let Some(handle) = (*handle_lock).take() {
-            if let Err(e) = handle.join() {
-                return Err(BlockCacheError::FlushFailed(format!("Failed to join background thread: {:?}", e)).into());
+/// Walks every block file under `blocks_dir` and verifies its checksum.
+///
+/// Blocks are read straight from disk one at a time and never enter the cache, so memory use
+/// stays bounded by a single block regardless of the size of the filesystem.
+pub(crate) fn scrub(blocks_dir: &Path) -> Result<ScrubReport> {
+    let mut report = ScrubReport::default();
+
+    for (block_id, path) in block_files(blocks_dir)? {
+        let verified = std::fs::read(path)
+            .map_err(BlockCacheError::from)
+            .and_then(|raw| verify_checksum(block_id, &raw).map(|_| ()));
+        match verified {
+            Ok(_) => report.good += 1,
+            Err(e) => {
+                warn!("Block {} failed verification: {}", block_id, e);
+                report.corrupt += 1;
+                report.corrupt_blocks.push(block_id);
             }
         }
+    }
 
-        Ok(())
+    report.corrupt_blocks.sort_unstable();
+    Ok(report)
+}
+
+/// Whether `name` is the temporary file of a block write, `block_N.tmpK` as written now or
+/// `block_N.bin.tmp` as written before each write had a file of its own.
+fn is_block_tmp_file(name: &str) -> bool {
+    let Some((id, suffix)) = name.strip_prefix("block_").and_then(|rest| rest.split_once('.')) else {
+        return false;
+    };
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    is_number(id) && (suffix == "bin.tmp" || suffix.strip_prefix("tmp").is_some_and(is_number))
+}
+
+/// Deletes the temporary files of block writes cut short by a crash, and moves every block
+/// failing verification, such as one left truncated, into `lost_found` so it reads as a hole
+/// instead of failing.
+pub(crate) fn repair_blocks(blocks_dir: &Path, lost_found: &Path) -> Result<BlockRepairReport> {
+    let mut report = BlockRepairReport::default();
+    for path in shard_files(blocks_dir)? {
+        if path.file_name().and_then(|name| name.to_str()).is_some_and(is_block_tmp_file) {
+            std::fs::remove_file(&path)?;
+            report.removed_tmp_files += 1;
+        }
+    }
+
+    let corrupt = scrub(blocks_dir)?.corrupt_blocks;
+    if !corrupt.is_empty() {
+        std::fs::create_dir_all(lost_found)?;
+    }
+    for block_id in corrupt {
+        let path = LocalFsBackend::block_path(blocks_dir, block_id);
+        let quarantined = lost_found.join(path.file_name().unwrap());
+        std::fs::rename(&path, &quarantined)?;
+        crate::sync_parent_dir(&path)?;
+        crate::sync_parent_dir(&quarantined)?;
+        report.quarantined.push(block_id);
     }
+    Ok(report)
 }
 
 impl BlockRef {
     pub fn new(id: u64) -> Self {
+        Self::with_size(id, 0)
+    }
+
+    pub fn with_size(id: u64, size: u32) -> Self {
         Self {
             block_id: id,
+            size,
+            written_at: SystemTime::now(),
+        }
+    }
+
+    /// A hole in a sparse file, reads as zeros and owns no block on disk.
+    pub fn hole() -> Self {
+        Self {
+            block_id: 0,
             size: 0,
+            written_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.block_id
+    }
+
+    /// The same contents stored under another id, as when blocks are imported.
+    pub fn with_id(&self, block_id: u64) -> Self {
+        Self { block_id, ..self.clone() }
+    }
+
+    #[inline]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// When the contents of this block were last written.
+    #[inline]
+    pub fn written_at(&self) -> SystemTime {
+        self.written_at
+    }
+
+    #[inline]
+    pub fn is_hole(&self) -> bool {
+        self.block_id == 0
+    }
+}
+
+/// Number of holders (live files, snapshots) referencing each block, and the size of each.
+///
+/// A block referenced more than once is shared and must be copied before being modified.
+#[derive(Debug, Default)]
+pub(crate) struct BlockRefCounts {
+    counts: DashMap<u64, BlockUse>,
+    /// Sum of the sizes of every block referenced, the bytes of block storage in use.
+    bytes: AtomicU64,
+}
+
+/// References to a block and its size, as persisted between mounts.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct BlockUse {
+    refs: u32,
+    size: u32,
+}
+
+impl BlockRefCounts {
+    pub fn from_map(counts: HashMap<u64, BlockUse>) -> Self {
+        let bytes = counts.values().map(|block| block.size as u64).sum();
+        Self { counts: counts.into_iter().collect(), bytes: AtomicU64::new(bytes) }
+    }
+
+    pub fn to_map(&self) -> HashMap<u64, BlockUse> {
+        self.counts.iter().map(|e| (*e.key(), *e.value())).collect()
+    }
+
+    pub fn acquire(&self, block: &BlockRef) {
+        let mut entry = self.counts.entry(block.id()).or_default();
+        entry.refs += 1;
+        self.bytes.fetch_add(block.size as u64, Ordering::Relaxed);
+        self.bytes.fetch_sub(entry.size as u64, Ordering::Relaxed);
+        entry.size = block.size;
+    }
+
+    /// Records that `block_id` was rewritten in place with `size` bytes.
+    pub fn resize(&self, block_id: u64, size: u32) {
+        if let Some(mut entry) = self.counts.get_mut(&block_id) {
+            self.bytes.fetch_add(size as u64, Ordering::Relaxed);
+            self.bytes.fetch_sub(entry.size as u64, Ordering::Relaxed);
+            entry.size = size;
         }
     }
-    
-    pub fn alloc_blocks(start_id: u64, size: u64) -> Vec<Self> {
-        let block_size = BLOCK_SIZE as u64;
-        let blocks_amount = (size + block_size - 1) / block_size;
-        (start_id..start_id + blocks_amount)
-            .into_iter()
-            .map(|id| Self::new(id))
-            .collect()
+
+    /// Drops one reference, returning whether it was the last one and the block is now unused.
+    pub fn release_last(&self, block_id: u64) -> bool {
+        let removed = self.counts.remove_if_mut(&block_id, |_, entry| {
+            entry.refs = entry.refs.saturating_sub(1);
+            entry.refs == 0
+        });
+        if let Some((_, entry)) = removed {
+            self.bytes.fetch_sub(entry.size as u64, Ordering::Relaxed);
+        }
+        removed.is_some()
+    }
+
+    pub fn clear(&self) {
+        self.counts.clear();
+        self.bytes.store(0, Ordering::Relaxed);
+    }
+
+    /// Bytes of the distinct blocks still referenced.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn count(&self, block_id: u64) -> u32 {
+        self.counts.get(&block_id).map_or(0, |entry| entry.refs)
+    }
+
+    #[inline]
+    pub fn is_shared(&self, block_id: u64) -> bool {
+        self.count(block_id) > 1
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::{FaultyBackend, MemoryBackend};
+    use crate::error::TimeFSError;
     use tempfile::{tempdir, TempDir};
 
     fn setup_test_dir() -> TempDir {
         tempdir().expect("Failed to create test dir")
     }
 
+    impl BlockCache<LocalFsBackend> {
+        fn new(max_capacity: u64, blocks_dir: &Path, flush_interval_secs: u64, flush_threads: NonZeroUsize) -> Self {
+            Self::with_cipher(max_capacity, blocks_dir, flush_interval_secs, flush_threads, None)
+        }
+
+        /// Creates a cache whose blocks are encrypted on disk when `cipher` is given.
+        /// Blocks are always kept as plaintext in memory.
+        fn with_cipher(
+            max_capacity: u64,
+            blocks_dir: &Path,
+            flush_interval_secs: u64,
+            flush_threads: NonZeroUsize,
+            cipher: Option<BlockCipher>,
+        ) -> Self {
+            Self::with_codec(max_capacity, blocks_dir, flush_interval_secs, flush_threads, BlockCodec::new(cipher), CachePolicy::default())
+        }
+
+        /// Creates a cache writing blocks to disk through `codec`, evicting them as `cache_policy` says.
+        fn with_codec(
+            max_capacity: u64,
+            blocks_dir: &Path,
+            flush_interval_secs: u64,
+            flush_threads: NonZeroUsize,
+            codec: BlockCodec,
+            cache_policy: CachePolicy,
+        ) -> Self {
+            let policy: Policy = Arc::new(AgePolicy::new(Duration::from_secs(flush_interval_secs)));
+            Self::with_flush_policy(max_capacity, blocks_dir, flush_threads, codec, Some(policy), cache_policy)
+        }
+    }
+
+    /// Opens a cache writing through `backend`, flushing blocks older than `flush_interval_secs`.
+    fn faulty_cache(backend: &FaultyBackend, flush_interval_secs: u64, codec: BlockCodec) -> BlockCache<FaultyBackend> {
+        let policy: Policy = Arc::new(AgePolicy::new(Duration::from_secs(flush_interval_secs)));
+        BlockCache::with_backend(1000, backend.clone(), default_flush_threads(), codec, Some(policy), CachePolicy::default())
+    }
+
+    /// Reads a block file, checking and stripping its checksum trailer.
+    fn read_block_file(path: &Path) -> Result<Vec<u8>> {
+        let raw = std::fs::read(path)?;
+        Ok(verify_checksum(0, &raw)?.to_vec())
+    }
+
     #[tokio::test]
     async fn test_basic_read_write() {
         let temp_dir = setup_test_dir();
         let cache_dir = temp_dir.path().to_path_buf();
 
-        let mut cache = BlockCache::new(
+        let cache = BlockCache::new(
             1000,
             &cache_dir,
             30,
+            default_flush_threads(),
         );
 
         let block_id = 42;
@@ -422,6 +1315,306 @@ mod tests {
         cache.shutdown().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_memory_backend() -> Result<()> {
+        let backend = MemoryBackend::default();
+        let open = || BlockCache::with_backend(1000, backend.clone(), default_flush_threads(), BlockCodec::default(), None, CachePolicy::default());
+
+        let cache = open();
+        cache.update_block(1, b"Hello, Backend!".to_vec()).await?;
+        assert_eq!(cache.get_block(1).await?, b"Hello, Backend!");
+        assert!(backend.blocks.is_empty(), "nothing is written before flushing");
+        assert!(cache.flush_block(1, true).await?);
+        assert_eq!(verify_checksum(1, &backend.blocks.get(&1).unwrap())?, b"Hello, Backend!");
+
+        // Changes stay in the cache until flushed, here on shutdown.
+        cache.update_block(1, b"Updated".to_vec()).await?;
+        cache.update_block(2, b"Second".to_vec()).await?;
+        assert_eq!(verify_checksum(1, &backend.blocks.get(&1).unwrap())?, b"Hello, Backend!");
+        cache.shutdown().await?;
+
+        let cache = open();
+        assert_eq!(cache.get_block(1).await?, b"Updated");
+        assert_eq!(cache.get_block(2).await?, b"Second");
+        assert!(cache.get_block(3).await?.is_empty(), "missing blocks read as empty");
+        let mut ids = cache.block_ids().await?;
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        cache.update_block(2, Vec::new()).await?;
+        cache.flush_block(2, true).await?;
+        assert!(!backend.blocks.contains_key(&2), "empty blocks are deleted");
+        assert!(cache.get_block(2).await?.is_empty());
+        cache.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_write_syncs_directory() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let cache = BlockCache::new(1000, &cache_dir, 30, default_flush_threads());
+
+        let block_id = 7;
+        cache.update_block(block_id, b"durable".to_vec()).await?;
+
+        let syncs = || crate::DIR_SYNCS.with(|syncs| syncs.get());
+        let syncs_before = syncs();
+        assert!(cache.flush_block(block_id, true).await?);
+        assert!(syncs() > syncs_before, "rename should be followed by a directory fsync");
+
+        let block_path = cache_dir.join("000").join(format!("block_{}.bin", block_id));
+        assert_eq!(read_block_file(&block_path)?, b"durable");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dirty_high_water_triggers_flush() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let block_size = BLOCK_SIZE as usize;
+        let high_water = 4 * block_size;
+
+        // The flush interval is far beyond the test, so only the dirty byte count can trigger a flush.
+        let cache = BlockCache::new(1000, &cache_dir, 3600, default_flush_threads())
+            .with_dirty_high_water(Some(high_water));
+
+        // The fifth block passes the mark, flushing every block dirty by then. Blocks written after
+        // the flush took its list would wait for the interval, so there are none.
+        for block_id in 1..=5 {
+            cache.update_block(block_id, vec![block_id as u8; block_size]).await?;
+        }
+
+        let block_path = |block_id: u64| cache_dir.join("000").join(format!("block_{}.bin", block_id));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while cache.dirty_bytes() > 0 || !(1..=5).all(|id| block_path(id).exists()) {
+            assert!(Instant::now() < deadline, "passing the high-water mark should start flushing");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(read_block_file(&block_path(1))?, vec![1; block_size]);
+
+        // Writing far past the mark makes the writer flush before returning.
+        for block_id in 10..20 {
+            cache.update_block(block_id, vec![0; block_size]).await?;
+            assert!(cache.dirty_bytes() <= 2 * high_water);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_operation_queue_stays_bounded_under_write_bursts() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let block_size = BLOCK_SIZE as usize;
+        // With a mark this low the writers keep crossing it, sending flush after flush.
+        let cache = Arc::new(BlockCache::new(10_000, &cache_dir, 3600, default_flush_threads()).with_dirty_high_water(Some(4 * block_size)));
+
+        let writers = (0..200u64).map(|block_id| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.update_block(block_id + 1, vec![block_id as u8; block_size]).await })
+        }).collect::<Vec<_>>();
+        while !writers.iter().all(JoinHandle::is_finished) {
+            assert!(cache.queued_operations() <= OPERATION_QUEUE_CAPACITY);
+            tokio::task::yield_now().await;
+        }
+        for writer in writers {
+            writer.await.expect("Failed to join")?;
+        }
+
+        cache.shutdown().await?;
+        let block_path = |block_id: u64| cache_dir.join(format!("{:03}", block_id / 1000)).join(format!("block_{}.bin", block_id));
+        for block_id in 0..200u64 {
+            assert_eq!(read_block_file(&block_path(block_id + 1))?, vec![block_id as u8; block_size]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transient_write_failure_is_retried() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let block_path = |block_id: u64| cache_dir.join(format!("{:03}", block_id / 1000)).join(format!("block_{}.bin", block_id));
+
+        // A couple of failures are absorbed by retrying the write.
+        let backend = FaultyBackend::new(&cache_dir);
+        let cache = faulty_cache(&backend, 3600, BlockCodec::default());
+        let block_id = 900_001;
+        backend.write_failures.insert(block_id, 2);
+        cache.update_block(block_id, b"retried".to_vec()).await?;
+        assert!(cache.flush_block(block_id, true).await?);
+        assert_eq!(read_block_file(&block_path(block_id))?, b"retried");
+        assert_eq!(cache.flush_errors(), 0);
+
+        // Failing every attempt leaves the block dirty for the periodic flush to retry.
+        let cache = faulty_cache(&backend, 0, BlockCodec::default());
+        let block_id = 900_002;
+        backend.write_failures.insert(block_id, WRITE_ATTEMPTS);
+        cache.update_block(block_id, b"eventually".to_vec()).await?;
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while !block_path(block_id).exists() {
+            assert!(Instant::now() < deadline, "the block should eventually be flushed");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(read_block_file(&block_path(block_id))?, b"eventually");
+        assert_eq!(cache.flush_errors(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cross_device_rename_falls_back_to_copy() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let backend = FaultyBackend::new(&cache_dir);
+        let cache = faulty_cache(&backend, 3600, BlockCodec::default());
+
+        let block_id = 910_001;
+        backend.cross_device.insert(block_id);
+        cache.update_block(block_id, b"copied across".to_vec()).await?;
+        assert!(cache.flush_block(block_id, true).await?);
+        cache.update_block(block_id, b"and over again".to_vec()).await?;
+        assert!(cache.flush_block(block_id, true).await?);
+
+        let shard = cache_dir.join(format!("{:03}", block_id / 1000));
+        assert_eq!(read_block_file(&shard.join(format!("block_{}.bin", block_id)))?, b"and over again");
+        let names = std::fs::read_dir(&shard)?.map(|e| Ok(e?.file_name())).collect::<Result<Vec<_>>>()?;
+        assert_eq!(names, vec![std::ffi::OsString::from(format!("block_{}.bin", block_id))], "no temporary file is left");
+        assert_eq!(cache.flush_errors(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_writes_reads_blocks_back() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let backend = FaultyBackend::new(&cache_dir);
+        let cache = faulty_cache(&backend, 3600, BlockCodec::default().with_verify_writes(true));
+
+        let (good, corrupted) = (920_001, 920_002);
+        backend.corrupt_writes.insert(corrupted);
+        cache.update_block(good, b"verified".to_vec()).await?;
+        assert!(cache.flush_block(good, true).await?);
+        let shard = cache_dir.join(format!("{:03}", good / 1000));
+        assert_eq!(read_block_file(&shard.join(format!("block_{}.bin", good)))?, b"verified");
+
+        cache.update_block(corrupted, b"lost by the disk".to_vec()).await?;
+        let result = cache.flush_block(corrupted, true).await;
+        assert!(matches!(result, Err(TimeFSError::BlockCacheError(BlockCacheError::VerifyFailed(id))) if id == corrupted));
+        assert_eq!((cache.flush_errors(), cache.dirty_blocks()), (1, 1));
+
+        // Without verifying, the write goes unnoticed until the block is read back.
+        let unverified_dir = setup_test_dir();
+        let unverified_backend = FaultyBackend::new(unverified_dir.path());
+        unverified_backend.corrupt_writes.insert(corrupted);
+        let unverified = faulty_cache(&unverified_backend, 3600, BlockCodec::default());
+        unverified.update_block(corrupted, b"lost by the disk".to_vec()).await?;
+        assert!(unverified.flush_block(corrupted, true).await?);
+        assert_eq!(unverified.flush_errors(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_flush_policy() -> Result<()> {
+        struct Immediate;
+
+        impl FlushPolicy for Immediate {
+            fn should_flush(&self, _entry: &CacheEntry, _now: Instant, _dirty_bytes: u64) -> bool {
+                true
+            }
+        }
+
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let cache = BlockCache::with_flush_policy(1000, &cache_dir, default_flush_threads(), BlockCodec::default(), Some(Arc::new(Immediate)), CachePolicy::default());
+
+        let block_id = 11;
+        cache.update_block(block_id, b"right away".to_vec()).await?;
+
+        // Ticks come every five seconds, the default age policy would wait for 30 more.
+        let block_path = cache_dir.join("000").join(format!("block_{}.bin", block_id));
+        let deadline = Instant::now() + Duration::from_secs(7);
+        while !block_path.exists() {
+            assert!(Instant::now() < deadline, "the block should be flushed on the next tick");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(read_block_file(&block_path)?, b"right away");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clean_blocks_are_never_flushed() -> Result<()> {
+        struct Immediate;
+
+        impl FlushPolicy for Immediate {
+            fn should_flush(&self, _entry: &CacheEntry, _now: Instant, _dirty_bytes: u64) -> bool {
+                true
+            }
+        }
+
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let block_id = 12;
+        let writer = BlockCache::new(1000, &cache_dir, 30, default_flush_threads());
+        writer.update_block(block_id, b"on disk".to_vec()).await?;
+        writer.shutdown().await?;
+        let block_path = cache_dir.join("000").join(format!("block_{}.bin", block_id));
+        let written_at = std::fs::metadata(&block_path)?.modified()?;
+
+        // Any dirty block would be written out on the first tick after the one at startup.
+        let cache = BlockCache::with_flush_policy(1000, &cache_dir, default_flush_threads(), BlockCodec::default(), Some(Arc::new(Immediate)), CachePolicy::default());
+        assert_eq!(cache.get_block(block_id).await?, b"on disk");
+        assert!(cache.get_block(13).await?.is_empty(), "missing blocks read as empty");
+        assert_eq!(cache.dirty_blocks(), 0);
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(cache.dirty_bytes(), 0);
+        assert!(cache.last_flush().is_none(), "nothing should have been written");
+        assert_eq!(std::fs::metadata(&block_path)?.modified()?, written_at);
+        assert!(!cache_dir.join("000").join("block_13.bin").exists());
+        cache.shutdown().await?;
+        assert!(cache.last_flush().is_none(), "clean blocks aren't written on shutdown either");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_block_is_not_evicted() -> Result<()> {
+        // In memory, evicted blocks are gone for good, which makes an eviction easy to spot.
+        let cache = BlockCache::in_memory(2);
+
+        cache.update_block(1, b"mapped".to_vec()).await?;
+        cache.pin_block(1).await?;
+        for block_id in 2..10 {
+            cache.update_block(block_id, vec![block_id as u8]).await?;
+        }
+        cache.update_block(1, b"rewritten".to_vec()).await?;
+        for block_id in 10..20 {
+            cache.update_block(block_id, vec![block_id as u8]).await?;
+        }
+        cache.blocks.run_pending_tasks().await;
+        assert_eq!(cache.get_block(1).await?, b"rewritten");
+
+        cache.pin_block(1).await?;
+        cache.unpin_block(1).await?;
+        assert!(cache.is_pinned(1), "pins are counted");
+        cache.unpin_block(1).await?;
+        assert!(!cache.is_pinned(1));
+        assert!(cache.blocks.get(&1).await.is_some_and(|entry| entry.weight() == 1), "unpinned blocks can be evicted again");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_cache() -> Result<()> {
+        let cache = BlockCache::in_memory(2);
+
+        cache.update_block(1, b"first".to_vec()).await?;
+        cache.update_block(2, b"second".to_vec()).await?;
+        assert_eq!(cache.get_block(1).await?, b"first");
+        assert!(!cache.flush_block(1, true).await?, "nothing is ever dirty in memory");
+        assert_eq!(cache.get_block(3).await?, b"", "unknown blocks read as empty");
+        cache.shutdown().await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_persistence_after_shutdown() -> Result<()> {
         let temp_dir = setup_test_dir();
@@ -431,12 +1624,12 @@ mod tests {
         let test_data = b"Persistent data".to_vec();
 
         {
-            let mut cache = BlockCache::new(1000, &cache_dir, 30);
+            let cache = BlockCache::new(1000, &cache_dir, 30, default_flush_threads());
             cache.update_block(block_id, test_data.clone()).await?;
             cache.shutdown().await?;
         }
         {
-            let mut cache = BlockCache::new(1000, &cache_dir, 30);
+            let cache = BlockCache::new(1000, &cache_dir, 30, default_flush_threads());
             let data = cache.get_block(block_id).await?;
             assert_eq!(test_data, data, "Should equal");
         }
@@ -449,7 +1642,7 @@ mod tests {
         let cache_dir = temp_dir.path().to_path_buf();
 
         let flush_interval_secs = 2;
-        let mut cache = BlockCache::new(1000, &cache_dir, flush_interval_secs);
+        let cache = BlockCache::new(1000, &cache_dir, flush_interval_secs, default_flush_threads());
 
         let block_id = 200;
         let test_data = b"This will be auto-flushed".to_vec();
@@ -460,13 +1653,13 @@ mod tests {
         assert_eq!(test_data, read_data, "Data should be contained in mem");
 
         let block_path = cache_dir.join("000").join(format!("block_{}.bin", block_id));
-        assert!(!block_path.exists() || std::fs::read(&block_path)? != test_data, "block shouldn't be flushed immediately");
+        assert!(!block_path.exists() || read_block_file(&block_path)? != test_data, "block shouldn't be flushed immediately");
 
         tokio::time::sleep(Duration::from_secs(flush_interval_secs + 5)).await;
 
         assert!(block_path.exists(), "block should be flushed to disk");
 
-        let disk_data = tokio::fs::read(&block_path).await?;
+        let disk_data = read_block_file(&block_path)?;
 
         assert_eq!(test_data, disk_data);
         cache.shutdown().await?;
@@ -479,7 +1672,7 @@ mod tests {
         let cache_dir = tempfile.path().to_path_buf();
 
         // a long interval
-        let mut cache = BlockCache::new(1000, &cache_dir, 3600);
+        let cache = BlockCache::new(1000, &cache_dir, 3600, default_flush_threads());
 
         let block_id = 42;
         let data = b"This will be flushed on shutdown".to_vec();
@@ -487,23 +1680,60 @@ mod tests {
         cache.update_block(block_id, data.clone()).await?;
 
         let block_path = cache_dir.join("000").join(format!("block_{}.bin", block_id));
-        assert!(!block_path.exists() || std::fs::read(&block_path)? != data, "block shouldn't be flushed immediately");
+        assert!(!block_path.exists() || read_block_file(&block_path)? != data, "block shouldn't be flushed immediately");
 
         cache.shutdown().await?;
 
         assert!(block_path.exists(), "block should be flushed to disk");
-        let disk_data = tokio::fs::read(&block_path).await?;
+        let disk_data = read_block_file(&block_path)?;
         assert_eq!(data, disk_data);
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_shutdown_waits_for_writes_in_flight() -> Result<()> {
+        struct Immediate;
+
+        impl FlushPolicy for Immediate {
+            fn should_flush(&self, _entry: &CacheEntry, _now: Instant, _dirty_bytes: u64) -> bool {
+                true
+            }
+        }
+
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let backend = FaultyBackend::new(&cache_dir);
+        let cache = BlockCache::with_backend(1000, backend.clone(), default_flush_threads(), BlockCodec::default(), Some(Arc::new(Immediate)), CachePolicy::default());
+        let block_path = |block_id: u64| cache_dir.join(format!("{:03}", block_id / 1000)).join(format!("block_{}.bin", block_id));
+
+        // Retrying slows the writes down, so the periodic flush is still busy with them below.
+        let block_ids = 910_001..910_020;
+        for block_id in block_ids.clone() {
+            backend.write_failures.insert(block_id, WRITE_ATTEMPTS - 1);
+            cache.update_block(block_id, block_id.to_le_bytes().to_vec()).await?;
+        }
+        let deadline = Instant::now() + Duration::from_secs(7);
+        while cache.dirty_bytes() > 0 {
+            assert!(Instant::now() < deadline, "the periodic flush should pick the blocks up");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!block_ids.clone().all(|id| block_path(id).exists()), "writes should still be in flight");
+
+        // As on a lazy unmount under load, nothing waits for the writes before shutting down.
+        cache.shutdown().await?;
+        for block_id in block_ids {
+            assert_eq!(read_block_file(&block_path(block_id))?, block_id.to_le_bytes());
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_concurrent_access() -> Result<()> {
         let temp_dir = setup_test_dir();
         let cache_dir = temp_dir.path().to_path_buf();
         let mut handles = vec![];
 
-        let cache = Arc::new(BlockCache::new(1000, &cache_dir, 30));
+        let cache = Arc::new(BlockCache::new(1000, &cache_dir, 30, default_flush_threads()));
 
         for id in 0..100 {
             let cache_cloned = cache.clone();
@@ -538,7 +1768,7 @@ mod tests {
         let cache_dir = temp_dir.path().to_path_buf();
 
         let max_blocks = 5;
-        let cache = BlockCache::new(max_blocks, &cache_dir, 30);
+        let cache = BlockCache::new(max_blocks, &cache_dir, 30, default_flush_threads());
 
         let block_count = max_blocks * 2;
         let mut block_data = HashMap::new();
@@ -561,12 +1791,44 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_scan_evicts_and_flushes_under_every_policy() -> Result<()> {
+        for cache_policy in [CachePolicy::TinyLfu, CachePolicy::Lru] {
+            let temp_dir = setup_test_dir();
+            let cache_dir = temp_dir.path().to_path_buf();
+            let max_blocks = 4;
+            let cache = BlockCache::with_codec(max_blocks, &cache_dir, 3600, default_flush_threads(), BlockCodec::default(), cache_policy);
+
+            // One hot block read between the writes of a scan far larger than the cache.
+            let data = |block_id: u64| format!("{:?} block {}", cache_policy, block_id).into_bytes();
+            cache.update_block(1, data(1)).await?;
+            for block_id in 100..100 + 8 * max_blocks {
+                cache.update_block(block_id, data(block_id)).await?;
+                assert_eq!(cache.get_block(1).await?, data(1));
+            }
+            cache.blocks.run_pending_tasks().await;
+
+            let block_ids = std::iter::once(1).chain(100..100 + 8 * max_blocks).collect::<Vec<_>>();
+            let evicted = block_ids.iter().filter(|&&id| !cache.is_cached(id)).collect::<Vec<_>>();
+            assert!(evicted.len() as u64 >= block_ids.len() as u64 - max_blocks, "{:?}: {} evicted", cache_policy, evicted.len());
+            for &block_id in evicted {
+                let path = cache_dir.join("000").join(format!("block_{}.bin", block_id));
+                assert_eq!(read_block_file(&path)?, data(block_id), "{:?}: block {} evicted unflushed", cache_policy, block_id);
+            }
+            for &block_id in &block_ids {
+                assert_eq!(cache.get_block(block_id).await?, data(block_id), "{:?}", cache_policy);
+            }
+            cache.shutdown().await?;
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_dirty_block_tracking() -> Result<()> {
         let temp_dir = setup_test_dir();
         let cache_dir = temp_dir.path().to_path_buf();
 
-        let cache = BlockCache::new(1000, &cache_dir, 30);
+        let cache = BlockCache::new(1000, &cache_dir, 30, default_flush_threads());
 
         let block_id = 4000;
         let initial_data = b"Initial data".to_vec();
@@ -576,12 +1838,12 @@ mod tests {
         cache.flush_block(block_id, true).await?;
 
         let block_path = cache_dir.join("004").join(format!("block_{}.bin", block_id));
-        let disk_data = std::fs::read(&block_path)?;
+        let disk_data = read_block_file(&block_path)?;
         assert_eq!(disk_data, initial_data, "Data should be equal");
 
         cache.update_block(block_id, update_data.clone()).await?;
 
-        let disk_data = std::fs::read(&block_path)?;
+        let disk_data = read_block_file(&block_path)?;
         assert_eq!(disk_data, initial_data);
 
         let cached_data = cache.get_block(block_id).await?;
@@ -589,8 +1851,134 @@ mod tests {
 
         cache.shutdown().await?;
 
-        let final_data = std::fs::read(&block_path)?;
+        let final_data = read_block_file(&block_path)?;
         assert_eq!(final_data, update_data);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_encrypted_blocks() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+        let key = [7u8; crate::crypto::KEY_SIZE];
+
+        let block_id = 5000;
+        let data = b"Top secret block".to_vec();
+
+        {
+            let cache = BlockCache::with_cipher(1000, &cache_dir, 30, default_flush_threads(), Some(BlockCipher::new(&key)));
+            cache.update_block(block_id, data.clone()).await?;
+            cache.shutdown().await?;
+        }
+
+        let block_path = cache_dir.join("005").join(format!("block_{}.bin", block_id));
+        let disk_data = std::fs::read(&block_path)?;
+        assert!(!disk_data.windows(data.len()).any(|w| w == data.as_slice()), "block should not be stored as plaintext");
+
+        {
+            let cache = BlockCache::with_cipher(1000, &cache_dir, 30, default_flush_threads(), Some(BlockCipher::new(&key)));
+            assert_eq!(cache.get_block(block_id).await?, data);
+        }
+        {
+            let wrong_key = [8u8; crate::crypto::KEY_SIZE];
+            let cache = BlockCache::with_cipher(1000, &cache_dir, 30, default_flush_threads(), Some(BlockCipher::new(&wrong_key)));
+            let result = cache.get_block(block_id).await;
+            assert!(matches!(result, Err(TimeFSError::BlockCacheError(BlockCacheError::Crypto(5000)))));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scrub_detects_corrupt_block() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().to_path_buf();
+
+        let cache = BlockCache::new(1000, &cache_dir, 30, default_flush_threads());
+        for block_id in [6000, 6001, 6002] {
+            cache.update_block(block_id, format!("Scrub data {}", block_id).into_bytes()).await?;
+        }
+        cache.shutdown().await?;
+
+        let block_path = cache_dir.join("006").join("block_6001.bin");
+        let mut raw = std::fs::read(&block_path)?;
+        raw[0] ^= 0xff;
+        std::fs::write(&block_path, raw)?;
+
+        let report = scrub(&cache_dir)?;
+        assert_eq!(report.good, 2);
+        assert_eq!(report.corrupt, 1);
+        assert_eq!(report.corrupt_blocks, vec![6001]);
+
+        let cache = BlockCache::new(1000, &cache_dir, 30, default_flush_threads());
+        let result = cache.get_block(6001).await;
+        assert!(matches!(result, Err(TimeFSError::BlockCacheError(BlockCacheError::Checksum(6001)))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_flush_threads() -> Result<()> {
+        for threads in [1, 4] {
+            let temp_dir = setup_test_dir();
+            let cache_dir = temp_dir.path().to_path_buf();
+
+            let cache = BlockCache::new(1000, &cache_dir, 3600, NonZeroUsize::new(threads).unwrap());
+            for block_id in 7000..7010 {
+                cache.update_block(block_id, format!("Flushed by {} threads", threads).into_bytes()).await?;
+            }
+            cache.flush_block(7000, true).await?;
+            cache.shutdown().await?;
+
+            for block_id in 7000..7010 {
+                let block_path = cache_dir.join("007").join(format!("block_{}.bin", block_id));
+                assert_eq!(read_block_file(&block_path)?, format!("Flushed by {} threads", threads).into_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies a block directory the way a backup tool would, file by file.
+    fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            let target = to.join(entry.file_name());
+            match entry.file_type()?.is_dir() {
+                true => copy_dir(&entry.path(), &target)?,
+                false => { std::fs::copy(entry.path(), target)?; }
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_freeze_holds_writes_back_from_disk() -> Result<()> {
+        let temp_dir = setup_test_dir();
+        let cache_dir = temp_dir.path().join("blocks");
+        let backup_dir = temp_dir.path().join("backup");
+        let block_path = |dir: &Path, block_id: u64| dir.join("008").join(format!("block_{}.bin", block_id));
+
+        let cache = Arc::new(BlockCache::new(1000, &cache_dir, 3600, default_flush_threads()));
+        cache.update_block(8000, b"before freeze".to_vec()).await?;
+
+        let frozen = cache.freeze().await?;
+        assert_eq!(read_block_file(&block_path(&cache_dir, 8000))?, b"before freeze", "freezing flushes dirty blocks");
+        assert!(matches!(cache.freeze().await, Err(TimeFSError::BlockCacheError(BlockCacheError::AlreadyFrozen))));
+
+        cache.update_block(8000, b"during freeze".to_vec()).await?;
+        cache.update_block(8001, b"new during freeze".to_vec()).await?;
+        assert_eq!(cache.get_block(8000).await?, b"during freeze");
+        assert_eq!(cache.dirty_bytes(), 0);
+
+        copy_dir(&cache_dir, &backup_dir)?;
+        assert_eq!(read_block_file(&block_path(&backup_dir, 8000))?, b"before freeze");
+        assert!(!block_path(&backup_dir, 8001).exists());
+
+        drop(frozen);
+        assert_eq!(cache.get_block(8001).await?, b"new during freeze");
+        cache.flush_block(8000, true).await?;
+        cache.flush_block(8001, true).await?;
+        assert_eq!(read_block_file(&block_path(&cache_dir, 8000))?, b"during freeze");
+        assert_eq!(read_block_file(&block_path(&cache_dir, 8001))?, b"new during freeze");
+        cache.shutdown().await
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use parking_lot::RwLock;
+use crate::block::BlockCacheError;
+
+/// Tag of blocks that didn't get any smaller and are stored as they are.
+const STORED: u8 = 0;
+const ZSTD: u8 = 1;
+const ZSTD_DICT: u8 = 2;
+/// Tag, dictionary id and uncompressed length in front of every block.
+const HEADER_SIZE: usize = 9;
+/// Largest dictionary trained from sample blocks.
+pub(crate) const MAX_DICTIONARY_SIZE: usize = 64 * 1024;
+
+/// Zstd compression of blocks at rest, with dictionaries trained on the filesystem's own blocks.
+///
+/// A compressed block is laid out as `tag || dictionary id || length || data`, the id being 0
+/// without a dictionary. Dictionaries are never deleted, so blocks compressed with an older one
+/// stay readable after a newer one is trained, which new writes then use.
+pub(crate) struct BlockCompressor {
+    level: i32,
+    /// Where dictionaries are kept.
+    dictionaries_dir: PathBuf,
+    dictionaries: RwLock<Dictionaries>,
+}
+
+#[derive(Default)]
+struct Dictionaries {
+    by_id: HashMap<u32, Arc<Vec<u8>>>,
+    /// Id of the dictionary new blocks are compressed with, 0 before any is trained.
+    current: u32,
+}
+
+impl BlockCompressor {
+    /// Creates a compressor keeping its dictionaries in `dir`, loading those trained before.
+    pub fn open(level: i32, dir: impl AsRef<Path>) -> crate::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut dictionaries = Dictionaries::default();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Some(id) = entry.file_name().to_str().and_then(parse_dictionary_file_name) else {
+                continue;
+            };
+            dictionaries.by_id.insert(id, Arc::new(std::fs::read(entry.path())?));
+            dictionaries.current = dictionaries.current.max(id);
+        }
+        Ok(Self { level, dictionaries_dir: dir, dictionaries: RwLock::new(dictionaries) })
+    }
+
+    /// Trains a dictionary on `samples` and compresses every block written from now on with it,
+    /// returning its id.
+    pub fn train<S: AsRef<[u8]>>(&self, samples: &[S]) -> crate::Result<u32> {
+        let dictionary = zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE)
+            .map_err(|e| BlockCacheError::Compression(format!("failed to train a dictionary: {}", e)))?;
+
+        let mut dictionaries = self.dictionaries.write();
+        let id = dictionaries.by_id.keys().max().map_or(1, |id| id + 1);
+        let path = self.dictionaries_dir.join(format!("dict_{}.bin", id));
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, &dictionary)?;
+        std::fs::rename(&tmp_path, &path)?;
+        crate::sync_parent_dir(&path)?;
+        dictionaries.by_id.insert(id, Arc::new(dictionary));
+        dictionaries.current = id;
+        Ok(id)
+    }
+
+    pub fn compress(&self, block_id: u64, data: &[u8]) -> Result<Vec<u8>, BlockCacheError> {
+        let (id, dictionary) = {
+            let dictionaries = self.dictionaries.read();
+            let id = dictionaries.current;
+            (id, dictionaries.by_id.get(&id).cloned())
+        };
+        let compressed = match dictionary {
+            Some(ref dictionary) => zstd::bulk::Compressor::with_dictionary(self.level, dictionary)
+                .and_then(|mut compressor| compressor.compress(data)),
+            None => zstd::bulk::compress(data, self.level),
+        };
+        let compressed = compressed.map_err(|e| BlockCacheError::Compression(format!("failed to compress block {}: {}", block_id, e)))?;
+
+        let (tag, id, payload) = match (compressed.len() < data.len(), dictionary) {
+            (false, _) => (STORED, 0, data),
+            (true, Some(_)) => (ZSTD_DICT, id, compressed.as_slice()),
+            (true, None) => (ZSTD, 0, compressed.as_slice()),
+        };
+        let mut out = Vec::with_capacity(HEADER_SIZE + payload.len());
+        out.push(tag);
+        out.extend_from_slice(&id.to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+        Ok(out)
+    }
+
+    pub fn decompress(&self, block_id: u64, data: &[u8]) -> Result<Vec<u8>, BlockCacheError> {
+        let corrupt = |reason: &str| BlockCacheError::Compression(format!("failed to decompress block {}: {}", block_id, reason));
+        if data.len() < HEADER_SIZE {
+            return Err(corrupt("header is cut short"));
+        }
+        let (header, payload) = data.split_at(HEADER_SIZE);
+        let id = u32::from_le_bytes(header[1..5].try_into().unwrap());
+        let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+
+        let decompressed = match header[0] {
+            STORED => return Ok(payload.to_vec()),
+            ZSTD => zstd::bulk::decompress(payload, len),
+            ZSTD_DICT => {
+                let dictionary = self.dictionaries.read().by_id.get(&id).cloned()
+                    .ok_or_else(|| corrupt(&format!("dictionary {} is missing", id)))?;
+                zstd::bulk::Decompressor::with_dictionary(&dictionary)
+                    .and_then(|mut decompressor| decompressor.decompress(payload, len))
+            }
+            tag => return Err(corrupt(&format!("unknown tag {}", tag))),
+        };
+        decompressed.map_err(|e| corrupt(&e.to_string()))
+    }
+}
+
+/// Parses the dictionary id out of a `dict_{id}.bin` file name.
+fn parse_dictionary_file_name(name: &str) -> Option<u32> {
+    name.strip_prefix("dict_")?.strip_suffix(".bin")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Small records sharing most of their structure, as many small files of one kind would.
+    fn sample_block(i: usize) -> Vec<u8> {
+        format!(
+            "{{\"id\": {}, \"kind\": \"invoice\", \"customer\": \"customer-{}\", \"currency\": \"EUR\", \"status\": \"{}\", \"lines\": [{{\"sku\": \"SKU-{:05}\", \"qty\": {}}}]}}",
+            i, i % 17, ["open", "paid", "void"][i % 3], i * 7, i % 5 + 1,
+        ).into_bytes()
+    }
+
+    #[test]
+    fn test_dictionary_shrinks_small_blocks() -> crate::Result<()> {
+        let temp_dir = tempdir()?;
+        let compressor = BlockCompressor::open(3, temp_dir.path())?;
+        let samples = (0..1000).map(sample_block).collect::<Vec<_>>();
+
+        let block = sample_block(4242);
+        let plain = compressor.compress(1, &block)?;
+        assert_eq!(compressor.decompress(1, &plain)?, block);
+
+        let id = compressor.train(&samples)?;
+        assert_eq!(compressor.dictionaries.read().current, id);
+        let with_dictionary = compressor.compress(1, &block)?;
+        assert!(with_dictionary.len() < plain.len(), "{} >= {}", with_dictionary.len(), plain.len());
+        assert_eq!(compressor.decompress(1, &with_dictionary)?, block);
+
+        // Blocks written before stay readable, and the dictionary is loaded again on reopening.
+        let reopened = BlockCompressor::open(3, temp_dir.path())?;
+        assert_eq!(reopened.dictionaries.read().current, id);
+        assert_eq!(reopened.decompress(1, &with_dictionary)?, block);
+        assert_eq!(reopened.decompress(1, &plain)?, block);
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+/// Inode number of the virtual `.timefs` directory in the root, holding read-only control files.
+pub(crate) const CONTROL_DIR_INO: u64 = u64::MAX - 2;
+pub(crate) const CONTROL_DIR_NAME: &str = ".timefs";
+
+/// Inode number of `.timefs/stats`, reporting the space taken by version history.
+pub(crate) const STATS_FILE_INO: u64 = u64::MAX - 3;
+pub(crate) const STATS_FILE_NAME: &str = "stats";
+
+/// Inode number of `.timefs/health`, a JSON liveness report regenerated on every read.
+pub(crate) const HEALTH_FILE_INO: u64 = u64::MAX - 4;
+pub(crate) const HEALTH_FILE_NAME: &str = "health";
+
+/// Inode number of `.timefs/handles`, listing the open file handles for tracking down leaks.
+pub(crate) const HANDLES_FILE_INO: u64 = u64::MAX - 5;
+pub(crate) const HANDLES_FILE_NAME: &str = "handles";
+
+
+/// `ioctl` commands on a file pinning its blocks in the cache while it's memory mapped, and
+/// releasing such a pin. Pins nest, the blocks stay pinned until every pin is released.
+pub(crate) const TIMEFS_IOC_PIN: u32 = 0x5446_0002;
+pub(crate) const TIMEFS_IOC_UNPIN: u32 = 0x5446_0003;
+
+/// `ioctl` commands on a file or directory excluding it from automatic versioning, like
+/// `chattr`, and including it again. New children of an excluded directory are excluded too.
+pub(crate) const TIMEFS_IOC_SET_NOVERSION: u32 = 0x5446_0004;
+pub(crate) const TIMEFS_IOC_CLEAR_NOVERSION: u32 = 0x5446_0005;
+
+/// `ioctl` command on a file rolling it back to one of its versions, taking a
+/// `struct { u64 secs; u32 nsecs; u32 flags; }` with the timestamp of the version since the epoch.
+pub(crate) const TIMEFS_IOC_RESTORE_VERSION: u32 = 0x5446_0006;
+/// Flag of `TIMEFS_IOC_RESTORE_VERSION` giving the file back the change time of the version too,
+/// instead of stamping it now.
+pub(crate) const TIMEFS_RESTORE_PRESERVE_TIMES: u32 = 1;
+
+/// `ioctl` command on a file recording its current contents as a version, answering with the
+/// `struct { u64 secs; u32 nsecs; u32 flags; }` of its timestamp, `flags` being zero.
+pub(crate) const TIMEFS_IOC_CAPTURE_VERSION: u32 = 0x5446_0007;
+/// `ioctl` command on a file reading from one of its versions, taking a
+/// `struct { u64 secs; u32 nsecs; u32 size; u64 offset; }` and answering with up to `size` bytes.
+pub(crate) const TIMEFS_IOC_READ_VERSION: u32 = 0x5446_0008;
+/// `ioctl` command on a file comparing two of its versions, taking two `struct { u64 secs;
+/// u32 nsecs; u32 flags; }` and answering with the `u64` start and end of each byte range that
+/// differs, as many as fit.
+pub(crate) const TIMEFS_IOC_DIFF_VERSIONS: u32 = 0x5446_0009;
+
+/// `ioctl` commands on the root freezing the block files so a backup can copy the block directory
+/// as a consistent whole, and thawing them again. Writes made while frozen are held in memory.
+pub(crate) const TIMEFS_IOC_FREEZE: u32 = 0x5446_000a;
+pub(crate) const TIMEFS_IOC_THAW: u32 = 0x5446_000b;
+
+/// `ioctl` command compacting the block list of a file, or of every file when issued on the root.
+pub(crate) const TIMEFS_IOC_COMPACT: u32 = 0x5446_000c;
+
+/// `ioctl` commands of `cp --reflink` making a file, or a block-aligned range of it, share the
+/// blocks of another file given by descriptor instead of copying them.
+pub(crate) const FICLONE: u32 = libc::FICLONE as u32;
+pub(crate) const FICLONERANGE: u32 = libc::FICLONERANGE as u32;
+
+/// Extended attribute overriding the block size of a single file, set before its first write.
+pub(crate) const BLOCK_SIZE_XATTR: &str = "user.timefs.blocksize";
+/// Block sizes `user.timefs.blocksize` accepts, powers of two only.
+pub(crate) const MIN_FILE_BLOCK_SIZE: u32 = 512;
+pub(crate) const MAX_FILE_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+/// Extended attribute excluding a file or directory from automatic versioning when `1`.
+pub(crate) const NO_VERSION_XATTR: &str = "user.timefs.noversion";
+/// Read-only extended attribute listing the versions of a file oldest first, one `secs.nsecs`
+/// timestamp per line.
+pub(crate) const VERSIONS_XATTR: &str = "user.timefs.versions";
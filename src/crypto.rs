@@ -0,0 +1,88 @@
+use std::path::Path;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use crate::block::{max_block_file_id, BlockCacheError};
+
+pub(crate) const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const KEY_CHECK: &[u8] = b"TimeFS key check";
+
+/// AES-256-GCM encryption of blocks at rest.
+///
+/// An encrypted block is laid out on disk as `nonce || ciphertext || tag`. Every write picks a
+/// fresh random nonce, since a block id is rewritten many times under the same key and GCM
+/// must never see a nonce twice. The block id is bound in as associated data instead, so a
+/// block file moved or copied over another one fails authentication.
+pub(crate) struct BlockCipher {
+    cipher: Aes256Gcm,
+}
+
+impl BlockCipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+
+    /// Loads a master key from a file holding exactly 32 raw bytes.
+    pub fn from_key_file(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let key: [u8; KEY_SIZE] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| BlockCacheError::BadKey(format!("key file must hold {} bytes, found {}", KEY_SIZE, bytes.len())))?;
+        Ok(Self::new(&key))
+    }
+
+    pub fn encrypt(&self, block_id: u64, data: &[u8]) -> std::result::Result<Vec<u8>, BlockCacheError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = block_id.to_le_bytes();
+        let ciphertext = self.cipher
+            .encrypt(&nonce, Payload { msg: data, aad: &aad })
+            .map_err(|_| BlockCacheError::Crypto(block_id))?;
+
+        let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, block_id: u64, data: &[u8]) -> std::result::Result<Vec<u8>, BlockCacheError> {
+        if data.len() < NONCE_SIZE {
+            return Err(BlockCacheError::Crypto(block_id));
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        let aad = block_id.to_le_bytes();
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| BlockCacheError::Crypto(block_id))
+    }
+}
+
+/// Makes sure the key a filesystem is opened with is the one its blocks were encrypted with.
+///
+/// The first encrypted open stores a known plaintext encrypted under the key in `path`, unless
+/// it's read-only, and is refused when `blocks_dir` already holds blocks written unencrypted.
+/// Later opens must decrypt it, and an unencrypted open of an encrypted filesystem is refused.
+pub(crate) fn check_key(cipher: Option<&BlockCipher>, path: &Path, blocks_dir: &Path, writable: bool) -> crate::Result<()> {
+    match (cipher, path.exists()) {
+        (Some(cipher), true) => {
+            let data = std::fs::read(path)?;
+            match cipher.decrypt(0, &data) {
+                Ok(plain) if plain == KEY_CHECK => Ok(()),
+                _ => Err(BlockCacheError::BadKey("key does not match the one this filesystem was encrypted with".into()).into()),
+            }
+        }
+        (Some(_), false) if max_block_file_id(blocks_dir)? > 0 => {
+            Err(BlockCacheError::BadKey("existing blocks were written unencrypted".into()).into())
+        }
+        (Some(cipher), false) if writable => {
+            std::fs::write(path, cipher.encrypt(0, KEY_CHECK)?)?;
+            Ok(())
+        }
+        (Some(_), false) => Ok(()),
+        (None, true) => Err(BlockCacheError::BadKey("filesystem is encrypted, a key file is required".into()).into()),
+        (None, false) => Ok(()),
+    }
+}
@@ -0,0 +1,253 @@
+//! Prints the on-disk layout of a storage directory for debugging: the superblock, then every
+//! inode as a tree from the root. Reads the files directly without opening the filesystem, so it
+//! gets as far as it can on damaged storage and flags what it can't parse instead of failing.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::Path;
+use fuser::{FileType, FUSE_ROOT_ID};
+use crate::control::{BLOCK_SIZE_XATTR, NO_VERSION_XATTR};
+use crate::inode::{INode, INodeType};
+use crate::superblock::SuperBlock;
+use crate::{from_bin_file, Result};
+
+/// Totals over the inodes a dump went through.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct DumpSummary {
+    pub(crate) files: usize,
+    pub(crate) directories: usize,
+    /// Blocks referenced by current file contents, holes not included.
+    pub(crate) block_refs: usize,
+    pub(crate) versions: usize,
+    /// Inodes on disk that no directory reaches from the root.
+    pub(crate) unreachable: usize,
+    /// Files that couldn't be parsed, the superblock included.
+    pub(crate) unreadable: usize,
+}
+
+/// Writes the layout of the storage at `storage_path` to `out`. Only fails when `out` does.
+pub(crate) fn dump(storage_path: &Path, out: &mut impl Write) -> Result<DumpSummary> {
+    let metadata_dir = storage_path.join("metadata");
+    let inode_dir = metadata_dir.join("inode");
+    let mut summary = DumpSummary::default();
+
+    match SuperBlock::from_file(metadata_dir.join("superblock.bin")) {
+        Ok(sb) => writeln!(
+            out,
+            "superblock: {} inodes, next inode {}, next block {}{}{}",
+            sb.inode_count(),
+            sb.next_inode_id(),
+            sb.next_block_id(),
+            if sb.is_clean() { "" } else { " (not unmounted cleanly)" },
+            if sb.is_recovered() { " (recovered from backup)" } else { "" },
+        )?,
+        Err(e) => {
+            summary.unreadable += 1;
+            writeln!(out, "superblock: UNREADABLE ({})", e)?;
+        }
+    }
+
+    let mut inodes = BTreeMap::new();
+    for (id, path) in inode_files(&inode_dir, out, &mut summary)? {
+        match INode::from_file(id, &inode_dir) {
+            Ok(inode) => {
+                inodes.insert(id, inode);
+            }
+            // The inode itself may be fine with only its entry log or buckets damaged.
+            Err(e) => match from_bin_file::<INode>(&path) {
+                Ok(inode) => {
+                    summary.unreadable += 1;
+                    writeln!(out, "inode {}: entries UNREADABLE ({}), listing the ones in {:?}", id, e, path)?;
+                    inodes.insert(id, inode);
+                }
+                Err(e) => {
+                    summary.unreadable += 1;
+                    writeln!(out, "inode {}: UNREADABLE {:?} ({})", id, path, e)?;
+                }
+            },
+        }
+    }
+
+    let mut visited = HashSet::new();
+    write_tree(&inodes, FUSE_ROOT_ID, "/", 0, &mut visited, out, &mut summary)?;
+    let unreachable = inodes.keys().copied().filter(|id| !visited.contains(id)).collect::<Vec<_>>();
+    if !unreachable.is_empty() {
+        writeln!(out, "unreachable:")?;
+    }
+    for id in unreachable {
+        if !visited.contains(&id) {
+            summary.unreachable += 1;
+            write_tree(&inodes, id, "?", 1, &mut visited, out, &mut summary)?;
+        }
+    }
+
+    writeln!(
+        out,
+        "{} files, {} directories, {} block references, {} versions, {} unreachable, {} unreadable",
+        summary.files, summary.directories, summary.block_refs, summary.versions, summary.unreachable, summary.unreadable,
+    )?;
+    Ok(summary)
+}
+
+/// Ids and paths of the inode files under `inode_dir`, reporting the directories it can't list.
+fn inode_files(inode_dir: &Path, out: &mut impl Write, summary: &mut DumpSummary) -> Result<BTreeMap<u64, std::path::PathBuf>> {
+    let mut files = BTreeMap::new();
+    let shards = match std::fs::read_dir(inode_dir) {
+        Ok(shards) => shards,
+        Err(e) => {
+            summary.unreadable += 1;
+            writeln!(out, "inodes: UNREADABLE {:?} ({})", inode_dir, e)?;
+            return Ok(files);
+        }
+    };
+    for shard in shards.flatten().map(|shard| shard.path()).filter(|path| path.is_dir()) {
+        let entries = match std::fs::read_dir(&shard) {
+            Ok(entries) => entries,
+            Err(e) => {
+                summary.unreadable += 1;
+                writeln!(out, "inodes: UNREADABLE {:?} ({})", shard, e)?;
+                continue;
+            }
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let id = path.file_name().and_then(OsStr::to_str).and_then(INode::parse_file_name);
+            if let Some(id) = id && path.extension().is_some_and(|ext| ext == "bin") {
+                files.insert(id, path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn write_tree(
+    inodes: &BTreeMap<u64, INode>,
+    id: u64,
+    name: &str,
+    depth: usize,
+    visited: &mut HashSet<u64>,
+    out: &mut impl Write,
+    summary: &mut DumpSummary,
+) -> Result<()> {
+    let indent = "  ".repeat(depth);
+    let Some(inode) = inodes.get(&id) else {
+        writeln!(out, "{}{} [inode {}] MISSING", indent, name, id)?;
+        return Ok(());
+    };
+    // A directory linked from two places would otherwise be walked forever.
+    if !visited.insert(id) {
+        writeln!(out, "{}{} [inode {}] already listed", indent, name, id)?;
+        return Ok(());
+    }
+
+    let xattrs = xattrs(inode);
+    match &inode.data {
+        INodeType::File { blocks, size, versions, .. } => {
+            summary.files += 1;
+            summary.versions += versions.len();
+            let blocks = blocks
+                .iter()
+                .map(|block| match block.is_hole() {
+                    true => "hole".to_string(),
+                    false => {
+                        summary.block_refs += 1;
+                        block.id().to_string()
+                    }
+                })
+                .collect::<Vec<_>>();
+            writeln!(
+                out,
+                "{}{} [inode {}, {}, {} bytes, {} versions, blocks [{}]{}]",
+                indent,
+                name,
+                id,
+                kind_name(inode.attr.kind),
+                size,
+                versions.len(),
+                blocks.join(", "),
+                xattrs,
+            )?;
+        }
+        INodeType::Directory { entries } => {
+            summary.directories += 1;
+            writeln!(out, "{}{} [inode {}, directory, {} entries{}]", indent, name, id, entries.len(), xattrs)?;
+            let entries = entries.iter().collect::<BTreeMap<_, _>>();
+            for (child_name, child) in entries {
+                write_tree(inodes, child.id, child_name, depth + 1, visited, out, summary)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The extended attributes of `inode` as listed by `listxattr`, with their values.
+fn xattrs(inode: &INode) -> String {
+    let mut xattrs = Vec::new();
+    if let INodeType::File { block_size: Some(block_size), .. } = inode.data {
+        xattrs.push(format!("{}={}", BLOCK_SIZE_XATTR, block_size));
+    }
+    if inode.no_version {
+        xattrs.push(format!("{}=1", NO_VERSION_XATTR));
+    }
+    match xattrs.is_empty() {
+        true => String::new(),
+        false => format!(", xattrs {}", xattrs.join(" ")),
+    }
+}
+
+fn kind_name(kind: FileType) -> &'static str {
+    match kind {
+        FileType::RegularFile => "file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symlink",
+        FileType::NamedPipe => "fifo",
+        FileType::Socket => "socket",
+        FileType::CharDevice => "char device",
+        FileType::BlockDevice => "block device",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use crate::fs::{Creator, TimeFS};
+    use crate::options::FsOptions;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dump_lists_inodes_and_blocks() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let storage_path = temp_dir.path().join("storage");
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), &storage_path, FsOptions::default())?;
+        let dir = fs.make_node(FUSE_ROOT_ID, "dir", libc::S_IFDIR | 0o755, 0, Creator::current_user())?;
+        let (file, _) = fs.create_file(dir.ino, "file.txt", libc::O_RDWR)?;
+        fs.write_at(file.ino, 0, &vec![7u8; 10_000]).await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let inode_dir = storage_path.join("metadata/inode");
+        let blocks = match INode::from_file(file.ino, &inode_dir)?.data {
+            INodeType::File { blocks, .. } => blocks.iter().map(|block| block.id().to_string()).collect::<Vec<_>>(),
+            INodeType::Directory { .. } => unreachable!(),
+        };
+        assert_eq!(blocks.len(), 3);
+
+        let mut out = Vec::new();
+        let summary = dump(&storage_path, &mut out)?;
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("dir [inode {}, directory, 1 entries]", dir.ino)), "{}", out);
+        let expected = format!("file.txt [inode {}, file, 10000 bytes, 0 versions, blocks [{}]]", file.ino, blocks.join(", "));
+        assert!(out.contains(&expected), "{}", out);
+        assert_eq!(summary, DumpSummary { files: 1, directories: 2, block_refs: blocks.len(), ..DumpSummary::default() });
+
+        // A damaged inode is flagged, the rest still listed.
+        std::fs::write(INode::inode_path(file.ino, &inode_dir), b"garbage")?;
+        let mut out = Vec::new();
+        let summary = dump(&storage_path, &mut out)?;
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("inode {}: UNREADABLE", file.ino)), "{}", out);
+        assert!(out.contains(&format!("file.txt [inode {}] MISSING", file.ino)), "{}", out);
+        assert_eq!(summary, DumpSummary { directories: 2, unreadable: 1, ..DumpSummary::default() });
+        Ok(())
+    }
+}
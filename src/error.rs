@@ -1,6 +1,8 @@
+use std::time::SystemTime;
 use libc::c_int;
 use thiserror::Error;
 use crate::block::BlockCacheError;
+use crate::fs::NAME_MAX;
 
 #[derive(Debug, Error)]
 pub enum TimeFSError {
@@ -16,28 +18,122 @@ pub enum TimeFSError {
     NotDirectory(u64),
     #[error("Inode {0} is a folder")]
     IsDirectory(u64),
+    #[error("Directory {0} is not empty")]
+    NotEmpty(u64),
     #[error("Name {0} has existed")]
     NameExist(String),
+    #[error("Invalid name {0:?}")]
+    InvalidName(String),
+    #[error("Name {0:?} is longer than {NAME_MAX} bytes")]
+    NameTooLong(String),
+    #[error("Version {1:?} of inode {0} not found")]
+    VersionNotFound(u64, SystemTime),
+    #[error("Unsupported file type {0:#o}")]
+    UnsupportedFileType(u32),
+    #[error("File system is mounted read-only")]
+    ReadOnly,
+    #[error("No space left in block storage")]
+    NoSpace,
+    #[error("Invalid argument: {0}")]
+    Invalid(String),
+    #[error("Block size {0} doesn't match the file system block size")]
+    BlockSizeMismatch(u32),
     #[error("block index error")]
     BlockIndexError,
     #[error("{0}")]
-    BlockCacheError(#[from] BlockCacheError)
+    BlockCacheError(#[from] BlockCacheError),
+    #[error("Bad superblock magic: {0:#x}")]
+    BadMagic(u64),
+    #[error("Unsupported on-disk format version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Unsupported metadata compression header: {0}")]
+    UnsupportedCompression(u8),
+    #[error("Extended attribute {0:?} not set")]
+    XattrNotFound(String),
+    #[error("Unsupported extended attribute {0:?}")]
+    UnsupportedXattr(String),
+    #[error("No {0} ids left to allocate")]
+    IdSpaceExhausted(&'static str),
+    #[error("Operation on inode {0} not permitted")]
+    NotPermitted(u64),
+    #[error("Too many open files")]
+    TooManyOpenFiles,
+    #[error("Range of inode {0} is locked by another owner")]
+    Locked(u64),
+    #[error("Disk quota of uid {0} exceeded")]
+    QuotaExceeded(u32),
+    #[error("Inode {0} would grow past the maximum file size")]
+    FileTooBig(u64),
+    #[error("Unsupported fallocate mode {0:#x}")]
+    UnsupportedFallocateMode(i32),
+    #[error("Handle {0} refers to a file that no longer exists")]
+    StaleHandle(u64),
 }
 
 pub type Result<T> = std::result::Result<T, TimeFSError>;
 
-impl Into<c_int> for TimeFSError {
-    fn into(self) -> c_int {
-        match self {
-            Self::Io(_) => libc::EIO,
-            Self::NotFound(_) => libc::ENOENT,
-            Self::NameNotFound(_) => libc::ENOENT,
-            Self::NotDirectory(_) => libc::ENOTDIR,
-            Self::IsDirectory(_) => libc::EISDIR,
-            Self::NameExist(_) => libc::EEXIST,
-            Self::BlockIndexError => libc::EINVAL,
-            Self::BlockCacheError(_) => libc::EIO,
-            _ => libc::EIO,
+impl From<TimeFSError> for c_int {
+    fn from(e: TimeFSError) -> c_int {
+        match e {
+            TimeFSError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            TimeFSError::Serialize(_) => libc::EIO,
+            TimeFSError::NotFound(_) => libc::ENOENT,
+            TimeFSError::NameNotFound(_) => libc::ENOENT,
+            TimeFSError::VersionNotFound(..) => libc::ENOENT,
+            TimeFSError::NotDirectory(_) => libc::ENOTDIR,
+            TimeFSError::IsDirectory(_) => libc::EISDIR,
+            TimeFSError::NotEmpty(_) => libc::ENOTEMPTY,
+            TimeFSError::NameExist(_) => libc::EEXIST,
+            TimeFSError::InvalidName(_) => libc::EINVAL,
+            TimeFSError::NameTooLong(_) => libc::ENAMETOOLONG,
+            TimeFSError::UnsupportedFileType(_) => libc::EPERM,
+            TimeFSError::ReadOnly => libc::EROFS,
+            TimeFSError::NoSpace => libc::ENOSPC,
+            TimeFSError::Invalid(_) => libc::EINVAL,
+            TimeFSError::BlockSizeMismatch(_) => libc::EINVAL,
+            TimeFSError::BlockIndexError => libc::EINVAL,
+            TimeFSError::BlockCacheError(_) => libc::EIO,
+            TimeFSError::BadMagic(_) => libc::EINVAL,
+            TimeFSError::UnsupportedVersion(_) => libc::EINVAL,
+            TimeFSError::UnsupportedCompression(_) => libc::EINVAL,
+            TimeFSError::XattrNotFound(_) => libc::ENODATA,
+            TimeFSError::UnsupportedXattr(_) => libc::ENOTSUP,
+            TimeFSError::IdSpaceExhausted(_) => libc::ENOSPC,
+            TimeFSError::NotPermitted(_) => libc::EPERM,
+            TimeFSError::TooManyOpenFiles => libc::EMFILE,
+            TimeFSError::Locked(_) => libc::EACCES,
+            TimeFSError::StaleHandle(_) => libc::ESTALE,
+            TimeFSError::QuotaExceeded(_) => libc::EDQUOT,
+            TimeFSError::FileTooBig(_) => libc::EFBIG,
+            TimeFSError::UnsupportedFallocateMode(_) => libc::EOPNOTSUPP,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errno_mapping() {
+        let errno = |e: TimeFSError| -> c_int { e.into() };
+        assert_eq!(errno(TimeFSError::NoSpace), libc::ENOSPC);
+        assert_eq!(errno(TimeFSError::ReadOnly), libc::EROFS);
+        assert_eq!(errno(TimeFSError::NotEmpty(2)), libc::ENOTEMPTY);
+        assert_eq!(errno(TimeFSError::NameTooLong("x".repeat(NAME_MAX + 1))), libc::ENAMETOOLONG);
+        assert_eq!(errno(TimeFSError::Invalid("offset".to_string())), libc::EINVAL);
+        assert_eq!(errno(TimeFSError::XattrNotFound("user.a".to_string())), libc::ENODATA);
+        assert_eq!(errno(TimeFSError::UnsupportedXattr("user.a".to_string())), libc::ENOTSUP);
+        assert_eq!(errno(TimeFSError::IdSpaceExhausted("inode")), libc::ENOSPC);
+        assert_eq!(errno(TimeFSError::NotPermitted(2)), libc::EPERM);
+        assert_eq!(errno(TimeFSError::TooManyOpenFiles), libc::EMFILE);
+        assert_eq!(errno(TimeFSError::Locked(1)), libc::EACCES);
+        assert_eq!(errno(TimeFSError::QuotaExceeded(1000)), libc::EDQUOT);
+        assert_eq!(errno(TimeFSError::FileTooBig(2)), libc::EFBIG);
+        assert_eq!(errno(TimeFSError::UnsupportedFallocateMode(libc::FALLOC_FL_PUNCH_HOLE)), libc::EOPNOTSUPP);
+
+        // I/O errors keep the errno the host file system reported.
+        assert_eq!(errno(std::io::Error::from_raw_os_error(libc::ENOSPC).into()), libc::ENOSPC);
+        assert_eq!(errno(std::io::Error::other("no errno").into()), libc::EIO);
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::inode::INode;
+
+/// A block changed since the stream's base timestamp, addressed both physically and logically
+/// so it can be applied regardless of the order inodes and blocks arrive in. `index` is its
+/// position in the file, or in the version of it that holds the block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExportedBlock {
+    pub(crate) ino: u64,
+    pub(crate) index: u64,
+    pub(crate) block_id: u64,
+    pub(crate) data: Vec<u8>,
+}
+
+/// An incremental export of a TimeFS: the full inode table plus every block written after `since`,
+/// those of versions included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExportStream {
+    pub(crate) since: SystemTime,
+    pub(crate) created_at: SystemTime,
+    pub(crate) inodes: Vec<INode>,
+    pub(crate) blocks: Vec<ExportedBlock>,
+}
+
+impl ExportStream {
+    pub fn new(since: SystemTime) -> Self {
+        Self {
+            since,
+            created_at: SystemTime::now(),
+            inodes: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+}
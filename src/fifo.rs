@@ -0,0 +1,60 @@
+use std::collections::VecDeque;
+use fuser::PollHandle;
+use log::warn;
+
+/// Bytes a FIFO holds before writers have to wait, the Linux default pipe size.
+pub(crate) const FIFO_CAPACITY: usize = 64 * 1024;
+
+/// In-memory contents of a named pipe. FIFO data never reaches the block store.
+#[derive(Default)]
+pub(crate) struct FifoBuffer {
+    data: VecDeque<u8>,
+    /// Poll handles of waiters to wake up once the readiness of the FIFO changes.
+    waiters: Vec<PollHandle>,
+}
+
+impl FifoBuffer {
+    /// Appends as much of `data` as fits, returning the number of bytes accepted.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(FIFO_CAPACITY - self.data.len());
+        self.data.extend(&data[..len]);
+        if len > 0 {
+            self.notify_waiters();
+        }
+        len
+    }
+
+    /// Takes up to `size` bytes from the front of the FIFO.
+    pub fn read(&mut self, size: usize) -> Vec<u8> {
+        let len = size.min(self.data.len());
+        let data = self.data.drain(..len).collect::<Vec<_>>();
+        if len > 0 {
+            self.notify_waiters();
+        }
+        data
+    }
+
+    /// Returns which of the requested poll `events` the FIFO is ready for.
+    pub fn readiness(&self, events: u32) -> u32 {
+        let mut revents = 0;
+        if !self.data.is_empty() {
+            revents |= libc::POLLIN as u32;
+        }
+        if self.data.len() < FIFO_CAPACITY {
+            revents |= libc::POLLOUT as u32;
+        }
+        revents & events
+    }
+
+    pub fn register(&mut self, ph: PollHandle) {
+        self.waiters.push(ph);
+    }
+
+    fn notify_waiters(&mut self) {
+        for ph in self.waiters.drain(..) {
+            if let Err(e) = ph.notify() {
+                warn!("Failed to notify poll waiter: {}", e);
+            }
+        }
+    }
+}
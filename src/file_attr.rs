@@ -3,6 +3,28 @@ use fuser::{FileAttr, FileType};
 use users::{get_current_gid, get_current_uid};
 use crate::fs::BLOCK_SIZE;
 
+/// Unit `st_blocks` is counted in, whatever the block size of the file.
+pub(crate) const STAT_BLOCK_SIZE: u64 = 512;
+
+/// `st_blocks` of a file holding `blocks` allocated blocks of `block_size` bytes each.
+pub(crate) fn stat_blocks(blocks: u64, block_size: u32) -> u64 {
+    blocks * (block_size as u64).div_ceil(STAT_BLOCK_SIZE)
+}
+
+/// Kind of file the `S_IFMT` bits of `mode` stand for.
+pub(crate) fn file_type_from_mode(mode: u32) -> Option<FileType> {
+    match mode & libc::S_IFMT {
+        libc::S_IFREG => Some(FileType::RegularFile),
+        libc::S_IFDIR => Some(FileType::Directory),
+        libc::S_IFLNK => Some(FileType::Symlink),
+        libc::S_IFIFO => Some(FileType::NamedPipe),
+        libc::S_IFCHR => Some(FileType::CharDevice),
+        libc::S_IFBLK => Some(FileType::BlockDevice),
+        libc::S_IFSOCK => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
 pub(crate) struct FileAttrBuilder {
     ino: u64,
     size: u64,
@@ -27,36 +49,6 @@ impl FileAttrBuilder {
         self
     }
     
-    pub fn size(mut self, size: u64) -> Self {
-        self.size = size;
-        self
-    }
-    
-    pub fn blocks(mut self, blocks: u64) -> Self {
-        self.blocks = blocks;
-        self
-    }
-    
-    pub fn atime(mut self, atime: SystemTime) -> Self {
-        self.atime = atime;
-        self
-    }
-    
-    pub fn mtime(mut self, mtime: SystemTime) -> Self {
-        self.mtime = mtime;
-        self
-    }
-    
-    pub fn ctime(mut self, ctime: SystemTime) -> Self {
-        self.ctime = ctime;
-        self
-    }
-    
-    pub fn crtime(mut self, crtime: SystemTime) -> Self {
-        self.crtime = crtime;
-        self
-    }
-    
     pub fn kind(mut self, kind: FileType) -> Self {
         self.kind = kind;
         self
@@ -67,60 +59,13 @@ impl FileAttrBuilder {
         self
     }
     
-    pub fn nlink(mut self, nlink: u32) -> Self {
-        self.nlink = nlink;
-        self
-    }
-    
-    pub fn uid(mut self, uid: u32) -> Self {
-        self.uid = uid;
-        self
-    }
-    
-    pub fn gid(mut self, gid: u32) -> Self {
-        self.gid = gid;
-        self
-    }
-    
-    pub fn rdev(mut self, rdev: u32) -> Self {
-        self.rdev = rdev;
-        self
-    }
-    
-    pub fn blksize(mut self, blksize: u32) -> Self {
-        self.blksize = blksize;
-        self
-    }
-    
     pub fn with_size(mut self, size: u64) -> Self {
         self.size = size;
-        self.blocks = (self.size + BLOCK_SIZE as u64) / BLOCK_SIZE as u64;
+        self.blocks = stat_blocks(size.div_ceil(BLOCK_SIZE as u64), BLOCK_SIZE);
         self.blksize = BLOCK_SIZE;
         self
     }
     
-    pub fn zero_size(mut self) -> Self {
-        self.size = 0;
-        self.blocks = 0;
-        self.blksize = BLOCK_SIZE;
-        self
-    }
-    
-    pub fn with_now(mut self) -> Self {
-        let now = SystemTime::now();
-        self.atime = now;
-        self.mtime = now;
-        self.ctime = now;
-        self.crtime = now;
-        self
-    }
-    
-    pub fn with_current_user(mut self) -> Self {
-        self.uid = get_current_uid();
-        self.gid = get_current_gid();
-        self
-    }
-    
     pub fn with_regular_file(mut self) -> Self {
         self.kind = FileType::RegularFile;
         self
@@ -132,26 +77,11 @@ impl FileAttrBuilder {
         self
     }
     
-    pub fn with_owner_read_write_other_read_write(mut self) -> Self {
-        self.perm = 0o755;
-        self
-    }
-    
-    pub fn with_owner_read_write_other_read(mut self) -> Self {
-        self.perm = 0o644;
-        self
-    }
-    
-    pub fn with_everyone_read_write(mut self) -> Self {
-        self.perm = 0o777;
-        self
-    }
-    
     pub fn build(self) -> FileAttr {
         FileAttr {
             ino: self.ino,
-            size: self.ino,
-            blocks: self.ino,
+            size: self.size,
+            blocks: self.blocks,
             atime: self.atime,
             mtime: self.mtime,
             ctime: self.ctime,
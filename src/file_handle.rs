@@ -1,12 +1,56 @@
 pub(crate) struct FileHandle {
     inode_id: u64,
+    /// Generation of the inode when it was opened, telling it apart from a later file reusing its id.
+    generation: u64,
     flags: i32, 
+    /// Small sequential writes not yet applied to the file, as their offset and bytes.
+    write_buffer: Option<(u64, Vec<u8>)>,
 }
 
 impl FileHandle {
     #[inline]
-    pub(crate) fn new(inode_id: u64, flags: i32) -> Self {
-        Self { inode_id, flags }
+    pub(crate) fn new(inode_id: u64, generation: u64, flags: i32) -> Self {
+        Self { inode_id, generation, flags, write_buffer: None }
+    }
+
+    #[inline]
+    pub(crate) fn inode_id(&self) -> u64 {
+        self.inode_id
+    }
+
+    #[inline]
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    #[inline]
+    pub(crate) fn has_buffered_writes(&self) -> bool {
+        self.write_buffer.is_some()
+    }
+
+    pub(crate) fn take_write_buffer(&mut self) -> Option<(u64, Vec<u8>)> {
+        self.write_buffer.take()
+    }
+
+    /// Adds `data` written at `offset` to the write buffer. Returns what has to be written
+    /// first: the previous buffer if `data` doesn't continue it, or the whole buffer once it
+    /// reaches the end of the block it started in.
+    pub(crate) fn buffer_write(&mut self, offset: u64, data: &[u8], block_size: u64) -> Option<(u64, Vec<u8>)> {
+        if let Some((start, ref buffer)) = self.write_buffer
+            && start + buffer.len() as u64 != offset
+        {
+            let pending = self.write_buffer.take();
+            self.write_buffer = Some((offset, data.to_vec()));
+            return pending;
+        }
+
+        let (start, buffer) = self.write_buffer.get_or_insert_with(|| (offset, Vec::new()));
+        buffer.extend_from_slice(data);
+        let block_end = (*start / block_size + 1) * block_size;
+        if *start + buffer.len() as u64 >= block_end {
+            return self.write_buffer.take();
+        }
+        None
     }
 } 
 
@@ -28,13 +72,8 @@ impl FileFlags for FileHandle {
     }
 
     #[inline]
-    fn is_create(&self) -> bool {
-        self.flags.is_create()
-    }
-
-    #[inline]
-    fn is_truncate(&self) -> bool {
-        self.flags.is_truncate()
+    fn is_exclusive(&self) -> bool {
+        self.flags.is_exclusive()
     }
 
     #[inline]
@@ -52,8 +91,7 @@ pub(crate) trait FileFlags {
     fn is_read_only(&self) -> bool;
     fn is_write_only(&self) -> bool;
     fn is_read_write(&self) -> bool;
-    fn is_create(&self) -> bool;
-    fn is_truncate(&self) -> bool;
+    fn is_exclusive(&self) -> bool;
     fn is_append(&self) -> bool;
     fn is_sync(&self) -> bool;
 }
@@ -61,7 +99,8 @@ pub(crate) trait FileFlags {
 impl FileFlags for i32 {
     #[inline]
     fn is_read_only(&self) -> bool {
-        self & libc::O_RDONLY != 0
+        // O_RDONLY is 0, so it's told apart by the whole access mode.
+        self & libc::O_ACCMODE == libc::O_RDONLY
     }
 
     #[inline]
@@ -75,13 +114,8 @@ impl FileFlags for i32 {
     }
 
     #[inline]
-    fn is_create(&self) -> bool {
-        self & libc::O_CREAT != 0
-    }
-
-    #[inline]
-    fn is_truncate(&self) -> bool {
-        self & libc::O_TRUNC != 0
+    fn is_exclusive(&self) -> bool {
+        self & libc::O_EXCL != 0
     }
 
     #[inline]
@@ -1,80 +1,581 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::ffi::OsStr;
-use std::num::{NonZero, NonZeroUsize};
-use std::ops::{Deref, DerefMut};
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut, Range, RangeInclusive};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
 use dashmap::DashMap;
-use fuser::{FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, Request, FUSE_ROOT_ID};
-use libc::{c_int, EEXIST, EISDIR, ENOENT};
-use log::{debug, error};
+use dashmap::mapref::entry::Entry;
+use fuser::consts::FUSE_POLL_SCHEDULE_NOTIFY;
+use fuser::{consts, fuse_forget_one, FileAttr, FileType, Filesystem, KernelConfig, PollHandle, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyOpen, ReplyPoll, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID};
+use libc::{c_int, ENOENT};
+use log::{debug, error, info, warn};
+use tracing::{debug_span, Instrument};
 use parking_lot::{Mutex, RwLock};
+use regex::Regex;
 use users::{get_current_gid, get_current_uid};
-use crate::block::{BlockCache};
-use crate::file_handle::FileHandle;
-use crate::inode::{INode, INodeType};
+use crate::block::{block_file_ids, check_compression, default_flush_threads, max_block_file_id, repair_blocks, DEFAULT_DIRTY_HIGH_WATER, scrub, AgePolicy, BlockCache, BlockRepairReport, BlockCodec, BlockRef, BlockRefCounts, FrozenCache, Policy, ScrubReport};
+#[cfg(feature = "zstd")]
+use crate::compress::BlockCompressor;
+use crate::file_handle::{FileFlags, FileHandle};
+use crate::group_commit::GroupCommit;
+use crate::quota::Quotas;
+use crate::inode::{ChildEntry, INode, INodeType};
 use crate::superblock::SuperBlock;
-use crate::{AutoSave, Result};
+use crate::Result;
 use crate::error::TimeFSError;
-use crate::file_attr::FileAttrBuilder;
+use crate::file_attr::{file_type_from_mode, stat_blocks, FileAttrBuilder};
+use crate::options::{paths_overlap, AtimePolicy, FsOptions, MetadataCompression, SyncOnClose, DEFAULT_FLUSH_INTERVAL, DEFAULT_STORAGE_HIGH_WATER, DEFAULT_TRASH_RETENTION, DEFAULT_TTL};
+use crate::fifo::FifoBuffer;
+use crate::lock::{LockTable, RangeLock};
+use crate::reply::{AttrReply, EntryReply};
+use crate::invalidate::Invalidator;
+use crate::kernel_config::InitConfig;
+use crate::control::{BLOCK_SIZE_XATTR, CONTROL_DIR_INO, CONTROL_DIR_NAME, FICLONE, FICLONERANGE, MAX_FILE_BLOCK_SIZE, MIN_FILE_BLOCK_SIZE, NO_VERSION_XATTR, VERSIONS_XATTR, HANDLES_FILE_INO, HANDLES_FILE_NAME, HEALTH_FILE_INO, HEALTH_FILE_NAME, STATS_FILE_INO, STATS_FILE_NAME, TIMEFS_IOC_CAPTURE_VERSION, TIMEFS_IOC_CLEAR_NOVERSION, TIMEFS_IOC_COMPACT, TIMEFS_IOC_DIFF_VERSIONS, TIMEFS_IOC_FREEZE, TIMEFS_IOC_PIN, TIMEFS_IOC_READ_VERSION, TIMEFS_IOC_RESTORE_VERSION, TIMEFS_IOC_SET_NOVERSION, TIMEFS_IOC_THAW, TIMEFS_IOC_UNPIN, TIMEFS_RESTORE_PRESERVE_TIMES};
+use crate::trash::{Trash, TrashEntry, TRASH_DIR_INO, TRASH_DIR_NAME};
+use crate::crypto::{check_key, BlockCipher};
+use crate::snapshot::{Snapshot, TIMEFS_IOC_SNAPSHOT};
+use crate::export::{ExportStream, ExportedBlock};
+use crate::{from_bin_compressed, from_bin_file, write_to_bin_compressed, write_to_bin_file};
 
 pub(crate) const BLOCK_SIZE: u32 = 4096;
 
-pub(crate) struct TimeFS {
+/// Longest directory entry name in bytes.
+pub(crate) const NAME_MAX: usize = 255;
+/// Blocks an in-memory filesystem can hold, 1 GiB worth.
+const IN_MEMORY_CAPACITY: u64 = 256 * 1024;
+/// Longest a single write is held back when storage is about to run full.
+const MAX_WRITE_DELAY: Duration = Duration::from_millis(100);
+/// Files with more blocks than this aren't read into the cache ahead of time when opened.
+const PREFETCH_MAX_BLOCKS: usize = 4;
+/// Delayed blocks a file may gather before they're allocated without waiting for a flush.
+const MAX_DELAYED_BLOCKS: usize = 1024;
+/// Delayed blocks all files may gather together, past which each file written allocates its own.
+const MAX_TOTAL_DELAYED_BLOCKS: usize = 16 * 1024;
+/// Blocks a compression dictionary is trained on, the most recently allocated ones.
+#[cfg(feature = "zstd")]
+const DICTIONARY_SAMPLES: usize = 1000;
+
+/// Checks a name about to be linked into a directory, which must be a single non-empty path
+/// component no longer than [`NAME_MAX`] bytes.
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    if name.len() > NAME_MAX {
+        return Err(TimeFSError::NameTooLong(name.to_string()));
+    }
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(TimeFSError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Whether `uid`, whose primary group is `gid`, is a member of `group`.
+fn in_group(uid: u32, gid: u32, group: u32) -> bool {
+    use users::os::unix::GroupExt;
+
+    group == gid
+        || users::get_user_by_uid(uid)
+            .zip(users::get_group_by_gid(group))
+            .is_some_and(|(user, group)| group.members().iter().any(|member| member.as_os_str() == user.name()))
+}
+
+/// Inconsistencies between directory entries and the inodes they point to.
+///
+/// Entries are a map keyed by name, so a directory can't list the same name twice and
+/// there is nothing to report for duplicate names.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct FsckReport {
+    /// `(directory, name, ino)` entries pointing to an inode that doesn't exist.
+    pub(crate) dangling_entries: Vec<(u64, String, u64)>,
+    /// `(ino, recorded, actual)` parents of inodes listed by another directory than their `parent`.
+    pub(crate) wrong_parents: Vec<(u64, u64, u64)>,
+    /// `(ino, recorded, actual)` link counts not matching the entries referencing an inode.
+    pub(crate) wrong_nlinks: Vec<(u64, u32, u32)>,
+    /// Inodes neither reachable from the root nor in the trash.
+    pub(crate) unreachable: Vec<u64>,
+    /// `(ino, block_id)` blocks referenced by an inode but neither on disk nor in the cache,
+    /// left behind by a crash before they were flushed. These aren't repaired.
+    pub(crate) missing_blocks: Vec<(u64, u64)>,
+    /// `(ino, path)` inode files claiming the same id as a newer one, which repairing moves to
+    /// `lost+found` of the storage path.
+    pub(crate) duplicate_inodes: Vec<(u64, PathBuf)>,
+    pub(crate) repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.dangling_entries.is_empty()
+            && self.wrong_parents.is_empty()
+            && self.wrong_nlinks.is_empty()
+            && self.unreachable.is_empty()
+            && self.missing_blocks.is_empty()
+            && self.duplicate_inodes.is_empty()
+    }
+}
+
+/// Space taken by version history, as reported by [`TimeFS::version_stats`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct VersionStats {
+    /// Versions retained across all files.
+    pub(crate) versions: u64,
+    /// Distinct blocks referenced by versions but not by the live data of any file.
+    pub(crate) history_blocks: u64,
+    pub(crate) history_bytes: u64,
+    /// Bytes that would be freed by keeping at most `i` versions per file, for every `i`
+    /// below the longest version list. Index 0 is all of `history_bytes`.
+    pub(crate) reclaimable_bytes: Vec<u64>,
+}
+
+impl Display for VersionStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "versions: {}", self.versions)?;
+        writeln!(f, "history_blocks: {}", self.history_blocks)?;
+        writeln!(f, "history_bytes: {}", self.history_bytes)?;
+        for (max_version, bytes) in self.reclaimable_bytes.iter().enumerate() {
+            writeln!(f, "reclaimable_bytes_max_version_{}: {}", max_version, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Liveness report served as `.timefs/health`, see [`TimeFS::health`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HealthReport {
+    pub(crate) uptime: Duration,
+    /// Blocks written to the cache but not yet to disk.
+    pub(crate) dirty_blocks: usize,
+    /// When a block was last written to disk, `None` before the first.
+    pub(crate) last_flush: Option<SystemTime>,
+}
+
+impl Display for HealthReport {
+    /// One JSON object, with the last flush in seconds since the epoch or `null`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let last_flush = match self.last_flush.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+            Some(since_epoch) => format!("{:.3}", since_epoch.as_secs_f64()),
+            None => "null".to_string(),
+        };
+        writeln!(
+            f,
+            "{{\"uptime_secs\": {}, \"dirty_blocks\": {}, \"last_flush\": {}}}",
+            self.uptime.as_secs(), self.dirty_blocks, last_flush,
+        )
+    }
+}
+
+/// An open file handle, as listed by [`TimeFS::list_open_handles`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OpenHandleInfo {
+    pub(crate) fh: u64,
+    pub(crate) ino: u64,
+    /// Where the file is linked, `None` once it's unlinked.
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) readable: bool,
+    pub(crate) writable: bool,
+    pub(crate) append: bool,
+    pub(crate) sync: bool,
+}
+
+impl OpenHandleInfo {
+    fn new(fh: u64, handle: &FileHandle) -> Self {
+        Self {
+            fh,
+            ino: handle.inode_id(),
+            path: None,
+            readable: handle.is_read_only() || handle.is_read_write(),
+            writable: handle.is_write_only() || handle.is_read_write(),
+            append: handle.is_append(),
+            sync: handle.is_sync(),
+        }
+    }
+}
+
+impl Display for OpenHandleInfo {
+    /// The handle, inode, access as in `ls -l`, flags and path, separated by spaces.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let access = format!("{}{}", if self.readable { 'r' } else { '-' }, if self.writable { 'w' } else { '-' });
+        let flags = [(self.append, "append"), (self.sync, "sync")]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        let flags = if flags.is_empty() { "-".to_string() } else { flags.join(",") };
+        let path = self.path.as_ref().map_or("-".into(), |path| path.to_string_lossy());
+        writeln!(f, "{} {} {} {} {}", self.fh, self.ino, access, flags, path)
+    }
+}
+
+/// Whoever creates an inode, which they then own with their umask applied to its mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Creator {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) umask: u32,
+}
+
+impl Creator {
+    /// The requester of a FUSE operation creating an inode with `umask`.
+    pub fn from_request(req: &Request<'_>, umask: u32) -> Self {
+        Self { uid: req.uid(), gid: req.gid(), umask }
+    }
+
+    /// The mounting user, masking nothing.
+    pub fn current_user() -> Self {
+        Self { uid: get_current_uid(), gid: get_current_gid(), umask: 0 }
+    }
+
+    fn perm(&self, mode: u32) -> u16 {
+        (mode & !self.umask & 0o7777) as u16
+    }
+}
+
+/// Handle on a mounted filesystem, shared with the background tasks working on it.
+#[derive(Clone)]
+pub(crate) struct TimeFS(Arc<TimeFSInner>);
+
+impl Deref for TimeFS {
+    type Target = TimeFSInner;
+
+    fn deref(&self) -> &TimeFSInner {
+        &self.0
+    }
+}
+
+pub(crate) struct TimeFSInner {
     mount_path: PathBuf,
     storage_path: PathBuf,
     metadata_dir: PathBuf,
     inode_dir: PathBuf,
     blocks_dir: PathBuf,
+    snapshots_dir: PathBuf,
     super_block: RwLock<SuperBlock>,
-    inodes: DashMap<u64, INode>,
+    inodes: Arc<DashMap<u64, INode>>,
+    /// File inodes changed since they were last written, when writes are batched on an interval.
+    dirty_inodes: Arc<Mutex<HashSet<u64>>>,
+    inode_flush_interval: Option<Duration>,
     file_handles: DashMap<u64, FileHandle>,
+    /// Files unlinked while open, kept with no links until their last handle is closed, see
+    /// [`TimeFS::unlink_inode`].
+    orphans: Mutex<HashSet<u64>>,
+    /// Open handles past which opening another file fails with `EMFILE`.
+    max_open_files: Option<usize>,
+    /// Size past which files can't grow, failing with `EFBIG`.
+    max_file_size: Option<u64>,
+    /// Request sizes asked of the kernel in `init`, see [`TimeFS::negotiate`].
+    max_write: Option<u64>,
+    max_readahead: Option<u64>,
+    /// Per-file locks serializing writes and truncation, see [`TimeFS::write_data`].
+    file_locks: DashMap<u64, Arc<tokio::sync::Mutex<()>>>,
+    /// Pin count of files whose blocks are kept resident while they're memory mapped.
+    mapped: DashMap<u64, usize>,
+    /// Block ids reserved for files expected to grow by several blocks, which their new blocks
+    /// take in order so they sit next to each other on disk, see [`TimeFS::reserve_block_run`].
+    block_runs: DashMap<u64, RangeInclusive<u64>>,
+    /// Contents of new blocks written while allocation is delayed, by file and index in the file.
+    /// Their slots in the block list stay holes until [`TimeFS::allocate_delayed`] gives them ids.
+    delayed_blocks: DashMap<u64, BTreeMap<usize, Vec<u8>>>,
+    /// Delayed blocks of all files together, held against [`MAX_TOTAL_DELAYED_BLOCKS`].
+    delayed_count: AtomicUsize,
+    delayed_allocation: bool,
+    /// Parent and name every inode is linked under, filled in as paths are resolved and kept
+    /// up to date by every operation changing links, see [`TimeFS::path_of`].
+    names: DashMap<u64, (u64, String)>,
+    /// Lookups of each inode the kernel holds, it's evicted from memory once they're all forgotten.
+    lookups: DashMap<u64, u64>,
     next_fs: Mutex<u64>,
     block_cache: Arc<BlockCache>,
+    /// Coalesces fsyncs arriving close together, when they're batched.
+    group_commit: Option<Arc<GroupCommit>>,
+    /// References to every block from all inodes on disk, loaded or not, their versions and
+    /// snapshots. Written out on a clean unmount and counted afresh after a crash.
+    block_refs: BlockRefCounts,
+    runtime: tokio::runtime::Handle,
+    read_only: bool,
+    /// Keep everything in memory, nothing is ever read from or written to the storage path.
+    in_memory: bool,
+    atime_policy: AtimePolicy,
+    sync_on_close: SyncOnClose,
+    trash: Option<Mutex<Trash>>,
+    trash_path: PathBuf,
+    /// Ids imported blocks were stored under here, by their ids in the filesystem exported, see
+    /// [`TimeFS::import`].
+    imported_blocks: Mutex<HashMap<u64, u64>>,
+    /// Blocks nothing references anymore, whose files are yet to be deleted before their ids
+    /// are handed out again, see [`TimeFS::delete_released_blocks`].
+    released_blocks: Mutex<Vec<u64>>,
+    /// Held from [`TimeFS::freeze_blocks`] until [`TimeFS::thaw_blocks`], while a backup copies
+    /// the block directory.
+    frozen_blocks: Mutex<Option<FrozenCache>>,
+    trash_retention: Duration,
+    storage_limit: Option<u64>,
+    storage_high_water: u8,
+    quotas: Option<Quotas>,
+    metadata_compression: MetadataCompression,
+    /// Compression inode and superblock files are written with, plain when unset.
+    compress_metadata: Option<MetadataCompression>,
+    /// Fsync metadata files and their directories after writing them.
+    metadata_sync: bool,
+    case_insensitive: bool,
+    auto_version: bool,
+    min_version_interval: Duration,
+    /// Globs of files never versioned automatically, see [`FsOptions::version_exclude`].
+    version_exclude: Vec<Regex>,
+    /// Caps on the versions of each file, by count and by the bytes only they hold.
+    max_versions: Option<usize>,
+    max_version_bytes: Option<u64>,
+    /// How long the kernel may cache attributes and name lookups before asking again.
+    attr_ttl: Duration,
+    entry_ttl: Duration,
+    fifos: DashMap<u64, FifoBuffer>,
+    /// POSIX byte-range locks, shared with the tasks of `setlkw` calls waiting on them.
+    locks: Arc<LockTable>,
+    /// Held for writing while a rename updates several entries, so lookups never see it half done.
+    namespace_lock: RwLock<()>,
+    /// Serializes moves of directories to another parent, so the ancestry a move checks for
+    /// loops can't change under it.
+    rename_lock: Mutex<()>,
+    /// Per-directory locks held by renames over the entries they read and change.
+    ///
+    /// Renames take the rename lock first when they need it, then the locks of both parents, the
+    /// lower inode id first, then the namespace lock. Inodes themselves are only ever borrowed
+    /// one at a time, as two guards on the same shard of `inodes` would deadlock.
+    dir_locks: DashMap<u64, Arc<Mutex<()>>>,
+    /// Tells the kernel about changes it didn't make itself, set once the session is mounted.
+    notifier: Arc<OnceLock<Box<dyn Invalidator>>>,
+    started_at: Instant,
 } 
 
 impl TimeFS {
-    fn new(mount_path: impl AsRef<Path>, storage_path: impl AsRef<Path>) -> Result<Self> {
+    pub(crate) fn with_options(
+        mount_path: impl AsRef<Path>,
+        storage_path: impl AsRef<Path>,
+        options: FsOptions,
+    ) -> Result<Self> {
         let storage_path = storage_path.as_ref().to_path_buf();
-        
+        // Without a mount path the filesystem is only used as a library, see [`crate::storage::Storage`].
+        let mount_path = mount_path.as_ref().to_path_buf();
+        if !options.in_memory && !mount_path.as_os_str().is_empty() && paths_overlap(&mount_path, &storage_path)? {
+            return Err(TimeFSError::Invalid(format!(
+                "mount path {:?} and storage path {:?} overlap, mounting would hide or recurse into the storage",
+                mount_path, storage_path,
+            )));
+        }
+
         let metadata_dir = storage_path.join("metadata");
         let blocks_dir = storage_path.join("blocks");
         let inode_dir = metadata_dir.join("inode");
+        let snapshots_dir = metadata_dir.join("snapshots");
+        let trash_dir = metadata_dir.join("trash");
+        let in_memory = options.in_memory;
+        // Whether anything may be written to the storage directory at all.
+        let persist = !options.read_only && !in_memory;
+        let metadata_sync = !options.no_metadata_sync;
+        let flush_interval = options.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL);
+        let compress_metadata = options.compress_metadata.then_some(options.metadata_compression);
+
+        if persist {
+            std::fs::create_dir_all(&metadata_dir)?;
+            std::fs::create_dir_all(&blocks_dir)?;
+            std::fs::create_dir_all(&inode_dir)?;
+            std::fs::create_dir_all(&snapshots_dir)?;
+            std::fs::create_dir_all(&trash_dir)?;
 
-        std::fs::create_dir_all(&metadata_dir)?;
-        std::fs::create_dir_all(&blocks_dir)?;
-        std::fs::create_dir_all(&inode_dir)?;
+            let migrated = INode::migrate_flat_layout(&inode_dir)?;
+            if migrated > 0 {
+                info!("Moved {} inode files into sharded directories", migrated);
+            }
+        } else if !in_memory && INode::has_flat_layout(&inode_dir)? {
+            return Err(TimeFSError::Invalid(format!(
+                "inode files in {:?} have to be moved into sharded directories, mount the storage writable once first",
+                inode_dir,
+            )));
+        }
 
         let super_block_path = metadata_dir.join("superblock.bin");
-        let super_block = if super_block_path.exists() {
+        let mut super_block = if !in_memory && super_block_path.exists() {
             SuperBlock::from_file(&super_block_path)?
         } else {
-            let sb = SuperBlock::new();
-            sb.write_to_file(&super_block_path)?;
-            sb
+            SuperBlock::new()
         };
+        let unclean = !super_block.is_clean();
+        // Marked in use before any id is handed out, so a crash is noticed on the next mount.
+        if persist {
+            super_block.set_clean(false);
+            super_block.write_to_file(&super_block_path, metadata_sync, compress_metadata)?;
+        }
         
-        let root_inode = Self::create_root_inode();
-        root_inode.write_to_file(inode_dir.as_path())?;
+        // Everything else is loaded lazily, starting from the entries of the root.
+        let root_inode = if !in_memory && INode::inode_path(FUSE_ROOT_ID, &inode_dir).exists() {
+            INode::from_file(FUSE_ROOT_ID, &inode_dir)?
+        } else {
+            let mut root_inode = Self::create_root_inode();
+            if persist {
+                root_inode.write_to_file(inode_dir.as_path(), metadata_sync, compress_metadata)?;
+            }
+            root_inode
+        };
 
-        let mut inodes = DashMap::new();
+        let inodes = Arc::new(DashMap::new());
         inodes.insert(FUSE_ROOT_ID, root_inode);
+        let dirty_inodes = Arc::default();
+        if persist && let Some(interval) = options.inode_flush_interval {
+            Self::spawn_inode_flusher(Arc::downgrade(&inodes), Arc::clone(&dirty_inodes), inode_dir.clone(), metadata_sync, compress_metadata, interval);
+        }
 
-        let blocks_dir_cloned = blocks_dir.clone();
-        
-        Ok(Self {
-            mount_path: mount_path.as_ref().to_path_buf(),
+        let trash_path = trash_dir.join("index.bin");
+        let trash = match options.trash {
+            true if in_memory => Some(Mutex::new(Trash::default())),
+            true => Some(Mutex::new(Trash::from_file(&trash_path)?)),
+            false => None,
+        };
+
+        let block_cache = if in_memory {
+            BlockCache::in_memory(IN_MEMORY_CAPACITY)
+        } else {
+            let cipher = options.key_file
+                .as_ref()
+                .map(BlockCipher::from_key_file)
+                .transpose()?;
+            check_key(cipher.as_ref(), &metadata_dir.join("key_check.bin"), &blocks_dir, persist)?;
+            check_compression(options.block_compression, &metadata_dir.join("block_compression"), &blocks_dir, persist)?;
+
+            #[allow(unused_mut)]
+            let mut codec = BlockCodec::new(cipher);
+            if options.block_compression {
+                #[cfg(feature = "zstd")]
+                {
+                    codec = codec.with_compressor(BlockCompressor::open(zstd::DEFAULT_COMPRESSION_LEVEL, metadata_dir.join("dictionaries"))?);
+                }
+                #[cfg(not(feature = "zstd"))]
+                return Err(TimeFSError::Invalid("block compression needs the zstd feature".to_string()));
+            }
+            codec = codec.with_verify_writes(options.verify_writes);
+
+            let flush_policy = (!flush_interval.is_zero()).then(|| Arc::new(AgePolicy::new(flush_interval)) as Policy);
+            BlockCache::with_flush_policy(
+                1000,
+                &blocks_dir,
+                options.flush_threads.unwrap_or_else(default_flush_threads),
+                codec,
+                flush_policy,
+                options.cache_policy,
+            )
+        };
+
+        // Flushing only on demand, piling up dirty blocks mustn't start flushing either.
+        let dirty_high_water = match options.dirty_high_water {
+            None if flush_interval.is_zero() => None,
+            bytes => Some(bytes.unwrap_or(DEFAULT_DIRTY_HIGH_WATER)),
+        };
+        let block_cache = Arc::new(block_cache.with_dirty_high_water(dirty_high_water));
+        let group_commit = options.fsync_batch_window
+            .filter(|_| persist)
+            .map(|window| Arc::new(GroupCommit::new(window, block_cache.clone(), inodes.clone(), Arc::clone(&dirty_inodes), inode_dir.clone(), compress_metadata)));
+
+        let quotas = options.quota_file.as_ref().map(Quotas::from_file).transpose()?;
+        let imported_blocks_path = metadata_dir.join("imported_blocks.bin");
+        let imported_blocks = match !in_memory && imported_blocks_path.exists() {
+            true => from_bin_file(&imported_blocks_path)?,
+            false => HashMap::new(),
+        };
+        let warm_hints = options.warm_cache.as_ref().map(std::fs::read_to_string).transpose()?;
+
+        // Only up to date after a clean unmount, counted from every inode otherwise.
+        let block_refs_path = metadata_dir.join("block_refs.bin");
+        let block_refs = match !in_memory && !unclean && block_refs_path.exists() {
+            true => Some(BlockRefCounts::from_map(from_bin_file(&block_refs_path)?)),
+            false => None,
+        };
+        let recount_block_refs = !in_memory && block_refs.is_none();
+
+        let fs = Self(Arc::new(TimeFSInner {
+            mount_path,
             storage_path,
             metadata_dir,
             blocks_dir,
             inode_dir,
+            snapshots_dir,
             super_block: RwLock::new(super_block),
             inodes,
+            dirty_inodes,
+            inode_flush_interval: options.inode_flush_interval,
             file_handles: DashMap::new(),
+            orphans: Mutex::new(HashSet::new()),
+            max_open_files: options.max_open_files,
+            max_file_size: options.max_file_size,
+            max_write: options.max_write,
+            max_readahead: options.max_readahead,
+            file_locks: DashMap::new(),
+            mapped: DashMap::new(),
+            block_runs: DashMap::new(),
+            delayed_blocks: DashMap::new(),
+            delayed_count: AtomicUsize::new(0),
+            delayed_allocation: options.delayed_allocation,
+            names: DashMap::new(),
+            lookups: DashMap::new(),
             next_fs: Mutex::new(1),
-            block_cache: Arc::new(BlockCache::new(1000, &blocks_dir_cloned, 30)),
-        })
+            block_cache,
+            group_commit,
+            block_refs: block_refs.unwrap_or_default(),
+            runtime: tokio::runtime::Handle::current(),
+            read_only: options.read_only,
+            in_memory,
+            atime_policy: options.atime,
+            sync_on_close: options.sync_on_close,
+            trash,
+            trash_path,
+            imported_blocks: Mutex::new(imported_blocks),
+            released_blocks: Mutex::new(Vec::new()),
+            frozen_blocks: Mutex::new(None),
+            trash_retention: options.trash_retention.unwrap_or(DEFAULT_TRASH_RETENTION),
+            storage_limit: options.storage_limit,
+            quotas,
+            storage_high_water: options.storage_high_water.unwrap_or(DEFAULT_STORAGE_HIGH_WATER),
+            metadata_compression: options.metadata_compression,
+            compress_metadata,
+            metadata_sync,
+            case_insensitive: options.case_insensitive,
+            auto_version: options.auto_version,
+            min_version_interval: options.min_version_interval.unwrap_or_default(),
+            version_exclude: options.version_exclude,
+            max_versions: options.max_versions.map(usize::from),
+            max_version_bytes: options.max_version_bytes,
+            attr_ttl: options.attr_ttl.unwrap_or(DEFAULT_TTL),
+            entry_ttl: options.entry_ttl.unwrap_or(DEFAULT_TTL),
+            fifos: DashMap::new(),
+            locks: Arc::default(),
+            namespace_lock: RwLock::new(()),
+            rename_lock: Mutex::new(()),
+            dir_locks: DashMap::new(),
+            notifier: Arc::default(),
+            started_at: Instant::now(),
+        }));
+        if options.delayed_allocation && !flush_interval.is_zero() {
+            fs.spawn_delayed_allocator(flush_interval);
+        }
+
+        // The backup or a superblock left behind by a crash may predate ids handed out since,
+        // which mustn't be handed out again.
+        if unclean || fs.super_block.read().is_recovered() {
+            if unclean {
+                warn!("TimeFS wasn't unmounted cleanly, rebuilding the inode index");
+            }
+            fs.rebuild_index()?;
+        } else {
+            if recount_block_refs {
+                fs.recount_block_refs()?;
+            }
+            fs.recompute_quotas()?;
+        }
+        if !fs.read_only {
+            fs.purge_trash(SystemTime::now())?;
+        }
+        if let Some(hints) = warm_hints {
+            fs.warm_cache(&hints);
+        }
+        Ok(fs)
     }
     
     fn create_root_inode() -> INode {
@@ -108,138 +609,6201 @@ impl TimeFS {
         )
     }
     
-    fn get_next_inode_id(&self) -> u64 {
-        let mut lock = self.super_block.write();
-        lock.get_next_inode_id()
+    /// Writes `inode` out, or only marks it dirty when file inodes are flushed on an interval.
+    /// Directories are always written right away, their entry log must stay in step with them.
+    fn persist_inode(&self, inode: &mut INode) -> Result<()> {
+        if let Some(ref quotas) = self.quotas {
+            quotas.charge(inode.id, inode.attr.uid, inode.allocated_bytes());
+        }
+        if self.in_memory {
+            return Ok(());
+        }
+        if self.inode_flush_interval.is_some() && !inode.is_directory() {
+            self.dirty_inodes.lock().insert(inode.id);
+            return Ok(());
+        }
+        inode.write_to_file(&self.inode_dir, self.metadata_sync, self.compress_metadata)
+    }
+
+    /// Writes out every dirty inode still loaded, leaving those that failed dirty.
+    fn write_dirty_inodes(inodes: &DashMap<u64, INode>, dirty: &Mutex<HashSet<u64>>, inode_dir: &Path, sync: bool, compression: Option<MetadataCompression>) -> Result<()> {
+        let ids = std::mem::take(&mut *dirty.lock());
+        let mut result = Ok(());
+        for id in ids {
+            // Inodes freed in the meantime are gone from the map and have nothing left to write.
+            let Some(mut inode) = inodes.get_mut(&id) else {
+                continue;
+            };
+            if let Err(e) = inode.write_to_file(inode_dir, sync, compression) {
+                dirty.lock().insert(id);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Flushes dirty inodes every `interval` for as long as the filesystem is around.
+    fn spawn_inode_flusher(inodes: Weak<DashMap<u64, INode>>, dirty: Arc<Mutex<HashSet<u64>>>, inode_dir: PathBuf, sync: bool, compression: Option<MetadataCompression>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(inodes) = inodes.upgrade() else {
+                    return;
+                };
+                let dirty = dirty.clone();
+                let inode_dir = inode_dir.clone();
+                let written = tokio::task::spawn_blocking(move || Self::write_dirty_inodes(&inodes, &dirty, &inode_dir, sync, compression)).await;
+                match written {
+                    Ok(Err(e)) => error!("Failed to write dirty inodes: {}", e),
+                    Err(e) => error!("Inode flush task failed: {}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+    }
+
+    /// Allocates the delayed blocks of every file every `interval`, so they're flushed like any
+    /// other written block, for as long as the filesystem is around.
+    fn spawn_delayed_allocator(&self, interval: Duration) {
+        let fs = Arc::downgrade(&self.0);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(fs) = fs.upgrade() else {
+                    return;
+                };
+                if let Err(e) = TimeFS(fs).allocate_all_delayed().await {
+                    error!("Failed to allocate delayed blocks: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Writes out and fsyncs every inode whose write was deferred.
+    pub(crate) fn flush_dirty_inodes(&self) -> Result<()> {
+        Self::write_dirty_inodes(&self.inodes, &self.dirty_inodes, &self.inode_dir, true, self.compress_metadata)
+    }
+
+    /// Makes directory `ino` durable as `fsyncdir` requires. Its entries may name files whose
+    /// inode writes are still deferred, so those are written out first.
+    pub(crate) fn fsync_dir(&self, ino: u64) -> Result<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+        self.flush_dirty_inodes()?;
+        self.get_inode_mut(ino)?.write_to_file(&self.inode_dir, true, self.compress_metadata)
+    }
+
+    /// Writes inode `ino` and fsyncs it right away if its write was deferred, as `fsync` requires.
+    pub(crate) fn sync_inode(&self, ino: u64) -> Result<()> {
+        Self::sync_dirty_inode(&self.inodes, &self.dirty_inodes, &self.inode_dir, self.compress_metadata, ino)
+    }
+
+    pub(crate) fn sync_dirty_inode(inodes: &DashMap<u64, INode>, dirty: &Mutex<HashSet<u64>>, inode_dir: &Path, compression: Option<MetadataCompression>, ino: u64) -> Result<()> {
+        if !dirty.lock().remove(&ino) {
+            return Ok(());
+        }
+        let result = match inodes.get_mut(&ino) {
+            Some(mut inode) => inode.write_to_file(inode_dir, true, compression),
+            None => Ok(()),
+        };
+        if result.is_err() {
+            dirty.lock().insert(ino);
+        }
+        result
+    }
+
+    /// Writes the contents and inode of `ino` to disk, as `fsync` requires, together with the
+    /// fsyncs of other files when they're batched.
+    pub(crate) async fn fsync_file(&self, ino: u64) -> Result<()> {
+        self.flush_write_buffers(ino).await?;
+        if let Some(ref group_commit) = self.group_commit {
+            return group_commit.sync(ino).await;
+        }
+        self.sync_blocks(ino).await?;
+        self.sync_inode(ino)
+    }
+
+    /// Writes every block of `ino` still dirty in the cache to disk.
+    async fn sync_blocks(&self, ino: u64) -> Result<()> {
+        let block_ids = self.get_inode(ino)?.referenced_blocks();
+        for block_id in block_ids {
+            self.block_cache.flush_block(block_id, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Applies what the handle `fh` buffers, then writes as much of `ino` to disk as the
+    /// sync-on-close policy asks for.
+    pub(crate) async fn flush_handle(&self, fh: u64, ino: u64) -> Result<()> {
+        self.flush_write_buffer(fh).await?;
+        if self.in_memory {
+            return Ok(());
+        }
+        match self.sync_on_close {
+            SyncOnClose::None => Ok(()),
+            SyncOnClose::Data => {
+                self.flush_write_buffers(ino).await?;
+                self.sync_blocks(ino).await
+            }
+            SyncOnClose::All => self.fsync_file(ino).await,
+        }
+    }
+
+    fn persist_entry_changes(&self, inode: &mut INode) -> Result<()> {
+        if self.in_memory {
+            inode.discard_entry_changes();
+            return Ok(());
+        }
+        inode.write_entry_changes(&self.inode_dir, self.metadata_sync, self.compress_metadata)?;
+        Ok(())
+    }
+
+    fn persist_trash(&self, trash: &Trash) -> Result<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+        trash.write_to_file(&self.trash_path, self.metadata_sync)
+    }
+
+    /// Fails with `EROFS` when mounted read-only, called first by every mutating operation.
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(TimeFSError::ReadOnly);
+        }
+        Ok(())
     }
 
-    fn get_next_block_id(&self) -> u64 {
+    /// Picks the id of a new block of `ino`, taking the next one of the run reserved for it first.
+    fn get_next_block_id(&self, ino: u64) -> Result<u64> {
+        let reserved = self.block_runs.get_mut(&ino).and_then(|mut run| run.next());
+        self.block_runs.remove_if(&ino, |_, run| run.is_empty());
+        if let Some(id) = reserved {
+            return Ok(id);
+        }
         let mut lock = self.super_block.write();
-        lock.get_next_block_id()
+        lock.alloc_block()
+    }
+
+    /// Reserves `count` block ids in a row for the next new blocks of `ino`, unless what's left
+    /// of its current run covers them. Single blocks aren't worth a run.
+    fn reserve_block_run(&self, ino: u64, count: usize) -> Result<()> {
+        if count < 2 {
+            return Ok(());
+        }
+        let remaining = |run: &RangeInclusive<u64>| if run.is_empty() { 0 } else { run.end() - run.start() + 1 };
+        if self.block_runs.get(&ino).is_some_and(|run| remaining(&run) >= count as u64) {
+            return Ok(());
+        }
+        let run = self.super_block.write().alloc_block_run(count as u64)?;
+        if let Some(unused) = self.block_runs.insert(ino, run) {
+            self.return_block_run(unused);
+        }
+        Ok(())
+    }
+
+    /// Gives the ids of a run that weren't used back to be handed out again.
+    fn return_block_run(&self, run: RangeInclusive<u64>) {
+        let mut super_block = self.super_block.write();
+        for id in run {
+            super_block.free_block(id);
+        }
+    }
+
+    /// Drops a reference to `block_id`. Once nothing uses the block anymore its file is deleted
+    /// in the background, and the id recycled only after that so no new block is deleted with it.
+    fn release_block(&self, block_id: u64) {
+        if !self.block_refs.release_last(block_id) {
+            return;
+        }
+        self.released_blocks.lock().push(block_id);
+        let fs = Arc::downgrade(&self.0);
+        self.runtime.spawn(async move {
+            let Some(fs) = fs.upgrade() else {
+                return;
+            };
+            if let Err(e) = TimeFS(fs).delete_released_blocks().await {
+                error!("Failed to delete unused blocks: {}", e);
+            }
+        });
+    }
+
+    /// Deletes the blocks released since the last call and recycles their ids. Those that failed
+    /// are kept for the next call.
+    pub(crate) async fn delete_released_blocks(&self) -> Result<()> {
+        let released = std::mem::take(&mut *self.released_blocks.lock());
+        for (i, &block_id) in released.iter().enumerate() {
+            if let Err(e) = self.block_cache.delete_block(block_id).await {
+                self.released_blocks.lock().extend_from_slice(&released[i..]);
+                return Err(e);
+            }
+            self.super_block.write().free_block(block_id);
+        }
+        Ok(())
     }
 
+    /// Returns inode `id`, loading it from disk the first time it's accessed.
+    ///
+    /// Loading needs to lock the map, so no other inode may be held while calling this.
     fn get_inode(&self, id: u64) -> Result<impl Deref<Target = INode> + '_> {
+        if let Some(inode) = self.inodes.get(&id) {
+            return Ok(inode);
+        }
+        self.load_inode(id)?;
         self.inodes
             .get(&id)
             .ok_or(TimeFSError::NotFound(id))
     }
 
     fn get_inode_mut(&self, id: u64) -> Result<impl DerefMut<Target = INode> + '_> {
+        if let Some(inode) = self.inodes.get_mut(&id) {
+            return Ok(inode);
+        }
+        self.load_inode(id)?;
         self.inodes
             .get_mut(&id)
             .ok_or(TimeFSError::NotFound(id))
     }
 
-    fn get_inode_by_name(&self, parent: u64, name: impl AsRef<str>) -> Result<impl Deref<Target = INode> + '_> {
-        let parent_node = self.get_inode(parent)?;
-        let parent_node = parent_node.deref();
-        let child_node = parent_node.get_child_id(name.as_ref())?;
-        Ok(self.get_inode(child_node)?)
+    /// Reads inode `id` from disk into memory. A missing file means the inode doesn't exist, and
+    /// any entry still pointing at it is dangling, while other failures are reported as I/O errors.
+    fn load_inode(&self, id: u64) -> Result<()> {
+        if self.in_memory {
+            return Err(TimeFSError::NotFound(id));
+        }
+
+        let inode = match INode::from_file(id, &self.inode_dir) {
+            Ok(inode) => inode,
+            Err(TimeFSError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Inode {} is referenced but missing from disk", id);
+                return Err(TimeFSError::NotFound(id));
+            }
+            Err(e) => return Err(e),
+        };
+        // Left over from a botched recovery, this file belongs to another inode that it would shadow.
+        if inode.id != id {
+            warn!("Inode file of {} claims id {}, ignoring it", id, inode.id);
+            return Err(TimeFSError::NotFound(id));
+        }
+
+        // Another thread may have loaded it meanwhile and changed it since.
+        if let Entry::Vacant(entry) = self.inodes.entry(id) {
+            entry.insert(inode);
+        }
+        Ok(())
     }
 
-    fn get_inode_mut_by_name(&self, parent: u64, name: impl AsRef<str>) -> Result<impl DerefMut<Target = INode> + '_> {
-        let parent_node = self.get_inode(parent)?;
-        let parent_node = parent_node.deref();
-        let child_node = parent_node.get_child_id(name.as_ref())?;
-        Ok(self.get_inode_mut(child_node)?)
+    /// Counts the references to every block afresh, loading every inode and snapshot to do so.
+    fn recount_block_refs(&self) -> Result<()> {
+        self.load_all_inodes()?;
+        self.block_refs.clear();
+        for inode in self.inodes.iter() {
+            for block in inode.held_blocks() {
+                self.block_refs.acquire(block);
+            }
+        }
+        if !self.in_memory {
+            for snapshot in Snapshot::load_all(&self.snapshots_dir)? {
+                for block in snapshot.files.values().flatten().filter(|b| !b.is_hole()) {
+                    self.block_refs.acquire(block);
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn create_file(&self, parent: u64, name: impl AsRef<str>, flags: i32) -> Result<(FileAttr, u64)> {
-        let mut parent_node = self.get_inode_mut(parent)?;
-        let parent_node = parent_node.deref_mut();
+    /// Loads every inode persisted on disk, for checks that must see the whole filesystem.
+    fn load_all_inodes(&self) -> Result<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+
+        // Nothing was ever written to storage opened read-only from the start.
+        let shards = match std::fs::read_dir(&self.inode_dir) {
+            Ok(shards) => shards,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for shard in shards {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in std::fs::read_dir(shard.path())? {
+                let name = entry?.file_name();
+                let id = name.to_str().and_then(INode::parse_file_name);
+                if let Some(id) = id.filter(|id| !self.inodes.contains_key(id)) {
+                    match self.load_inode(id) {
+                        Err(TimeFSError::NotFound(_)) => {}
+                        result => result?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let child_id = parent_node.get_child_id(name.as_ref())?;
+    /// Every inode file on disk along with its ctime, by the id it claims. Normally each id has
+    /// one file at its own path, several are left over from a botched recovery.
+    fn inode_files_by_id(&self) -> Result<HashMap<u64, Vec<(PathBuf, SystemTime)>>> {
+        let mut files: HashMap<u64, Vec<(PathBuf, SystemTime)>> = HashMap::new();
+        for shard in std::fs::read_dir(&self.inode_dir)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in std::fs::read_dir(shard.path())? {
+                let path = entry?.path();
+                let is_inode = path.extension().is_some_and(|ext| ext == "bin")
+                    && path.file_name().and_then(OsStr::to_str).and_then(INode::parse_file_name).is_some();
+                if is_inode {
+                    let inode: INode = from_bin_file(&path)?;
+                    files.entry(inode.id).or_default().push((path, inode.attr.ctime));
+                }
+            }
+        }
+        Ok(files)
+    }
 
-        let inode = self.get_inode(child_id);
+    /// Moves the older files claiming `ino` to `lost+found`, leaving `newest` at the path of `ino`.
+    /// A copy of the inode loaded from a moved file is dropped, to be loaded again from `newest`.
+    fn quarantine_duplicate_inodes(&self, ino: u64, newest: &Path, older: &[PathBuf]) -> Result<()> {
+        let lost_found = self.storage_path.join("lost+found");
+        std::fs::create_dir_all(&lost_found)?;
+        for path in older {
+            let quarantined = (0..)
+                .map(|n| match n {
+                    0 => lost_found.join(format!("inode_{}.bin", ino)),
+                    n => lost_found.join(format!("inode_{}.{}.bin", ino, n)),
+                })
+                .find(|path| !path.exists())
+                .unwrap();
+            warn!("Inode file {:?} duplicates inode {}, moving it to {:?}", path, ino, quarantined);
+            std::fs::rename(path, &quarantined)?;
+        }
 
-        if let Ok(inode) = inode {
-            let inode = inode.deref();
-            return Ok((inode.attr, self.alloc_file_handle(child_id, flags)));
+        let path = INode::inode_path(ino, &self.inode_dir);
+        if newest != path {
+            std::fs::rename(newest, &path)?;
+            if self.inodes.remove(&ino).is_some() {
+                self.names.remove(&ino);
+            }
         }
+        crate::sync_parent_dir(&path)?;
+        Ok(())
+    }
 
-        let inode = self.alloc_inode(parent, FileType::RegularFile);
-        let inode_id = inode.id;
-        let attr = inode.attr;
+    /// Name `name` is stored under in `parent`. Only differs from `name` when lookups are case
+    /// insensitive and an entry matches it up to case.
+    fn stored_name(&self, parent: u64, name: &str) -> Result<String> {
+        if !self.case_insensitive {
+            return Ok(name.to_string());
+        }
+        let parent_node = self.get_inode(parent)?;
+        Ok(parent_node.find_entry_ignore_case(name).unwrap_or(name).to_string())
+    }
 
-        self.inodes.insert(child_id, inode);
-        Ok((attr, inode_id))
+    fn child_id(&self, parent: u64, name: &str) -> Result<u64> {
+        let name = self.stored_name(parent, name)?;
+        self.get_inode(parent)?.get_child_id(name)
     }
 
-    fn alloc_inode(&self, parent: u64, kind: FileType) -> INode {
-        let mut sb_lock = self.super_block.write();
-        sb_lock.alloc_inode();
+    /// Kind of the child `entry` points at, only loading the child for entries from before
+    /// directories kept the kinds of their children.
+    fn entry_kind(&self, entry: ChildEntry) -> Result<FileType> {
+        match entry.kind {
+            Some(kind) => Ok(kind),
+            None => Ok(self.get_attr(entry.id)?.kind),
+        }
+    }
 
-        let next_inode_id = sb_lock.get_next_inode_id();
+    fn get_inode_by_name(&self, parent: u64, name: impl AsRef<str>) -> Result<impl Deref<Target = INode> + '_> {
+        let child_node = self.child_id(parent, name.as_ref())?;
+        self.get_inode(child_node)
+    }
 
-        match kind {
-            FileType::RegularFile =>  {
-                let attr = FileAttrBuilder::default()
-                    .ino(next_inode_id)
-                    .build();
+    pub(crate) fn create_file(&self, parent: u64, name: impl AsRef<str>, flags: i32) -> Result<(FileAttr, u64)> {
+        self.create_file_as(parent, name, flags, 0o755, Creator::current_user())
+    }
 
-                INode::new(next_inode_id, parent, INodeType::empty_file(), attr)
-            }
-            FileType::Directory => {
-                let attr = FileAttrBuilder::default()
-                    .ino(next_inode_id)
-                    .with_directory()
-                    .build();
+    /// Creates a regular file owned by `creator` with `mode` masked by its umask, or opens the
+    /// file already there unless `flags` has `O_EXCL`.
+    fn create_file_as(&self, parent: u64, name: impl AsRef<str>, flags: i32, mode: u32, creator: Creator) -> Result<(FileAttr, u64)> {
+        self.ensure_writable()?;
+        // Checked up front too, so a file isn't created only for opening it to fail.
+        self.check_open_files()?;
+        let name = name.as_ref();
+        validate_name(name)?;
 
-                INode::new(next_inode_id, parent, INodeType::empty_directory(), attr)
+        let stored_name = self.stored_name(parent, name)?;
+        if stored_name != name {
+            return Err(TimeFSError::NameExist(stored_name));
+        }
+        let child_id = self.get_inode(parent)?.get_child_id(name);
+        match child_id {
+            Ok(_) if flags.is_exclusive() => return Err(TimeFSError::NameExist(name.to_string())),
+            Ok(child_id) => {
+                let attr = self.get_attr(child_id)?;
+                return Ok((attr, self.alloc_file_handle(child_id, flags)?));
             }
-            _ => unreachable!(),
+            Err(TimeFSError::NameNotFound(_)) => {}
+            Err(e) => return Err(e),
         }
-    }
 
-    fn alloc_file_handle(&self, inode_id: u64, flags: i32) -> u64 {
-        let mut lock = self.next_fs.lock();
-        let handle_id = *lock;
-        *lock += 1;
+        let mut inode = self.alloc_inode(parent, FileType::RegularFile, mode, creator)?;
+        let inode_id = inode.id;
+        let attr = inode.attr;
+        self.persist_inode(&mut inode)?;
+        self.inodes.insert(inode_id, inode);
 
-        self.file_handles.insert(handle_id, FileHandle::new(inode_id, flags));
-        handle_id
-    }
+        {
+            let mut parent_node = self.get_inode_mut(parent)?;
+            parent_node.add_entry(name, ChildEntry::new(inode_id, attr.kind))?;
+            self.persist_entry_changes(&mut parent_node)?;
+        }
+        self.names.insert(inode_id, (parent, name.to_string()));
 
-    fn get_attr(&self, inode_id: u64) -> Result<FileAttr> {
-        let inode = self.get_inode(inode_id)?;
-        let inode = inode.deref();
-        Ok(inode.attr)
+        Ok((attr, self.alloc_file_handle(inode_id, flags)?))
     }
-}
 
-impl Filesystem for TimeFS {
-    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> std::result::Result<(), c_int> {
-        debug!("TimeFS has inited");
-        Ok(())
+    /// Creates a regular file, directory, FIFO, socket or device of the kind `mode` names, devices
+    /// being numbered `rdev`. Symlinks have targets to store and can't be made this way.
+    pub(crate) fn make_node(&self, parent: u64, name: &str, mode: u32, rdev: u32, creator: Creator) -> Result<FileAttr> {
+        self.ensure_writable()?;
+        let kind = match file_type_from_mode(mode) {
+            Some(FileType::Symlink) | None => return Err(TimeFSError::UnsupportedFileType(mode & libc::S_IFMT)),
+            Some(kind) => kind,
+        };
+        validate_name(name)?;
+
+        if self.child_id(parent, name).is_ok() {
+            return Err(TimeFSError::NameExist(name.to_string()));
+        }
+
+        let mut inode = self.alloc_inode(parent, kind, mode, creator)?;
+        if matches!(kind, FileType::CharDevice | FileType::BlockDevice) {
+            inode.attr.rdev = rdev;
+        }
+        let inode_id = inode.id;
+        let attr = inode.attr;
+        self.persist_inode(&mut inode)?;
+        self.inodes.insert(inode_id, inode);
+
+        {
+            let mut parent_node = self.get_inode_mut(parent)?;
+            parent_node.add_entry(name, ChildEntry::new(inode_id, attr.kind))?;
+            self.persist_entry_changes(&mut parent_node)?;
+        }
+        if kind == FileType::Directory {
+            self.adjust_subdir_links(parent, true)?;
+        }
+        self.names.insert(inode_id, (parent, name.to_string()));
+        Ok(attr)
     }
 
-    fn destroy(&mut self) {
-        debug!("TimeFS has destroyed");
+    fn is_fifo(&self, ino: u64) -> Result<bool> {
+        Ok(self.get_attr(ino)?.kind == FileType::NamedPipe)
     }
 
-    fn create(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
-        debug!("create(parent = {}, name = {:?}, mode = {}, umask = {}, flags = {})", parent, name, mode, umask, flags);
+    /// Returns which of the poll `events` `ino` is ready for. Regular files are always ready.
+    pub(crate) fn poll_events(&self, ino: u64, events: u32) -> Result<u32> {
+        if self.is_fifo(ino)? {
+            return Ok(self.fifos.entry(ino).or_default().readiness(events));
+        }
+        Ok(events & (libc::POLLIN | libc::POLLOUT) as u32)
+    }
 
-        let name_str= name.to_str();
+    /// Unlinks `name` from `parent`, moving the inode to the trash when enabled and freeing it otherwise.
+    /// Directories must be empty.
+    pub(crate) fn remove_entry(&self, parent: u64, name: &str, is_dir: bool) -> Result<()> {
+        self.ensure_writable()?;
+        let name = &self.stored_name(parent, name)?;
+        let entry = self.get_inode(parent)?.get_child_entry(name)?;
+        let child_id = entry.id;
+        let child_is_dir = self.entry_kind(entry)? == FileType::Directory;
 
-        if name_str.is_none() {
-            error!("{:?} is not a valid UTF-8 string", name_str);
-            reply.error(libc::EINVAL);
-            return;
+        match (child_is_dir, is_dir) {
+            (true, false) => return Err(TimeFSError::IsDirectory(child_id)),
+            (false, true) => return Err(TimeFSError::NotDirectory(child_id)),
+            (true, true) => {
+                if let INodeType::Directory { ref entries } = self.get_inode(child_id)?.data
+                    && !entries.is_empty()
+                {
+                    return Err(TimeFSError::NotEmpty(child_id));
+                }
+            }
+            (false, false) => {}
         }
 
-        let name_str = name_str.unwrap();
+        {
+            let mut parent_node = self.get_inode_mut(parent)?;
+            parent_node.remove_entry(name)?;
+            self.persist_entry_changes(&mut parent_node)?;
+        }
+        if child_is_dir {
+            self.adjust_subdir_links(parent, false)?;
+        }
+        self.names.remove(&child_id);
 
-        match self.create_file(parent, name_str, flags) {
-            Ok((attr, handle_id)) => {
-                let ttl = std::time::Duration::from_secs(1);
-                reply.created(&ttl, &attr, 0, handle_id, flags as u32);
+        match self.trash {
+            Some(ref trash) => {
+                let mut trash = trash.lock();
+                trash.push(TrashEntry::new(child_id, parent, name));
+                self.persist_trash(&trash)?;
             }
-            Err(e) => reply.error(e.into())
+            None => self.unlink_inode(child_id)?,
         }
+
+        self.purge_trash(SystemTime::now())?;
+        Ok(())
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+    /// Moves `parent/name` to `new_parent/new_name`, replacing an existing target unless
+    /// `RENAME_NOREPLACE` is given. With `RENAME_EXCHANGE` both entries must exist and are swapped.
+    pub(crate) fn rename_entry(&self, parent: u64, name: &str, new_parent: u64, new_name: &str, flags: u32) -> Result<()> {
+        self.ensure_writable()?;
+        validate_name(new_name)?;
+
+        // Whether a directory changes parents is only certain once both are locked, so the rare
+        // rename finding out it does only then starts over with the rename lock.
+        let mut moves_dir = false;
+        loop {
+            let _rename = moves_dir.then(|| self.rename_lock.lock());
+            let (first_lock, second_lock) = (self.dir_lock(parent.min(new_parent)), self.dir_lock(parent.max(new_parent)));
+            let _first = first_lock.lock();
+            let _second = (parent != new_parent).then(|| second_lock.lock());
 
+            let exchange = flags & libc::RENAME_EXCHANGE != 0;
+            if !moves_dir && parent != new_parent && (self.is_dir_entry(parent, name) || exchange && self.is_dir_entry(new_parent, new_name)) {
+                moves_dir = true;
+                continue;
+            }
+            return self.rename_locked(parent, name, new_parent, new_name, flags);
+        }
     }
-}
\ No newline at end of file
+
+    /// Renames with the locks of both parents held, see [`TimeFS::rename_entry`].
+    fn rename_locked(&self, parent: u64, name: &str, new_parent: u64, new_name: &str, flags: u32) -> Result<()> {
+        let name = &self.stored_name(parent, name)?;
+        let dst_name = &self.stored_name(new_parent, new_name)?;
+        let src_entry = self.get_inode(parent)?.get_child_entry(name)?;
+        let dst_entry = self.get_inode(new_parent)?.get_child_entry(dst_name);
+        let src = src_entry.id;
+        let src_is_dir = self.entry_kind(src_entry)? == FileType::Directory;
+
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            let dst_entry = dst_entry?;
+            let dst = dst_entry.id;
+            let dst_is_dir = self.entry_kind(dst_entry)? == FileType::Directory;
+            if src_is_dir && self.is_ancestor(src, new_parent)? || dst_is_dir && self.is_ancestor(dst, parent)? {
+                return Err(TimeFSError::Invalid(format!("can't exchange {} and {}, one contains the other", src, dst)));
+            }
+            let _namespace = self.namespace_lock.write();
+            self.set_entry(parent, name, dst_entry)?;
+            self.set_entry(new_parent, dst_name, src_entry)?;
+            self.move_inode(src, new_parent)?;
+            self.move_inode(dst, parent)?;
+            if parent != new_parent && src_is_dir != dst_is_dir {
+                self.adjust_subdir_links(parent, dst_is_dir)?;
+                self.adjust_subdir_links(new_parent, src_is_dir)?;
+            }
+            self.names.insert(src, (new_parent, dst_name.clone()));
+            self.names.insert(dst, (parent, name.clone()));
+            return Ok(());
+        }
+
+        if src_is_dir && self.is_ancestor(src, new_parent)? {
+            return Err(TimeFSError::Invalid(format!("can't move directory {} into itself", src)));
+        }
+
+        let _namespace = self.namespace_lock.write();
+        match dst_entry {
+            // Renaming an entry to itself, which only changes anything when its case does.
+            Ok(dst) if dst.id == src && dst_name == new_name => return Ok(()),
+            Ok(dst) if dst.id == src => {}
+            Ok(_) if flags & libc::RENAME_NOREPLACE != 0 => return Err(TimeFSError::NameExist(new_name.to_string())),
+            Ok(dst_entry) => {
+                let dst = dst_entry.id;
+                let dst_is_dir = self.entry_kind(dst_entry)? == FileType::Directory;
+                match (src_is_dir, dst_is_dir) {
+                    (true, false) => return Err(TimeFSError::NotDirectory(dst)),
+                    (false, true) => return Err(TimeFSError::IsDirectory(dst)),
+                    _ => self.remove_entry(new_parent, dst_name, src_is_dir)?,
+                }
+            }
+            Err(TimeFSError::NameNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        {
+            let mut parent_node = self.get_inode_mut(parent)?;
+            parent_node.remove_entry(name)?;
+            self.persist_entry_changes(&mut parent_node)?;
+        }
+        {
+            let mut new_parent_node = self.get_inode_mut(new_parent)?;
+            new_parent_node.add_entry(new_name, src_entry)?;
+            self.persist_entry_changes(&mut new_parent_node)?;
+        }
+        if src_is_dir && parent != new_parent {
+            self.adjust_subdir_links(parent, false)?;
+            self.adjust_subdir_links(new_parent, true)?;
+        }
+        self.names.insert(src, (new_parent, new_name.to_string()));
+        self.move_inode(src, new_parent)
+    }
+
+    /// Counts a subdirectory linked into or out of `dir`, as the `..` of each adds a link to it.
+    fn adjust_subdir_links(&self, dir: u64, linked: bool) -> Result<()> {
+        let mut dir_node = self.get_inode_mut(dir)?;
+        dir_node.attr.nlink = match linked {
+            true => dir_node.attr.nlink + 1,
+            false => dir_node.attr.nlink.saturating_sub(1).max(2),
+        };
+        dir_node.touch_ctime();
+        self.persist_inode(&mut dir_node)
+    }
+
+    fn dir_lock(&self, ino: u64) -> Arc<Mutex<()>> {
+        self.dir_locks.entry(ino).or_default().clone()
+    }
+
+    /// Whether `parent/name` exists and is a directory.
+    fn is_dir_entry(&self, parent: u64, name: &str) -> bool {
+        let Ok(name) = self.stored_name(parent, name) else {
+            return false;
+        };
+        let entry = self.get_inode(parent).and_then(|parent_node| parent_node.get_child_entry(name));
+        entry.and_then(|entry| self.entry_kind(entry)).is_ok_and(|kind| kind == FileType::Directory)
+    }
+
+    /// Points the existing entry `name` of `parent` at another inode.
+    fn set_entry(&self, parent: u64, name: &str, entry: ChildEntry) -> Result<()> {
+        let mut parent_node = self.get_inode_mut(parent)?;
+        parent_node.remove_entry(name)?;
+        parent_node.add_entry(name, entry)?;
+        self.persist_entry_changes(&mut parent_node)
+    }
+
+    fn move_inode(&self, ino: u64, new_parent: u64) -> Result<()> {
+        let mut inode = self.get_inode_mut(ino)?;
+        inode.parent = new_parent;
+        inode.touch_ctime();
+        self.persist_inode(&mut inode)
+    }
+
+    /// Whether `ancestor` is `ino` itself or one of the directories above it.
+    fn is_ancestor(&self, ancestor: u64, mut ino: u64) -> Result<bool> {
+        loop {
+            if ino == ancestor {
+                return Ok(true);
+            }
+            if ino == FUSE_ROOT_ID {
+                return Ok(false);
+            }
+            ino = self.get_inode(ino)?.parent;
+        }
+    }
+
+    /// Path of `ino` from the root of the filesystem, e.g. `/dir/file.txt`, taking one index
+    /// lookup per directory above it. Ancestors needn't be loaded, a parent seen twice is a cycle.
+    pub(crate) fn path_of(&self, ino: u64) -> Result<PathBuf> {
+        let mut components = Vec::new();
+        let mut visited = HashSet::new();
+        let mut current = ino;
+        while current != FUSE_ROOT_ID {
+            if !visited.insert(current) {
+                return Err(TimeFSError::Invalid(format!("inode {} isn't linked below the root", ino)));
+            }
+            let (parent, name) = self.name_of(current)?;
+            components.push(name);
+            current = parent;
+        }
+        Ok(std::iter::once("/").chain(components.iter().rev().map(String::as_str)).collect())
+    }
+
+    /// Parent and name `ino` is linked under, searching the entries of its parent the first time.
+    fn name_of(&self, ino: u64) -> Result<(u64, String)> {
+        if let Some(linked) = self.names.get(&ino) {
+            return Ok(linked.clone());
+        }
+        let parent = self.get_inode(ino)?.parent;
+        let name = match self.get_inode(parent)?.data {
+            INodeType::Directory { ref entries } => entries.iter().find(|&(_, entry)| entry.id == ino).map(|(name, _)| name.clone()),
+            INodeType::File { .. } => None,
+        };
+        let name = name.ok_or(TimeFSError::NotFound(ino))?;
+        self.names.insert(ino, (parent, name.clone()));
+        Ok((parent, name))
+    }
+
+    /// Frees an inode that lost its last link, unless it's still open: then it's kept as an orphan
+    /// with no links until its last handle is closed, so its id isn't handed to another file while
+    /// old handles may still write to it.
+    fn unlink_inode(&self, id: u64) -> Result<()> {
+        let mut orphans = self.orphans.lock();
+        if !self.file_handles.iter().any(|handle| handle.inode_id() == id) {
+            return self.free_inode(id);
+        }
+        let mut inode = self.get_inode_mut(id)?;
+        inode.attr.nlink = 0;
+        inode.touch_ctime();
+        self.persist_inode(&mut inode)?;
+        orphans.insert(id);
+        debug!("Keeping unlinked inode {} until its last handle is closed", id);
+        Ok(())
+    }
+
+    /// Frees `ino` if it's an orphan with no handles left open.
+    fn free_orphan(&self, ino: u64) -> Result<()> {
+        let mut orphans = self.orphans.lock();
+        if !orphans.contains(&ino) || self.file_handles.iter().any(|handle| handle.inode_id() == ino) {
+            return Ok(());
+        }
+        orphans.remove(&ino);
+        self.free_inode(ino)
+    }
+
+    /// Drops an unlinked inode for good, releasing the blocks it held.
+    fn free_inode(&self, id: u64) -> Result<()> {
+        self.file_locks.remove(&id);
+        self.dir_locks.remove(&id);
+        self.names.remove(&id);
+        if let Some((_, unused)) = self.block_runs.remove(&id) {
+            self.return_block_run(unused);
+        }
+        self.dirty_inodes.lock().remove(&id);
+        let inode = match self.inodes.remove(&id) {
+            Some((_, inode)) => inode,
+            None if self.in_memory => return Err(TimeFSError::NotFound(id)),
+            None => INode::from_file(id, &self.inode_dir)?,
+        };
+
+        for block_id in inode.referenced_blocks() {
+            self.release_block(block_id);
+        }
+        if let Some(ref quotas) = self.quotas {
+            quotas.release(id);
+        }
+        let mut super_block = self.super_block.write();
+        super_block.free_inode(id, inode.generation);
+        if !self.in_memory {
+            INode::remove_file(id, &self.inode_dir)?;
+            super_block.write_to_file(self.metadata_dir.join("superblock.bin"), self.metadata_sync, self.compress_metadata)?;
+        }
+        Ok(())
+    }
+
+    /// Counts a lookup of `ino` the kernel holds on to until it forgets it.
+    fn remember_lookup(&self, ino: u64) {
+        *self.lookups.entry(ino).or_default() += 1;
+    }
+
+    /// Drops `nlookup` of the kernel's lookups of `ino`, evicting it from memory once none are left.
+    pub(crate) fn forget_inode(&self, ino: u64, nlookup: u64) {
+        let forgotten = match self.lookups.entry(ino) {
+            Entry::Occupied(mut lookups) if *lookups.get() > nlookup => {
+                *lookups.get_mut() -= nlookup;
+                false
+            }
+            Entry::Occupied(lookups) => {
+                lookups.remove();
+                true
+            }
+            Entry::Vacant(_) => true,
+        };
+        if forgotten && let Err(e) = self.evict_inode(ino) {
+            warn!("Failed to evict inode {}: {}", ino, e);
+        }
+    }
+
+    /// Writes inode `ino` out and drops it from memory, to be loaded again on its next access.
+    /// Kept while open, mapped, unlinked or holding delayed blocks. Returns whether it was evicted.
+    fn evict_inode(&self, ino: u64) -> Result<bool> {
+        if ino == FUSE_ROOT_ID || self.in_memory || self.mapped.contains_key(&ino) || self.delayed_blocks.contains_key(&ino) {
+            return Ok(false);
+        }
+        if self.file_handles.iter().any(|handle| handle.inode_id() == ino) {
+            return Ok(false);
+        }
+
+        let mut result = Ok(());
+        let evicted = self.inodes.remove_if_mut(&ino, |_, inode| {
+            if inode.attr.nlink == 0 {
+                return false;
+            }
+            // With deferred writes the flusher may have taken it off the dirty set without writing it yet.
+            if self.inode_flush_interval.is_some() {
+                self.dirty_inodes.lock().remove(&ino);
+                result = inode.write_to_file(&self.inode_dir, self.metadata_sync, self.compress_metadata);
+                if result.is_err() {
+                    self.dirty_inodes.lock().insert(ino);
+                }
+            }
+            result.is_ok()
+        });
+        result?;
+        if evicted.is_none() {
+            return Ok(false);
+        }
+        debug!("Evicted inode {} from memory", ino);
+        Ok(true)
+    }
+
+    /// Asks the kernel for readdirplus and the configured request sizes, settling for the nearest
+    /// sizes it supports.
+    fn negotiate(&self, config: &mut dyn InitConfig) {
+        // Listing a directory then answers the lookups of its entries in the same request.
+        if let Err(unsupported) = config.add_capabilities(consts::FUSE_DO_READDIRPLUS) {
+            debug!("Kernel doesn't support readdirplus (capabilities {:#x})", unsupported);
+        }
+        if let Some(max_write) = self.max_write {
+            negotiate_size("max write", max_write, |size| config.set_max_write(size));
+        }
+        if let Some(max_readahead) = self.max_readahead {
+            negotiate_size("max readahead", max_readahead, |size| config.set_max_readahead(size));
+        }
+    }
+
+    /// Frees trashed inodes past the retention period, then the oldest remaining ones while
+    /// block storage is over its limit. Returns how many were purged.
+    pub(crate) fn purge_trash(&self, now: SystemTime) -> Result<usize> {
+        self.purge_trash_for(now, 0)
+    }
+
+    /// Like [`TimeFS::purge_trash`], but purges until `needed` more bytes fit within the limit.
+    fn purge_trash_for(&self, now: SystemTime, needed: u64) -> Result<usize> {
+        let Some(ref trash) = self.trash else {
+            return Ok(0);
+        };
+        let mut trash = trash.lock();
+
+        let expired = trash.take_expired(now, self.trash_retention);
+        let mut purged = expired.len();
+        for entry in expired {
+            self.unlink_inode(entry.ino)?;
+            self.invalidate_entry(TRASH_DIR_INO, &entry.trash_name());
+        }
+
+        if let Some(limit) = self.storage_limit {
+            while self.storage_used() + needed > limit {
+                let Some(entry) = trash.pop_oldest() else {
+                    break;
+                };
+                self.unlink_inode(entry.ino)?;
+                self.invalidate_entry(TRASH_DIR_INO, &entry.trash_name());
+                purged += 1;
+            }
+        }
+
+        if purged > 0 {
+            debug!("Purged {} inodes from the trash", purged);
+            self.persist_trash(&trash)?;
+        }
+        Ok(purged)
+    }
+
+    /// Moves the trashed inode `ino` back into the tree as `new_parent/new_name`.
+    pub(crate) fn restore(&self, ino: u64, new_parent: u64, new_name: &str) -> Result<()> {
+        self.ensure_writable()?;
+        validate_name(new_name)?;
+        let Some(ref trash) = self.trash else {
+            return Err(TimeFSError::NotFound(ino));
+        };
+        let mut trash = trash.lock();
+        if !trash.entries().iter().any(|e| e.ino == ino) {
+            return Err(TimeFSError::NotFound(ino));
+        }
+
+        let kind = self.get_attr(ino)?.kind;
+        let entry = ChildEntry::new(ino, kind);
+        {
+            let mut parent_node = self.get_inode_mut(new_parent)?;
+            parent_node.add_entry(new_name, entry)?;
+            self.persist_entry_changes(&mut parent_node)?;
+        }
+        if kind == FileType::Directory {
+            self.adjust_subdir_links(new_parent, true)?;
+        }
+        {
+            let mut inode = self.get_inode_mut(ino)?;
+            inode.parent = new_parent;
+            inode.touch_ctime();
+            self.persist_inode(&mut inode)?;
+        }
+        self.names.insert(ino, (new_parent, new_name.to_string()));
+
+        let entry = trash.take(ino);
+        self.persist_trash(&trash)?;
+        if let Some(entry) = entry {
+            self.invalidate_entry(TRASH_DIR_INO, &entry.trash_name());
+        }
+        self.invalidate_entry(new_parent, new_name);
+        self.invalidate_inode(ino, false);
+        Ok(())
+    }
+
+    pub(crate) fn mount_path(&self) -> &Path {
+        &self.mount_path
+    }
+
+    /// Where the notifier of the mounted session goes, so changes made outside of FUSE requests
+    /// can be pushed to the kernel.
+    pub(crate) fn notifier_slot(&self) -> Arc<OnceLock<Box<dyn Invalidator>>> {
+        self.notifier.clone()
+    }
+
+    /// Makes the kernel drop the cached attributes of `ino`, and its cached data too with `data`.
+    ///
+    /// Sent from a blocking task, as the kernel may wait on locks held for the request being
+    /// handled. Inodes the kernel doesn't know about fail with `ENOENT`, which is fine.
+    fn invalidate_inode(&self, ino: u64, data: bool) {
+        let notifier = self.notifier.clone();
+        self.runtime.spawn_blocking(move || {
+            let Some(notifier) = notifier.get() else {
+                return;
+            };
+            let offset = if data { 0 } else { -1 };
+            match notifier.inval_inode(ino, offset, 0) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => warn!("Failed to invalidate inode {} in the kernel: {}", ino, e),
+                _ => {}
+            }
+        });
+    }
+
+    /// Makes the kernel forget what it cached for `parent/name`, including that it doesn't exist.
+    fn invalidate_entry(&self, parent: u64, name: &str) {
+        let notifier = self.notifier.clone();
+        let name = name.to_string();
+        self.runtime.spawn_blocking(move || {
+            let Some(notifier) = notifier.get() else {
+                return;
+            };
+            match notifier.inval_entry(parent, OsStr::new(&name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    warn!("Failed to invalidate entry {:?} of inode {} in the kernel: {}", name, parent, e)
+                }
+                _ => {}
+            }
+        });
+    }
+
+    /// Bytes of block storage referenced by live files, versions, snapshots and the trash.
+    fn storage_used(&self) -> u64 {
+        self.block_refs.bytes()
+    }
+
+    /// How long to hold back a write so writers slow down as storage fills up. Nothing below
+    /// the high watermark, growing linearly to [`MAX_WRITE_DELAY`] at the storage limit.
+    fn write_delay(&self) -> Duration {
+        let Some(limit) = self.storage_limit else {
+            return Duration::ZERO;
+        };
+        let high_water = (limit as u128 * self.storage_high_water as u128 / 100) as u64;
+        let used = self.storage_used();
+        if used <= high_water || limit <= high_water {
+            return Duration::ZERO;
+        }
+
+        let fullness = (used - high_water).min(limit - high_water) as f64 / (limit - high_water) as f64;
+        MAX_WRITE_DELAY.mul_f64(fullness)
+    }
+
+    /// Charges every inode to its owner's quota afresh, loading all of them to do so.
+    fn recompute_quotas(&self) -> Result<()> {
+        let Some(ref quotas) = self.quotas else {
+            return Ok(());
+        };
+        self.load_all_inodes()?;
+        let charges = self.inodes
+            .iter()
+            .map(|inode| (inode.id, inode.attr.uid, inode.allocated_bytes()))
+            .collect::<Vec<_>>();
+        quotas.recompute(charges);
+        Ok(())
+    }
+
+    /// Fails with `ENOSPC` when allocating blocks of `needed` more bytes would take storage past
+    /// its limit, even after purging the trash.
+    fn ensure_space(&self, needed: u64) -> Result<()> {
+        let Some(limit) = self.storage_limit else {
+            return Ok(());
+        };
+        if self.storage_used() + needed > limit {
+            self.purge_trash_for(SystemTime::now(), needed)?;
+        }
+        if self.storage_used() + needed > limit {
+            return Err(TimeFSError::NoSpace);
+        }
+        Ok(())
+    }
+
+    fn trash_dir_attr(&self) -> FileAttr {
+        FileAttrBuilder::default()
+            .ino(TRASH_DIR_INO)
+            .with_directory()
+            .perm(0o555)
+            .build()
+    }
+
+    fn control_dir_attr(&self) -> FileAttr {
+        FileAttrBuilder::default()
+            .ino(CONTROL_DIR_INO)
+            .with_directory()
+            .perm(0o555)
+            .build()
+    }
+
+    /// Attributes of a file in `.timefs`, sized to what reading it right now returns.
+    fn control_file_attr(&self, ino: u64) -> FileAttr {
+        FileAttrBuilder::default()
+            .ino(ino)
+            .with_regular_file()
+            .with_size(self.control_file(ino).map_or(0, |contents| contents.len()) as u64)
+            .perm(0o444)
+            .build()
+    }
+
+    /// Contents of a file in `.timefs`, generated afresh on every read.
+    fn control_file(&self, ino: u64) -> Option<String> {
+        match ino {
+            STATS_FILE_INO => self.version_stats().ok().map(|stats| stats.to_string()),
+            HEALTH_FILE_INO => Some(self.health().to_string()),
+            HANDLES_FILE_INO => Some(self.list_open_handles().iter().map(OpenHandleInfo::to_string).collect()),
+            _ => None,
+        }
+    }
+
+    /// Every open file handle with the inode it's for and the flags it was opened with, by handle.
+    pub(crate) fn list_open_handles(&self) -> Vec<OpenHandleInfo> {
+        let mut handles = self.file_handles
+            .iter()
+            .map(|handle| OpenHandleInfo::new(*handle.key(), &handle))
+            .collect::<Vec<_>>();
+        handles.sort_unstable_by_key(|handle| handle.fh);
+        // Only resolved once the handles aren't locked any more.
+        for handle in &mut handles {
+            handle.path = self.path_of(handle.ino).ok();
+        }
+        handles
+    }
+
+    /// How long the filesystem has been up and how far the block cache is behind on flushing.
+    pub(crate) fn health(&self) -> HealthReport {
+        HealthReport {
+            uptime: self.started_at.elapsed(),
+            dirty_blocks: self.block_cache.dirty_blocks(),
+            last_flush: self.block_cache.last_flush(),
+        }
+    }
+
+    /// Attributes of the virtual directories and files that aren't backed by an inode.
+    fn virtual_attr(&self, ino: u64) -> Option<FileAttr> {
+        match ino {
+            TRASH_DIR_INO if self.trash.is_some() => Some(self.trash_dir_attr()),
+            CONTROL_DIR_INO => Some(self.control_dir_attr()),
+            STATS_FILE_INO | HEALTH_FILE_INO | HANDLES_FILE_INO => Some(self.control_file_attr(ino)),
+            _ => None,
+        }
+    }
+
+    /// Looks up `name` in `parent`, resolving the virtual `.trash` and `.timefs` directories and their entries.
+    pub(crate) fn lookup_attr(&self, parent: u64, name: &str) -> Result<FileAttr> {
+        match (parent, name) {
+            (FUSE_ROOT_ID, CONTROL_DIR_NAME) => return Ok(self.control_dir_attr()),
+            (CONTROL_DIR_INO, STATS_FILE_NAME) => return Ok(self.control_file_attr(STATS_FILE_INO)),
+            (CONTROL_DIR_INO, HEALTH_FILE_NAME) => return Ok(self.control_file_attr(HEALTH_FILE_INO)),
+            (CONTROL_DIR_INO, HANDLES_FILE_NAME) => return Ok(self.control_file_attr(HANDLES_FILE_INO)),
+            (CONTROL_DIR_INO, _) => return Err(TimeFSError::NameNotFound(name.to_string())),
+            _ => {}
+        }
+
+        match self.trash {
+            Some(_) if parent == FUSE_ROOT_ID && name == TRASH_DIR_NAME => Ok(self.trash_dir_attr()),
+            Some(ref trash) if parent == TRASH_DIR_INO => {
+                let ino = trash
+                    .lock()
+                    .find(name)
+                    .map(|e| e.ino)
+                    .ok_or(TimeFSError::NameNotFound(name.to_string()))?;
+                self.get_attr(ino)
+            }
+            _ => {
+                let _namespace = self.namespace_lock.read();
+                Ok(self.get_inode_by_name(parent, name)?.attr)
+            }
+        }
+    }
+
+    /// Entries of a directory as `(ino, kind, name)` from `offset`, sorted by name so offsets stay
+    /// stable between calls. The kind of each is only looked up as the listing is consumed, so a
+    /// page of a huge directory doesn't load every child.
+    fn dir_entries(&self, ino: u64, offset: usize) -> Result<impl Iterator<Item = Result<(u64, FileType, String)>> + '_> {
+        let mut listing = vec![(ino, Some(FileType::Directory), ".".to_string())];
+        if ino == CONTROL_DIR_INO {
+            listing.extend([
+                (FUSE_ROOT_ID, Some(FileType::Directory), "..".to_string()),
+                (HANDLES_FILE_INO, Some(FileType::RegularFile), HANDLES_FILE_NAME.to_string()),
+                (HEALTH_FILE_INO, Some(FileType::RegularFile), HEALTH_FILE_NAME.to_string()),
+                (STATS_FILE_INO, Some(FileType::RegularFile), STATS_FILE_NAME.to_string()),
+            ]);
+        } else if let Some(trash) = self.trash.as_ref().filter(|_| ino == TRASH_DIR_INO) {
+            listing.push((FUSE_ROOT_ID, Some(FileType::Directory), "..".to_string()));
+            listing.extend(trash.lock().entries().iter().map(|entry| (entry.ino, None, entry.trash_name())));
+        } else {
+            let (parent, mut names) = {
+                let inode = self.get_inode(ino)?;
+                let INodeType::Directory { ref entries } = inode.data else {
+                    return Err(TimeFSError::NotDirectory(ino));
+                };
+                (inode.parent, entries.iter().map(|(name, &entry)| (name.clone(), entry)).collect::<Vec<_>>())
+            };
+            names.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+            listing.push((parent, Some(FileType::Directory), "..".to_string()));
+            if ino == FUSE_ROOT_ID {
+                listing.push((CONTROL_DIR_INO, Some(FileType::Directory), CONTROL_DIR_NAME.to_string()));
+                if self.trash.is_some() {
+                    listing.push((TRASH_DIR_INO, Some(FileType::Directory), TRASH_DIR_NAME.to_string()));
+                }
+            }
+            listing.extend(names.into_iter().map(|(name, entry)| (entry.id, entry.kind, name)));
+        }
+
+        Ok(listing.into_iter().skip(offset).map(|(child, kind, name)| {
+            let kind = match kind {
+                Some(kind) => kind,
+                None => self.get_attr(child)?.kind,
+            };
+            Ok((child, kind, name))
+        }))
+    }
+
+    /// Entries of a directory like [`TimeFS::dir_entries`], with the attributes and generation of
+    /// every entry for readdirplus.
+    fn dir_entries_plus(&self, ino: u64, offset: usize) -> Result<impl Iterator<Item = Result<(u64, String, FileAttr, u64)>> + '_> {
+        Ok(self.dir_entries(ino, offset)?.map(|entry| {
+            let (child, _, name) = entry?;
+            let attr = match self.virtual_attr(child) {
+                Some(attr) => attr,
+                None => self.get_attr(child)?,
+            };
+            Ok((child, name, attr, self.generation(child)))
+        }))
+    }
+
+    fn alloc_inode(&self, parent: u64, kind: FileType, mode: u32, creator: Creator) -> Result<INode> {
+        let (no_version, setgid_group) = match self.get_inode(parent) {
+            Ok(parent) => (parent.no_version, (parent.attr.perm as u32 & libc::S_ISGID != 0).then_some(parent.attr.gid)),
+            Err(_) => (false, None),
+        };
+        let (next_inode_id, generation) = self.super_block.write().alloc_inode()?;
+
+        // Everything but directories is stored as a file, the kind in its attributes telling them apart.
+        let mut inode = match kind {
+            FileType::Directory => {
+                let attr = FileAttrBuilder::default()
+                    .ino(next_inode_id)
+                    .with_directory()
+                    .build();
+
+                INode::new(next_inode_id, parent, INodeType::empty_directory(), attr)
+            }
+            kind => {
+                let attr = FileAttrBuilder::default()
+                    .ino(next_inode_id)
+                    .kind(kind)
+                    .build();
+
+                INode::new(next_inode_id, parent, INodeType::empty_file(), attr)
+            }
+        };
+        inode.attr.perm = creator.perm(mode);
+        inode.attr.uid = creator.uid;
+        inode.attr.gid = creator.gid;
+        // Like on other filesystems, a setgid directory hands its group down, and its setgid bit to subdirectories.
+        if let Some(gid) = setgid_group {
+            inode.attr.gid = gid;
+            if kind == FileType::Directory {
+                inode.attr.perm |= libc::S_ISGID as u16;
+            }
+        }
+        inode.generation = generation;
+        inode.no_version = no_version;
+        Ok(inode)
+    }
+
+    /// Generation of `ino` to hand to the kernel along with its attributes.
+    fn generation(&self, ino: u64) -> u64 {
+        self.get_inode(ino).map(|inode| inode.generation).unwrap_or(0)
+    }
+
+    fn alloc_file_handle(&self, inode_id: u64, flags: i32) -> Result<u64> {
+        let mut lock = self.next_fs.lock();
+        self.check_open_files()?;
+        let handle_id = *lock;
+        *lock += 1;
+
+        self.file_handles.insert(handle_id, FileHandle::new(inode_id, self.generation(inode_id), flags));
+        Ok(handle_id)
+    }
+
+    /// Fails with `ESTALE` when `fh` was opened on another file than `ino`, including one that had
+    /// its id before.
+    fn check_handle(&self, fh: u64, ino: u64) -> Result<()> {
+        match self.file_handles.get(&fh) {
+            Some(handle) if handle.inode_id() != ino || handle.generation() != self.generation(ino) => Err(TimeFSError::StaleHandle(fh)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `EMFILE` once `max_open_files` handles are open.
+    fn check_open_files(&self) -> Result<()> {
+        match self.max_open_files {
+            Some(max) if self.file_handles.len() >= max => Err(TimeFSError::TooManyOpenFiles),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `EFBIG` when `ino` growing to `size` would take it past `max_file_size`.
+    fn check_file_size(&self, ino: u64, size: u64) -> Result<()> {
+        match self.max_file_size {
+            Some(max) if size > max => Err(TimeFSError::FileTooBig(ino)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens an existing file, returning its new handle.
+    fn open_file(&self, ino: u64, flags: i32) -> Result<u64> {
+        if matches!(ino, STATS_FILE_INO | HEALTH_FILE_INO | HANDLES_FILE_INO) {
+            return self.alloc_file_handle(ino, flags);
+        }
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            self.ensure_writable()?;
+        }
+        if self.get_inode(ino)?.attr.kind == FileType::Directory {
+            return Err(TimeFSError::IsDirectory(ino));
+        }
+
+        let fh = self.alloc_file_handle(ino, flags)?;
+        self.prefetch_blocks(ino)?;
+        Ok(fh)
+    }
+
+    /// Starts reading the blocks of the files listed in `hints` into the cache in the background,
+    /// each line holding an inode id or an absolute path. Lines starting with `#` are skipped, as
+    /// are hints not naming a file, with a warning.
+    pub(crate) fn warm_cache(&self, hints: &str) {
+        let mut block_ids = Vec::new();
+        for hint in hints.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let inode = self.resolve_hint(hint).and_then(|ino| self.get_inode(ino));
+            match inode.as_deref().map(|inode| &inode.data) {
+                Ok(INodeType::File { blocks, .. }) => block_ids.extend(blocks.iter().filter(|block| !block.is_hole()).map(|block| block.id())),
+                Ok(INodeType::Directory { .. }) => warn!("Not warming the cache with directory {:?}", hint),
+                Err(e) => warn!("Not warming the cache with {:?}: {}", hint, e),
+            }
+        }
+        if block_ids.is_empty() {
+            return;
+        }
+
+        info!("Warming the cache with {} blocks", block_ids.len());
+        let block_cache = self.block_cache.clone();
+        self.runtime.spawn(async move {
+            for block_id in block_ids {
+                if let Err(e) = block_cache.get_block(block_id).await {
+                    warn!("Failed to warm the cache with block {}: {}", block_id, e);
+                }
+            }
+        });
+    }
+
+    /// Inode a cache hint names, either by id or by its absolute path.
+    fn resolve_hint(&self, hint: &str) -> Result<u64> {
+        if let Ok(ino) = hint.parse() {
+            return Ok(ino);
+        }
+        match Path::new(hint).strip_prefix("/") {
+            Ok(relative) => self.inode_at(relative),
+            Err(_) => Err(TimeFSError::Invalid(format!("{:?} is neither an inode id nor an absolute path", hint))),
+        }
+    }
+
+    /// Starts reading the blocks of a small file into the cache in the background, so the first
+    /// reads after opening it don't each wait on disk.
+    fn prefetch_blocks(&self, ino: u64) -> Result<()> {
+        let block_ids = match self.get_inode(ino)?.data {
+            INodeType::File { ref blocks, .. } if blocks.len() <= PREFETCH_MAX_BLOCKS => {
+                blocks.iter().filter(|block| !block.is_hole()).map(|block| block.id()).collect::<Vec<_>>()
+            }
+            _ => return Ok(()),
+        };
+        if block_ids.is_empty() {
+            return Ok(());
+        }
+
+        let block_cache = self.block_cache.clone();
+        self.runtime.spawn(async move {
+            for block_id in block_ids {
+                if let Err(e) = block_cache.get_block(block_id).await {
+                    warn!("Failed to prefetch block {} of inode {}: {}", block_id, ino, e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn get_attr(&self, inode_id: u64) -> Result<FileAttr> {
+        let inode = self.get_inode(inode_id)?;
+        let inode = inode.deref();
+        Ok(inode.attr)
+    }
+
+    /// Reads up to `size` bytes at `offset`, stopping at the end of the file. Holes read as zeros.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn read_at(&self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        if let Some(contents) = self.control_file(ino) {
+            let contents = contents.into_bytes();
+            let start = (offset as usize).min(contents.len());
+            let end = start.saturating_add(size as usize).min(contents.len());
+            return Ok(contents[start..end].to_vec());
+        }
+        if self.is_fifo(ino)? {
+            return Ok(self.fifos.entry(ino).or_default().read(size as usize));
+        }
+        self.flush_write_buffers(ino).await?;
+
+        let inode = self.get_inode(ino)?;
+        let block_size = inode.block_size() as u64;
+        let (blocks, file_size) = match inode.data {
+            INodeType::File { ref blocks, size, .. } => (blocks.clone(), size),
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(ino)),
+        };
+        drop(inode);
+        // Reading nothing doesn't count as an access, atime is only updated for reads of a byte or more.
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let buf = self.read_blocks(&blocks, block_size, file_size, offset, size).await?;
+        self.touch_atime(ino)?;
+        Ok(buf)
+    }
+
+    /// Reads up to `size` bytes at `offset` of the contents `blocks` held when a file was
+    /// `file_size` bytes long.
+    async fn read_blocks(&self, blocks: &[BlockRef], block_size: u64, file_size: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let end = file_size.min(offset.saturating_add(size as u64));
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        let mut buf = Vec::with_capacity((end - offset) as usize);
+
+        for index in (offset / block_size)..=((end - 1) / block_size) {
+            let block_start = index * block_size;
+            let read_start = (offset.max(block_start) - block_start) as usize;
+            let read_end = (end.min(block_start + block_size) - block_start) as usize;
+
+            let mut content = match blocks.get(index as usize) {
+                Some(block) if !block.is_hole() => self.block_cache.get_block(block.id()).await?,
+                _ => Vec::new(),
+            };
+            content.resize(read_end.max(content.len()), 0);
+            buf.extend_from_slice(&content[read_start..read_end]);
+        }
+        Ok(buf)
+    }
+
+    /// Timestamps of the versions recorded for `ino`, oldest first.
+    /// Byte ranges of `ino` that differ between its versions taken at `a` and `b`, see
+    /// [`INode::diff_versions`].
+    pub(crate) fn diff_versions(&self, ino: u64, a: SystemTime, b: SystemTime) -> Result<Vec<Range<u64>>> {
+        self.get_inode(ino)?.diff_versions(a, b)
+    }
+
+    pub(crate) fn list_versions(&self, ino: u64) -> Result<Vec<SystemTime>> {
+        let inode = self.get_inode(ino)?;
+        match inode.data {
+            INodeType::File { ref versions, .. } => Ok(versions.iter().map(|v| v.timestamp).collect()),
+            INodeType::Directory { .. } => Err(TimeFSError::IsDirectory(ino)),
+        }
+    }
+
+    /// Reads up to `size` bytes at `offset` of `ino` as it was when the version `timestamp` was taken.
+    pub(crate) async fn read_version(&self, ino: u64, timestamp: SystemTime, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let inode = self.get_inode(ino)?;
+        let block_size = inode.block_size() as u64;
+        let version = inode.get_version(timestamp)?.clone();
+        drop(inode);
+
+        self.read_blocks(&version.blocks, block_size, version.size, offset, size).await
+    }
+
+    /// Updates the access time of `ino` after a read, as far as the atime policy allows.
+    fn touch_atime(&self, ino: u64) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let now = SystemTime::now();
+        let mut inode = self.get_inode_mut(ino)?;
+        if self.atime_policy.should_update(&inode.attr, now) {
+            inode.attr.atime = now;
+            self.persist_inode(&mut inode)?;
+        }
+        Ok(())
+    }
+
+    /// Maps the logical block `idx` of a file to the id of the block storing it, 0 for a hole.
+    pub(crate) fn map_block(&self, ino: u64, blocksize: u32, idx: u64) -> Result<u64> {
+        let inode = self.get_inode(ino)?;
+        if blocksize != inode.block_size() {
+            return Err(TimeFSError::BlockSizeMismatch(blocksize));
+        }
+
+        match inode.data {
+            INodeType::File { ref blocks, .. } => Ok(blocks
+                .get(idx as usize)
+                .map(|block| block.id())
+                .unwrap_or(0)),
+            INodeType::Directory { .. } => Err(TimeFSError::IsDirectory(ino)),
+        }
+    }
+
+    /// Writes `data` at `offset`, copying any block shared with a snapshot before modifying it.
+    pub(crate) async fn write_at(&self, ino: u64, offset: u64, data: &[u8]) -> Result<u32> {
+        self.write_data(ino, Some(offset), data).await
+    }
+
+    /// Writes `data` at the end of the file, as for handles opened with `O_APPEND`.
+    pub(crate) async fn append(&self, ino: u64, data: &[u8]) -> Result<u32> {
+        self.write_data(ino, None, data).await
+    }
+
+    /// Writes through the handle `fh`, coalescing small sequential writes in its write buffer
+    /// so a run of tiny writes updates each block once instead of once per write.
+    pub(crate) async fn write_buffered(&self, fh: u64, ino: u64, offset: u64, data: &[u8]) -> Result<u32> {
+        self.ensure_writable()?;
+        // Buffering nothing would still flush a buffer it doesn't continue.
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let block_size = self.get_inode(ino)?.block_size() as u64;
+        if data.len() as u64 >= block_size || !self.file_handles.contains_key(&fh) || self.is_fifo(ino)? {
+            self.flush_write_buffer(fh).await?;
+            return self.write_at(ino, offset, data).await;
+        }
+
+        let pending = self.file_handles
+            .get_mut(&fh)
+            .and_then(|mut handle| handle.buffer_write(offset, data, block_size));
+        if let Some((offset, buffered)) = pending {
+            self.write_at(ino, offset, &buffered).await?;
+        }
+        Ok(data.len() as u32)
+    }
+
+    /// Writes through the handle `fh` on behalf of `lock_owner`, at the end of the file when
+    /// `append` is set, failing with `EACCES` if another owner holds a lock on the range written.
+    pub(crate) async fn write_handle(&self, fh: u64, ino: u64, offset: u64, data: &[u8], append: bool, lock_owner: Option<u64>) -> Result<u32> {
+        self.ensure_writable()?;
+        self.check_handle(fh, ino)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if append {
+            self.flush_write_buffer(fh).await?;
+        }
+        if let Some(owner) = lock_owner {
+            let start = if append { self.get_inode(ino)?.file_size() } else { offset };
+            let range = RangeLock { start, end: start + data.len() as u64 - 1, typ: libc::F_WRLCK, owner, pid: 0 };
+            if self.locks.conflict(ino, &range).is_some() {
+                return Err(TimeFSError::Locked(ino));
+            }
+        }
+        match append {
+            true => self.append(ino, data).await,
+            false => self.write_buffered(fh, ino, offset, data).await,
+        }
+    }
+
+    /// Applies the buffered writes of the handle `fh` to its file.
+    /// Writes out what `fh` still buffers and forgets the handle, even when writing fails. Closing
+    /// the last handle of an orphan frees it.
+    pub(crate) async fn close_handle(&self, fh: u64) -> Result<()> {
+        let flushed = self.flush_write_buffer(fh).await;
+        let freed = match self.file_handles.remove(&fh) {
+            Some((_, handle)) => self.free_orphan(handle.inode_id()),
+            None => Ok(()),
+        };
+        flushed.and(freed)
+    }
+
+    pub(crate) async fn flush_write_buffer(&self, fh: u64) -> Result<()> {
+        let Some((ino, (offset, buffered))) = self.file_handles
+            .get_mut(&fh)
+            .and_then(|mut handle| Some((handle.inode_id(), handle.take_write_buffer()?)))
+        else {
+            return Ok(());
+        };
+        self.write_at(ino, offset, &buffered).await?;
+        Ok(())
+    }
+
+    /// Applies the buffered writes of every handle open on `ino` and allocates its delayed blocks,
+    /// so they're seen by reads and size changes.
+    async fn flush_write_buffers(&self, ino: u64) -> Result<()> {
+        let handles = self.file_handles
+            .iter()
+            .filter(|handle| handle.inode_id() == ino && handle.has_buffered_writes())
+            .map(|handle| *handle.key())
+            .collect::<Vec<_>>();
+        for fh in handles {
+            self.flush_write_buffer(fh).await?;
+        }
+        self.allocate_delayed(ino).await
+    }
+
+    /// Exclusive access to the contents of a file, held while its block list is rewritten.
+    fn file_lock(&self, ino: u64) -> Arc<tokio::sync::Mutex<()>> {
+        self.file_locks.entry(ino).or_default().clone()
+    }
+
+    /// Writes `data` at `offset`, or at the current end of the file when `None`. The file lock
+    /// makes picking the end and extending past it atomic, so concurrent appends never overlap.
+    #[tracing::instrument(level = "debug", skip(self, data), fields(len = data.len()))]
+    async fn write_data(&self, ino: u64, offset: Option<u64>, data: &[u8]) -> Result<u32> {
+        self.ensure_writable()?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if self.is_fifo(ino)? {
+            return Ok(self.fifos.entry(ino).or_default().write(data) as u32);
+        }
+
+        let delay = self.write_delay();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        // The version takes in what was written so far, blocks still delayed included.
+        if self.delayed_blocks.contains_key(&ino) && self.version_due(ino)? {
+            self.allocate_delayed_locked(ino).await?;
+        }
+        self.auto_capture_version(ino)?;
+        let inode = self.get_inode(ino)?;
+        let block_size = inode.block_size() as u64;
+        let (mut blocks, size) = match inode.data {
+            INodeType::File { ref blocks, size, .. } => (blocks.clone(), size),
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(ino)),
+        };
+        let owner = inode.attr.uid;
+        drop(inode);
+        let offset = offset.unwrap_or(size);
+        let mapped_blocks = self.mapped.contains_key(&ino).then(|| blocks.clone());
+        // Mapped files have their blocks pinned in the cache, so theirs can't wait for an id.
+        let delays = self.delayed_allocation && mapped_blocks.is_none();
+
+        let end = offset.checked_add(data.len() as u64).ok_or(TimeFSError::FileTooBig(ino))?;
+        self.check_file_size(ino, end)?;
+        let first_index = (offset / block_size) as usize;
+        let last_index = ((end - 1) / block_size) as usize;
+
+        let new_blocks = (first_index..=last_index)
+            .filter(|&index| blocks.get(index).is_none_or(|b| b.is_hole() || self.block_refs.is_shared(b.id())))
+            .count();
+        // Blocks of this file alone grow in place, new blocks and copies of shared ones take it all.
+        let needed = (first_index..=last_index)
+            .map(|index| {
+                let written = end.min((index as u64 + 1) * block_size) - index as u64 * block_size;
+                match blocks.get(index) {
+                    Some(b) if !b.is_hole() && !self.block_refs.is_shared(b.id()) => written.saturating_sub(b.size() as u64),
+                    old => written.max(old.map_or(0, |b| b.size() as u64)),
+                }
+            })
+            .sum();
+        self.ensure_space(needed)?;
+        // Delayed blocks get a run of their own when they're allocated, only copies need one now.
+        let eager_blocks = match delays {
+            true => (first_index..=last_index)
+                .filter(|&index| blocks.get(index).is_some_and(|b| !b.is_hole() && self.block_refs.is_shared(b.id())))
+                .count(),
+            false => new_blocks,
+        };
+        self.reserve_block_run(ino, eager_blocks)?;
+        if let Some(ref quotas) = self.quotas {
+            // Copies of shared blocks replace blocks the owner is already charged for.
+            let filled = (first_index..=last_index)
+                .filter(|&index| blocks.get(index).is_none_or(BlockRef::is_hole))
+                .count();
+            quotas.check(owner, filled as u64 * block_size)?;
+        }
+
+        if blocks.len() <= last_index {
+            blocks.resize(last_index + 1, BlockRef::hole());
+        }
+
+        for (index, slot) in blocks.iter_mut().enumerate().take(last_index + 1).skip(first_index) {
+            let block_start = index as u64 * block_size;
+            let write_start = offset.max(block_start);
+            let write_end = end.min(block_start + block_size);
+
+            let old = &*slot;
+            let delayed = delays && old.is_hole();
+            let mut content = if delayed {
+                self.delayed_blocks.get(&ino).and_then(|file| file.get(&index).cloned()).unwrap_or_default()
+            } else if old.is_hole() {
+                Vec::new()
+            } else {
+                self.block_cache.get_block(old.id()).await?
+            };
+
+            let in_block = (write_start - block_start) as usize..(write_end - block_start) as usize;
+            if content.len() < in_block.end {
+                content.resize(in_block.end, 0);
+            }
+            let in_data = (write_start - offset) as usize..(write_end - offset) as usize;
+            content[in_block].copy_from_slice(&data[in_data]);
+
+            if delayed {
+                if self.delayed_blocks.entry(ino).or_default().insert(index, content).is_none() {
+                    self.delayed_count.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            let block = self.write_block(ino, old, content).await?;
+            *slot = block;
+        }
+        if let Some(old_blocks) = mapped_blocks {
+            self.repin(&old_blocks, &blocks).await?;
+        }
+
+        let mut inode = self.get_inode_mut(ino)?;
+        let new_size = inode.file_size().max(end);
+        if let INodeType::File { blocks: ref mut inode_blocks, .. } = inode.data {
+            *inode_blocks = blocks;
+        }
+        inode.set_size(new_size);
+
+        inode.touch_mtime();
+        self.persist_inode(&mut inode)?;
+        drop(inode);
+
+        let file_full = self.delayed_blocks.get(&ino).is_some_and(|file| file.len() >= MAX_DELAYED_BLOCKS);
+        if file_full || self.delayed_count.load(Ordering::Relaxed) >= MAX_TOTAL_DELAYED_BLOCKS {
+            self.allocate_delayed_locked(ino).await?;
+        }
+        Ok(data.len() as u32)
+    }
+
+    /// Gives the blocks of `ino` written while allocation is delayed their ids, a run of them in
+    /// file order, and hands their contents to the block cache.
+    async fn allocate_delayed(&self, ino: u64) -> Result<()> {
+        if !self.delayed_blocks.contains_key(&ino) {
+            return Ok(());
+        }
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        self.allocate_delayed_locked(ino).await
+    }
+
+    /// [`TimeFS::allocate_delayed`] for every file, as before recording the whole filesystem.
+    async fn allocate_all_delayed(&self) -> Result<()> {
+        let files = self.delayed_blocks.iter().map(|file| *file.key()).collect::<Vec<_>>();
+        for ino in files {
+            self.allocate_delayed(ino).await?;
+        }
+        Ok(())
+    }
+
+    /// [`TimeFS::allocate_delayed`] with the file lock of `ino` already held, as by every
+    /// operation changing its block list before doing so.
+    async fn allocate_delayed_locked(&self, ino: u64) -> Result<()> {
+        let Some((_, delayed)) = self.delayed_blocks.remove(&ino) else {
+            return Ok(());
+        };
+        self.delayed_count.fetch_sub(delayed.len(), Ordering::Relaxed);
+        let mut blocks = self.file_blocks(ino)?;
+        // Slots filled or dropped since were already given contents of their own.
+        let delayed = delayed
+            .into_iter()
+            .filter(|(index, _)| blocks.get(*index).is_some_and(BlockRef::is_hole))
+            .collect::<Vec<_>>();
+        if delayed.is_empty() {
+            return Ok(());
+        }
+
+        self.reserve_block_run(ino, delayed.len())?;
+        for (index, content) in delayed {
+            blocks[index] = self.write_block(ino, &BlockRef::hole(), content).await?;
+        }
+
+        let mut inode = self.get_inode_mut(ino)?;
+        if let INodeType::File { blocks: ref mut inode_blocks, .. } = inode.data {
+            *inode_blocks = blocks;
+        }
+        self.persist_inode(&mut inode)
+    }
+
+    /// Keeps the blocks of `ino` resident in the cache while the file is memory mapped, so the
+    /// mapping never sees a block evicted and read back in. Pins nest.
+    pub(crate) async fn pin_file(&self, ino: u64) -> Result<()> {
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        self.allocate_delayed_locked(ino).await?;
+        let blocks = self.file_blocks(ino)?;
+
+        let first = {
+            let mut count = self.mapped.entry(ino).or_default();
+            *count += 1;
+            *count == 1
+        };
+        if first {
+            self.repin(&[], &blocks).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn unpin_file(&self, ino: u64) -> Result<()> {
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        let blocks = self.file_blocks(ino)?;
+
+        let last = match self.mapped.get_mut(&ino) {
+            Some(mut count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => return Err(TimeFSError::Invalid(format!("inode {} is not pinned", ino))),
+        };
+        if last {
+            self.mapped.remove(&ino);
+            self.repin(&blocks, &[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets an extended attribute. Only `user.timefs.blocksize` is supported, which takes the
+    /// block size as a decimal number and can't change once the file holds data.
+    pub(crate) async fn set_xattr(&self, ino: u64, name: &str, value: &[u8]) -> Result<()> {
+        self.ensure_writable()?;
+        let value = std::str::from_utf8(value).ok().map(|value| value.trim_end_matches('\0').trim());
+        match name {
+            BLOCK_SIZE_XATTR => self.set_block_size(ino, value).await,
+            NO_VERSION_XATTR => match value {
+                Some("1") => self.set_no_version(ino, true),
+                Some("0") => self.set_no_version(ino, false),
+                _ => Err(TimeFSError::Invalid(format!("{} must be 0 or 1", NO_VERSION_XATTR))),
+            },
+            _ => Err(TimeFSError::UnsupportedXattr(name.to_string())),
+        }
+    }
+
+    async fn set_block_size(&self, ino: u64, value: Option<&str>) -> Result<()> {
+        let block_size = value
+            .and_then(|value| value.parse::<u32>().ok())
+            .filter(|size| size.is_power_of_two() && (MIN_FILE_BLOCK_SIZE..=MAX_FILE_BLOCK_SIZE).contains(size))
+            .ok_or_else(|| TimeFSError::Invalid(format!("bad block size {:?}", value.unwrap_or_default())))?;
+
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        self.allocate_delayed_locked(ino).await?;
+        let mut inode = self.get_inode_mut(ino)?;
+        inode.set_block_size(block_size)?;
+        inode.touch_ctime();
+        self.persist_inode(&mut inode)?;
+        Ok(())
+    }
+
+    /// Excludes `ino` from automatic versioning or includes it again.
+    pub(crate) fn set_no_version(&self, ino: u64, no_version: bool) -> Result<()> {
+        self.ensure_writable()?;
+        let mut inode = self.get_inode_mut(ino)?;
+        inode.no_version = no_version;
+        inode.touch_ctime();
+        self.persist_inode(&mut inode)
+    }
+
+    pub(crate) fn get_xattr(&self, ino: u64, name: &str) -> Result<Vec<u8>> {
+        let inode = self.get_inode(ino)?;
+        match inode.data {
+            INodeType::File { block_size: Some(block_size), .. } if name == BLOCK_SIZE_XATTR => {
+                Ok(block_size.to_string().into_bytes())
+            }
+            _ if name == NO_VERSION_XATTR && inode.no_version => Ok(b"1".to_vec()),
+            INodeType::File { ref versions, .. } if name == VERSIONS_XATTR && !versions.is_empty() => Ok(versions
+                .iter()
+                .map(|v| v.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default())
+                .map(|since| format!("{}.{:09}\n", since.as_secs(), since.subsec_nanos()))
+                .collect::<String>()
+                .into_bytes()),
+            _ => Err(TimeFSError::XattrNotFound(name.to_string())),
+        }
+    }
+
+    /// Names of the extended attributes set on `ino`, each terminated by a NUL byte.
+    pub(crate) fn list_xattr(&self, ino: u64) -> Result<Vec<u8>> {
+        let inode = self.get_inode(ino)?;
+        let mut names = Vec::new();
+        if let INodeType::File { block_size: Some(_), .. } = inode.data {
+            names.extend_from_slice(BLOCK_SIZE_XATTR.as_bytes());
+            names.push(0);
+        }
+        if inode.no_version {
+            names.extend_from_slice(NO_VERSION_XATTR.as_bytes());
+            names.push(0);
+        }
+        if let INodeType::File { ref versions, .. } = inode.data
+            && !versions.is_empty()
+        {
+            names.extend_from_slice(VERSIONS_XATTR.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    fn file_blocks(&self, ino: u64) -> Result<Vec<BlockRef>> {
+        match self.get_inode(ino)?.data {
+            INodeType::File { ref blocks, .. } => Ok(blocks.clone()),
+            INodeType::Directory { .. } => Err(TimeFSError::IsDirectory(ino)),
+        }
+    }
+
+    /// Moves the pins of a mapped file from the blocks it used to the ones that replaced them.
+    async fn repin(&self, old: &[BlockRef], new: &[BlockRef]) -> Result<()> {
+        let ids = |blocks: &[BlockRef]| blocks.iter().filter(|b| !b.is_hole()).map(|b| b.id()).collect::<HashSet<_>>();
+        let (old, new) = (ids(old), ids(new));
+        for &block_id in new.difference(&old) {
+            self.block_cache.pin_block(block_id).await?;
+        }
+        for &block_id in old.difference(&new) {
+            self.block_cache.unpin_block(block_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes `dst` a copy of `src` sharing all of its blocks, replacing whatever it held before.
+    pub(crate) async fn clone_file(&self, src: u64, dst: u64) -> Result<()> {
+        if src == dst {
+            return Err(TimeFSError::Invalid("can't clone a file onto itself".to_string()));
+        }
+        self.truncate(dst, 0).await?;
+        self.clone_range(src, 0, 0, dst, 0).await
+    }
+
+    /// Makes `len` bytes of `dst` from `dst_offset` share the blocks holding those of `src` from
+    /// `src_offset`, the rest of `src` when `len` is 0, without copying any data. Shared blocks are
+    /// copied before either file changes them, so the two diverge as if `dst` had been written.
+    ///
+    /// Offsets must be block aligned, and so must the end unless it's the end of `src` and the
+    /// clone runs to the end of `dst` too.
+    pub(crate) async fn clone_range(&self, src: u64, src_offset: u64, len: u64, dst: u64, dst_offset: u64) -> Result<()> {
+        self.ensure_writable()?;
+        if src == dst {
+            return Err(TimeFSError::Invalid("can't clone a file onto itself".to_string()));
+        }
+        self.flush_write_buffers(src).await?;
+        self.flush_write_buffers(dst).await?;
+
+        // Locked in inode order, so clones in opposite directions can't deadlock.
+        let (first_lock, second_lock) = (self.file_lock(src.min(dst)), self.file_lock(src.max(dst)));
+        let _first = first_lock.lock().await;
+        let _second = second_lock.lock().await;
+        self.allocate_delayed_locked(src).await?;
+        self.allocate_delayed_locked(dst).await?;
+        self.auto_capture_version(dst)?;
+
+        let inode = self.get_inode(src)?;
+        let block_size = inode.block_size() as u64;
+        let (src_blocks, src_size) = match inode.data {
+            INodeType::File { ref blocks, size, .. } => (blocks.clone(), size),
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(src)),
+        };
+        drop(inode);
+        let inode = self.get_inode(dst)?;
+        let (mut blocks, dst_size, has_versions) = match inode.data {
+            INodeType::File { ref blocks, size, ref versions, .. } => (blocks.clone(), size, !versions.is_empty()),
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(dst)),
+        };
+        // An empty file can still take on the block size of the source.
+        let adopt_block_size = inode.block_size() as u64 != block_size;
+        if adopt_block_size && (!blocks.is_empty() || has_versions) {
+            return Err(TimeFSError::Invalid(format!("inodes {} and {} have different block sizes", src, dst)));
+        }
+        drop(inode);
+
+        let len = if len == 0 { src_size.saturating_sub(src_offset) } else { len };
+        let src_end = src_offset.checked_add(len)
+            .filter(|&end| end <= src_size)
+            .ok_or_else(|| TimeFSError::Invalid(format!("clone range runs past the end of inode {}", src)))?;
+        let dst_end = dst_offset.checked_add(len).ok_or_else(|| TimeFSError::Invalid("clone range overflows".to_string()))?;
+        if dst_end > dst_size {
+            self.check_file_size(dst, dst_end)?;
+        }
+        let aligned = |offset: u64| offset.is_multiple_of(block_size);
+        if !aligned(src_offset) || !aligned(dst_offset) || (!aligned(src_end) && (src_end < src_size || dst_end < dst_size)) {
+            return Err(TimeFSError::Invalid("clone range isn't block aligned".to_string()));
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let old_blocks = blocks.clone();
+        let src_first = (src_offset / block_size) as usize;
+        let dst_first = (dst_offset / block_size) as usize;
+        let count = len.div_ceil(block_size) as usize;
+        if blocks.len() < dst_first + count {
+            blocks.resize(dst_first + count, BlockRef::hole());
+        }
+        for index in 0..count {
+            let block = src_blocks.get(src_first + index).cloned().unwrap_or_else(BlockRef::hole);
+            if !block.is_hole() {
+                self.block_refs.acquire(&block);
+            }
+            let old = std::mem::replace(&mut blocks[dst_first + index], block);
+            if !old.is_hole() {
+                self.release_block(old.id());
+            }
+        }
+        while blocks.last().is_some_and(|b| b.is_hole()) {
+            blocks.pop();
+        }
+        if self.mapped.contains_key(&dst) {
+            self.repin(&old_blocks, &blocks).await?;
+        }
+
+        let mut inode = self.get_inode_mut(dst)?;
+        if adopt_block_size {
+            inode.set_block_size(block_size as u32)?;
+        }
+        if let INodeType::File { blocks: ref mut inode_blocks, .. } = inode.data {
+            *inode_blocks = blocks;
+        }
+        inode.set_size(dst_size.max(dst_end));
+        inode.touch_mtime();
+        self.persist_inode(&mut inode)?;
+        drop(inode);
+        self.invalidate_inode(dst, true);
+        Ok(())
+    }
+
+    /// Serves `FICLONE` and `FICLONERANGE` on `dst`, whose argument names the source by a file
+    /// descriptor of the calling process.
+    async fn clone_ioctl(&self, pid: u32, dst: u64, cmd: u32, in_data: &[u8]) -> Result<()> {
+        let malformed = || TimeFSError::Invalid("malformed clone request".to_string());
+        if cmd == FICLONE {
+            let fd = in_data.get(..4).ok_or_else(malformed)?;
+            let src = self.caller_fd_inode(pid, i32::from_ne_bytes(fd.try_into().unwrap()))?;
+            return self.clone_file(src, dst).await;
+        }
+        // struct file_clone_range: src_fd, src_offset, src_length and dest_offset.
+        let field = |index: usize| in_data
+            .get(index * 8..(index + 1) * 8)
+            .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(malformed);
+        let src = self.caller_fd_inode(pid, field(0)? as i32)?;
+        self.clone_range(src, field(1)?, field(2)?, dst, field(3)?).await
+    }
+
+    /// Inode of the file process `pid` has open as `fd`, which has to be on this filesystem.
+    fn caller_fd_inode(&self, pid: u32, fd: i32) -> Result<u64> {
+        let path = std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd))?;
+        let Ok(relative) = path.strip_prefix(&self.mount_path) else {
+            return Err(std::io::Error::from_raw_os_error(libc::EXDEV).into());
+        };
+        self.inode_at(relative)
+    }
+
+    /// Inode at `relative`, a path from the root of the filesystem.
+    fn inode_at(&self, relative: &Path) -> Result<u64> {
+        let mut ino = FUSE_ROOT_ID;
+        for component in relative.components() {
+            let name = component.as_os_str().to_string_lossy();
+            ino = self.lookup_attr(ino, &name)?.ino;
+        }
+        Ok(ino)
+    }
+
+    /// Id to store new contents of `old` under, a fresh block of `ino` unless `old` is referenced by this file alone.
+    fn writable_block_id(&self, ino: u64, old: &BlockRef) -> Result<u64> {
+        if !old.is_hole() && !self.block_refs.is_shared(old.id()) {
+            return Ok(old.id());
+        }
+
+        let id = self.get_next_block_id(ino)?;
+        if !old.is_hole() {
+            self.release_block(old.id());
+        }
+        self.block_refs.acquire(&BlockRef::new(id));
+        Ok(id)
+    }
+
+    /// Stores `content` as the new contents of `old` under the id [`TimeFS::writable_block_id`]
+    /// picks, returning the reference to it.
+    async fn write_block(&self, ino: u64, old: &BlockRef, content: Vec<u8>) -> Result<BlockRef> {
+        let block_id = self.writable_block_id(ino, old)?;
+        let size = content.len() as u32;
+        self.block_cache.update_block(block_id, content).await?;
+        self.block_refs.resize(block_id, size);
+        Ok(BlockRef::with_size(block_id, size))
+    }
+
+    /// Changes the size of a file. Growing only records the new size, so the extension reads
+    /// as a hole, while shrinking drops whole blocks past the end and cuts the last one short
+    /// so its old tail can't reappear if the file grows again.
+    pub(crate) async fn truncate(&self, ino: u64, new_size: u64) -> Result<()> {
+        self.ensure_writable()?;
+        if self.is_fifo(ino)? {
+            return Ok(());
+        }
+        self.flush_write_buffers(ino).await?;
+
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        self.allocate_delayed_locked(ino).await?;
+
+        let inode = self.get_inode(ino)?;
+        let block_size = inode.block_size() as u64;
+        let (mut blocks, old_size) = match inode.data {
+            INodeType::File { ref blocks, size, .. } => (blocks.clone(), size),
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(ino)),
+        };
+        drop(inode);
+        if new_size > old_size {
+            self.check_file_size(ino, new_size)?;
+        }
+        let mapped_blocks = self.mapped.contains_key(&ino).then(|| blocks.clone());
+
+        if new_size < old_size {
+            let keep = (new_size.div_ceil(block_size) as usize).min(blocks.len());
+            for block in blocks.drain(keep..).filter(|b| !b.is_hole()) {
+                self.release_block(block.id());
+            }
+
+            let tail = (new_size % block_size) as usize;
+            if let Some(last) = blocks.last_mut().filter(|b| tail > 0 && !b.is_hole()) {
+                let mut content = self.block_cache.get_block(last.id()).await?;
+                if content.len() > tail {
+                    content.truncate(tail);
+                    *last = self.write_block(ino, last, content).await?;
+                }
+            }
+        }
+        if let Some(old_blocks) = mapped_blocks {
+            self.repin(&old_blocks, &blocks).await?;
+        }
+
+        let mut inode = self.get_inode_mut(ino)?;
+        if let INodeType::File { blocks: ref mut inode_blocks, .. } = inode.data {
+            *inode_blocks = blocks;
+        }
+        inode.set_size(new_size);
+
+        inode.touch_mtime();
+        self.persist_inode(&mut inode)?;
+        Ok(())
+    }
+
+    /// Preallocates `length` bytes at `offset` for `fallocate`, growing the file to cover them
+    /// unless `FALLOC_FL_KEEP_SIZE` is given. The range stays a hole reading as zeros, but the
+    /// ids of the blocks filling it are reserved in a row so they end up next to each other.
+    pub(crate) async fn allocate(&self, ino: u64, offset: u64, length: u64, mode: i32) -> Result<()> {
+        self.ensure_writable()?;
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(TimeFSError::UnsupportedFallocateMode(mode));
+        }
+        if length == 0 {
+            return Err(TimeFSError::Invalid("fallocate of 0 bytes".to_string()));
+        }
+        let end = offset.checked_add(length).ok_or(TimeFSError::FileTooBig(ino))?;
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+        if !keep_size {
+            self.check_file_size(ino, end)?;
+        }
+
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        self.allocate_delayed_locked(ino).await?;
+
+        let inode = self.get_inode(ino)?;
+        let block_size = inode.block_size() as u64;
+        let (holes, size) = match inode.data {
+            INodeType::File { ref blocks, size, .. } => {
+                let holes = (offset / block_size..=(end - 1) / block_size)
+                    .filter(|&index| blocks.get(index as usize).is_none_or(BlockRef::is_hole))
+                    .count();
+                (holes, size)
+            }
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(ino)),
+        };
+        drop(inode);
+        self.ensure_space(holes as u64 * block_size)?;
+        self.reserve_block_run(ino, holes)?;
+
+        if !keep_size && end > size {
+            let mut inode = self.get_inode_mut(ino)?;
+            inode.set_size(end);
+            inode.touch_mtime();
+            self.persist_inode(&mut inode)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the block list of a file without changing its contents: blocks holding only
+    /// zeros become holes, tails past the end of the file are cut off, blocks beyond it are
+    /// dropped and blocks left short by an earlier end of the file are padded to full ones, so
+    /// reads don't piece them together from zeros. Shared blocks aren't padded, that would copy
+    /// them. Files open for writing are left alone. Returns the number of blocks freed.
+    pub(crate) async fn compact_file(&self, ino: u64) -> Result<usize> {
+        self.ensure_writable()?;
+        if self.file_handles.iter().any(|h| h.inode_id() == ino && (h.is_write_only() || h.is_read_write())) {
+            debug!("Not compacting inode {} while it is open for writing", ino);
+            return Ok(0);
+        }
+
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        self.allocate_delayed_locked(ino).await?;
+
+        let inode = self.get_inode(ino)?;
+        let block_size = inode.block_size() as u64;
+        let (mut blocks, size) = match inode.data {
+            INodeType::File { ref blocks, size, .. } => (blocks.clone(), size),
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(ino)),
+        };
+        drop(inode);
+        let old_blocks = blocks.clone();
+        let allocated = |blocks: &[BlockRef]| blocks.iter().filter(|b| !b.is_hole()).count();
+
+        blocks.truncate(size.div_ceil(block_size) as usize);
+        for (index, slot) in blocks.iter_mut().enumerate().filter(|(_, b)| !b.is_hole()) {
+            let mut content = self.block_cache.get_block(slot.id()).await?;
+            let in_file = (size - index as u64 * block_size).min(block_size) as usize;
+            content.truncate(in_file);
+
+            if content.iter().all(|&b| b == 0) {
+                self.release_block(slot.id());
+                *slot = BlockRef::hole();
+            } else if content.len() < slot.size() as usize {
+                *slot = self.write_block(ino, slot, content).await?;
+            } else if content.len() < in_file && !self.block_refs.is_shared(slot.id()) {
+                content.resize(in_file, 0);
+                *slot = self.write_block(ino, slot, content).await?;
+            }
+        }
+        for block in old_blocks.iter().skip(blocks.len()).filter(|b| !b.is_hole()) {
+            self.release_block(block.id());
+        }
+        while blocks.last().is_some_and(|b| b.is_hole()) {
+            blocks.pop();
+        }
+        if self.mapped.contains_key(&ino) {
+            self.repin(&old_blocks, &blocks).await?;
+        }
+
+        let remaining = allocated(&blocks);
+        let freed = allocated(&old_blocks).saturating_sub(remaining);
+        let mut inode = self.get_inode_mut(ino)?;
+        if let INodeType::File { blocks: ref mut inode_blocks, .. } = inode.data {
+            *inode_blocks = blocks;
+        }
+        inode.attr.blocks = stat_blocks(remaining as u64, block_size as u32);
+        inode.touch_ctime();
+        self.persist_inode(&mut inode)?;
+        drop(inode);
+        self.invalidate_inode(ino, false);
+        Ok(freed)
+    }
+
+    /// Compacts every file, see [`TimeFS::compact_file`]. Returns the number of blocks freed.
+    pub(crate) async fn compact_all(&self) -> Result<usize> {
+        self.ensure_writable()?;
+        self.load_all_inodes()?;
+        let files = self.inodes
+            .iter()
+            .filter(|inode| !inode.is_directory())
+            .map(|inode| inode.id)
+            .collect::<Vec<_>>();
+        let mut freed = 0;
+        for ino in files {
+            freed += self.compact_file(ino).await?;
+        }
+        debug!("Compacting all files freed {} blocks", freed);
+        Ok(freed)
+    }
+
+    /// Applies the attribute changes of a `setattr` call, resizing the file first.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn set_attr(
+        &self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<FileAttr> {
+        self.ensure_writable()?;
+        if let Some(size) = size {
+            self.truncate(ino, size).await?;
+        }
+
+        let resolve = |time: TimeOrNow| match time {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => SystemTime::now(),
+        };
+
+        let mut inode = self.get_inode_mut(ino)?;
+        if let Some(mode) = mode {
+            inode.attr.perm = (mode & 0o7777) as u16;
+        }
+        if let Some(uid) = uid {
+            inode.attr.uid = uid;
+        }
+        if let Some(gid) = gid {
+            inode.attr.gid = gid;
+        }
+        if let Some(atime) = atime {
+            inode.attr.atime = resolve(atime);
+        }
+        if let Some(mtime) = mtime {
+            inode.attr.mtime = resolve(mtime);
+        }
+        inode.touch_ctime();
+        self.persist_inode(&mut inode)?;
+        Ok(inode.attr)
+    }
+
+    /// Checks that the user `req_uid` may change the owner of `ino` to `uid` and its group to
+    /// `gid`. Only root may give a file away, while the owner may only change its group to one
+    /// they're a member of.
+    pub(crate) fn check_chown(&self, ino: u64, uid: Option<u32>, gid: Option<u32>, req_uid: u32, req_gid: u32) -> Result<()> {
+        if req_uid == 0 {
+            return Ok(());
+        }
+        let attr = self.get_attr(ino)?;
+        if uid.is_some_and(|uid| uid != attr.uid) {
+            return Err(TimeFSError::NotPermitted(ino));
+        }
+        if let Some(gid) = gid.filter(|&gid| gid != attr.gid)
+            && (req_uid != attr.uid || !in_group(req_uid, req_gid, gid))
+        {
+            return Err(TimeFSError::NotPermitted(ino));
+        }
+        Ok(())
+    }
+
+    /// Records the current contents of `ino` as a new version, returning its timestamp.
+    pub(crate) async fn capture_version(&self, ino: u64) -> Result<SystemTime> {
+        self.ensure_writable()?;
+        self.allocate_delayed(ino).await?;
+        let mut inode = self.get_inode_mut(ino)?;
+        self.record_version(&mut inode)
+    }
+
+    /// Rolls `ino` back to its version taken at `timestamp`, first recording the current contents
+    /// as a version of their own so the rollback can be undone. The file gets back the modification
+    /// time it had then, and with `preserve_times` its change time too, which is otherwise now.
+    pub(crate) async fn restore_version(&self, ino: u64, timestamp: SystemTime, preserve_times: bool) -> Result<()> {
+        self.ensure_writable()?;
+        self.flush_write_buffers(ino).await?;
+
+        let file_lock = self.file_lock(ino);
+        let _guard = file_lock.lock().await;
+        self.allocate_delayed_locked(ino).await?;
+
+        let mut inode = self.get_inode_mut(ino)?;
+        let version = inode.get_version(timestamp)?.clone();
+        // Taken before recording the current contents, which may prune the version restored.
+        for block in version.blocks.iter().filter(|b| !b.is_hole()) {
+            self.block_refs.acquire(block);
+        }
+        self.record_version(&mut inode)?;
+
+        let old_blocks = match inode.data {
+            INodeType::File { ref mut blocks, .. } => std::mem::replace(blocks, version.blocks.clone()),
+            INodeType::Directory { .. } => return Err(TimeFSError::IsDirectory(ino)),
+        };
+        inode.set_size(version.size);
+        inode.attr.mtime = version.mtime;
+        inode.attr.ctime = if preserve_times { version.ctime } else { SystemTime::now() };
+        self.persist_inode(&mut inode)?;
+        drop(inode);
+
+        for block in old_blocks.iter().filter(|b| !b.is_hole()) {
+            self.release_block(block.id());
+        }
+        if self.mapped.contains_key(&ino) {
+            self.repin(&old_blocks, &version.blocks).await?;
+        }
+        self.invalidate_inode(ino, true);
+        Ok(())
+    }
+
+    /// Handles `TIMEFS_IOC_RESTORE_VERSION`, naming the version by its timestamp.
+    async fn restore_ioctl(&self, ino: u64, in_data: &[u8]) -> Result<()> {
+        let malformed = || TimeFSError::Invalid("malformed restore request".to_string());
+        let timestamp = ioctl_timestamp(in_data).ok_or_else(malformed)?;
+        let flags = u32::from_ne_bytes(in_data.get(12..16).ok_or_else(malformed)?.try_into().unwrap());
+        self.restore_version(ino, timestamp, flags & TIMEFS_RESTORE_PRESERVE_TIMES != 0).await
+    }
+
+    /// Handles the version ioctls answering with data, at most `out_size` bytes of it: capturing,
+    /// reading and diffing versions.
+    async fn version_ioctl(&self, ino: u64, cmd: u32, in_data: &[u8], out_size: u32) -> Result<Vec<u8>> {
+        let malformed = || TimeFSError::Invalid("malformed version request".to_string());
+        let timestamp_at = |at: usize| in_data.get(at..).and_then(ioctl_timestamp).ok_or_else(malformed);
+        match cmd {
+            TIMEFS_IOC_CAPTURE_VERSION => {
+                let since = self.capture_version(ino).await?.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                Ok([&since.as_secs().to_ne_bytes()[..], &since.subsec_nanos().to_ne_bytes(), &0u32.to_ne_bytes()].concat())
+            }
+            TIMEFS_IOC_READ_VERSION => {
+                let size = u32::from_ne_bytes(in_data.get(12..16).ok_or_else(malformed)?.try_into().unwrap());
+                let offset = u64::from_ne_bytes(in_data.get(16..24).ok_or_else(malformed)?.try_into().unwrap());
+                self.read_version(ino, timestamp_at(0)?, offset, size.min(out_size)).await
+            }
+            TIMEFS_IOC_DIFF_VERSIONS => {
+                let ranges = self.diff_versions(ino, timestamp_at(0)?, timestamp_at(16)?)?;
+                Ok(ranges
+                    .into_iter()
+                    .take(out_size as usize / 16)
+                    .flat_map(|range| [range.start, range.end])
+                    .flat_map(u64::to_ne_bytes)
+                    .collect())
+            }
+            _ => Err(TimeFSError::Invalid(format!("{:#x} is no version ioctl", cmd))),
+        }
+    }
+
+    /// Records a version of a file about to be written, unless automatic versioning is off, the
+    /// file is excluded from it or already got a version less than `min_version_interval` ago.
+    fn auto_capture_version(&self, ino: u64) -> Result<()> {
+        if self.version_due(ino)? {
+            self.record_version(&mut *self.get_inode_mut(ino)?)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a write to `ino` is to record a version first, see [`TimeFS::auto_capture_version`].
+    fn version_due(&self, ino: u64) -> Result<bool> {
+        if !self.auto_version || self.excluded_from_versions(ino) {
+            return Ok(false);
+        }
+        let inode = self.get_inode(ino)?;
+        if inode.no_version || inode.file_size() == 0 {
+            return Ok(false);
+        }
+        let now = SystemTime::now();
+        Ok(inode
+            .last_version_at()
+            .is_none_or(|at| now.duration_since(at).unwrap_or_default() >= self.min_version_interval))
+    }
+
+    /// Whether `ino` matches one of the `version_exclude` globs by its path or name. Files that
+    /// aren't linked anywhere anymore have neither and are never excluded.
+    fn excluded_from_versions(&self, ino: u64) -> bool {
+        if self.version_exclude.is_empty() {
+            return false;
+        }
+        let Ok(path) = self.path_of(ino) else {
+            return false;
+        };
+        let relative = path.strip_prefix("/").unwrap_or(&path).to_string_lossy();
+        let name = path.file_name().map(OsStr::to_string_lossy).unwrap_or_default();
+        self.version_exclude.iter().any(|glob| glob.is_match(&relative) || glob.is_match(&name))
+    }
+
+    fn record_version(&self, inode: &mut INode) -> Result<SystemTime> {
+        let version = inode.record_version(SystemTime::now())?;
+        for block in version.blocks.iter().filter(|b| !b.is_hole()) {
+            self.block_refs.acquire(block);
+        }
+
+        let timestamp = version.timestamp;
+        self.prune_versions(inode);
+        self.persist_inode(inode)?;
+        Ok(timestamp)
+    }
+
+    /// Drops the oldest versions of a file while it has more than `max_versions` of them or they
+    /// hold more than `max_version_bytes` on their own, releasing their blocks. The newest version
+    /// is never dropped for its size. Returns how many versions were dropped.
+    fn prune_versions(&self, inode: &mut INode) -> usize {
+        let mut pruned = 0;
+        loop {
+            let count = inode.version_count();
+            let over_count = self.max_versions.is_some_and(|max| count > max);
+            let over_bytes = count > 1 && self.max_version_bytes.is_some_and(|max| inode.history_bytes() > max);
+            if !over_count && !over_bytes {
+                break;
+            }
+            let Some(version) = inode.remove_oldest_version() else {
+                break;
+            };
+            for block in version.blocks.iter().filter(|b| !b.is_hole()) {
+                self.release_block(block.id());
+            }
+            pruned += 1;
+        }
+        if pruned > 0 {
+            debug!("Dropped the {} oldest versions of inode {}", pruned, inode.id);
+        }
+        pruned
+    }
+
+    /// Counts the versions retained and the blocks only history keeps alive. A block shared with
+    /// live data costs nothing extra, so only blocks no file currently uses are counted.
+    pub(crate) fn version_stats(&self) -> Result<VersionStats> {
+        self.load_all_inodes()?;
+        let mut stats = VersionStats::default();
+        let mut live = HashSet::new();
+        // Block id to its size and the position, newest first, of the newest version holding it.
+        let mut history: HashMap<u64, (u64, usize)> = HashMap::new();
+
+        for inode in self.inodes.iter() {
+            let INodeType::File { ref blocks, ref versions, .. } = inode.data else {
+                continue;
+            };
+            live.extend(blocks.iter().filter(|b| !b.is_hole()).map(|b| b.id()));
+
+            stats.versions += versions.len() as u64;
+            for (age, version) in versions.iter().rev().enumerate() {
+                for block in version.blocks.iter().filter(|b| !b.is_hole()) {
+                    let entry = history.entry(block.id()).or_insert((block.size() as u64, age));
+                    entry.1 = entry.1.min(age);
+                }
+            }
+        }
+
+        history.retain(|id, _| !live.contains(id));
+        stats.history_blocks = history.len() as u64;
+
+        // Keeping `max_version` versions frees the blocks whose newest version falls past that.
+        let longest = history.values().map(|&(_, age)| age + 1).max().unwrap_or(0);
+        stats.reclaimable_bytes = vec![0; longest];
+        for &(size, age) in history.values() {
+            stats.history_bytes += size;
+            for reclaimable in &mut stats.reclaimable_bytes[..=age] {
+                *reclaimable += size;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Streams every inode plus the blocks written after `ts`, for shipping incremental backups.
+    /// Blocks only versions hold are streamed too, each block once however many lists share it.
+    /// Orphans are left out, they're gone but for the handles still open on them.
+    pub(crate) async fn export_since(&self, ts: SystemTime, writer: impl Write) -> Result<()> {
+        self.allocate_all_delayed().await?;
+        self.load_all_inodes()?;
+        let mut stream = ExportStream::new(ts);
+        let orphans = self.orphans.lock().clone();
+        let inodes = self.inodes
+            .iter()
+            .filter(|inode| !orphans.contains(&inode.id))
+            .map(|e| e.value().clone())
+            .collect::<Vec<_>>();
+
+        let mut exported = HashSet::new();
+        for inode in &inodes {
+            if let INodeType::File { ref blocks, ref versions, .. } = inode.data {
+                let lists = std::iter::once(blocks).chain(versions.iter().map(|v| &v.blocks));
+                for (index, block) in lists.flat_map(|list| list.iter().enumerate()) {
+                    if block.is_hole() || block.written_at() <= ts || !exported.insert(block.id()) {
+                        continue;
+                    }
+
+                    stream.blocks.push(ExportedBlock {
+                        ino: inode.id,
+                        index: index as u64,
+                        block_id: block.id(),
+                        data: self.block_cache.get_block(block.id()).await?,
+                    });
+                }
+            }
+        }
+
+        stream.inodes = inodes;
+        debug!("Exporting {} inodes and {} blocks changed since {:?}", stream.inodes.len(), stream.blocks.len(), ts);
+        write_to_bin_compressed(&stream, writer, self.metadata_compression)
+    }
+
+    /// Applies a stream produced by [`TimeFS::export_since`], making the inode table the exported
+    /// one: inodes are replaced with their exported state and those the stream no longer has are
+    /// freed. The root stays the local one, only its entries are taken from the stream.
+    ///
+    /// Blocks are stored under ids of their own, which later streams keep referring to by the
+    /// source's ids, so the ids they were given are remembered.
+    pub(crate) async fn import(&self, reader: impl Read) -> Result<()> {
+        self.ensure_writable()?;
+        let stream: ExportStream = from_bin_compressed(reader)?;
+        self.load_all_inodes()?;
+
+        let mut imported = self.imported_blocks.lock().clone();
+        // Blocks since freed here can't be referred to any more.
+        imported.retain(|_, id| self.block_refs.count(*id) > 0);
+
+        // Checked up front, so a stream missing blocks or not fitting leaves nothing half imported.
+        if !stream.inodes.iter().any(|inode| inode.id == FUSE_ROOT_ID) {
+            return Err(TimeFSError::Invalid("stream has no root directory".to_string()));
+        }
+        let streamed = stream.blocks.iter().map(|block| (block.block_id, block.data.len() as u64)).collect::<HashMap<_, _>>();
+        for inode in &stream.inodes {
+            if let Some(block) = inode.held_blocks().into_iter().find(|b| !streamed.contains_key(&b.id()) && !imported.contains_key(&b.id())) {
+                return Err(TimeFSError::Invalid(format!(
+                    "block {} of inode {} is neither in the stream nor imported before",
+                    block.id(), inode.id,
+                )));
+            }
+        }
+        let live = stream.inodes.iter().map(|inode| inode.id).collect::<HashSet<_>>();
+        let removed = self.inodes
+            .iter()
+            .filter(|inode| !live.contains(&inode.id))
+            .map(|inode| inode.id)
+            .collect::<Vec<_>>();
+        self.check_import_quotas(&stream.inodes, &removed)?;
+        self.ensure_space(streamed.values().sum())?;
+
+        let mut written = HashSet::new();
+        for block in stream.blocks {
+            if !written.insert(block.block_id) {
+                continue;
+            }
+            let block_id = self.get_next_block_id(block.ino)?;
+            self.block_cache.update_block(block_id, block.data).await?;
+            imported.insert(block.block_id, block_id);
+        }
+
+        for mut inode in stream.inodes {
+            if let INodeType::File { ref mut blocks, ref mut versions, .. } = inode.data {
+                let all = blocks.iter_mut().chain(versions.iter_mut().flat_map(|v| v.blocks.iter_mut()));
+                for block in all.filter(|b| !b.is_hole()) {
+                    *block = block.with_id(imported[&block.id()]);
+                }
+            }
+            // Taken before letting go of the old state, which shares the blocks left unchanged.
+            for block in inode.held_blocks() {
+                self.block_refs.acquire(block);
+            }
+            let old = match self.get_inode(inode.id) {
+                Ok(old) => old.referenced_blocks(),
+                Err(TimeFSError::NotFound(_)) => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            for block_id in old {
+                self.release_block(block_id);
+            }
+
+            self.invalidate_inode(inode.id, true);
+            if inode.id == FUSE_ROOT_ID {
+                let mut root = self.get_inode_mut(FUSE_ROOT_ID)?;
+                root.data = inode.data;
+                root.attr.nlink = inode.attr.nlink;
+                root.touch_ctime();
+                self.persist_inode(&mut root)?;
+                continue;
+            }
+            self.persist_inode(&mut inode)?;
+            self.inodes.insert(inode.id, inode);
+        }
+
+        // Freed by the trash purge making room, if not by the source.
+        let removed = removed.into_iter().filter(|ino| self.inodes.contains_key(ino)).collect::<Vec<_>>();
+        if let Some(ref trash) = self.trash {
+            let mut trash = trash.lock();
+            let taken = removed.iter().filter(|&&ino| trash.take(ino).is_some()).count();
+            if taken > 0 {
+                self.persist_trash(&trash)?;
+            }
+        }
+        for &ino in &removed {
+            self.unlink_inode(ino)?;
+            self.invalidate_inode(ino, true);
+        }
+        // Names are found again through the imported entries.
+        self.names.clear();
+
+        let mut super_block = self.super_block.write();
+        super_block.adopt_inodes(&live);
+        if !self.in_memory {
+            super_block.write_to_file(self.metadata_dir.join("superblock.bin"), self.metadata_sync, self.compress_metadata)?;
+            write_to_bin_file(&imported, &self.metadata_dir.join("imported_blocks.bin"), self.metadata_sync)?;
+        }
+        drop(super_block);
+        debug!("Imported {} inodes, freeing {} the source no longer has", live.len(), removed.len());
+        *self.imported_blocks.lock() = imported;
+        Ok(())
+    }
+
+    /// Fails with `EDQUOT` when taking over the `inodes` of a stream, in place of the local ones
+    /// with their ids and the `removed` ones, would take an owner past their quota.
+    fn check_import_quotas(&self, inodes: &[INode], removed: &[u64]) -> Result<()> {
+        let Some(ref quotas) = self.quotas else {
+            return Ok(());
+        };
+        let mut growth = HashMap::<u32, i64>::new();
+        for inode in inodes {
+            *growth.entry(inode.attr.uid).or_default() += inode.allocated_bytes() as i64;
+        }
+        let replaced = inodes.iter().map(|inode| inode.id).chain(removed.iter().copied());
+        for old in replaced.filter_map(|ino| self.get_inode(ino).ok()) {
+            *growth.entry(old.attr.uid).or_default() -= old.allocated_bytes() as i64;
+        }
+        for (uid, bytes) in growth.into_iter().filter(|&(_, bytes)| bytes > 0) {
+            quotas.check(uid, bytes as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Verifies the checksum of every block persisted on disk.
+    pub(crate) fn scrub(&self) -> Result<ScrubReport> {
+        scrub(&self.blocks_dir)
+    }
+
+    /// Cleans up the block files a crash may have left behind, see [`repair_blocks`], quarantining
+    /// bad blocks in `lost+found` of the storage path and looking up the files that lost data.
+    pub(crate) fn repair_blocks(&self) -> Result<BlockRepairReport> {
+        self.ensure_writable()?;
+        if self.in_memory {
+            return Ok(BlockRepairReport::default());
+        }
+        let mut report = repair_blocks(&self.blocks_dir, &self.storage_path.join("lost+found"))?;
+        if report.quarantined.is_empty() {
+            return Ok(report);
+        }
+
+        self.load_all_inodes()?;
+        let quarantined = report.quarantined.iter().collect::<HashSet<_>>();
+        let mut affected = self.inodes
+            .iter()
+            .filter(|inode| inode.referenced_blocks().iter().any(|id| quarantined.contains(id)))
+            .map(|inode| inode.id)
+            .collect::<Vec<_>>();
+        affected.sort_unstable();
+        for ino in affected {
+            let path = self.path_of(ino).ok();
+            warn!("Inode {} ({:?}) lost data in quarantined blocks", ino, path);
+            report.affected_inodes.push((ino, path));
+        }
+        Ok(report)
+    }
+
+    /// Loads every inode on disk and recomputes the superblock counters from them, for when the
+    /// superblock is stale. Directory entries and parents are checked and repaired like `fsck`.
+    pub(crate) fn rebuild_index(&self) -> Result<()> {
+        let report = self.fsck(!self.read_only)?;
+        if !report.is_clean() {
+            warn!("Rebuilding the inode index found inconsistencies: {:?}", report);
+        }
+        self.recount_block_refs()?;
+        if !self.read_only {
+            self.free_crash_orphans()?;
+        }
+
+        let live = self.inodes.iter().map(|inode| inode.id).collect::<HashSet<_>>();
+        let mut max_block_id = self.inodes
+            .iter()
+            .flat_map(|inode| inode.referenced_blocks())
+            .max()
+            .unwrap_or(0);
+        if !self.in_memory {
+            max_block_id = max_block_id.max(max_block_file_id(&self.blocks_dir)?);
+        }
+
+        let mut super_block = self.super_block.write();
+        let stale = (super_block.inode_count(), super_block.next_inode_id(), super_block.next_block_id());
+        super_block.reset_counters(&live, max_block_id);
+        let rebuilt = (super_block.inode_count(), super_block.next_inode_id(), super_block.next_block_id());
+        if stale != rebuilt {
+            warn!(
+                "Superblock counters were stale: inode count {} -> {}, next inode id {} -> {}, next block id {} -> {}",
+                stale.0, rebuilt.0, stale.1, rebuilt.1, stale.2, rebuilt.2,
+            );
+        }
+        if !self.in_memory && !self.read_only {
+            super_block.write_to_file(self.metadata_dir.join("superblock.bin"), self.metadata_sync, self.compress_metadata)?;
+        }
+        drop(super_block);
+        self.recompute_quotas()
+    }
+
+    /// Frees the files left unlinked but open by a crash, which no handle can reach anymore.
+    fn free_crash_orphans(&self) -> Result<()> {
+        let orphans = self.inodes
+            .iter()
+            .filter(|inode| inode.id != FUSE_ROOT_ID && !inode.is_directory() && inode.attr.nlink == 0)
+            .map(|inode| inode.id)
+            .collect::<Vec<_>>();
+        for ino in orphans {
+            info!("Freeing inode {} left unlinked but open before the crash", ino);
+            self.free_inode(ino)?;
+        }
+        Ok(())
+    }
+
+    /// Writes everything out on unmount.
+    ///
+    /// Crash safety rests on metadata never being durable before the blocks it points to, so this
+    /// goes in stages, each fsynced before the next starts: buffered writes and dirty blocks first,
+    /// then inodes, the trash and block reference counts, then the superblock, marked clean. A crash in between leaves metadata that's
+    /// older than the blocks at worst, which lazy loading and fsck cope with, never references to
+    /// blocks that didn't make it to disk.
+    pub(crate) async fn shutdown(&self) -> Result<()> {
+        self.shutdown_blocks().await?;
+        self.shutdown_metadata()
+    }
+
+    /// First stage of [`TimeFS::shutdown`]: applies buffered writes, frees what's left to free
+    /// and flushes every dirty block.
+    async fn shutdown_blocks(&self) -> Result<()> {
+        // A backup left running is cut short, the writes it held back are flushed with the rest.
+        let frozen = self.frozen_blocks.lock().take();
+        drop(frozen);
+        let handles = self.file_handles.iter().map(|handle| *handle.key()).collect::<Vec<_>>();
+        for fh in handles {
+            self.flush_write_buffer(fh).await?;
+        }
+        self.allocate_all_delayed().await?;
+        let orphans = std::mem::take(&mut *self.orphans.lock());
+        for ino in orphans {
+            self.free_inode(ino)?;
+        }
+        self.delete_released_blocks().await?;
+        self.block_cache.shutdown().await
+    }
+
+    /// Second stage of [`TimeFS::shutdown`]: writes the metadata pointing to the flushed blocks,
+    /// the superblock, marked clean, last.
+    fn shutdown_metadata(&self) -> Result<()> {
+        if self.in_memory || self.read_only {
+            return Ok(());
+        }
+
+        self.dirty_inodes.lock().clear();
+        for mut inode in self.inodes.iter_mut() {
+            inode.write_to_file(&self.inode_dir, true, self.compress_metadata)?;
+        }
+        if let Some(ref trash) = self.trash {
+            trash.lock().write_to_file(&self.trash_path, true)?;
+        }
+        write_to_bin_file(&self.block_refs.to_map(), &self.metadata_dir.join("block_refs.bin"), true)?;
+        let mut super_block = self.super_block.write();
+        super_block.set_clean(true);
+        super_block.write_to_file(self.metadata_dir.join("superblock.bin"), true, self.compress_metadata)?;
+        Ok(())
+    }
+
+    /// Trains a compression dictionary on the newest blocks on disk, returning its id.
+    #[cfg(feature = "zstd")]
+    pub(crate) async fn train_block_dictionary(&self) -> Result<u32> {
+        self.ensure_writable()?;
+        let mut block_ids = self.block_cache.block_ids().await?;
+        block_ids.sort_unstable_by(|a, b| b.cmp(a));
+        block_ids.truncate(DICTIONARY_SAMPLES);
+        self.block_cache.train_dictionary(&block_ids).await
+    }
+
+    /// Checks that directory entries and inode parents, link counts and reachability agree,
+    /// fixing dangling entries, parents and link counts when `repair` is set.
+    pub(crate) fn fsck(&self, repair: bool) -> Result<FsckReport> {
+        if repair {
+            self.ensure_writable()?;
+        }
+
+        let mut report = FsckReport::default();
+        let mut duplicates = Vec::new();
+        if !self.in_memory {
+            for (ino, mut files) in self.inode_files_by_id()? {
+                if files.len() < 2 {
+                    continue;
+                }
+                files.sort_by_key(|&(_, ctime)| std::cmp::Reverse(ctime));
+                let (newest, _) = files.remove(0);
+                let older = files.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+                report.duplicate_inodes.extend(older.iter().map(|path| (ino, path.clone())));
+                duplicates.push((ino, newest, older));
+            }
+            report.duplicate_inodes.sort();
+        }
+        // Duplicates go first, so the rest of the check already sees the inodes that are kept.
+        if repair {
+            for (ino, newest, older) in &duplicates {
+                self.quarantine_duplicate_inodes(*ino, newest, older)?;
+            }
+        }
+        self.load_all_inodes()?;
+        // The inodes kept may point at other blocks than the copies dropped.
+        if repair && !duplicates.is_empty() {
+            self.recount_block_refs()?;
+        }
+
+        let mut listed_by: HashMap<u64, Vec<(u64, String)>> = HashMap::new();
+        let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut subdirs: HashMap<u64, u32> = HashMap::new();
+
+        let mut dirs = self.inodes
+            .iter()
+            .filter_map(|inode| match inode.data {
+                INodeType::Directory { ref entries } => Some((inode.id, entries.clone())),
+                INodeType::File { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        dirs.sort_by_key(|(id, _)| *id);
+
+        for (dir, entries) in &dirs {
+            let mut entries = entries.iter().map(|(name, entry)| (name, entry.id)).collect::<Vec<_>>();
+            entries.sort();
+            for (name, child) in entries {
+                match self.inodes.get(&child) {
+                    Some(inode) => {
+                        if inode.is_directory() {
+                            *subdirs.entry(*dir).or_default() += 1;
+                        }
+                        listed_by.entry(child).or_default().push((*dir, name.clone()));
+                        children.entry(*dir).or_default().push(child);
+                    }
+                    None => report.dangling_entries.push((*dir, name.clone(), child)),
+                }
+            }
+        }
+
+        let trashed = self.trash
+            .as_ref()
+            .map(|trash| trash.lock().entries().iter().map(|e| e.ino).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let orphans = self.orphans.lock().clone();
+
+        let mut reachable = HashSet::from([FUSE_ROOT_ID]);
+        let mut pending = vec![FUSE_ROOT_ID];
+        while let Some(dir) = pending.pop() {
+            for &child in children.get(&dir).into_iter().flatten() {
+                if reachable.insert(child) {
+                    pending.push(child);
+                }
+            }
+        }
+
+        let mut inodes = self.inodes.iter().map(|inode| (inode.id, inode.parent, inode.attr.nlink, inode.is_directory())).collect::<Vec<_>>();
+        inodes.sort();
+
+        for (ino, parent, nlink, is_dir) in inodes {
+            let refs = listed_by.get(&ino).map(Vec::as_slice).unwrap_or_default();
+            if let Some((actual, _)) = refs.first().filter(|(actual, _)| *actual != parent) {
+                report.wrong_parents.push((ino, parent, *actual));
+            }
+
+            let expected = match is_dir {
+                true => 2 + subdirs.get(&ino).copied().unwrap_or(0),
+                false => refs.len() as u32,
+            };
+            if ino != FUSE_ROOT_ID && refs.is_empty() && (trashed.contains(&ino) || orphans.contains(&ino)) {
+                continue;
+            }
+            if nlink != expected {
+                report.wrong_nlinks.push((ino, nlink, expected));
+            }
+            if !reachable.contains(&ino) {
+                report.unreachable.push(ino);
+            }
+        }
+
+        if !self.in_memory {
+            let on_disk = block_file_ids(&self.blocks_dir)?;
+            let mut files = self.inodes.iter().map(|inode| (inode.id, inode.referenced_blocks())).collect::<Vec<_>>();
+            files.sort();
+            for (ino, mut block_ids) in files {
+                block_ids.sort();
+                block_ids.dedup();
+                for block_id in block_ids {
+                    if !on_disk.contains(&block_id) && !self.block_cache.is_cached(block_id) {
+                        report.missing_blocks.push((ino, block_id));
+                    }
+                }
+            }
+        }
+
+        if repair && !report.is_clean() {
+            for (dir, name, _) in &report.dangling_entries {
+                let mut dir_node = self.get_inode_mut(*dir)?;
+                let ino = dir_node.remove_entry(name)?;
+                self.persist_entry_changes(&mut dir_node)?;
+                self.names.remove(&ino);
+            }
+            for &(ino, _, actual) in &report.wrong_parents {
+                let mut inode = self.get_inode_mut(ino)?;
+                inode.parent = actual;
+                self.persist_inode(&mut inode)?;
+                self.names.remove(&ino);
+            }
+            for &(ino, _, actual) in &report.wrong_nlinks {
+                let mut inode = self.get_inode_mut(ino)?;
+                inode.attr.nlink = actual;
+                inode.touch_ctime();
+                self.persist_inode(&mut inode)?;
+            }
+            report.repaired = true;
+        }
+        Ok(report)
+    }
+
+    /// Writes every block out and leaves the block files alone until [`TimeFS::thaw_blocks`], so
+    /// the block directory can be backed up while the filesystem stays in use.
+    pub(crate) async fn freeze_blocks(&self) -> Result<()> {
+        self.ensure_writable()?;
+        self.allocate_all_delayed().await?;
+        let frozen = self.block_cache.freeze().await?;
+        *self.frozen_blocks.lock() = Some(frozen);
+        info!("Froze the block files");
+        Ok(())
+    }
+
+    /// Applies the writes made since [`TimeFS::freeze_blocks`], failing when nothing is frozen.
+    pub(crate) fn thaw_blocks(&self) -> Result<()> {
+        let frozen = self.frozen_blocks.lock().take();
+        match frozen {
+            Some(frozen) => drop(frozen),
+            None => return Err(TimeFSError::Invalid("block files aren't frozen".to_string())),
+        }
+        info!("Thawed the block files");
+        Ok(())
+    }
+
+    /// Records the block list of every file as an immutable snapshot named `name`.
+    pub(crate) async fn snapshot(&self, name: &str) -> Result<()> {
+        self.ensure_writable()?;
+        validate_name(name)?;
+        if Snapshot::exists(name, &self.snapshots_dir) {
+            return Err(TimeFSError::NameExist(name.to_string()));
+        }
+        self.allocate_all_delayed().await?;
+        self.load_all_inodes()?;
+
+        let mut files = HashMap::new();
+        for inode in self.inodes.iter() {
+            if let INodeType::File { ref blocks, .. } = inode.data {
+                for block in blocks.iter().filter(|b| !b.is_hole()) {
+                    self.block_refs.acquire(block);
+                }
+                files.insert(inode.id, blocks.clone());
+            }
+        }
+
+        // In memory a snapshot only pins the blocks it holds, there is nowhere to record it.
+        if !self.in_memory {
+            Snapshot::new(name, files).write_to_file(&self.snapshots_dir, self.metadata_sync)?;
+        }
+        debug!("Snapshot {} has been taken", name);
+        Ok(())
+    }
+
+    fn reply_attr(&self, attr: Result<FileAttr>, reply: impl AttrReply) {
+        match attr {
+            Ok(attr) => reply.attr(&self.attr_ttl, &attr),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn reply_entry(&self, attr: Result<FileAttr>, reply: impl EntryReply) {
+        match attr {
+            Ok(attr) => {
+                self.remember_lookup(attr.ino);
+                reply.entry(&self.entry_ttl, &attr, self.generation(attr.ino));
+            }
+            Err(e) => reply.error(e.into()),
+        }
+    }
+}
+
+/// Answers an xattr request with the size of `value` when the caller passed a zero size,
+/// as the kernel does to size its buffer, and with the value itself otherwise.
+fn reply_xattr(value: Result<Vec<u8>>, size: u32, reply: ReplyXattr) {
+    match value {
+        Ok(value) if size == 0 => reply.size(value.len() as u32),
+        Ok(value) if value.len() > size as usize => reply.error(libc::ERANGE),
+        Ok(value) => reply.data(&value),
+        Err(e) => reply.error(e.into()),
+    }
+}
+
+impl Filesystem for TimeFS {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> std::result::Result<(), c_int> {
+        self.negotiate(config);
+        debug!("TimeFS has inited");
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        if let Err(e) = self.runtime.block_on(self.shutdown()) {
+            error!("Failed to write out TimeFS on unmount: {}", e);
+        }
+        let flush_errors = self.block_cache.flush_errors();
+        if flush_errors > 0 {
+            error!("{} block writes failed while mounted", flush_errors);
+        }
+        debug!("TimeFS has destroyed after {} block updates", self.block_cache.updates());
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup(parent = {}, name = {:?})", parent, name);
+        let _span = debug_span!("lookup", unique = req.unique(), parent).entered();
+
+        let Some(name_str) = name.to_str() else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        self.reply_entry(self.lookup_attr(parent, name_str), reply);
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        debug!("forget(ino = {}, nlookup = {})", ino, nlookup);
+        self.forget_inode(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        debug!("batch_forget({} inodes)", nodes.len());
+        for node in nodes {
+            self.forget_inode(node.nodeid, node.nlookup);
+        }
+    }
+
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        debug!("create(parent = {}, name = {:?}, mode = {}, umask = {}, flags = {})", parent, name, mode, umask, flags);
+        let _span = debug_span!("create", unique = req.unique(), parent).entered();
+
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let name_str= name.to_str();
+
+        if name_str.is_none() {
+            error!("{:?} is not a valid UTF-8 string", name_str);
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let name_str = name_str.unwrap();
+
+        match self.create_file_as(parent, name_str, flags, mode, Creator::from_request(req, umask)) {
+            Ok((attr, handle_id)) => {
+                self.remember_lookup(attr.ino);
+                reply.created(&self.entry_ttl, &attr, self.generation(attr.ino), handle_id, flags as u32);
+            }
+            Err(e) => reply.error(e.into())
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        debug!("open(ino = {}, flags = {:#o})", ino, flags);
+        let _span = debug_span!("open", unique = req.unique(), ino).entered();
+
+        // The health report and handle list change all the time, so the page cache mustn't keep an old one.
+        let open_flags = if matches!(ino, HEALTH_FILE_INO | HANDLES_FILE_INO) { consts::FOPEN_DIRECT_IO } else { 0 };
+        match self.open_file(ino, flags) {
+            Ok(fh) => reply.opened(fh, open_flags),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        debug!("getattr(ino = {}, fh = {:?})", ino, fh);
+        let _span = debug_span!("getattr", unique = req.unique(), ino).entered();
+
+        let attr = match self.virtual_attr(ino) {
+            Some(attr) => Ok(attr),
+            None => self.runtime.block_on(self.flush_write_buffers(ino)).and_then(|_| self.get_attr(ino)),
+        };
+        self.reply_attr(attr, reply);
+    }
+
+    fn mknod(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, rdev: u32, reply: ReplyEntry) {
+        debug!("mknod(parent = {}, name = {:?}, mode = {:#o}, umask = {:#o}, rdev = {})", parent, name, mode, umask, rdev);
+        let _span = debug_span!("mknod", unique = req.unique(), parent).entered();
+
+        let Some(name_str) = name.to_str() else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+
+        self.reply_entry(self.make_node(parent, name_str, mode, rdev, Creator::from_request(req, umask)), reply);
+    }
+
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        debug!("mkdir(parent = {}, name = {:?}, mode = {:#o}, umask = {:#o})", parent, name, mode, umask);
+        let _span = debug_span!("mkdir", unique = req.unique(), parent).entered();
+
+        let Some(name_str) = name.to_str() else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+
+        self.reply_entry(self.make_node(parent, name_str, mode | libc::S_IFDIR, 0, Creator::from_request(req, umask)), reply);
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink(parent = {}, name = {:?})", parent, name);
+        let _span = debug_span!("unlink", unique = req.unique(), parent).entered();
+
+        let Some(name_str) = name.to_str() else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        match self.remove_entry(parent, name_str, false) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir(parent = {}, name = {:?})", parent, name);
+        let _span = debug_span!("rmdir", unique = req.unique(), parent).entered();
+
+        let Some(name_str) = name.to_str() else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        match self.remove_entry(parent, name_str, true) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn rename(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        debug!("rename(parent = {}, name = {:?}, newparent = {}, newname = {:?}, flags = {})", parent, name, newparent, newname, flags);
+        let _span = debug_span!("rename", unique = req.unique(), parent).entered();
+
+        // Entries only leave the trash by being restored, and only unlink puts them there.
+        if newparent == TRASH_DIR_INO {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        let (Some(name_str), Some(newname_str)) = (name.to_str(), newname.to_str()) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+
+        let renamed = match parent {
+            TRASH_DIR_INO => self
+                .lookup_attr(parent, name_str)
+                .and_then(|attr| self.restore(attr.ino, newparent, newname_str)),
+            _ => self.rename_entry(parent, name_str, newparent, newname_str, flags),
+        };
+        match renamed {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        debug!("readdir(ino = {}, fh = {}, offset = {})", ino, fh, offset);
+        let _span = debug_span!("readdir", unique = req.unique(), ino).entered();
+
+        let listing = match self.dir_entries(ino, offset as usize) {
+            Ok(listing) => listing,
+            Err(e) => {
+                reply.error(e.into());
+                return;
+            }
+        };
+
+        for (i, entry) in listing.enumerate() {
+            let (child, kind, name) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    reply.error(e.into());
+                    return;
+                }
+            };
+            if reply.add(child, offset + i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdirplus(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+        debug!("readdirplus(ino = {}, fh = {}, offset = {})", ino, fh, offset);
+        let _span = debug_span!("readdirplus", unique = req.unique(), ino).entered();
+
+        let listing = match self.dir_entries_plus(ino, offset as usize) {
+            Ok(listing) => listing,
+            Err(e) => {
+                reply.error(e.into());
+                return;
+            }
+        };
+
+        for (i, entry) in listing.enumerate() {
+            let (child, name, attr, generation) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    reply.error(e.into());
+                    return;
+                }
+            };
+            if reply.add(child, offset + i as i64 + 1, &name, &self.entry_ttl, &attr, generation) {
+                break;
+            }
+            // The kernel takes a lookup of every entry but `.` and `..`.
+            if name != "." && name != ".." {
+                self.remember_lookup(child);
+            }
+        }
+        reply.ok();
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr(ino = {}, mode = {:?}, uid = {:?}, gid = {:?}, size = {:?}, fh = {:?}, flags = {:?})", ino, mode, uid, gid, size, fh, flags);
+        let _span = debug_span!("setattr", unique = req.unique(), ino).entered();
+
+        if let Err(e) = self.check_chown(ino, uid, gid, req.uid(), req.gid()) {
+            reply.error(e.into());
+            return;
+        }
+        let attr = self.runtime.block_on(self.set_attr(ino, mode, uid, gid, size, atime, mtime));
+        self.reply_attr(attr, reply);
+    }
+
+    fn read(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyData) {
+        debug!("read(ino = {}, fh = {}, offset = {}, size = {}, flags = {}, lock_owner = {:?})", ino, fh, offset, size, flags, lock_owner);
+        let _span = debug_span!("read", unique = req.unique(), ino).entered();
+
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        if let Err(e) = self.check_handle(fh, ino) {
+            reply.error(e.into());
+            return;
+        }
+        match self.runtime.block_on(self.read_at(ino, offset as u64, size)) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], write_flags: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyWrite) {
+        debug!("write(ino = {}, fh = {}, offset = {}, len = {}, write_flags = {}, flags = {}, lock_owner = {:?})", ino, fh, offset, data.len(), write_flags, flags, lock_owner);
+        let _span = debug_span!("write", unique = req.unique(), ino).entered();
+
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        // Writes from the page cache don't say who made them, so only the others are checked
+        // against locks.
+        let lock_owner = lock_owner.filter(|_| write_flags & consts::FUSE_WRITE_LOCKOWNER != 0);
+        let append = flags & libc::O_APPEND != 0 || self.file_handles.get(&fh).is_some_and(|h| h.is_append());
+        match self.runtime.block_on(self.write_handle(fh, ino, offset as u64, data, append, lock_owner)) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn fallocate(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        debug!("fallocate(ino = {}, fh = {}, offset = {}, length = {}, mode = {:#x})", ino, fh, offset, length, mode);
+        let _span = debug_span!("fallocate", unique = req.unique(), ino).entered();
+
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match self.runtime.block_on(self.allocate(ino, offset as u64, length as u64, mode)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn release(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, lock_owner: Option<u64>, flush: bool, reply: ReplyEmpty) {
+        debug!("release(ino = {}, fh = {}, flags = {}, lock_owner = {:?}, flush = {})", ino, fh, flags, lock_owner, flush);
+        let _span = debug_span!("release", unique = req.unique(), ino).entered();
+
+        // Closes that went through flush already synced as the policy asks.
+        let synced = match flush {
+            true => self.runtime.block_on(self.flush_handle(fh, ino)),
+            false => Ok(()),
+        };
+        let closed = self.runtime.block_on(self.close_handle(fh));
+        let flushed = synced.and(closed);
+        if let Some(owner) = lock_owner {
+            self.locks.release_owner(ino, owner);
+        }
+        match flushed {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("Failed to write buffered data of inode {} on release: {}", ino, e);
+                reply.error(e.into());
+            }
+        }
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush(ino = {}, fh = {}, lock_owner = {})", ino, fh, lock_owner);
+        let _span = debug_span!("flush", unique = req.unique(), ino).entered();
+
+        match self.runtime.block_on(self.flush_handle(fh, ino)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("fsync(ino = {}, fh = {}, datasync = {})", ino, fh, datasync);
+        let _span = debug_span!("fsync", unique = req.unique(), ino).entered();
+
+        let Some(ref group_commit) = self.group_commit else {
+            match self.runtime.block_on(self.fsync_file(ino)) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.into()),
+            }
+            return;
+        };
+        if let Err(e) = self.runtime.block_on(self.flush_write_buffers(ino)) {
+            reply.error(e.into());
+            return;
+        }
+        // Waiting here would keep other fsyncs from joining the batch, so the reply is sent once it's committed.
+        let group_commit = group_commit.clone();
+        self.runtime.spawn(async move {
+            match group_commit.sync(ino).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.into()),
+            }
+        }.in_current_span());
+    }
+
+    fn fsyncdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("fsyncdir(ino = {}, fh = {}, datasync = {})", ino, fh, datasync);
+        let _span = debug_span!("fsyncdir", unique = req.unique(), ino).entered();
+
+        match self.fsync_dir(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn getlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        debug!("getlk(ino = {}, fh = {}, lock_owner = {}, start = {}, end = {}, typ = {}, pid = {})", ino, fh, lock_owner, start, end, typ, pid);
+        let _span = debug_span!("getlk", unique = req.unique(), ino).entered();
+
+        let lock = RangeLock { start, end, typ, owner: lock_owner, pid };
+        match self.locks.conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, pid),
+        }
+    }
+
+    fn setlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        debug!("setlk(ino = {}, fh = {}, lock_owner = {}, start = {}, end = {}, typ = {}, pid = {}, sleep = {})", ino, fh, lock_owner, start, end, typ, pid, sleep);
+        let _span = debug_span!("setlk", unique = req.unique(), ino).entered();
+
+        let lock = RangeLock { start, end, typ, owner: lock_owner, pid };
+        match self.locks.try_lock(ino, lock) {
+            Ok(()) => reply.ok(),
+            // Waiting here would stall every other request, so the reply is sent once the lock is taken.
+            Err(_) if sleep => {
+                let locks = self.locks.clone();
+                self.runtime.spawn(async move {
+                    locks.lock(ino, lock).await;
+                    reply.ok();
+                }.in_current_span());
+            }
+            Err(_) => reply.error(libc::EAGAIN),
+        }
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        debug!("bmap(ino = {}, blocksize = {}, idx = {})", ino, blocksize, idx);
+        let _span = debug_span!("bmap", unique = req.unique(), ino).entered();
+
+        // Delayed blocks have no place on disk to report until they're allocated.
+        let mapped = self.runtime.block_on(self.allocate_delayed(ino)).and_then(|_| self.map_block(ino, blocksize, idx));
+        match mapped {
+            Ok(block) => reply.bmap(block),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn poll(&mut self, req: &Request<'_>, ino: u64, fh: u64, ph: PollHandle, events: u32, flags: u32, reply: ReplyPoll) {
+        debug!("poll(ino = {}, fh = {}, ph = {:?}, events = {:#x}, flags = {:#x})", ino, fh, ph, events, flags);
+        let _span = debug_span!("poll", unique = req.unique(), ino).entered();
+
+        match self.poll_events(ino, events) {
+            Ok(0) if flags & FUSE_POLL_SCHEDULE_NOTIFY != 0 => {
+                if let Some(mut fifo) = self.fifos.get_mut(&ino) {
+                    fifo.register(ph);
+                }
+                reply.poll(0);
+            }
+            Ok(revents) => reply.poll(revents),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, position: u32, reply: ReplyEmpty) {
+        debug!("setxattr(ino = {}, name = {:?}, flags = {}, position = {})", ino, name, flags, position);
+        let _span = debug_span!("setxattr", unique = req.unique(), ino).entered();
+
+        let Some(name) = name.to_str() else {
+            reply.error(libc::ENOTSUP);
+            return;
+        };
+        match self.runtime.block_on(self.set_xattr(ino, name, value)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr(ino = {}, name = {:?}, size = {})", ino, name, size);
+        let _span = debug_span!("getxattr", unique = req.unique(), ino).entered();
+
+        let value = match name.to_str() {
+            Some(name) => self.get_xattr(ino, name),
+            None => Err(TimeFSError::XattrNotFound(name.to_string_lossy().into_owned())),
+        };
+        reply_xattr(value, size, reply);
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr(ino = {}, size = {})", ino, size);
+        let _span = debug_span!("listxattr", unique = req.unique(), ino).entered();
+
+        reply_xattr(self.list_xattr(ino), size, reply);
+    }
+
+    fn ioctl(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32, reply: ReplyIoctl) {
+        debug!("ioctl(ino = {}, fh = {}, flags = {}, cmd = {:#x}, out_size = {})", ino, fh, flags, cmd, out_size);
+        let _span = debug_span!("ioctl", unique = req.unique(), ino).entered();
+
+        let result = match cmd {
+            TIMEFS_IOC_SNAPSHOT if ino == FUSE_ROOT_ID => match std::str::from_utf8(in_data) {
+                Ok(name) => self.runtime.block_on(self.snapshot(name.trim_end_matches('\0'))),
+                Err(_) => Err(TimeFSError::Invalid("snapshot name is not UTF-8".to_string())),
+            },
+            TIMEFS_IOC_FREEZE if ino == FUSE_ROOT_ID => self.runtime.block_on(self.freeze_blocks()),
+            TIMEFS_IOC_THAW if ino == FUSE_ROOT_ID => self.thaw_blocks(),
+            TIMEFS_IOC_COMPACT if ino == FUSE_ROOT_ID => self.runtime.block_on(self.compact_all()).map(drop),
+            TIMEFS_IOC_COMPACT => self.runtime.block_on(self.compact_file(ino)).map(drop),
+            TIMEFS_IOC_PIN => self.runtime.block_on(self.pin_file(ino)),
+            TIMEFS_IOC_UNPIN => self.runtime.block_on(self.unpin_file(ino)),
+            TIMEFS_IOC_SET_NOVERSION => self.set_no_version(ino, true),
+            TIMEFS_IOC_CLEAR_NOVERSION => self.set_no_version(ino, false),
+            TIMEFS_IOC_RESTORE_VERSION => self.runtime.block_on(self.restore_ioctl(ino, in_data)),
+            FICLONE | FICLONERANGE => self.runtime.block_on(self.clone_ioctl(req.pid(), ino, cmd, in_data)),
+            TIMEFS_IOC_CAPTURE_VERSION | TIMEFS_IOC_READ_VERSION | TIMEFS_IOC_DIFF_VERSIONS => {
+                match self.runtime.block_on(self.version_ioctl(ino, cmd, in_data, out_size)) {
+                    Ok(data) => reply.ioctl(0, &data),
+                    Err(e) => reply.error(e.into()),
+                }
+                return;
+            }
+            _ => {
+                reply.error(libc::ENOTTY);
+                return;
+            }
+        };
+
+        match result {
+            Ok(()) => reply.ioctl(0, &[]),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+}
+
+/// Reads the `{ u64 secs; u32 nsecs; }` timestamp of a version at the start of an ioctl argument.
+fn ioctl_timestamp(data: &[u8]) -> Option<SystemTime> {
+    let secs = u64::from_ne_bytes(data.get(0..8)?.try_into().unwrap());
+    let nanos = u32::from_ne_bytes(data.get(8..12)?.try_into().unwrap());
+    (nanos < 1_000_000_000).then(|| SystemTime::UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// Sets the size `what` to `requested` through `set`, or to the nearest size `set` says the
+/// kernel supports instead.
+fn negotiate_size(what: &str, requested: u64, mut set: impl FnMut(u32) -> std::result::Result<u32, u32>) {
+    if let Err(nearest) = set(u32::try_from(requested).unwrap_or(u32::MAX)) {
+        warn!("Kernel doesn't support a {} of {} bytes, using {}", what, requested, nearest);
+        let _ = set(nearest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BlockCacheError;
+    use crate::options::parse_glob;
+    use tempfile::{tempdir, TempDir};
+
+    impl TimeFS {
+        fn new(mount_path: impl AsRef<Path>, storage_path: impl AsRef<Path>) -> Result<Self> {
+            Self::with_options(mount_path, storage_path, FsOptions::default())
+        }
+
+        /// Lists a directory as `(ino, kind, name)`, as readdir pages through it.
+        fn list_dir(&self, ino: u64) -> Result<Vec<(u64, FileType, String)>> {
+            self.dir_entries(ino, 0)?.collect()
+        }
+
+        /// Lists a directory like `list_dir`, with what readdirplus adds to every entry.
+        fn list_dir_plus(&self, ino: u64) -> Result<Vec<(u64, String, FileAttr, u64)>> {
+            self.dir_entries_plus(ino, 0)?.collect()
+        }
+    }
+
+    fn setup_fs() -> (TempDir, TimeFS) {
+        let temp_dir = tempdir().expect("Failed to create test dir");
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))
+            .expect("Failed to create TimeFS");
+        (temp_dir, fs)
+    }
+
+    fn file_blocks(fs: &TimeFS, ino: u64) -> Vec<BlockRef> {
+        match fs.get_inode(ino).unwrap().data {
+            INodeType::File { ref blocks, .. } => blocks.clone(),
+            INodeType::Directory { .. } => panic!("{} is not a file", ino),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_keeps_original_blocks() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        fs.create_file(FUSE_ROOT_ID, "test.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "test.txt")?.id;
+        fs.write_at(ino, 0, b"Hello World").await?;
+        let original = file_blocks(&fs, ino);
+
+        fs.snapshot("before").await?;
+        fs.write_at(ino, 6, b"TimeFS").await?;
+
+        let snapshot = Snapshot::from_file("before", &fs.snapshots_dir)?;
+        let snapshot_blocks = &snapshot.files[&ino];
+        assert_eq!(snapshot_blocks[0].id(), original[0].id());
+
+        let current = file_blocks(&fs, ino);
+        assert_ne!(current[0].id(), original[0].id(), "shared block should be copied on write");
+
+        assert_eq!(fs.block_cache.get_block(snapshot_blocks[0].id()).await?, b"Hello World");
+        assert_eq!(fs.block_cache.get_block(current[0].id()).await?, b"Hello TimeFS");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_diff_captured_versions() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        fs.create_file(FUSE_ROOT_ID, "test.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "test.txt")?.id;
+        let block_size = BLOCK_SIZE as u64;
+
+        fs.write_at(ino, 0, &vec![b'a'; 2 * BLOCK_SIZE as usize]).await?;
+        let first = fs.capture_version(ino).await?;
+        fs.write_at(ino, block_size + 10, b"changed").await?;
+        let second = fs.capture_version(ino).await?;
+
+        let ranges = fs.get_inode(ino)?.diff_versions(first, second)?;
+        assert_eq!(ranges, vec![block_size..2 * block_size]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_version_brings_back_its_times() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "notes.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"first draft").await?;
+        let then = fs.get_attr(attr.ino)?;
+        let version = fs.capture_version(attr.ino).await?;
+
+        std::thread::sleep(Duration::from_millis(10));
+        fs.write_at(attr.ino, 0, b"second draft, longer").await?;
+        fs.restore_version(attr.ino, version, true).await?;
+        let restored = fs.get_attr(attr.ino)?;
+        assert_eq!((restored.mtime, restored.ctime, restored.size), (then.mtime, then.ctime, 11));
+        assert_eq!(fs.read_at(attr.ino, 0, 100).await?, b"first draft");
+
+        // The contents replaced became a version of their own, and by default the restore is a
+        // change of the file now.
+        let versions = fs.list_versions(attr.ino)?;
+        assert_eq!(versions.len(), 2);
+        fs.restore_version(attr.ino, versions[1], false).await?;
+        assert_eq!(fs.read_at(attr.ino, 0, 100).await?, b"second draft, longer");
+        let undone = fs.get_attr(attr.ino)?;
+        assert!(undone.mtime > then.mtime && undone.ctime > restored.ctime);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_through_ioctls_and_xattr() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as u64;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "data.bin", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, &vec![b'a'; 3 * BLOCK_SIZE as usize]).await?;
+        assert!(matches!(fs.get_xattr(attr.ino, VERSIONS_XATTR), Err(TimeFSError::XattrNotFound(_))));
+
+        let first = fs.version_ioctl(attr.ino, TIMEFS_IOC_CAPTURE_VERSION, &[], 16).await?;
+        assert_eq!(first.len(), 16);
+        fs.write_at(attr.ino, block_size, b"changed").await?;
+        let second = fs.version_ioctl(attr.ino, TIMEFS_IOC_CAPTURE_VERSION, &[], 16).await?;
+
+        let [a, b] = fs.list_versions(attr.ino)?[..] else { panic!("expected two versions") };
+        assert_eq!(ioctl_timestamp(&first), Some(a));
+        assert_eq!(ioctl_timestamp(&second), Some(b));
+        let listed = String::from_utf8(fs.get_xattr(attr.ino, VERSIONS_XATTR)?).unwrap();
+        assert_eq!(listed.lines().count(), 2);
+        assert!(fs.list_xattr(attr.ino)?.split(|&c| c == 0).any(|name| name == VERSIONS_XATTR.as_bytes()));
+
+        let read = [&second[..12], &4u32.to_ne_bytes(), &block_size.to_ne_bytes()].concat();
+        assert_eq!(fs.version_ioctl(attr.ino, TIMEFS_IOC_READ_VERSION, &read, 2).await?, b"ch");
+        let diff = [&first[..], &second[..]].concat();
+        let ranges = fs.version_ioctl(attr.ino, TIMEFS_IOC_DIFF_VERSIONS, &diff, 64).await?;
+        let ranges = ranges.chunks(8).map(|n| u64::from_ne_bytes(n.try_into().unwrap())).collect::<Vec<_>>();
+        assert_eq!(ranges, [block_size, 2 * block_size]);
+
+        assert!(matches!(fs.version_ioctl(attr.ino, TIMEFS_IOC_READ_VERSION, &read[..12], 64).await, Err(TimeFSError::Invalid(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frozen_block_files_stay_untouched_until_thawed() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_files = || -> Result<Vec<(PathBuf, Vec<u8>)>> {
+            let mut files = Vec::new();
+            for shard in std::fs::read_dir(&fs.blocks_dir)? {
+                for file in std::fs::read_dir(shard?.path())? {
+                    let path = file?.path();
+                    files.push((path.clone(), std::fs::read(path)?));
+                }
+            }
+            files.sort();
+            Ok(files)
+        };
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "backup.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"before backup").await?;
+
+        fs.freeze_blocks().await?;
+        let frozen = block_files()?;
+        assert!(!frozen.is_empty(), "freezing writes every block out");
+        assert!(matches!(fs.freeze_blocks().await, Err(TimeFSError::BlockCacheError(BlockCacheError::AlreadyFrozen))));
+        fs.write_at(attr.ino, 0, b"during backup").await?;
+        fs.write_at(attr.ino, BLOCK_SIZE as u64, b"grown").await?;
+        fs.fsync_file(attr.ino).await?;
+        assert_eq!(block_files()?, frozen);
+        assert_eq!(fs.read_at(attr.ino, 0, 13).await?, b"during backup");
+
+        fs.thaw_blocks()?;
+        assert!(matches!(fs.thaw_blocks(), Err(TimeFSError::Invalid(_))));
+        fs.fsync_file(attr.ino).await?;
+        assert_ne!(block_files()?, frozen);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_stats() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as u64;
+
+        fs.create_file(FUSE_ROOT_ID, "test.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "test.txt")?.id;
+
+        // Each rewrite of the first block copies it, leaving the previous copy to history alone.
+        fs.write_at(ino, 0, &vec![b'a'; 2 * BLOCK_SIZE as usize]).await?;
+        fs.capture_version(ino).await?;
+        fs.write_at(ino, 0, &vec![b'b'; BLOCK_SIZE as usize]).await?;
+        fs.capture_version(ino).await?;
+        fs.write_at(ino, 0, &vec![b'c'; BLOCK_SIZE as usize]).await?;
+        fs.capture_version(ino).await?;
+
+        let stats = fs.version_stats()?;
+        assert_eq!(stats.versions, 3);
+        assert_eq!(stats.history_blocks, 2);
+        assert_eq!(stats.history_bytes, 2 * block_size);
+        // The newest version only shares live blocks, the middle one keeps the `b` block.
+        assert_eq!(stats.reclaimable_bytes, vec![2 * block_size, 2 * block_size, block_size]);
+
+        let attr = fs.lookup_attr(CONTROL_DIR_INO, STATS_FILE_NAME)?;
+        let report = fs.read_at(STATS_FILE_INO, 0, attr.size as u32).await?;
+        assert_eq!(String::from_utf8(report).unwrap(), stats.to_string());
+        assert!(stats.to_string().contains("history_blocks: 2\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_open_handles() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (log, _) = fs.create_file(FUSE_ROOT_ID, "app.log", libc::O_RDWR)?;
+        let (data, _) = fs.create_file(FUSE_ROOT_ID, "data.bin", libc::O_RDWR)?;
+        for fh in fs.list_open_handles().iter().map(|handle| handle.fh) {
+            fs.close_handle(fh).await?;
+        }
+
+        let reader = fs.open_file(data.ino, libc::O_RDONLY)?;
+        let appender = fs.open_file(log.ino, libc::O_WRONLY | libc::O_APPEND)?;
+        let syncer = fs.open_file(data.ino, libc::O_RDWR | libc::O_SYNC)?;
+        let handles = fs.list_open_handles();
+        let summary = handles.iter().map(|h| (h.fh, h.ino, h.readable, h.writable, h.append, h.sync)).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (reader, data.ino, true, false, false, false),
+            (appender, log.ino, false, true, true, false),
+            (syncer, data.ino, true, true, false, true),
+        ]);
+        assert_eq!(handles[1].path.as_deref(), Some(Path::new("/app.log")));
+
+        let attr = fs.lookup_attr(CONTROL_DIR_INO, HANDLES_FILE_NAME)?;
+        let listing = String::from_utf8(fs.read_at(attr.ino, 0, 4096).await?).unwrap();
+        assert_eq!(listing.lines().nth(1), Some(format!("{} {} -w append /app.log", appender, log.ino).as_str()));
+
+        fs.close_handle(appender).await?;
+        assert!(fs.list_open_handles().iter().all(|handle| handle.fh != appender));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_health_file_reports_last_flush() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let read_health = async |fs: &TimeFS| -> Result<HashMap<String, String>> {
+            let attr = fs.lookup_attr(CONTROL_DIR_INO, HEALTH_FILE_NAME)?;
+            let report = String::from_utf8(fs.read_at(attr.ino, 0, 4096).await?).unwrap();
+            // A flat JSON object of numbers and nulls.
+            let fields = report.trim().strip_prefix('{').and_then(|r| r.strip_suffix('}')).expect("not a JSON object");
+            Ok(fields
+                .split(", ")
+                .map(|field| {
+                    let (key, value) = field.split_once(": ").expect("not a JSON member");
+                    let key = key.strip_prefix('"').and_then(|k| k.strip_suffix('"')).expect("unquoted key");
+                    assert!(value == "null" || value.parse::<f64>().is_ok(), "{:?} isn't a JSON number", value);
+                    (key.to_string(), value.to_string())
+                })
+                .collect())
+        };
+
+        let health = read_health(&fs).await?;
+        assert_eq!(health["last_flush"], "null");
+        assert!(health.contains_key("uptime_secs"));
+
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "probe", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"alive").await?;
+        assert_eq!(read_health(&fs).await?["dirty_blocks"], "1");
+        fs.fsync_file(attr.ino).await?;
+
+        let health = read_health(&fs).await?;
+        assert_eq!(health["dirty_blocks"], "0");
+        let last_flush = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(health["last_flush"].parse().unwrap());
+        assert!(SystemTime::now().duration_since(last_flush).unwrap_or_default() < Duration::from_secs(60));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_pruned_past_byte_cap() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { max_version_bytes: Some(2 * BLOCK_SIZE as u64), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "big.txt", libc::O_RDWR)?;
+
+        // Every version but the newest ends up holding one block nothing else uses.
+        let mut versions = Vec::new();
+        let mut history = Vec::new();
+        for fill in [b'a', b'b', b'c', b'd'] {
+            fs.write_at(attr.ino, 0, &vec![fill; BLOCK_SIZE as usize]).await?;
+            history.push(file_blocks(&fs, attr.ino)[0].id());
+            versions.push(fs.capture_version(attr.ino).await?);
+        }
+
+        let inode = fs.get_inode(attr.ino)?;
+        assert_eq!(inode.version_count(), 3);
+        assert!(inode.get_version(versions[0]).is_err(), "oldest version should be dropped");
+        for &timestamp in &versions[1..] {
+            inode.get_version(timestamp)?;
+        }
+        assert_eq!(inode.history_bytes(), 2 * BLOCK_SIZE as u64);
+        drop(inode);
+        assert_eq!(fs.block_refs.count(history[0]), 0);
+        assert_eq!(fs.block_refs.count(history[1]), 1);
+
+        // The count cap applies on top, whichever is hit first.
+        let options = FsOptions { max_versions: Some(2), max_version_bytes: Some(u64::MAX), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt2"), temp_dir.path().join("storage2"), options)?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "small.txt", libc::O_RDWR)?;
+        for _ in 0..3 {
+            fs.capture_version(attr.ino).await?;
+        }
+        assert_eq!(fs.get_inode(attr.ino)?.version_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_inode_file_is_enoent() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (lost, _) = fs.create_file(FUSE_ROOT_ID, "lost.txt", libc::O_RDWR)?;
+        fs.create_file(FUSE_ROOT_ID, "kept.txt", libc::O_RDWR)?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let inode_path = INode::inode_path(lost.ino, &temp_dir.path().join("storage/metadata/inode"));
+        std::fs::remove_file(inode_path)?;
+
+        // Only the root is in memory after mounting, children are loaded on first lookup.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let err = fs.lookup_attr(FUSE_ROOT_ID, "lost.txt").unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::ENOENT);
+        assert!(fs.lookup_attr(FUSE_ROOT_ID, "kept.txt").is_ok());
+        assert_eq!(fs.fsck(false)?.dangling_entries, vec![(FUSE_ROOT_ID, "lost.txt".to_string(), lost.ino)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flat_inode_layout_is_migrated() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        fs.create_file(FUSE_ROOT_ID, "old.txt", libc::O_RDWR)?;
+        drop(fs);
+
+        // Recreate the layout from before sharding, every inode file directly in the inode dir.
+        let inode_dir = temp_dir.path().join("storage/metadata/inode");
+        for shard in std::fs::read_dir(&inode_dir)? {
+            for file in std::fs::read_dir(shard?.path())? {
+                let file = file?;
+                std::fs::rename(file.path(), inode_dir.join(file.file_name()))?;
+            }
+        }
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let ino = fs.lookup_attr(FUSE_ROOT_ID, "old.txt")?.ino;
+        assert!(INode::inode_path(ino, &inode_dir).exists());
+        assert!(!inode_dir.join(format!("inode_{}.bin", ino)).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata_sync_option() -> Result<()> {
+        let syncs = || crate::FILE_SYNCS.with(|syncs| syncs.get());
+
+        let (_temp_dir, fs) = setup_fs();
+        let before = syncs();
+        fs.create_file(FUSE_ROOT_ID, "durable.txt", libc::O_RDWR)?;
+        // The new inode and the entry log of the root.
+        assert!(syncs() >= before + 2);
+
+        let temp_dir = tempdir()?;
+        let options = FsOptions { no_metadata_sync: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let before = syncs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "fast.txt", libc::O_RDWR)?;
+        fs.set_attr(attr.ino, Some(0o600), None, None, None, None, None).await?;
+        assert_eq!(syncs(), before);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_compressed_blocks_need_compression_to_mount() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let (mount, storage) = (temp_dir.path().join("mnt"), temp_dir.path().join("storage"));
+        let compressed = || FsOptions { block_compression: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(&mount, &storage, compressed())?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "log.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, &b"repetitive ".repeat(300)).await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let block_id = block_file_ids(&storage.join("blocks"))?.into_iter().next().unwrap();
+        let on_disk = std::fs::metadata(storage.join("blocks/000").join(format!("block_{}.bin", block_id)))?.len();
+        assert!(on_disk < 300, "{} bytes on disk", on_disk);
+
+        assert!(TimeFS::new(&mount, &storage).is_err());
+        let fs = TimeFS::with_options(&mount, &storage, compressed())?;
+        assert_eq!(fs.read_at(attr.ino, 0, 4096).await?, b"repetitive ".repeat(300));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_fails_past_max_open_files() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { max_open_files: Some(2), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let (attr, first) = fs.create_file(FUSE_ROOT_ID, "a.txt", libc::O_RDWR)?;
+        fs.open_file(attr.ino, libc::O_RDONLY)?;
+
+        let err = fs.open_file(attr.ino, libc::O_RDONLY).unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::EMFILE);
+        assert!(matches!(fs.create_file(FUSE_ROOT_ID, "b.txt", libc::O_RDWR), Err(TimeFSError::TooManyOpenFiles)));
+        assert!(matches!(fs.lookup_attr(FUSE_ROOT_ID, "b.txt"), Err(TimeFSError::NameNotFound(_))));
+
+        fs.close_handle(first).await?;
+        fs.open_file(attr.ino, libc::O_RDONLY)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chown_requires_root_to_give_files_away() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let owner = Creator { uid: 1000, gid: 1000, umask: 0 };
+        let (attr, _) = fs.create_file_as(FUSE_ROOT_ID, "owned.txt", libc::O_RDWR, 0o644, owner)?;
+
+        let err = fs.check_chown(attr.ino, Some(0), None, 1000, 1000).unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::EPERM);
+        assert!(matches!(fs.check_chown(attr.ino, None, Some(0), 1000, 1000), Err(TimeFSError::NotPermitted(_))));
+        assert!(matches!(fs.check_chown(attr.ino, None, Some(2000), 2000, 2000), Err(TimeFSError::NotPermitted(_))));
+
+        // Keeping the owner and moving to the caller's own group is fine.
+        fs.check_chown(attr.ino, Some(1000), Some(1000), 1000, 1000)?;
+        fs.check_chown(attr.ino, Some(0), Some(0), 0, 0)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlink_recycles_inode_and_block_ids() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (old, fh) = fs.create_file(FUSE_ROOT_ID, "old.txt", libc::O_RDWR)?;
+        fs.write_at(old.ino, 0, b"short lived").await?;
+        fs.close_handle(fh).await?;
+        let old_block = file_blocks(&fs, old.ino)[0].id();
+        let old_generation = fs.generation(old.ino);
+        fs.remove_entry(FUSE_ROOT_ID, "old.txt", false)?;
+        fs.delete_released_blocks().await?;
+
+        let (new, _) = fs.create_file(FUSE_ROOT_ID, "new.txt", libc::O_RDWR)?;
+        assert_eq!(new.ino, old.ino);
+        assert_eq!(fs.generation(new.ino), old_generation + 1);
+
+        fs.write_at(new.ino, 0, b"reused").await?;
+        assert_eq!(file_blocks(&fs, new.ino)[0].id(), old_block);
+        assert_eq!(fs.read_at(new.ino, 0, 6).await?, b"reused");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlinked_open_file_keeps_its_id_until_closed() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (a, fh) = fs.create_file(FUSE_ROOT_ID, "a.txt", libc::O_RDWR)?;
+        fs.write_handle(fh, a.ino, 0, b"written through a", false, None).await?;
+        fs.remove_entry(FUSE_ROOT_ID, "a.txt", false)?;
+
+        let (b, b_fh) = fs.create_file(FUSE_ROOT_ID, "b.txt", libc::O_RDWR)?;
+        assert_ne!(b.ino, a.ino, "the id of an open file isn't reused");
+        fs.write_handle(fh, a.ino, 0, b"still a", false, None).await?;
+        assert!(matches!(fs.write_handle(fh, b.ino, 0, b"stale", false, None).await, Err(TimeFSError::StaleHandle(_))));
+        fs.close_handle(b_fh).await?;
+        assert_eq!(fs.get_attr(b.ino)?.size, 0);
+        assert_eq!(fs.read_at(a.ino, 0, 17).await?, b"still a through a");
+
+        fs.close_handle(fh).await?;
+        assert!(matches!(fs.get_inode(a.ino), Err(TimeFSError::NotFound(_))));
+        let (c, _) = fs.create_file(FUSE_ROOT_ID, "c.txt", libc::O_RDWR)?;
+        assert_eq!(c.ino, a.ino, "the id is recycled once the orphan is closed");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlink_deletes_unshared_block_files() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_path = |block_id: u64| fs.blocks_dir.join(format!("{:03}", block_id / 1000)).join(format!("block_{}.bin", block_id));
+        let (gone, gone_fh) = fs.create_file(FUSE_ROOT_ID, "gone.txt", libc::O_RDWR)?;
+        fs.write_at(gone.ino, 0, b"deleted with the file").await?;
+        fs.fsync_file(gone.ino).await?;
+        let (kept, _) = fs.create_file(FUSE_ROOT_ID, "kept.txt", libc::O_RDWR)?;
+        fs.clone_file(gone.ino, kept.ino).await?;
+        let (only, only_fh) = fs.create_file(FUSE_ROOT_ID, "only.txt", libc::O_RDWR)?;
+        fs.write_at(only.ino, 0, b"no other reference").await?;
+        fs.fsync_file(only.ino).await?;
+        let shared = file_blocks(&fs, gone.ino)[0].id();
+        let unshared = file_blocks(&fs, only.ino)[0].id();
+        assert!(block_path(shared).exists() && block_path(unshared).exists());
+        fs.close_handle(gone_fh).await?;
+        fs.close_handle(only_fh).await?;
+
+        fs.remove_entry(FUSE_ROOT_ID, "gone.txt", false)?;
+        fs.remove_entry(FUSE_ROOT_ID, "only.txt", false)?;
+        fs.delete_released_blocks().await?;
+        assert!(block_path(shared).exists(), "the clone still uses the block");
+        assert!(!block_path(unshared).exists());
+        assert_eq!(fs.read_at(kept.ino, 0, 64).await?, b"deleted with the file");
+
+        // Nothing comes back once the cache is flushed.
+        fs.shutdown().await?;
+        assert!(!block_path(unshared).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inode_writes_batched_until_flush() -> Result<()> {
+        let syncs = || crate::FILE_SYNCS.with(|syncs| syncs.get());
+
+        let temp_dir = tempdir()?;
+        let options = FsOptions { inode_flush_interval: Some(Duration::from_secs(3600)), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "busy.txt", libc::O_RDWR)?;
+        fs.flush_dirty_inodes()?;
+
+        let before = syncs();
+        for i in 0..50 {
+            fs.set_attr(attr.ino, Some(0o600 + i % 2), None, None, None, None, None).await?;
+        }
+        assert_eq!(syncs(), before);
+        fs.flush_dirty_inodes()?;
+        assert_eq!(syncs(), before + 1);
+        fs.flush_dirty_inodes()?;
+        assert_eq!(syncs(), before + 1, "nothing is left dirty");
+
+        // fsync writes the one inode right away.
+        fs.set_attr(attr.ino, Some(0o640), None, None, None, None, None).await?;
+        assert_eq!(INode::from_file(attr.ino, &fs.inode_dir)?.attr.perm, 0o601);
+        fs.sync_inode(attr.ino)?;
+        assert_eq!(syncs(), before + 2);
+        assert_eq!(INode::from_file(attr.ino, &fs.inode_dir)?.attr.perm, 0o640);
+
+        // fsyncdir writes the files its entries name too.
+        fs.set_attr(attr.ino, Some(0o644), None, None, None, None, None).await?;
+        fs.fsync_dir(FUSE_ROOT_ID)?;
+        assert_eq!(INode::from_file(attr.ino, &fs.inode_dir)?.attr.perm, 0o644);
+        assert!(fs.dirty_inodes.lock().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_zero_flush_interval_only_flushes_on_demand() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { flush_interval: Some(Duration::ZERO), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let on_disk = |ino: u64| file_blocks(&fs, ino)
+            .iter()
+            .map(|block| fs.blocks_dir.join(format!("{:03}", block.id() / 1000)).join(format!("block_{}.bin", block.id())))
+            .map(|path| path.exists())
+            .collect::<HashSet<_>>();
+
+        // Well past the dirty high water, which would otherwise start flushing.
+        let (synced, _) = fs.create_file(FUSE_ROOT_ID, "synced.bin", libc::O_RDWR)?;
+        fs.write_at(synced.ino, 0, &vec![b's'; DEFAULT_DIRTY_HIGH_WATER + 8 * BLOCK_SIZE as usize]).await?;
+        let (unsynced, _) = fs.create_file(FUSE_ROOT_ID, "unsynced.bin", libc::O_RDWR)?;
+        fs.write_at(unsynced.ino, 0, b"until unmount").await?;
+
+        // Longer than the periodic flush takes between two looks at the dirty blocks.
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(on_disk(synced.ino), HashSet::from([false]));
+        assert_eq!(on_disk(unsynced.ino), HashSet::from([false]));
+
+        fs.fsync_file(synced.ino).await?;
+        assert_eq!(on_disk(synced.ino), HashSet::from([true]));
+        assert_eq!(on_disk(unsynced.ino), HashSet::from([false]));
+
+        fs.shutdown().await?;
+        assert_eq!(on_disk(unsynced.ino), HashSet::from([true]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_on_close_policy() -> Result<()> {
+        for policy in [SyncOnClose::None, SyncOnClose::Data, SyncOnClose::All] {
+            let temp_dir = tempdir()?;
+            let options = FsOptions {
+                inode_flush_interval: Some(Duration::from_secs(3600)),
+                sync_on_close: policy,
+                ..FsOptions::default()
+            };
+            let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+            let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "closed.txt", libc::O_RDWR)?;
+            fs.flush_dirty_inodes()?;
+            fs.write_buffered(fh, attr.ino, 0, b"closed").await?;
+            fs.flush_handle(fh, attr.ino).await?;
+            fs.close_handle(fh).await?;
+
+            let block_id = file_blocks(&fs, attr.ino)[0].id();
+            let block_path = fs.blocks_dir.join(format!("{:03}", block_id / 1000)).join(format!("block_{}.bin", block_id));
+            let data_on_disk = block_path.exists();
+            let size_on_disk = INode::from_file(attr.ino, &fs.inode_dir)?.file_size();
+            match policy {
+                SyncOnClose::None => assert!(!data_on_disk && size_on_disk == 0, "{:?}", policy),
+                SyncOnClose::Data => assert!(data_on_disk && size_on_disk == 0, "{:?}", policy),
+                SyncOnClose::All => assert!(data_on_disk && size_on_disk == 6, "{:?}", policy),
+            }
+
+            // What close left behind is written by the periodic flushes.
+            fs.block_cache.flush_dirty().await?;
+            fs.flush_dirty_inodes()?;
+            assert!(block_path.exists());
+            assert_eq!(INode::from_file(attr.ino, &fs.inode_dir)?.file_size(), 6);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_fsyncs_share_one_commit() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions {
+            inode_flush_interval: Some(Duration::from_secs(3600)),
+            fsync_batch_window: Some(Duration::from_millis(50)),
+            ..FsOptions::default()
+        };
+        let fs = Arc::new(TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?);
+
+        let mut files = Vec::new();
+        for i in 0..16 {
+            let (attr, _) = fs.create_file(FUSE_ROOT_ID, format!("db-{}.wal", i), libc::O_RDWR)?;
+            let data = format!("transaction {}", i).into_bytes();
+            fs.write_at(attr.ino, 0, &data).await?;
+            files.push((attr.ino, data));
+        }
+
+        let tasks = files
+            .iter()
+            .map(|&(ino, _)| {
+                let fs = fs.clone();
+                tokio::spawn(async move { fs.fsync_file(ino).await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.expect("Fsync task panicked")?;
+        }
+        let commits = fs.group_commit.as_ref().unwrap().commits();
+        assert!((1..files.len() as u64).contains(&commits), "{} commits for {} fsyncs", commits, files.len());
+
+        // Every fsync returned with its file's block and inode on disk.
+        for (ino, data) in files {
+            let block_id = file_blocks(&fs, ino)[0].id();
+            let raw = std::fs::read(fs.blocks_dir.join(format!("{:03}", block_id / 1000)).join(format!("block_{}.bin", block_id)))?;
+            assert_eq!(raw[..raw.len() - 4], data);
+            assert_eq!(INode::from_file(ino, &fs.inode_dir)?.file_size(), data.len() as u64);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_getattr_loads_inode_after_remount() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "stat.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"persisted").await?;
+        let expected = fs.set_attr(attr.ino, Some(0o640), None, None, None, None, None).await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert!(!fs.inodes.contains_key(&attr.ino));
+        let loaded = fs.get_attr(attr.ino)?;
+        assert_eq!((loaded.ino, loaded.size, loaded.perm, loaded.kind), (expected.ino, 9, 0o640, FileType::RegularFile));
+        assert_eq!(loaded.mtime, expected.mtime);
+        assert!(fs.inodes.contains_key(&attr.ino));
+
+        assert!(matches!(fs.get_attr(attr.ino + 100), Err(TimeFSError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crash_during_shutdown_leaves_no_missing_blocks() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "durable.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, &vec![b'd'; 2 * BLOCK_SIZE as usize]).await?;
+        let block_ids = file_blocks(&fs, attr.ino).iter().map(|block| block.id()).collect::<Vec<_>>();
+
+        // Crashing between the stages.
+        fs.shutdown_blocks().await?;
+        drop(fs);
+
+        // Never marked clean, the next mount rebuilds the index from every inode on disk.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert!(fs.inodes.contains_key(&attr.ino));
+        let report = fs.fsck(false)?;
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(fs.read_at(attr.ino, 0, 2 * BLOCK_SIZE).await?, vec![b'd'; 2 * BLOCK_SIZE as usize]);
+        drop(fs);
+
+        // A block lost before reaching disk is what fsck has to report.
+        std::fs::remove_file(temp_dir.path().join("storage/blocks/000").join(format!("block_{}.bin", block_ids[1])))?;
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert_eq!(fs.fsck(false)?.missing_blocks, vec![(attr.ino, block_ids[1])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ids_not_handed_out_twice_after_crash() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (a, _) = fs.create_file(FUSE_ROOT_ID, "a.txt", libc::O_RDWR)?;
+        fs.write_at(a.ino, 0, b"hello").await?;
+        fs.flush_dirty_inodes()?;
+        drop(fs);
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let (b, _) = fs.create_file(FUSE_ROOT_ID, "b.txt", libc::O_RDWR)?;
+        assert_ne!(b.ino, a.ino);
+        assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, "a.txt")?.size, 5);
+        fs.shutdown().await?;
+        drop(fs);
+
+        // Unmounted cleanly this time, inodes are loaded lazily again rather than rebuilt.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert!(!fs.inodes.contains_key(&b.ino));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_freed_ids_reused_before_crash_not_handed_out_again() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (a, fh) = fs.create_file(FUSE_ROOT_ID, "a.txt", libc::O_RDWR)?;
+        fs.write_at(a.ino, 0, b"first").await?;
+        let a_block = file_blocks(&fs, a.ino)[0].id();
+        fs.close_handle(fh).await?;
+        fs.remove_entry(FUSE_ROOT_ID, "a.txt", false)?;
+        fs.delete_released_blocks().await?;
+
+        // Both ids come off the free lists, which were last written holding them.
+        let (b, _) = fs.create_file(FUSE_ROOT_ID, "b.txt", libc::O_RDWR)?;
+        fs.write_at(b.ino, 0, b"second").await?;
+        assert_eq!((b.ino, file_blocks(&fs, b.ino)[0].id()), (a.ino, a_block));
+        fs.fsync_file(b.ino).await?;
+        drop(fs);
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let (c, _) = fs.create_file(FUSE_ROOT_ID, "c.txt", libc::O_RDWR)?;
+        fs.write_at(c.ino, 0, b"third").await?;
+        assert_ne!(c.ino, b.ino);
+        assert_ne!(file_blocks(&fs, c.ino)[0].id(), a_block);
+        assert_eq!(fs.read_at(b.ino, 0, 64).await?, b"second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chmod_changes_ctime_only() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (created, _) = fs.create_file(FUSE_ROOT_ID, "times.txt", libc::O_RDWR)?;
+        fs.write_at(created.ino, 0, b"first").await?;
+        let written = fs.get_attr(created.ino)?;
+
+        std::thread::sleep(Duration::from_millis(10));
+        let chmodded = fs.set_attr(created.ino, Some(0o600), None, None, None, None, None).await?;
+        assert_eq!(chmodded.mtime, written.mtime);
+        assert!(chmodded.ctime > written.ctime);
+
+        std::thread::sleep(Duration::from_millis(10));
+        fs.write_at(created.ino, 0, b"second").await?;
+        let rewritten = fs.get_attr(created.ino)?;
+        assert!(rewritten.mtime > chmodded.mtime);
+        assert!(rewritten.ctime > chmodded.ctime);
+        assert_eq!(rewritten.mtime, rewritten.ctime);
+
+        std::thread::sleep(Duration::from_millis(10));
+        fs.rename_entry(FUSE_ROOT_ID, "times.txt", FUSE_ROOT_ID, "renamed.txt", 0)?;
+        let renamed = fs.get_attr(created.ino)?;
+        assert_eq!(renamed.mtime, rewritten.mtime);
+        assert!(renamed.ctime > rewritten.ctime);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_applies_umask_and_requester() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let creator = Creator { uid: 1234, gid: 5678, umask: 0o022 };
+        let (attr, _) = fs.create_file_as(FUSE_ROOT_ID, "private.txt", libc::O_RDWR, 0o600, creator)?;
+        assert_eq!((attr.perm, attr.uid, attr.gid), (0o600, 1234, 5678));
+
+        let (attr, _) = fs.create_file_as(FUSE_ROOT_ID, "shared.txt", libc::O_RDWR, 0o666, creator)?;
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(fs.get_attr(attr.ino)?.perm, 0o644);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_setgid_directory_passes_group_down() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let owner = Creator { uid: 1000, gid: 100, umask: 0o022 };
+        let shared = fs.make_node(FUSE_ROOT_ID, "shared", libc::S_IFDIR | libc::S_ISGID | 0o775, 0, owner)?;
+        assert_eq!(shared.perm as u32 & libc::S_ISGID, libc::S_ISGID);
+
+        let other = Creator { uid: 2000, gid: 200, umask: 0o022 };
+        let (file, _) = fs.create_file_as(shared.ino, "notes.txt", libc::O_RDWR, 0o644, other)?;
+        assert_eq!((file.uid, file.gid, file.perm), (2000, 100, 0o644));
+        assert_eq!(fs.get_attr(file.ino)?.gid, 100);
+
+        let sub = fs.make_node(shared.ino, "sub", libc::S_IFDIR | 0o755, 0, other)?;
+        assert_eq!((sub.gid, sub.perm as u32), (100, libc::S_ISGID | 0o755));
+        let (nested, _) = fs.create_file_as(sub.ino, "nested.txt", libc::O_RDWR, 0o644, other)?;
+        assert_eq!(nested.gid, 100);
+
+        // Without the setgid bit children keep the creator's group.
+        let plain = fs.make_node(FUSE_ROOT_ID, "plain", libc::S_IFDIR | 0o775, 0, owner)?;
+        let (file, _) = fs.create_file_as(plain.ino, "notes.txt", libc::O_RDWR, 0o644, other)?;
+        assert_eq!(file.gid, 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_exclusive_fails_on_existing_file() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "lock", libc::O_RDWR | libc::O_CREAT | libc::O_EXCL)?;
+        fs.write_at(attr.ino, 0, b"held").await?;
+
+        let handles = fs.file_handles.len();
+        let result = fs.create_file(FUSE_ROOT_ID, "lock", libc::O_RDWR | libc::O_CREAT | libc::O_EXCL);
+        assert!(matches!(result, Err(TimeFSError::NameExist(_))));
+        assert_eq!(fs.file_handles.len(), handles, "no handle is left open");
+        assert_eq!(fs.read_at(attr.ino, 0, 4).await?, b"held");
+
+        // Without O_EXCL the existing file is opened.
+        let (reopened, _) = fs.create_file(FUSE_ROOT_ID, "lock", libc::O_RDWR | libc::O_CREAT)?;
+        assert_eq!(reopened.ino, attr.ino);
+        assert_eq!(reopened.size, 4);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_open_prefetches_small_files() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (small, _) = fs.create_file(FUSE_ROOT_ID, "small.txt", libc::O_RDWR)?;
+        fs.write_at(small.ino, 0, &vec![b's'; 3 * BLOCK_SIZE as usize]).await?;
+        let (large, _) = fs.create_file(FUSE_ROOT_ID, "large.txt", libc::O_RDWR)?;
+        fs.write_at(large.ino, 0, &vec![b'l'; (PREFETCH_MAX_BLOCKS + 1) * BLOCK_SIZE as usize]).await?;
+        let small_blocks = file_blocks(&fs, small.ino).iter().map(|block| block.id()).collect::<Vec<_>>();
+        let large_blocks = file_blocks(&fs, large.ino).iter().map(|block| block.id()).collect::<Vec<_>>();
+        for &block_id in small_blocks.iter().chain(&large_blocks) {
+            fs.block_cache.flush_block(block_id, true).await?;
+        }
+        drop(fs);
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert!(small_blocks.iter().all(|&block_id| !fs.block_cache.is_cached(block_id)));
+        fs.open_file(small.ino, libc::O_RDONLY)?;
+        fs.open_file(large.ino, libc::O_RDONLY)?;
+
+        let resident = async {
+            while !small_blocks.iter().all(|&block_id| fs.block_cache.is_cached(block_id)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), resident).await.expect("blocks weren't prefetched");
+        assert!(large_blocks.iter().all(|&block_id| !fs.block_cache.is_cached(block_id)), "large files aren't prefetched");
+        assert_eq!(fs.read_at(small.ino, 0, 16).await?, vec![b's'; 16]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_warm_cache_reads_hinted_files() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let dir = fs.make_node(FUSE_ROOT_ID, "dir", libc::S_IFDIR | 0o755, 0, Creator::current_user())?;
+        let (hot, _) = fs.create_file(dir.ino, "hot.bin", libc::O_RDWR)?;
+        fs.write_at(hot.ino, 0, &vec![b'h'; (PREFETCH_MAX_BLOCKS + 2) * BLOCK_SIZE as usize]).await?;
+        let (by_id, _) = fs.create_file(FUSE_ROOT_ID, "by_id.bin", libc::O_RDWR)?;
+        fs.write_at(by_id.ino, 0, b"by id").await?;
+        let (cold, _) = fs.create_file(FUSE_ROOT_ID, "cold.bin", libc::O_RDWR)?;
+        fs.write_at(cold.ino, 0, b"cold").await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let hints = temp_dir.path().join("hints");
+        std::fs::write(&hints, format!("# hot set\n/dir/hot.bin\n{}\n/missing\nrelative\n", by_id.ino))?;
+        let options = FsOptions { warm_cache: Some(hints), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let warmed = [hot.ino, by_id.ino].iter().flat_map(|&ino| file_blocks(&fs, ino)).map(|block| block.id()).collect::<Vec<_>>();
+
+        let resident = async {
+            while !warmed.iter().all(|&block_id| fs.block_cache.is_cached(block_id)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), resident).await.expect("hinted blocks weren't read");
+        assert!(file_blocks(&fs, cold.ino).iter().all(|block| !fs.block_cache.is_cached(block.id())));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_index_restores_counters() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs.create_file(FUSE_ROOT_ID, name, libc::O_RDWR)?;
+        }
+        let ino = fs.lookup_attr(FUSE_ROOT_ID, "b.txt")?.ino;
+        fs.write_at(ino, 0, &vec![b'b'; 2 * BLOCK_SIZE as usize]).await?;
+        let counters = |fs: &TimeFS| {
+            let super_block = fs.super_block.read();
+            (super_block.inode_count(), super_block.next_inode_id(), super_block.next_block_id())
+        };
+        let expected = counters(&fs);
+        drop(fs);
+
+        let super_block_path = temp_dir.path().join("storage/metadata/superblock.bin");
+        SuperBlock::new().write_to_file(&super_block_path, true, None)?;
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert_ne!(counters(&fs), expected);
+        fs.rebuild_index()?;
+        assert_eq!(counters(&fs), expected);
+
+        let reopened = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert_eq!(counters(&reopened), expected, "rebuilt counters should be persisted");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_superblock_recovered_from_backup() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        for name in ["a.txt", "b.txt"] {
+            let (attr, _) = fs.create_file(FUSE_ROOT_ID, name, libc::O_RDWR)?;
+            fs.write_at(attr.ino, 0, name.as_bytes()).await?;
+        }
+        let counters = |fs: &TimeFS| {
+            let super_block = fs.super_block.read();
+            (super_block.inode_count(), super_block.next_inode_id(), super_block.next_block_id())
+        };
+        let expected = counters(&fs);
+        fs.shutdown().await?;
+        drop(fs);
+
+        let super_block_path = temp_dir.path().join("storage/metadata/superblock.bin");
+        assert!(super_block_path.with_extension("bak").exists());
+        std::fs::write(&super_block_path, b"not a superblock")?;
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert_eq!(counters(&fs), expected, "counters the backup missed are rebuilt");
+        assert_eq!(fs.read_at(fs.lookup_attr(FUSE_ROOT_ID, "b.txt")?.ino, 0, 16).await?, b"b.txt");
+        assert!(!SuperBlock::from_file(&super_block_path)?.is_recovered(), "the recovered superblock is written back");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init_negotiates_request_sizes() -> Result<()> {
+        /// Takes sizes within the bounds a kernel would, like `KernelConfig`.
+        struct Kernel {
+            capabilities: u32,
+            max_write: u32,
+            max_readahead: u32,
+        }
+
+        impl InitConfig for Kernel {
+            fn add_capabilities(&mut self, capabilities: u32) -> std::result::Result<(), u32> {
+                self.capabilities |= capabilities;
+                Ok(())
+            }
+
+            fn set_max_write(&mut self, value: u32) -> std::result::Result<u32, u32> {
+                match value {
+                    0 => Err(1),
+                    _ if value > 16 * 1024 * 1024 => Err(16 * 1024 * 1024),
+                    _ => Ok(std::mem::replace(&mut self.max_write, value)),
+                }
+            }
+
+            fn set_max_readahead(&mut self, value: u32) -> std::result::Result<u32, u32> {
+                match value {
+                    0 => Err(1),
+                    _ if value > 128 * 1024 => Err(128 * 1024),
+                    _ => Ok(std::mem::replace(&mut self.max_readahead, value)),
+                }
+            }
+        }
+        let kernel = || Kernel { capabilities: 0, max_write: 4096, max_readahead: 4096 };
+
+        let (_temp_dir, fs) = setup_fs();
+        let mut defaults = kernel();
+        fs.negotiate(&mut defaults);
+        assert_eq!((defaults.capabilities, defaults.max_write, defaults.max_readahead), (consts::FUSE_DO_READDIRPLUS, 4096, 4096));
+
+        let temp_dir = tempdir()?;
+        let options = FsOptions { max_write: Some(1024 * 1024), max_readahead: Some(1024 * 1024 * 1024), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let mut config = kernel();
+        fs.negotiate(&mut config);
+        // More readahead than the kernel allows settles for its maximum.
+        assert_eq!((config.max_write, config.max_readahead), (1024 * 1024, 128 * 1024));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_files_cant_grow_past_max_file_size() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { max_file_size: Some(10_000), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "capped.bin", libc::O_RDWR)?;
+
+        fs.write_at(attr.ino, 0, &[1u8; 9_000]).await?;
+        fs.write_at(attr.ino, 9_000, &[2u8; 1_000]).await?;
+        assert!(matches!(fs.write_at(attr.ino, 9_999, b"xy").await, Err(TimeFSError::FileTooBig(_))));
+        assert!(matches!(fs.append(attr.ino, b"z").await, Err(TimeFSError::FileTooBig(_))));
+        assert!(matches!(fs.truncate(attr.ino, 10_001).await, Err(TimeFSError::FileTooBig(_))));
+        assert_eq!(fs.get_attr(attr.ino)?.size, 10_000);
+
+        // Rewriting and shrinking a file at the limit is fine.
+        fs.write_at(attr.ino, 0, b"rewritten").await?;
+        fs.truncate(attr.ino, 5_000).await?;
+        fs.truncate(attr.ino, 10_000).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fallocate_reserves_contiguous_block_ids() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "big.bin", libc::O_RDWR)?;
+        let (other, _) = fs.create_file(FUSE_ROOT_ID, "other.bin", libc::O_RDWR)?;
+        let block_size = BLOCK_SIZE as u64;
+
+        fs.allocate(attr.ino, 0, 4 * block_size, 0).await?;
+        assert_eq!(fs.get_attr(attr.ino)?.size, 4 * block_size);
+        assert!(file_blocks(&fs, attr.ino).is_empty());
+        let run = fs.block_runs.get(&attr.ino).map(|run| run.clone()).unwrap();
+        assert_eq!(run.end() - run.start(), 3);
+
+        // Blocks of other files written in between don't take ids from the run.
+        for index in 0..4 {
+            fs.write_at(attr.ino, index * block_size, b"data").await?;
+            fs.write_at(other.ino, index * block_size, b"data").await?;
+        }
+        let ids = file_blocks(&fs, attr.ino).iter().map(BlockRef::id).collect::<Vec<_>>();
+        assert_eq!(ids, run.collect::<Vec<_>>());
+        assert!(!fs.block_runs.contains_key(&attr.ino));
+
+        fs.allocate(other.ino, 8 * block_size, block_size, libc::FALLOC_FL_KEEP_SIZE).await?;
+        assert_eq!(fs.get_attr(other.ino)?.size, 3 * block_size + 4);
+        assert!(matches!(fs.allocate(attr.ino, 0, 1, libc::FALLOC_FL_PUNCH_HOLE).await, Err(TimeFSError::UnsupportedFallocateMode(_))));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delayed_allocation_lays_blocks_out_in_file_order() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { delayed_allocation: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options.clone())?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "reversed.bin", libc::O_RDWR)?;
+        let (other, _) = fs.create_file(FUSE_ROOT_ID, "other.bin", libc::O_RDWR)?;
+        let block_size = BLOCK_SIZE as u64;
+
+        // Written last block first, with blocks of another file in between.
+        for index in (0..8u64).rev() {
+            fs.write_at(attr.ino, index * block_size, &vec![index as u8; block_size as usize]).await?;
+            fs.write_at(other.ino, index * block_size, b"other").await?;
+        }
+        assert!(file_blocks(&fs, attr.ino).iter().all(BlockRef::is_hole));
+
+        fs.fsync_file(attr.ino).await?;
+        let ids = file_blocks(&fs, attr.ino).iter().map(BlockRef::id).collect::<Vec<_>>();
+        assert_eq!(ids, (ids[0]..ids[0] + 8).collect::<Vec<_>>());
+        fs.shutdown().await?;
+        drop(fs);
+
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        for index in 0..8u64 {
+            assert_eq!(fs.read_at(attr.ino, index * block_size, 1).await?, vec![index as u8]);
+            assert_eq!(fs.read_at(other.ino, index * block_size, 5).await?, b"other");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delayed_blocks_allocated_every_flush_interval() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { delayed_allocation: true, flush_interval: Some(Duration::from_millis(50)), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "idle.bin", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"left alone").await?;
+
+        // Nothing writes or syncs the file again, the allocation happens in the background.
+        let allocated = async {
+            while file_blocks(&fs, attr.ino).iter().any(BlockRef::is_hole) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), allocated).await.expect("delayed blocks weren't allocated");
+        assert_eq!(fs.delayed_count.load(Ordering::Relaxed), 0);
+        assert_eq!(fs.read_at(attr.ino, 0, 10).await?, b"left alone");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writes_past_quota_fail_with_edquot() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let quota_file = temp_dir.path().join("quotas");
+        std::fs::write(&quota_file, "# uid limit\n1234 8K\n")?;
+        let options = FsOptions { quota_file: Some(quota_file.clone()), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options.clone())?;
+
+        let user = Creator { uid: 1234, gid: 1234, umask: 0o022 };
+        let (attr, fh) = fs.create_file_as(FUSE_ROOT_ID, "mine.bin", libc::O_RDWR, 0o644, user)?;
+        fs.close_handle(fh).await?;
+        let block = vec![7u8; BLOCK_SIZE as usize];
+        fs.write_at(attr.ino, 0, &block).await?;
+        fs.write_at(attr.ino, BLOCK_SIZE as u64, &block).await?;
+
+        // Both blocks of the quota are used, overwriting them is fine but growing isn't.
+        fs.write_at(attr.ino, 100, b"rewrite").await?;
+        let over = fs.write_at(attr.ino, 2 * BLOCK_SIZE as u64, b"x").await;
+        assert!(matches!(over, Err(TimeFSError::QuotaExceeded(1234))));
+        let (other, fh) = fs.create_file(FUSE_ROOT_ID, "theirs.bin", libc::O_RDWR)?;
+        fs.close_handle(fh).await?;
+        fs.write_at(other.ino, 0, &block).await?;
+
+        // Usage is recomputed from the inodes on disk when mounting again.
+        drop(fs);
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        assert!(matches!(fs.write_at(attr.ino, 2 * BLOCK_SIZE as u64, b"x").await, Err(TimeFSError::QuotaExceeded(1234))));
+
+        // Deleting the file frees its share of the quota.
+        fs.remove_entry(FUSE_ROOT_ID, "mine.bin", false)?;
+        let (attr, fh) = fs.create_file_as(FUSE_ROOT_ID, "again.bin", libc::O_RDWR, 0o644, user)?;
+        fs.close_handle(fh).await?;
+        fs.write_at(attr.ino, 0, &[block.clone(), block].concat()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writes_slow_down_near_storage_limit() -> Result<()> {
+        let block_size = BLOCK_SIZE as u64;
+        let options = FsOptions {
+            in_memory: true,
+            storage_limit: Some(20 * block_size),
+            storage_high_water: Some(50),
+            ..FsOptions::default()
+        };
+        let fs = TimeFS::with_options(PathBuf::new(), PathBuf::new(), options)?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "fill.bin", libc::O_RDWR)?;
+        let block = vec![b'f'; BLOCK_SIZE as usize];
+
+        for index in 0..=10 {
+            assert_eq!(fs.write_delay(), Duration::ZERO);
+            fs.write_at(attr.ino, index * block_size, &block).await?;
+        }
+
+        let mut delays = Vec::new();
+        for index in 11..20 {
+            let delay = fs.write_delay();
+            let start = std::time::Instant::now();
+            fs.write_at(attr.ino, index * block_size, &block).await?;
+            assert!(start.elapsed() >= delay);
+            delays.push(delay);
+        }
+        assert!(delays[0] > Duration::ZERO);
+        assert!(delays.windows(2).all(|w| w[0] < w[1]), "delay should grow with fullness: {:?}", delays);
+        assert!(delays.iter().all(|&d| d <= MAX_WRITE_DELAY));
+
+        // Rewriting allocated blocks still works at the limit, growing the file doesn't.
+        fs.write_at(attr.ino, 0, b"rewrite").await?;
+        assert!(matches!(fs.write_at(attr.ino, 20 * block_size, &block).await, Err(TimeFSError::NoSpace)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writes_to_pinned_file() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as usize;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "mapped.bin", libc::O_RDWR)?;
+        let ino = attr.ino;
+
+        fs.write_at(ino, 0, &vec![b'a'; 2 * block_size]).await?;
+        fs.pin_file(ino).await?;
+        let pinned = file_blocks(&fs, ino);
+        assert!(pinned.iter().all(|b| fs.block_cache.is_pinned(b.id())));
+
+        // A version shares the blocks, so this write copies the first one and the pin has to follow.
+        fs.capture_version(ino).await?;
+        fs.write_at(ino, 10, b"mapped write").await?;
+        let blocks = file_blocks(&fs, ino);
+        assert_ne!(blocks[0].id(), pinned[0].id());
+        assert!(fs.block_cache.is_pinned(blocks[0].id()));
+        assert!(!fs.block_cache.is_pinned(pinned[0].id()));
+        assert_eq!(fs.read_at(ino, 10, 12).await?, b"mapped write");
+        assert_eq!(fs.read_at(ino, 0, 10).await?, vec![b'a'; 10]);
+
+        fs.unpin_file(ino).await?;
+        assert!(blocks.iter().all(|b| !fs.block_cache.is_pinned(b.id())));
+        assert!(matches!(fs.unpin_file(ino).await, Err(TimeFSError::Invalid(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_size_xattr() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "media.bin", libc::O_RDWR)?;
+        let ino = attr.ino;
+        let mib = 1024 * 1024;
+
+        assert!(matches!(fs.set_xattr(ino, BLOCK_SIZE_XATTR, b"1000").await, Err(TimeFSError::Invalid(_))));
+        assert!(matches!(fs.set_xattr(ino, "user.other", b"1").await, Err(TimeFSError::UnsupportedXattr(_))));
+        assert!(matches!(fs.get_xattr(ino, BLOCK_SIZE_XATTR), Err(TimeFSError::XattrNotFound(_))));
+
+        fs.set_xattr(ino, BLOCK_SIZE_XATTR, mib.to_string().as_bytes()).await?;
+        assert_eq!(fs.get_xattr(ino, BLOCK_SIZE_XATTR)?, mib.to_string().into_bytes());
+        assert_eq!(fs.list_xattr(ino)?, format!("{}\0", BLOCK_SIZE_XATTR).into_bytes());
+
+        let data = (0..2 * mib).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs.write_at(ino, 0, &data).await?;
+        let blocks = file_blocks(&fs, ino);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|b| b.size() == mib as u32));
+        assert_eq!(fs.get_attr(ino)?.blocks, 2 * mib as u64 / 512);
+        assert_eq!(fs.read_at(ino, mib as u64 - 3, 6).await?, data[mib - 3..mib + 3]);
+
+        // The block list would have to be rewritten, so the size is fixed after the first write.
+        assert!(matches!(fs.set_xattr(ino, BLOCK_SIZE_XATTR, b"4096").await, Err(TimeFSError::Invalid(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_used_counts_real_block_sizes() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let mib = 1024 * 1024;
+        let options = || FsOptions { storage_limit: Some(mib + 4096), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options())?;
+
+        let (small, _) = fs.create_file(FUSE_ROOT_ID, "small.txt", libc::O_RDWR)?;
+        fs.write_at(small.ino, 0, b"short tail").await?;
+        assert_eq!(fs.storage_used(), 10);
+
+        let (media, _) = fs.create_file(FUSE_ROOT_ID, "media.bin", libc::O_RDWR)?;
+        fs.set_xattr(media.ino, BLOCK_SIZE_XATTR, mib.to_string().as_bytes()).await?;
+        fs.write_at(media.ino, 0, &vec![7; mib as usize]).await?;
+        assert_eq!(fs.storage_used(), mib + 10);
+
+        // Growing the tail in place takes only what it grows by, a second large block doesn't fit.
+        fs.write_at(small.ino, 10, &[b'!'; 4086]).await?;
+        assert_eq!(fs.storage_used(), mib + 4096);
+        assert!(matches!(fs.write_at(media.ino, mib, b"x").await, Err(TimeFSError::NoSpace)));
+        fs.shutdown().await?;
+        drop(fs);
+
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options())?;
+        assert_eq!(fs.storage_used(), mib + 4096);
+        fs.truncate(small.ino, 100).await?;
+        assert_eq!(fs.storage_used(), mib + 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_version_flag_is_inherited() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { auto_version: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+
+        let (versioned, _) = fs.create_file(FUSE_ROOT_ID, "versioned.txt", libc::O_RDWR)?;
+        fs.set_xattr(FUSE_ROOT_ID, NO_VERSION_XATTR, b"1").await?;
+        assert_eq!(fs.get_xattr(FUSE_ROOT_ID, NO_VERSION_XATTR)?, b"1");
+        let (scratch, _) = fs.create_file(FUSE_ROOT_ID, "scratch.txt", libc::O_RDWR)?;
+        assert!(fs.get_inode(scratch.ino)?.no_version, "new children inherit the flag");
+
+        for ino in [versioned.ino, scratch.ino] {
+            fs.write_at(ino, 0, b"first").await?;
+            fs.write_at(ino, 0, b"second").await?;
+        }
+        assert_eq!(fs.get_inode(versioned.ino)?.version_count(), 1);
+        assert_eq!(fs.get_inode(scratch.ino)?.version_count(), 0);
+
+        fs.set_xattr(scratch.ino, NO_VERSION_XATTR, b"0").await?;
+        assert!(matches!(fs.get_xattr(scratch.ino, NO_VERSION_XATTR), Err(TimeFSError::XattrNotFound(_))));
+        fs.write_at(scratch.ino, 0, b"third").await?;
+        assert_eq!(fs.get_inode(scratch.ino)?.version_count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_excluded_files_not_versioned_automatically() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let version_exclude = ["*.tmp", "build/**"].map(|glob| parse_glob(glob).unwrap()).to_vec();
+        let options = FsOptions { auto_version: true, version_exclude, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let build = fs.make_node(FUSE_ROOT_ID, "build", libc::S_IFDIR | 0o755, 0, Creator::current_user())?.ino;
+
+        let (kept, _) = fs.create_file(FUSE_ROOT_ID, "notes.txt", libc::O_RDWR)?;
+        let (by_name, _) = fs.create_file(build, "draft.tmp", libc::O_RDWR)?;
+        let (by_path, _) = fs.create_file(build, "out.o", libc::O_RDWR)?;
+        for ino in [kept.ino, by_name.ino, by_path.ino] {
+            fs.write_at(ino, 0, b"first").await?;
+            fs.write_at(ino, 0, b"second").await?;
+        }
+        assert_eq!(fs.get_inode(kept.ino)?.version_count(), 1);
+        assert_eq!(fs.get_inode(by_name.ino)?.version_count(), 0);
+        assert_eq!(fs.get_inode(by_path.ino)?.version_count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_small_writes_are_coalesced() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "log.txt", libc::O_RDWR)?;
+        let ino = attr.ino;
+        let expected = (0..BLOCK_SIZE).map(|i| b'a' + (i % 26) as u8).collect::<Vec<_>>();
+
+        let updates = fs.block_cache.updates();
+        for (offset, byte) in expected.iter().enumerate() {
+            fs.write_buffered(fh, ino, offset as u64, std::slice::from_ref(byte)).await?;
+        }
+        assert_eq!(fs.block_cache.updates() - updates, 1);
+        assert!(!fs.file_handles.get(&fh).unwrap().has_buffered_writes());
+
+        // A partial block stays buffered until something needs to see it.
+        fs.write_buffered(fh, ino, BLOCK_SIZE as u64, b"tail").await?;
+        assert_eq!(fs.get_attr(ino)?.size, BLOCK_SIZE as u64);
+        assert_eq!(fs.read_at(ino, BLOCK_SIZE as u64 - 2, 6).await?, [&expected[BLOCK_SIZE as usize - 2..], b"tail"].concat());
+        assert_eq!(fs.block_cache.updates() - updates, 2);
+
+        // Writes elsewhere in the file flush what came before them first.
+        fs.write_buffered(fh, ino, 2 * BLOCK_SIZE as u64, b"more").await?;
+        fs.write_buffered(fh, ino, 0, b"A").await?;
+        fs.flush_write_buffer(fh).await?;
+        assert_eq!(fs.read_at(ino, 0, 2).await?, b"Ab");
+        assert_eq!(fs.read_at(ino, 2 * BLOCK_SIZE as u64, 4).await?, b"more");
+        assert_eq!(fs.read_at(ino, 0, BLOCK_SIZE).await?[1..], expected[1..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_file() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as usize;
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "tiny.bin", libc::O_RDWR)?;
+        let ino = attr.ino;
+
+        let mut expected = vec![0u8; 2 * block_size];
+        expected.extend(b"tail".repeat(100));
+        for (index, chunk) in expected.chunks(100).enumerate() {
+            fs.write_at(ino, (index * 100) as u64, chunk).await?;
+        }
+        let allocated = |fs: &TimeFS| file_blocks(fs, ino).iter().filter(|b| !b.is_hole()).count();
+        assert_eq!(allocated(&fs), 3);
+
+        // Still open for writing.
+        assert_eq!(fs.compact_file(ino).await?, 0);
+        assert_eq!(allocated(&fs), 3);
+
+        fs.file_handles.remove(&fh);
+        assert_eq!(fs.compact_file(ino).await?, 2);
+        assert_eq!(allocated(&fs), 1);
+        assert_eq!(fs.get_attr(ino)?.blocks, block_size as u64 / 512);
+        assert_eq!(fs.get_attr(ino)?.size, expected.len() as u64);
+        assert_eq!(fs.read_at(ino, 0, expected.len() as u32 + 10).await?, expected);
+
+        // Former tails in the middle of files are padded to full blocks by the compaction pass.
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "appended.bin", libc::O_RDWR)?;
+        for chunk in b"grown in tiny appends".chunks(3) {
+            fs.append(attr.ino, chunk).await?;
+        }
+        fs.write_at(attr.ino, 2 * block_size as u64, b"end").await?;
+        fs.close_handle(fh).await?;
+        assert_eq!(file_blocks(&fs, attr.ino)[0].size(), 21);
+        fs.compact_all().await?;
+        let blocks = file_blocks(&fs, attr.ino);
+        assert_eq!((blocks[0].size(), blocks[2].size()), (BLOCK_SIZE, 3));
+        let mut expected = b"grown in tiny appends".to_vec();
+        expected.resize(2 * block_size, 0);
+        expected.extend(b"end");
+        assert_eq!(fs.read_at(attr.ino, 0, expected.len() as u32).await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_changed_blocks_round_trip() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as u64;
+
+        fs.create_file(FUSE_ROOT_ID, "a.txt", libc::O_RDWR)?;
+        fs.create_file(FUSE_ROOT_ID, "b.txt", libc::O_RDWR)?;
+        let a = fs.get_inode_by_name(FUSE_ROOT_ID, "a.txt")?.id;
+        let b = fs.get_inode_by_name(FUSE_ROOT_ID, "b.txt")?.id;
+        fs.write_at(a, 0, &vec![b'a'; 2 * BLOCK_SIZE as usize]).await?;
+        fs.write_at(b, 0, b"untouched").await?;
+
+        // The target's history holds blocks of its own under the ids the source used.
+        let (other_dir, other) = setup_fs();
+        let local = other.create_file(FUSE_ROOT_ID, "local.txt", libc::O_RDWR)?.0.ino;
+        other.write_at(local, 0, b"local data").await?;
+        other.snapshot("before").await?;
+        let mut full = Vec::new();
+        fs.export_since(SystemTime::UNIX_EPOCH, &mut full).await?;
+
+        let since = SystemTime::now();
+        fs.write_at(a, block_size, b"changed").await?;
+
+        let mut stream = Vec::new();
+        fs.export_since(since, &mut stream).await?;
+
+        let decoded: ExportStream = from_bin_compressed(stream.as_slice())?;
+        assert_eq!(decoded.inodes.len(), 3);
+        assert_eq!(decoded.blocks.len(), 1, "only the rewritten block should be exported");
+        assert_eq!((decoded.blocks[0].ino, decoded.blocks[0].index), (a, 1));
+
+        // Blocks left out of the stream must have come with an earlier one.
+        assert!(matches!(other.import(stream.as_slice()).await, Err(TimeFSError::Invalid(_))));
+        other.import(full.as_slice()).await?;
+        other.shutdown().await?;
+        drop(other);
+        let other = TimeFS::new(other_dir.path().join("mnt"), other_dir.path().join("storage"))?;
+        other.import(stream.as_slice()).await?;
+
+        let mut expected = b"changed".to_vec();
+        expected.resize(BLOCK_SIZE as usize, b'a');
+        let blocks = file_blocks(&other, a);
+        assert_eq!(other.block_cache.get_block(blocks[1].id()).await?, expected);
+        assert_eq!(other.read_at(a, 0, 4).await?, b"aaaa");
+        assert_eq!(other.get_attr(a)?.size, 2 * block_size);
+        assert_eq!(other.read_at(b, 0, 9).await?, b"untouched");
+        let snapshot = Snapshot::from_file("before", &other.snapshots_dir)?;
+        assert_eq!(other.block_cache.get_block(snapshot.files[&local][0].id()).await?, b"local data");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_mirrors_source_with_versions() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let kept = fs.create_file(FUSE_ROOT_ID, "kept.txt", libc::O_RDWR)?.0.ino;
+        fs.write_at(kept, 0, b"first").await?;
+        let first = fs.capture_version(kept).await?;
+        fs.write_at(kept, 0, b"second").await?;
+        let gone = fs.create_file(FUSE_ROOT_ID, "gone.txt", libc::O_RDWR)?.0.ino;
+        fs.write_at(gone, 0, b"deleted at the source").await?;
+        let mut full = Vec::new();
+        fs.export_since(SystemTime::UNIX_EPOCH, &mut full).await?;
+
+        // Files of the target's own are dropped, whether their ids come with the stream or not.
+        let (_other_dir, other) = setup_fs();
+        let mut local = Vec::new();
+        for name in ["one.txt", "two.txt", "three.txt"] {
+            let (attr, fh) = other.create_file(FUSE_ROOT_ID, name, libc::O_RDWR)?;
+            other.write_at(attr.ino, 0, b"local data").await?;
+            other.close_handle(fh).await?;
+            local.push(attr.ino);
+        }
+        let root_generation = other.generation(FUSE_ROOT_ID);
+        other.import(full.as_slice()).await?;
+        assert!(matches!(other.get_inode(local[2]), Err(TimeFSError::NotFound(_))));
+        assert_eq!(other.generation(FUSE_ROOT_ID), root_generation);
+        assert_eq!(other.lookup_attr(FUSE_ROOT_ID, "kept.txt")?.ino, kept);
+        assert!(other.lookup_attr(FUSE_ROOT_ID, "one.txt").is_err());
+        assert_eq!(other.read_at(kept, 0, 6).await?, b"second");
+        assert_eq!(other.list_versions(kept)?, vec![first]);
+        assert_eq!(other.read_version(kept, first, 0, 5).await?, b"first");
+
+        let since = SystemTime::now();
+        fs.remove_entry(FUSE_ROOT_ID, "gone.txt", false)?;
+        let second = fs.capture_version(kept).await?;
+        fs.write_at(kept, 0, b"third!").await?;
+        let mut stream = Vec::new();
+        fs.export_since(since, &mut stream).await?;
+        other.import(stream.as_slice()).await?;
+
+        assert!(matches!(other.get_inode(gone), Err(TimeFSError::NotFound(_))));
+        assert!(other.lookup_attr(FUSE_ROOT_ID, "gone.txt").is_err());
+        assert_eq!(other.path_of(kept)?, PathBuf::from("/kept.txt"));
+        assert_eq!(other.read_at(kept, 0, 6).await?, b"third!");
+        assert_eq!(other.read_version(kept, first, 0, 5).await?, b"first");
+        assert_eq!(other.read_version(kept, second, 0, 6).await?, b"second");
+        assert!(other.fsck(false)?.is_clean());
+
+        // A stream that doesn't fit is refused before anything changes.
+        let temp_dir = tempdir()?;
+        let options = FsOptions { storage_limit: Some(16), ..FsOptions::default() };
+        let small = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        assert!(matches!(small.import(full.as_slice()).await, Err(TimeFSError::NoSpace)));
+        assert!(small.lookup_attr(FUSE_ROOT_ID, "kept.txt").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_rejects_writes() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+
+        fs.create_file(FUSE_ROOT_ID, "test.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "test.txt")?.id;
+        fs.write_at(ino, 0, b"Hello World").await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let options = FsOptions { read_only: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+
+        let err = fs.write_at(ino, 0, b"Goodbye").await.unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::EROFS);
+        assert!(matches!(fs.create_file(FUSE_ROOT_ID, "new.txt", libc::O_RDWR), Err(TimeFSError::ReadOnly)));
+        assert!(matches!(fs.snapshot("daily").await, Err(TimeFSError::ReadOnly)));
+
+        assert_eq!(fs.read_at(ino, 0, 64).await?, b"Hello World");
+        assert_eq!(fs.get_attr(ino)?.size, 11);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_open_leaves_storage_untouched() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let storage_path = temp_dir.path().join("storage");
+        let options = FsOptions { read_only: true, ..FsOptions::default() };
+
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), &storage_path, options)?;
+        assert!(!storage_path.exists());
+        assert!(fs.get_attr(FUSE_ROOT_ID).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_open_refuses_to_migrate_layout() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        fs.shutdown().await?;
+        drop(fs);
+
+        // A root inode left where the flat layout put it.
+        let inode_dir = temp_dir.path().join("storage").join("metadata").join("inode");
+        let sharded = INode::inode_path(FUSE_ROOT_ID, &inode_dir);
+        let flat = inode_dir.join(sharded.file_name().unwrap());
+        std::fs::rename(&sharded, &flat)?;
+
+        let options = FsOptions { read_only: true, ..FsOptions::default() };
+        let opened = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options);
+        assert!(matches!(opened, Err(TimeFSError::Invalid(_))));
+        assert!(flat.exists() && !sharded.exists());
+
+        TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert!(!flat.exists() && sharded.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_open_requires_matching_key() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let storage_path = temp_dir.path().join("storage");
+        let key_file = temp_dir.path().join("key");
+        let wrong_key_file = temp_dir.path().join("wrong_key");
+        std::fs::write(&key_file, [1u8; crate::crypto::KEY_SIZE])?;
+        std::fs::write(&wrong_key_file, [2u8; crate::crypto::KEY_SIZE])?;
+
+        let with_key = |key_file: &Path| FsOptions { key_file: Some(key_file.to_path_buf()), ..FsOptions::default() };
+        let mount_path = temp_dir.path().join("mnt");
+
+        TimeFS::with_options(&mount_path, &storage_path, with_key(&key_file))?;
+        TimeFS::with_options(&mount_path, &storage_path, with_key(&key_file))?;
+
+        let wrong = TimeFS::with_options(&mount_path, &storage_path, with_key(&wrong_key_file));
+        assert!(matches!(wrong, Err(TimeFSError::BlockCacheError(BlockCacheError::BadKey(_)))));
+        let missing = TimeFS::with_options(&mount_path, &storage_path, FsOptions::default());
+        assert!(matches!(missing, Err(TimeFSError::BlockCacheError(BlockCacheError::BadKey(_)))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_not_set_up_read_only_or_over_unencrypted_blocks() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let storage_path = temp_dir.path().join("storage");
+        let key_check = storage_path.join("metadata").join("key_check.bin");
+        let key_file = temp_dir.path().join("key");
+        std::fs::write(&key_file, [1u8; crate::crypto::KEY_SIZE])?;
+        let mount_path = temp_dir.path().join("mnt");
+
+        let read_only = FsOptions { key_file: Some(key_file.clone()), read_only: true, ..FsOptions::default() };
+        TimeFS::with_options(&mount_path, &storage_path, read_only)?;
+        assert!(!key_check.exists());
+
+        let fs = TimeFS::with_options(&mount_path, &storage_path, FsOptions::default())?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "plain.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"unencrypted").await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let with_key = FsOptions { key_file: Some(key_file), ..FsOptions::default() };
+        let opened = TimeFS::with_options(&mount_path, &storage_path, with_key);
+        assert!(matches!(opened, Err(TimeFSError::BlockCacheError(BlockCacheError::BadKey(_)))));
+        assert!(!key_check.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_noatime_leaves_atime_unchanged() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { atime: AtimePolicy::Noatime, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+
+        fs.create_file(FUSE_ROOT_ID, "test.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "test.txt")?.id;
+        fs.write_at(ino, 0, b"Hello World").await?;
+
+        let atime = fs.get_attr(ino)?.atime;
+        fs.read_at(ino, 0, 64).await?;
+        assert_eq!(fs.get_attr(ino)?.atime, atime);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relatime_conditions() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        assert_eq!(fs.atime_policy, AtimePolicy::Relatime);
+
+        fs.create_file(FUSE_ROOT_ID, "test.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "test.txt")?.id;
+        fs.write_at(ino, 0, b"Hello World").await?;
+
+        // Written after the last access, so the read must record a new atime.
+        let written = fs.get_attr(ino)?;
+        fs.read_at(ino, 0, 64).await?;
+        let accessed = fs.get_attr(ino)?.atime;
+        assert!(accessed > written.mtime);
+
+        // Already newer than mtime and ctime and less than a day old.
+        fs.read_at(ino, 0, 64).await?;
+        assert_eq!(fs.get_attr(ino)?.atime, accessed);
+
+        // More than a day old.
+        let stale = SystemTime::now() - std::time::Duration::from_secs(25 * 60 * 60);
+        {
+            let mut inode = fs.get_inode_mut(ino)?;
+            inode.attr.mtime = stale - std::time::Duration::from_secs(60);
+            inode.attr.ctime = stale - std::time::Duration::from_secs(60);
+            inode.attr.atime = stale;
+        }
+        fs.read_at(ino, 0, 64).await?;
+        assert!(fs.get_attr(ino)?.atime > stale);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_map_block() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as u64;
+
+        fs.create_file(FUSE_ROOT_ID, "sparse.bin", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "sparse.bin")?.id;
+        fs.write_at(ino, 0, b"first").await?;
+        fs.write_at(ino, 3 * block_size, b"fourth").await?;
+
+        let blocks = file_blocks(&fs, ino);
+        assert_eq!(fs.map_block(ino, BLOCK_SIZE, 0)?, blocks[0].id());
+        assert_eq!(fs.map_block(ino, BLOCK_SIZE, 1)?, 0, "hole should map to no block");
+        assert_eq!(fs.map_block(ino, BLOCK_SIZE, 2)?, 0, "hole should map to no block");
+        assert_eq!(fs.map_block(ino, BLOCK_SIZE, 3)?, blocks[3].id());
+        assert_ne!(blocks[3].id(), 0);
+        assert_eq!(fs.map_block(ino, BLOCK_SIZE, 10)?, 0, "past the end should map to no block");
+
+        let err = fs.map_block(ino, 512, 0).unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::EINVAL);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_dir_plus_includes_attributes() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        for (i, name) in ["a.txt", "b.txt", "c.txt"].into_iter().enumerate() {
+            fs.create_file(FUSE_ROOT_ID, name, libc::O_RDWR)?;
+            let ino = fs.get_inode_by_name(FUSE_ROOT_ID, name)?.id;
+            fs.write_at(ino, 0, &vec![b'x'; (i + 1) * 100]).await?;
+        }
+
+        let listing = fs.list_dir_plus(FUSE_ROOT_ID)?;
+        let names = listing.iter().map(|(_, name, _, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", CONTROL_DIR_NAME, "a.txt", "b.txt", "c.txt"]);
+
+        assert_eq!(listing[0].2.ino, FUSE_ROOT_ID);
+        assert_eq!((listing[2].2.ino, listing[2].2.kind), (CONTROL_DIR_INO, FileType::Directory));
+        for (i, (child, name, attr, generation)) in listing[3..].iter().enumerate() {
+            let inode = fs.get_inode_by_name(FUSE_ROOT_ID, name)?;
+            assert_eq!(*child, inode.id);
+            assert_eq!(*attr, inode.attr);
+            assert_eq!(attr.kind, FileType::RegularFile);
+            assert_eq!(attr.size, (i as u64 + 1) * 100);
+            assert_eq!(*generation, inode.generation);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlink_moves_file_to_trash() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let storage = temp_dir.path().join("storage");
+        let options = FsOptions { trash: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), &storage, options.clone())?;
+
+        fs.create_file(FUSE_ROOT_ID, "notes.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "notes.txt")?.id;
+        fs.write_at(ino, 0, b"keep me").await?;
+        let block_id = file_blocks(&fs, ino)[0].id();
+
+        fs.remove_entry(FUSE_ROOT_ID, "notes.txt", false)?;
+        assert!(fs.get_inode_by_name(FUSE_ROOT_ID, "notes.txt").is_err());
+        assert_eq!(fs.block_refs.count(block_id), 1, "trashed file should keep its blocks");
+
+        let trash_name = format!("notes.txt~{}", ino);
+        let listing = fs.list_dir(TRASH_DIR_INO)?;
+        assert!(listing.iter().any(|(child, _, name)| *child == ino && *name == trash_name));
+        assert_eq!(fs.lookup_attr(TRASH_DIR_INO, &trash_name)?.ino, ino);
+
+        fs.restore(ino, FUSE_ROOT_ID, "restored.txt")?;
+        assert_eq!(fs.get_inode_by_name(FUSE_ROOT_ID, "restored.txt")?.id, ino);
+        assert_eq!(fs.read_at(ino, 0, 64).await?, b"keep me");
+        assert!(fs.list_dir(TRASH_DIR_INO)?.iter().all(|(child, _, _)| *child != ino));
+
+        fs.remove_entry(FUSE_ROOT_ID, "restored.txt", false)?;
+        drop(fs);
+
+        // The trash index survives a remount, and purging happens once the retention has elapsed.
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), &storage, options)?;
+        assert_eq!(fs.purge_trash(SystemTime::now())?, 0);
+        assert_eq!(fs.purge_trash(SystemTime::now() + DEFAULT_TRASH_RETENTION)?, 1);
+        assert!(fs.list_dir(TRASH_DIR_INO)?.iter().all(|(child, _, _)| *child != ino));
+        assert!(!INode::inode_path(ino, &storage.join("metadata/inode")).exists());
+        Ok(())
+    }
+
+    /// Records invalidations instead of sending them to a kernel.
+    #[derive(Clone, Default)]
+    struct RecordingInvalidator(Arc<Mutex<Vec<(u64, String)>>>);
+
+    impl Invalidator for RecordingInvalidator {
+        fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> std::io::Result<()> {
+            self.0.lock().push((ino, format!("inode {}+{}", offset, len)));
+            Ok(())
+        }
+
+        fn inval_entry(&self, parent: u64, name: &OsStr) -> std::io::Result<()> {
+            self.0.lock().push((parent, format!("entry {}", name.to_string_lossy())));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_invalidates_kernel_caches() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { trash: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let recorder = RecordingInvalidator::default();
+        assert!(fs.notifier_slot().set(Box::new(recorder.clone())).is_ok());
+
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "undo.txt", libc::O_RDWR)?;
+        fs.remove_entry(FUSE_ROOT_ID, "undo.txt", false)?;
+        fs.restore(attr.ino, FUSE_ROOT_ID, "undone.txt")?;
+
+        let expected = [
+            (TRASH_DIR_INO, format!("entry undo.txt~{}", attr.ino)),
+            (FUSE_ROOT_ID, "entry undone.txt".to_string()),
+            (attr.ino, "inode -1+0".to_string()),
+        ];
+        let sent = async {
+            while !expected.iter().all(|call| recorder.0.lock().contains(call)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), sent).await.expect("restore wasn't pushed to the kernel");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_limit_counts_unloaded_trash() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let block_size = BLOCK_SIZE as u64;
+        let options = || FsOptions { trash: true, storage_limit: Some(4 * block_size), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options())?;
+        let (old, _) = fs.create_file(FUSE_ROOT_ID, "old.bin", libc::O_RDWR)?;
+        fs.write_at(old.ino, 0, &vec![b'o'; 3 * BLOCK_SIZE as usize]).await?;
+        fs.remove_entry(FUSE_ROOT_ID, "old.bin", false)?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options())?;
+        assert!(!fs.inodes.contains_key(&old.ino));
+        assert_eq!(fs.storage_used(), 3 * block_size);
+
+        // Making room purges the trashed file, though it was never loaded.
+        let (new, _) = fs.create_file(FUSE_ROOT_ID, "new.bin", libc::O_RDWR)?;
+        fs.write_at(new.ino, 0, &vec![b'n'; 3 * BLOCK_SIZE as usize]).await?;
+        fs.fsync_file(new.ino).await?;
+        assert!(fs.trash.as_ref().unwrap().lock().entries().is_empty());
+        assert_eq!(fs.storage_used(), 3 * block_size);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reused_inode_id_gets_new_generation() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        let (_, fh) = fs.create_file(FUSE_ROOT_ID, "old.txt", libc::O_RDWR)?;
+        fs.close_handle(fh).await?;
+        let (ino, generation) = {
+            let inode = fs.get_inode_by_name(FUSE_ROOT_ID, "old.txt")?;
+            (inode.id, inode.generation)
+        };
+        fs.remove_entry(FUSE_ROOT_ID, "old.txt", false)?;
+
+        fs.create_file(FUSE_ROOT_ID, "new.txt", libc::O_RDWR)?;
+        let inode = fs.get_inode_by_name(FUSE_ROOT_ID, "new.txt")?;
+        assert_eq!(inode.id, ino, "freed inode id should be reused");
+        assert_ne!(inode.generation, generation);
+        assert_eq!(fs.generation(ino), inode.generation);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_special_files_report_their_kind() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let nodes = [
+            ("pipe", libc::S_IFIFO, FileType::NamedPipe, 0),
+            ("socket", libc::S_IFSOCK, FileType::Socket, 0),
+            ("null", libc::S_IFCHR, FileType::CharDevice, libc::makedev(1, 3) as u32),
+            ("disk", libc::S_IFBLK, FileType::BlockDevice, libc::makedev(8, 0) as u32),
+        ];
+        for (name, mode, kind, rdev) in nodes {
+            let attr = fs.make_node(FUSE_ROOT_ID, name, mode | 0o644, rdev, Creator::current_user())?;
+            assert_eq!((attr.kind, attr.rdev), (kind, rdev), "{}", name);
+            assert_eq!(fs.get_attr(attr.ino)?.kind, kind);
+            assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, name)?.kind, kind);
+        }
+        let symlink = fs.make_node(FUSE_ROOT_ID, "link", libc::S_IFLNK | 0o777, 0, Creator::current_user());
+        assert!(matches!(symlink, Err(TimeFSError::UnsupportedFileType(libc::S_IFLNK))));
+        fs.shutdown().await?;
+        drop(fs);
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let listed = fs.list_dir(FUSE_ROOT_ID)?.into_iter().map(|(_, kind, name)| (name, kind)).collect::<HashMap<_, _>>();
+        for (name, _, kind, rdev) in nodes {
+            assert_eq!(listed[name], kind);
+            assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, name)?.rdev, rdev);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_poll_fifo() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (pollin, pollout) = (libc::POLLIN as u32, libc::POLLOUT as u32);
+
+        let ino = fs.make_node(FUSE_ROOT_ID, "pipe", libc::S_IFIFO | 0o644, 0, Creator::current_user())?.ino;
+        assert_eq!(fs.poll_events(ino, pollin | pollout)?, pollout, "empty FIFO shouldn't be readable");
+
+        fs.write_at(ino, 0, b"ping").await?;
+        assert_eq!(fs.poll_events(ino, pollin | pollout)?, pollin | pollout);
+
+        assert_eq!(fs.read_at(ino, 0, 64).await?, b"ping");
+        assert_eq!(fs.poll_events(ino, pollin)?, 0);
+
+        fs.create_file(FUSE_ROOT_ID, "regular.txt", libc::O_RDWR)?;
+        let file = fs.get_inode_by_name(FUSE_ROOT_ID, "regular.txt")?.id;
+        assert_eq!(fs.poll_events(file, pollin | pollout)?, pollin | pollout);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fsck_repairs_wrong_parent() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        fs.create_file(FUSE_ROOT_ID, "child.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "child.txt")?.id;
+        assert!(fs.fsck(false)?.is_clean());
+
+        fs.get_inode_mut(ino)?.parent = 42;
+        fs.get_inode_mut(FUSE_ROOT_ID)?.add_entry("ghost", ChildEntry::new(9999, FileType::RegularFile))?;
+
+        let report = fs.fsck(false)?;
+        assert_eq!(report.wrong_parents, vec![(ino, 42, FUSE_ROOT_ID)]);
+        assert_eq!(report.dangling_entries, vec![(FUSE_ROOT_ID, "ghost".to_string(), 9999)]);
+        assert!(!report.repaired);
+        assert_eq!(fs.get_inode(ino)?.parent, 42, "a check alone shouldn't change anything");
+
+        assert!(fs.fsck(true)?.repaired);
+        assert_eq!(fs.get_inode(ino)?.parent, FUSE_ROOT_ID);
+        assert!(fs.get_inode_by_name(FUSE_ROOT_ID, "ghost").is_err());
+        assert!(fs.fsck(false)?.is_clean());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fsck_quarantines_duplicate_inodes() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "twice.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"current").await?;
+        fs.flush_dirty_inodes()?;
+
+        // An older copy of the inode, restored under another id's name by a botched recovery.
+        let mut older = INode::from_file(attr.ino, &fs.inode_dir)?;
+        older.attr.ctime = SystemTime::UNIX_EPOCH;
+        let planted = INode::inode_path(attr.ino + 100, &fs.inode_dir);
+        std::fs::create_dir_all(planted.parent().unwrap())?;
+        write_to_bin_file(&older, &planted, false)?;
+        assert!(matches!(fs.get_attr(attr.ino + 100), Err(TimeFSError::NotFound(_))), "it mustn't load as another inode");
+
+        let report = fs.fsck(false)?;
+        assert_eq!(report.duplicate_inodes, vec![(attr.ino, planted.clone())]);
+        assert!(!report.is_clean());
+        assert!(planted.exists(), "a check alone shouldn't move anything");
+
+        assert!(fs.fsck(true)?.repaired);
+        assert!(!planted.exists());
+        let lost_found = temp_dir.path().join("storage").join("lost+found");
+        assert!(lost_found.join(format!("inode_{}.bin", attr.ino)).exists());
+        assert!(fs.fsck(false)?.is_clean());
+
+        // A newer copy wins over the file at the inode's own path.
+        let mut newer = INode::from_file(attr.ino, &fs.inode_dir)?;
+        newer.attr.ctime = SystemTime::now() + Duration::from_secs(60);
+        newer.attr.perm = 0o600;
+        write_to_bin_file(&newer, &planted, false)?;
+        assert!(fs.fsck(true)?.repaired);
+        assert!(lost_found.join(format!("inode_{}.1.bin", attr.ino)).exists());
+        assert_eq!(fs.get_attr(attr.ino)?.perm, 0o600);
+        assert_eq!(fs.read_at(attr.ino, 0, 16).await?, b"current");
+        assert!(fs.fsck(false)?.is_clean());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_stays_shared_after_remount() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (a, _) = fs.create_file(FUSE_ROOT_ID, "a", libc::O_RDWR)?;
+        fs.write_at(a.ino, 0, b"original").await?;
+        let (b, _) = fs.create_file(FUSE_ROOT_ID, "b", libc::O_RDWR)?;
+        fs.clone_file(a.ino, b.ino).await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        // Only the file written is loaded, yet the block is known to be shared with the clone.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        fs.write_at(a.ino, 0, b"CHANGED!").await?;
+        assert_eq!(fs.read_at(b.ino, 0, 8).await?, b"original");
+        assert_eq!(fs.read_at(a.ino, 0, 8).await?, b"CHANGED!");
+        fs.truncate(a.ino, 0).await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        // Nor was its id recycled when the other file let go of it.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let (c, _) = fs.create_file(FUSE_ROOT_ID, "c", libc::O_RDWR)?;
+        fs.write_at(c.ino, 0, b"new file").await?;
+        assert_eq!(fs.read_at(b.ino, 0, 8).await?, b"original");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_blocks_kept_after_remount() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (a, _) = fs.create_file(FUSE_ROOT_ID, "a", libc::O_RDWR)?;
+        fs.write_at(a.ino, 0, b"original").await?;
+        fs.snapshot("before").await?;
+        let pinned = file_blocks(&fs, a.ino)[0].id();
+        fs.shutdown().await?;
+        drop(fs);
+
+        // Overwriting and truncating the file leaves the block to the snapshot alone.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        fs.write_at(a.ino, 0, b"CHANGED!").await?;
+        assert_ne!(file_blocks(&fs, a.ino)[0].id(), pinned);
+        fs.truncate(a.ino, 0).await?;
+        let (b, _) = fs.create_file(FUSE_ROOT_ID, "b", libc::O_RDWR)?;
+        fs.write_at(b.ino, 0, b"new file").await?;
+        assert_ne!(file_blocks(&fs, b.ino)[0].id(), pinned);
+        drop(fs);
+
+        // After a crash the snapshot's references are counted again from its file.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let (c, _) = fs.create_file(FUSE_ROOT_ID, "c", libc::O_RDWR)?;
+        fs.write_at(c.ino, 0, b"new file").await?;
+        assert_ne!(file_blocks(&fs, c.ino)[0].id(), pinned);
+        assert_eq!(fs.block_refs.count(pinned), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_shares_blocks_until_written() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as usize;
+        let data = (0..3 * block_size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let (src, _) = fs.create_file(FUSE_ROOT_ID, "original", libc::O_RDWR)?;
+        fs.write_at(src.ino, 0, &data).await?;
+        let (dst, _) = fs.create_file(FUSE_ROOT_ID, "clone", libc::O_RDWR)?;
+        fs.write_at(dst.ino, 0, b"replaced by the clone").await?;
+
+        let next_block_id = fs.super_block.read().next_block_id();
+        fs.clone_file(src.ino, dst.ino).await?;
+        assert_eq!(fs.super_block.read().next_block_id(), next_block_id, "cloning shouldn't allocate blocks");
+        let ids = |ino: u64| file_blocks(&fs, ino).iter().map(|b| b.id()).collect::<Vec<_>>();
+        assert_eq!(ids(dst.ino), ids(src.ino));
+        assert_eq!(fs.get_attr(dst.ino)?.size, data.len() as u64);
+        assert_eq!(fs.read_at(dst.ino, 0, data.len() as u32).await?, data);
+
+        // Writing either file copies the block it changes and leaves the other one alone.
+        fs.write_at(dst.ino, 10, b"clone").await?;
+        fs.write_at(src.ino, 2 * block_size as u64, b"original").await?;
+        let mut expected_dst = data.clone();
+        expected_dst[10..15].copy_from_slice(b"clone");
+        let mut expected_src = data.clone();
+        expected_src[2 * block_size..2 * block_size + 8].copy_from_slice(b"original");
+        assert_eq!(fs.read_at(dst.ino, 0, data.len() as u32).await?, expected_dst);
+        assert_eq!(fs.read_at(src.ino, 0, data.len() as u32).await?, expected_src);
+        assert_eq!(ids(dst.ino)[1], ids(src.ino)[1], "untouched blocks stay shared");
+        assert_ne!(ids(dst.ino)[0], ids(src.ino)[0]);
+
+        // Ranges have to start on block boundaries.
+        assert!(matches!(fs.clone_range(src.ino, 1, 0, dst.ino, 0).await, Err(TimeFSError::Invalid(_))));
+        fs.clone_range(src.ino, 2 * block_size as u64, 0, dst.ino, 0).await?;
+        assert_eq!(fs.read_at(dst.ino, 0, 8).await?, b"original");
+        assert_eq!(fs.get_attr(dst.ino)?.size, data.len() as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_entries_know_child_kind_without_loading_it() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let owner = Creator { uid: 1000, gid: 1000, umask: 0o022 };
+        let dir = fs.make_node(FUSE_ROOT_ID, "sub", libc::S_IFDIR | 0o755, 0, owner)?;
+        let fifo = fs.make_node(FUSE_ROOT_ID, "pipe", libc::S_IFIFO | 0o644, 0, owner)?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        // Only the root is loaded after mounting again.
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        let root = fs.get_inode(FUSE_ROOT_ID)?;
+        assert_eq!(root.get_child_entry("sub")?, ChildEntry::new(dir.ino, FileType::Directory));
+        assert_eq!(root.get_child_entry("pipe")?, ChildEntry::new(fifo.ino, FileType::NamedPipe));
+        drop(root);
+
+        let kinds = fs.list_dir(FUSE_ROOT_ID)?.into_iter().map(|(_, kind, name)| (name, kind)).collect::<HashMap<_, _>>();
+        assert_eq!((kinds["sub"], kinds["pipe"]), (FileType::Directory, FileType::NamedPipe));
+        assert!(matches!(fs.remove_entry(FUSE_ROOT_ID, "sub", false), Err(TimeFSError::IsDirectory(_))));
+        assert!(!fs.inodes.contains_key(&dir.ino) && !fs.inodes.contains_key(&fifo.ino));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_directory_nlink_counts_subdirectories() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let dir = |parent: u64, name: &str| fs.make_node(parent, name, libc::S_IFDIR | 0o755, 0, Creator::current_user()).map(|attr| attr.ino);
+        let top = dir(FUSE_ROOT_ID, "top")?;
+        dir(top, "a")?;
+        dir(top, "b")?;
+        fs.create_file(top, "file.txt", libc::O_RDWR)?;
+        assert_eq!(fs.get_attr(top)?.nlink, 4);
+        assert_eq!(fs.get_attr(FUSE_ROOT_ID)?.nlink, 3);
+
+        fs.remove_entry(top, "a", true)?;
+        assert_eq!(fs.get_attr(top)?.nlink, 3);
+
+        // Moving a directory elsewhere takes its link along.
+        fs.rename_entry(top, "b", FUSE_ROOT_ID, "b", 0)?;
+        assert_eq!((fs.get_attr(top)?.nlink, fs.get_attr(FUSE_ROOT_ID)?.nlink), (2, 4));
+        drop(fs);
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert_eq!(fs.get_attr(FUSE_ROOT_ID)?.nlink, 4);
+        assert!(fs.fsck(false)?.wrong_nlinks.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_of_follows_renames() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let dir = |parent: u64, name: &str| fs.make_node(parent, name, libc::S_IFDIR | 0o755, 0, Creator::current_user()).map(|attr| attr.ino);
+        let a = dir(FUSE_ROOT_ID, "a")?;
+        let b = dir(a, "b")?;
+        let (file, _) = fs.create_file(b, "file.txt", libc::O_RDWR)?;
+        assert_eq!(fs.path_of(file.ino)?, PathBuf::from("/a/b/file.txt"));
+        assert_eq!(fs.path_of(FUSE_ROOT_ID)?, PathBuf::from("/"));
+
+        let c = dir(FUSE_ROOT_ID, "c")?;
+        fs.rename_entry(a, "b", c, "moved", 0)?;
+        assert_eq!(fs.path_of(file.ino)?, PathBuf::from("/c/moved/file.txt"));
+        fs.rename_entry(b, "file.txt", FUSE_ROOT_ID, "top.txt", 0)?;
+        assert_eq!(fs.path_of(file.ino)?, PathBuf::from("/top.txt"));
+
+        // Inodes loaded from disk are found through their parent's entries.
+        fs.shutdown().await?;
+        drop(fs);
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert_eq!(fs.path_of(b)?, PathBuf::from("/c/moved"));
+
+        fs.remove_entry(FUSE_ROOT_ID, "top.txt", false)?;
+        assert!(fs.path_of(file.ino).is_err());
+
+        // Ancestors evicted from memory are loaded again on the way up.
+        let dir = |parent: u64, name: &str| fs.make_node(parent, name, libc::S_IFDIR | 0o755, 0, Creator::current_user()).map(|attr| attr.ino);
+        let x = dir(FUSE_ROOT_ID, "x")?;
+        let y = dir(x, "y")?;
+        let z = dir(y, "z")?;
+        for ino in [z, y, x, a, b, c] {
+            fs.forget_inode(ino, 1);
+        }
+        assert!(![x, y, z].iter().any(|ino| fs.inodes.contains_key(ino)));
+        assert_eq!(fs.path_of(z)?, PathBuf::from("/x/y/z"));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_crossing_renames_dont_deadlock() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let fs = Arc::new(fs);
+        let dir = |parent: u64, name: &str| fs.make_node(parent, name, libc::S_IFDIR | 0o755, 0, Creator::current_user()).map(|attr| attr.ino);
+        let pairs = [(dir(FUSE_ROOT_ID, "a")?, dir(FUSE_ROOT_ID, "b")?), (dir(FUSE_ROOT_ID, "c")?, dir(FUSE_ROOT_ID, "d")?)];
+        let files = 8;
+        for &(from, _) in &pairs {
+            for i in 0..files {
+                fs.create_file(from, format!("file_{}", i), libc::O_RDWR)?;
+            }
+        }
+        // A directory moved back and forth between the pairs, taking the rename lock.
+        dir(pairs[0].0, "sub")?;
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let renamers = pairs.iter()
+            .flat_map(|&(x, y)| [(x, y), (y, x)])
+            .chain([(pairs[0].0, pairs[1].1), (pairs[1].1, pairs[0].0)])
+            .map(|(from, to)| {
+                let (fs, done) = (fs.clone(), done.clone());
+                std::thread::spawn(move || {
+                    for round in 0..200 {
+                        let name = format!("file_{}", round % files);
+                        // Losing a race for the same entry fails harmlessly.
+                        let _ = fs.rename_entry(from, &name, to, &name, libc::RENAME_NOREPLACE);
+                        let _ = fs.rename_entry(from, "sub", to, "sub", libc::RENAME_NOREPLACE);
+                    }
+                    done.send(()).unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..renamers.len() {
+            finished.recv_timeout(Duration::from_secs(60)).expect("renames deadlocked");
+        }
+
+        // Every entry is in exactly one directory, and its inode agrees on where.
+        let listed = |dirs: &[u64]| -> Result<Vec<(u64, String, u64)>> {
+            let mut listed = Vec::new();
+            for &dir in dirs {
+                for (ino, _, name) in fs.list_dir(dir)?.into_iter().skip(2) {
+                    assert_eq!(fs.get_inode(ino)?.parent, dir, "{} is listed in {} only", name, dir);
+                    listed.push((ino, name, dir));
+                }
+            }
+            Ok(listed)
+        };
+        let all = listed(&[pairs[0].0, pairs[0].1, pairs[1].0, pairs[1].1])?;
+        assert_eq!(all.iter().filter(|(_, name, _)| name == "sub").count(), 1);
+        for i in 0..files {
+            let name = format!("file_{}", i);
+            assert_eq!(all.iter().filter(|(_, n, _)| *n == name).count(), 2, "{}", name);
+        }
+        for (ino, name, dir) in all {
+            assert_eq!(fs.path_of(ino)?, fs.path_of(dir)?.join(name));
+        }
+        Ok(())
+    }
+
+    /// Name, fields and parent's name of a span.
+    type RecordedSpan = (String, HashMap<String, String>, Option<String>);
+
+    /// Spans opened while it's the subscriber.
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<RecordedSpan>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for SpanRecorder
+    where S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a> {
+        fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            struct Fields<'a>(&'a mut HashMap<String, String>);
+            impl tracing::field::Visit for Fields<'_> {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    self.0.insert(field.name().to_string(), format!("{:?}", value));
+                }
+            }
+            let mut fields = HashMap::new();
+            attrs.record(&mut Fields(&mut fields));
+            let parent = ctx.span(id).and_then(|span| span.parent()).map(|parent| parent.name().to_string());
+            self.0.lock().push((attrs.metadata().name().to_string(), fields, parent));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_emits_span_with_inode() -> Result<()> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "traced.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"traced").await?;
+        let block_id = file_blocks(&fs, attr.ino)[0].id();
+        // Mounted again so the block has to be read from disk.
+        fs.shutdown().await?;
+        drop(fs);
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+
+        let recorder = SpanRecorder::default();
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+        assert_eq!(fs.read_at(attr.ino, 0, 6).await?, b"traced");
+
+        let spans = recorder.0.lock();
+        let read = spans.iter().find(|(name, _, _)| name == "read_at").expect("no read_at span");
+        assert_eq!(read.1["ino"], attr.ino.to_string());
+        // The block read from disk is traced within the read.
+        let get_block = spans.iter().find(|(name, _, _)| name == "get_block").expect("no get_block span");
+        assert_eq!((get_block.1["block_id"].as_str(), get_block.2.as_deref()), (block_id.to_string().as_str(), Some("read_at")));
+        assert!(spans.iter().any(|(name, _, parent)| name == "block_fault" && parent.as_deref() == Some("get_block")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repair_removes_tmp_files_and_quarantines_bad_blocks() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let (good, _) = fs.create_file(FUSE_ROOT_ID, "good.txt", libc::O_RDWR)?;
+        fs.write_at(good.ino, 0, b"intact").await?;
+        let (bad, _) = fs.create_file(FUSE_ROOT_ID, "bad.txt", libc::O_RDWR)?;
+        fs.write_at(bad.ino, 0, b"cut short by a crash").await?;
+        let bad_block = file_blocks(&fs, bad.ino)[0].id();
+        fs.shutdown().await?;
+        drop(fs);
+
+        let storage = temp_dir.path().join("storage");
+        let shard = storage.join("blocks/000");
+        let bad_path = shard.join(format!("block_{}.bin", bad_block));
+        let raw = std::fs::read(&bad_path)?;
+        std::fs::write(&bad_path, &raw[..3])?;
+        // Named as written now, and as before each write had a temporary file of its own.
+        let tmp_paths = [format!("block_{}.tmp7", bad_block + 1), format!("block_{}.bin.tmp", bad_block + 2)].map(|name| shard.join(name));
+        for tmp_path in &tmp_paths {
+            std::fs::write(tmp_path, b"half written")?;
+        }
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), &storage)?;
+        let report = fs.repair_blocks()?;
+        assert_eq!(report.removed_tmp_files, 2);
+        assert!(tmp_paths.iter().all(|tmp_path| !tmp_path.exists()));
+        assert_eq!(report.quarantined, vec![bad_block]);
+        assert!(!bad_path.exists());
+        assert_eq!(std::fs::read(storage.join("lost+found").join(format!("block_{}.bin", bad_block)))?, &raw[..3]);
+        assert_eq!(report.affected_inodes, vec![(bad.ino, Some(PathBuf::from("/bad.txt")))]);
+
+        // The rest reads fine, and the lost block reads as a hole instead of failing.
+        assert_eq!(fs.read_at(good.ino, 0, 6).await?, b"intact");
+        assert!(fs.read_at(bad.ino, 0, 20).await?.iter().all(|&b| b == 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_into_range_locked_by_another_owner_rejected() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "locked.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"0123456789").await?;
+        fs.locks.try_lock(attr.ino, RangeLock { start: 0, end: 4, typ: libc::F_RDLCK, owner: 1, pid: 1 }).unwrap();
+
+        let rejected = fs.write_handle(fh, attr.ino, 2, b"xx", false, Some(2)).await;
+        assert!(matches!(rejected, Err(TimeFSError::Locked(_))), "{:?}", rejected);
+        assert_eq!(Into::<c_int>::into(rejected.unwrap_err()), libc::EACCES);
+
+        // The owner itself, ranges outside the lock and writes without an owner go through.
+        fs.write_handle(fh, attr.ino, 2, b"ab", false, Some(1)).await?;
+        fs.write_handle(fh, attr.ino, 5, b"cd", false, Some(2)).await?;
+        fs.write_handle(fh, attr.ino, 0, b"ef", false, None).await?;
+        fs.flush_write_buffer(fh).await?;
+        assert_eq!(fs.read_at(attr.ino, 0, 10).await?, b"efab4cd789");
+
+        // Appends are checked against the end of the file.
+        fs.locks.try_lock(attr.ino, RangeLock { start: 10, end: u64::MAX, typ: libc::F_WRLCK, owner: 1, pid: 1 }).unwrap();
+        assert!(matches!(fs.write_handle(fh, attr.ino, 0, b"tail", true, Some(2)).await, Err(TimeFSError::Locked(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_file_has_no_blocks() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "empty.txt", libc::O_RDWR)?;
+        assert!(file_blocks(&fs, attr.ino).is_empty());
+
+        let attr = fs.lookup_attr(FUSE_ROOT_ID, "empty.txt")?;
+        assert_eq!((attr.size, attr.blocks), (0, 0));
+        assert_eq!(fs.read_at(attr.ino, 0, 4096).await?, b"");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zero_length_write_is_a_no_op() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "empty.txt", libc::O_RDWR)?;
+        let next_block = fs.super_block.read().next_block_id();
+
+        assert_eq!(fs.write_handle(fh, attr.ino, 1 << 20, b"", false, None).await?, 0);
+        assert_eq!(fs.write_handle(fh, attr.ino, 0, b"", true, None).await?, 0);
+        assert_eq!(fs.write_at(attr.ino, 1 << 20, b"").await?, 0);
+        assert!(!fs.file_handles.get(&fh).unwrap().has_buffered_writes());
+        fs.close_handle(fh).await?;
+
+        assert!(file_blocks(&fs, attr.ino).is_empty());
+        assert_eq!(fs.get_attr(attr.ino)?.size, 0);
+        assert_eq!(fs.super_block.read().next_block_id(), next_block, "no block should be allocated");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zero_length_read_is_a_no_op() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { atime: AtimePolicy::Strict, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "data.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"some data").await?;
+        let atime = fs.get_attr(attr.ino)?.atime;
+
+        assert_eq!(fs.read_at(attr.ino, 3, 0).await?, b"");
+        assert_eq!(fs.read_at(attr.ino, 1 << 20, 0).await?, b"");
+        assert_eq!(fs.get_attr(attr.ino)?.atime, atime, "reading nothing isn't an access");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reads_stop_at_eof() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "short.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, &[b'a'; 50]).await?;
+
+        assert_eq!(fs.read_at(attr.ino, 0, 100).await?, [b'a'; 50]);
+        assert_eq!(fs.read_at(attr.ino, 40, 100).await?, [b'a'; 10]);
+        assert_eq!(fs.read_at(attr.ino, 50, 100).await?, b"");
+        assert_eq!(fs.read_at(attr.ino, 51, 100).await?, b"");
+        assert_eq!(fs.read_at(attr.ino, u64::MAX, 100).await?, b"");
+
+        // What a shrunk file's last block held past its new end isn't read back either.
+        fs.write_at(attr.ino, 0, &[b'b'; BLOCK_SIZE as usize]).await?;
+        fs.truncate(attr.ino, 50).await?;
+        assert_eq!(fs.read_at(attr.ino, 0, BLOCK_SIZE).await?, [b'b'; 50]);
+        assert_eq!(fs.read_at(attr.ino, 50, BLOCK_SIZE).await?, b"");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_truncate_grow_reads_zeros() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let block_size = BLOCK_SIZE as u64;
+
+        fs.create_file(FUSE_ROOT_ID, "grow.bin", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "grow.bin")?.id;
+        fs.write_at(ino, 0, &vec![b'x'; 2 * BLOCK_SIZE as usize]).await?;
+
+        // Shrinking into the first block and growing again must not bring the old 'x's back.
+        fs.truncate(ino, 100).await?;
+        let attr = fs.set_attr(ino, None, None, None, Some(3 * block_size), None, None).await?;
+        assert_eq!(attr.size, 3 * block_size);
+        assert_eq!(attr.blocks, 3 * block_size / 512);
+
+        let data = fs.read_at(ino, 0, 3 * BLOCK_SIZE).await?;
+        assert_eq!(data.len() as u64, 3 * block_size);
+        assert!(data[..100].iter().all(|&b| b == b'x'));
+        assert!(data[100..].iter().all(|&b| b == 0), "extended region should read as zeros");
+        assert_eq!(file_blocks(&fs, ino).len(), 1, "growing shouldn't allocate blocks");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocks_counted_in_512_byte_units() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "small", libc::O_RDWR)?;
+        assert_eq!(attr.blocks, 0);
+        fs.write_at(attr.ino, 0, &[b'x'; 100]).await?;
+        assert_eq!(fs.get_attr(attr.ino)?.blocks, 8, "one 4096-byte block is eight 512-byte units");
+
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "two-blocks", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, &[b'x'; 5000]).await?;
+        assert_eq!(fs.get_attr(attr.ino)?.blocks, 16);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_mount_and_storage_paths_rejected() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        std::fs::create_dir(dir.join("real"))?;
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link"))?;
+
+        let overlapping = [
+            (dir.join("same"), dir.join("same")),
+            (dir.join("storage").join("mnt"), dir.join("storage")),
+            (dir.join("mnt"), dir.join("mnt").join("storage")),
+            (dir.join("link"), dir.join("real").join("storage")),
+        ];
+        for (mount, storage) in overlapping {
+            let err = TimeFS::with_options(&mount, &storage, FsOptions::default()).err().expect("overlapping paths should be rejected");
+            assert!(matches!(err, TimeFSError::Invalid(ref message) if message.contains("overlap")), "{}", err);
+            assert!(!storage.join("metadata").exists(), "nothing should be written before the check");
+        }
+
+        TimeFS::with_options(dir.join("mnt"), dir.join("storage"), FsOptions::default())?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_names_rejected() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        let long_name = "a".repeat(NAME_MAX + 1);
+        let err = fs.create_file(FUSE_ROOT_ID, &long_name, libc::O_RDWR).unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::ENAMETOOLONG);
+        fs.create_file(FUSE_ROOT_ID, "a".repeat(NAME_MAX), libc::O_RDWR)?;
+
+        for name in ["dir/file", "nul\0", ".", "..", ""] {
+            let err = fs.create_file(FUSE_ROOT_ID, name, libc::O_RDWR).unwrap_err();
+            assert_eq!(Into::<c_int>::into(err), libc::EINVAL, "{:?} should be rejected", name);
+        }
+
+        let err = fs.make_node(FUSE_ROOT_ID, "fifo/pipe", libc::S_IFIFO, 0, Creator::current_user()).unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::EINVAL);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_leaves_no_files() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let storage = temp_dir.path().join("storage");
+        let options = FsOptions { in_memory: true, trash: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), &storage, options)?;
+
+        fs.create_file(FUSE_ROOT_ID, "scratch.txt", libc::O_RDWR)?;
+        let ino = fs.get_inode_by_name(FUSE_ROOT_ID, "scratch.txt")?.id;
+        fs.write_at(ino, 0, b"ephemeral").await?;
+        fs.block_cache.flush_block(file_blocks(&fs, ino)[0].id(), true).await?;
+        assert_eq!(fs.read_at(ino, 0, 64).await?, b"ephemeral");
+        fs.snapshot("snap").await?;
+        fs.remove_entry(FUSE_ROOT_ID, "scratch.txt", false)?;
+
+        assert!(!storage.exists(), "in-memory mode shouldn't touch the storage path");
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+        let options = FsOptions { in_memory: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(PathBuf::new(), PathBuf::new(), options)?;
+        fs.create_file(FUSE_ROOT_ID, "other.txt", libc::O_RDWR)?;
+        Ok(())
+    }
+
+    /// Stands in for the fuser replies, recording the TTL or errno the reply was sent with.
+    #[derive(Default)]
+    struct CapturedReply(Option<std::result::Result<Duration, c_int>>);
+
+    impl AttrReply for &mut CapturedReply {
+        fn attr(self, ttl: &Duration, _attr: &FileAttr) {
+            self.0 = Some(Ok(*ttl));
+        }
+
+        fn error(self, err: c_int) {
+            self.0 = Some(Err(err));
+        }
+    }
+
+    impl EntryReply for &mut CapturedReply {
+        fn entry(self, ttl: &Duration, _attr: &FileAttr, _generation: u64) {
+            self.0 = Some(Ok(*ttl));
+        }
+
+        fn error(self, err: c_int) {
+            self.0 = Some(Err(err));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configured_ttls_are_replied() -> Result<()> {
+        let options = FsOptions {
+            in_memory: true,
+            attr_ttl: Some(Duration::ZERO),
+            entry_ttl: Some(Duration::from_secs(60)),
+            ..FsOptions::default()
+        };
+        let fs = TimeFS::with_options(PathBuf::new(), PathBuf::new(), options)?;
+        fs.create_file(FUSE_ROOT_ID, "cached.txt", libc::O_RDWR)?;
+
+        let mut reply = CapturedReply::default();
+        fs.reply_entry(fs.lookup_attr(FUSE_ROOT_ID, "cached.txt"), &mut reply);
+        assert_eq!(reply.0, Some(Ok(Duration::from_secs(60))));
+
+        fs.reply_attr(fs.get_attr(FUSE_ROOT_ID), &mut reply);
+        assert_eq!(reply.0, Some(Ok(Duration::ZERO)));
+
+        fs.reply_entry(fs.lookup_attr(FUSE_ROOT_ID, "missing.txt"), &mut reply);
+        assert_eq!(reply.0, Some(Err(ENOENT)));
+
+        let (_temp_dir, fs) = setup_fs();
+        fs.reply_attr(fs.get_attr(FUSE_ROOT_ID), &mut reply);
+        assert_eq!(reply.0, Some(Ok(DEFAULT_TTL)));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_forgotten_inodes_are_evicted() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { inode_flush_interval: Some(Duration::from_secs(3600)), ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+        let (attr, fh) = fs.create_file(FUSE_ROOT_ID, "notes.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"evicted and back").await?;
+        let blocks = fs.file_blocks(attr.ino)?;
+
+        let mut reply = CapturedReply::default();
+        fs.reply_entry(fs.lookup_attr(FUSE_ROOT_ID, "notes.txt"), &mut reply);
+        fs.reply_entry(fs.lookup_attr(FUSE_ROOT_ID, "notes.txt"), &mut reply);
+        fs.forget_inode(attr.ino, 2);
+        assert!(fs.inodes.contains_key(&attr.ino), "open files stay loaded");
+
+        fs.close_handle(fh).await?;
+        fs.reply_entry(fs.lookup_attr(FUSE_ROOT_ID, "notes.txt"), &mut reply);
+        fs.reply_entry(fs.lookup_attr(FUSE_ROOT_ID, "notes.txt"), &mut reply);
+        fs.forget_inode(attr.ino, 1);
+        assert!(fs.inodes.contains_key(&attr.ino), "the kernel still holds a lookup");
+        fs.forget_inode(attr.ino, 1);
+        assert!(!fs.inodes.contains_key(&attr.ino));
+        assert!(fs.dirty_inodes.lock().is_empty(), "the deferred write happens on eviction");
+        assert!(blocks.iter().all(|block| fs.block_refs.count(block.id()) == 1), "still counted while evicted");
+
+        // Loaded again from disk on the next access, with its blocks still counted once.
+        assert_eq!(fs.read_at(attr.ino, 0, 16).await?, b"evicted and back");
+        assert_eq!(fs.get_attr(attr.ino)?.size, 16);
+        assert!(blocks.iter().all(|block| fs.block_refs.count(block.id()) == 1));
+
+        // Sharing blocks with a snapshot doesn't keep it loaded, their counts cover both.
+        fs.snapshot("daily").await?;
+        fs.forget_inode(attr.ino, 1);
+        assert!(!fs.inodes.contains_key(&attr.ino));
+        assert!(blocks.iter().all(|block| fs.block_refs.count(block.id()) == 2));
+        fs.forget_inode(FUSE_ROOT_ID, 1);
+        assert!(fs.inodes.contains_key(&FUSE_ROOT_ID));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_whole_filesystem_walks_see_unloaded_inodes() -> Result<()> {
+        let (temp_dir, fs) = setup_fs();
+        let since = SystemTime::now();
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "notes.txt", libc::O_RDWR)?;
+        fs.write_at(attr.ino, 0, b"first").await?;
+        fs.capture_version(attr.ino).await?;
+        fs.write_at(attr.ino, 0, b"again").await?;
+        fs.shutdown().await?;
+        drop(fs);
+
+        let fs = TimeFS::new(temp_dir.path().join("mnt"), temp_dir.path().join("storage"))?;
+        assert!(!fs.inodes.contains_key(&attr.ino));
+        assert_eq!(fs.version_stats()?.versions, 1);
+
+        let mut stream = Vec::new();
+        fs.export_since(since, &mut stream).await?;
+        let decoded: ExportStream = from_bin_compressed(stream.as_slice())?;
+        assert!(decoded.blocks.iter().any(|block| block.ino == attr.ino));
+
+        fs.snapshot("daily").await?;
+        let snapshot = Snapshot::from_file("daily", &fs.snapshots_dir)?;
+        let ids = |blocks: &[BlockRef]| blocks.iter().map(BlockRef::id).collect::<Vec<_>>();
+        assert_eq!(ids(&snapshot.files[&attr.ino]), ids(&file_blocks(&fs, attr.ino)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_lookup() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let options = FsOptions { in_memory: true, case_insensitive: true, ..FsOptions::default() };
+        let fs = TimeFS::with_options(temp_dir.path().join("mnt"), temp_dir.path().join("storage"), options)?;
+
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "foo", libc::O_RDWR)?;
+        assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, "FOO")?.ino, attr.ino);
+        assert!(matches!(fs.create_file(FUSE_ROOT_ID, "FOO", libc::O_RDWR), Err(TimeFSError::NameExist(_))));
+        assert!(matches!(fs.make_node(FUSE_ROOT_ID, "Foo", libc::S_IFREG, 0, Creator::current_user()), Err(TimeFSError::NameExist(_))));
+
+        // Renaming to a different case keeps the entry but changes how it's listed.
+        fs.rename_entry(FUSE_ROOT_ID, "FOO", FUSE_ROOT_ID, "Foo", 0)?;
+        let names = match fs.get_inode(FUSE_ROOT_ID)?.data {
+            INodeType::Directory { ref entries } => entries.keys().cloned().collect::<Vec<_>>(),
+            INodeType::File { .. } => unreachable!(),
+        };
+        assert_eq!(names, ["Foo"]);
+        assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, "foo")?.ino, attr.ino);
+
+        fs.remove_entry(FUSE_ROOT_ID, "FOO", false)?;
+        assert!(fs.lookup_attr(FUSE_ROOT_ID, "foo").is_err());
+
+        // Case matters by default.
+        let (_temp_dir, fs) = setup_fs();
+        fs.create_file(FUSE_ROOT_ID, "foo", libc::O_RDWR)?;
+        fs.create_file(FUSE_ROOT_ID, "FOO", libc::O_RDWR)?;
+        assert!(matches!(fs.lookup_attr(FUSE_ROOT_ID, "Foo"), Err(TimeFSError::NameNotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_exchange() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        for name in ["a.txt", "b.txt"] {
+            let (_, fh) = fs.create_file(FUSE_ROOT_ID, name, libc::O_RDWR)?;
+            fs.close_handle(fh).await?;
+        }
+        let a = fs.get_inode_by_name(FUSE_ROOT_ID, "a.txt")?.id;
+        let b = fs.get_inode_by_name(FUSE_ROOT_ID, "b.txt")?.id;
+
+        fs.rename_entry(FUSE_ROOT_ID, "a.txt", FUSE_ROOT_ID, "b.txt", libc::RENAME_EXCHANGE)?;
+        assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, "a.txt")?.ino, b);
+        assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, "b.txt")?.ino, a);
+
+        let err = fs.rename_entry(FUSE_ROOT_ID, "a.txt", FUSE_ROOT_ID, "missing", libc::RENAME_EXCHANGE).unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::ENOENT);
+
+        let err = fs.rename_entry(FUSE_ROOT_ID, "a.txt", FUSE_ROOT_ID, "b.txt", libc::RENAME_NOREPLACE).unwrap_err();
+        assert_eq!(Into::<c_int>::into(err), libc::EEXIST);
+
+        // A plain rename replaces the target, freeing the inode it pointed to.
+        fs.rename_entry(FUSE_ROOT_ID, "a.txt", FUSE_ROOT_ID, "b.txt", 0)?;
+        assert_eq!(fs.lookup_attr(FUSE_ROOT_ID, "b.txt")?.ino, b);
+        assert!(fs.lookup_attr(FUSE_ROOT_ID, "a.txt").is_err());
+        assert!(fs.get_inode(a).is_err());
+        assert!(fs.fsck(false)?.is_clean());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_appends() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+        let fs = Arc::new(fs);
+        let (attr, _) = fs.create_file(FUSE_ROOT_ID, "log.txt", libc::O_WRONLY | libc::O_APPEND)?;
+
+        let tasks = (0..8u8)
+            .map(|writer| {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    for _ in 0..50 {
+                        // Lines straddle block boundaries, so appends also race on shared blocks.
+                        fs.append(attr.ino, &[b'a' + writer; 100]).await?;
+                    }
+                    Ok::<_, TimeFSError>(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.expect("Append task panicked")?;
+        }
+
+        let data = fs.read_at(attr.ino, 0, 8 * 50 * 100).await?;
+        assert_eq!(fs.get_inode(attr.ino)?.file_size(), 8 * 50 * 100);
+        assert_eq!(data.len(), 8 * 50 * 100);
+        for chunk in data.chunks(100) {
+            assert!(chunk.iter().all(|&b| b == chunk[0] && b != 0), "appends must not overlap");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_name_taken() -> Result<()> {
+        let (_temp_dir, fs) = setup_fs();
+
+        fs.snapshot("daily").await?;
+        assert!(matches!(fs.snapshot("daily").await, Err(TimeFSError::NameExist(_))));
+        assert!(matches!(fs.snapshot("a/b").await, Err(TimeFSError::InvalidName(_))));
+        Ok(())
+    }
+}
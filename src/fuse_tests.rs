@@ -0,0 +1,170 @@
+//! End-to-end tests mounting TimeFS through the kernel and going at it with plain `std::fs`
+//! calls, so every request passes through the FUSE operations. They need FUSE on the machine
+//! running them and only build with `cargo test --features fuse-tests`.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use fuser::{BackgroundSession, MountOption};
+use tempfile::{tempdir, TempDir};
+use crate::fs::TimeFS;
+use crate::options::FsOptions;
+
+/// TimeFS mounted on `mnt` of a temporary directory, storing into `storage` next to it.
+/// Unmounted when dropped.
+struct Mount {
+    session: Option<BackgroundSession>,
+    runtime: tokio::runtime::Runtime,
+    temp_dir: TempDir,
+}
+
+impl Mount {
+    fn new() -> Self {
+        let temp_dir = tempdir().expect("Failed to create test dir");
+        fs::create_dir(temp_dir.path().join("mnt")).expect("Failed to create mount point");
+        let runtime = tokio::runtime::Runtime::new().expect("Failed to build Tokio runtime");
+        let mut mount = Self { session: None, runtime, temp_dir };
+        mount.mount();
+        mount
+    }
+
+    fn mount(&mut self) {
+        let mount_path = self.temp_dir.path().join("mnt");
+        let fs = {
+            let _guard = self.runtime.enter();
+            TimeFS::with_options(&mount_path, self.temp_dir.path().join("storage"), FsOptions::default()).expect("Failed to open TimeFS storage")
+        };
+        let notifier = fs.notifier_slot();
+        let session = fuser::Session::new(fs, &mount_path, &[MountOption::FSName("timefs".to_string())]).expect("Failed to mount TimeFS");
+        let _ = notifier.set(Box::new(session.notifier()));
+        self.session = Some(session.spawn().expect("Failed to serve TimeFS"));
+    }
+
+    /// Unmounts and waits for TimeFS to write everything out.
+    fn unmount(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.join();
+        }
+    }
+
+    fn remount(&mut self) {
+        self.unmount();
+        self.mount();
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.temp_dir.path().join("mnt").join(relative)
+    }
+
+    fn list(&self, relative: &str) -> HashSet<String> {
+        fs::read_dir(self.path(relative))
+            .expect("Failed to list directory")
+            .map(|entry| entry.expect("Failed to read entry").file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        self.unmount();
+    }
+}
+
+#[test]
+fn test_create_write_read() -> std::io::Result<()> {
+    let mount = Mount::new();
+    let path = mount.path("hello.txt");
+    fs::write(&path, b"Hello, TimeFS!")?;
+    assert_eq!(fs::read(&path)?, b"Hello, TimeFS!");
+    assert_eq!(fs::metadata(&path)?.len(), 14);
+
+    // Writes in the middle and past the end, leaving a hole reading as zeros.
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    file.seek(SeekFrom::Start(7))?;
+    file.write_all(b"kernel")?;
+    file.seek(SeekFrom::Start(20_000))?;
+    file.write_all(b"end")?;
+    drop(file);
+
+    let contents = fs::read(&path)?;
+    assert_eq!(contents.len(), 20_003);
+    assert_eq!(&contents[..14], b"Hello, kernel!");
+    assert!(contents[14..20_000].iter().all(|&b| b == 0));
+    assert_eq!(&contents[20_000..], b"end");
+    Ok(())
+}
+
+#[test]
+fn test_append_and_truncate() -> std::io::Result<()> {
+    let mount = Mount::new();
+    let path = mount.path("log.txt");
+    for line in ["one\n", "two\n", "three\n"] {
+        OpenOptions::new().create(true).append(true).open(&path)?.write_all(line.as_bytes())?;
+    }
+    assert_eq!(fs::read_to_string(&path)?, "one\ntwo\nthree\n");
+
+    let file = OpenOptions::new().write(true).open(&path)?;
+    file.set_len(4)?;
+    assert_eq!(fs::read_to_string(&path)?, "one\n");
+    file.set_len(8)?;
+    assert_eq!(fs::read(&path)?, b"one\n\0\0\0\0");
+
+    let mut contents = String::new();
+    OpenOptions::new().write(true).truncate(true).open(&path)?;
+    fs::File::open(&path)?.read_to_string(&mut contents)?;
+    assert!(contents.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_readdir_and_unlink() -> std::io::Result<()> {
+    let mount = Mount::new();
+    fs::create_dir(mount.path("dir"))?;
+    fs::write(mount.path("dir/a.txt"), b"a")?;
+    fs::write(mount.path("dir/b.txt"), b"b")?;
+    fs::create_dir(mount.path("dir/sub"))?;
+    assert_eq!(mount.list("dir"), HashSet::from(["a.txt".to_string(), "b.txt".to_string(), "sub".to_string()]));
+    assert!(fs::metadata(mount.path("dir/sub"))?.is_dir());
+
+    fs::remove_file(mount.path("dir/a.txt"))?;
+    assert_eq!(fs::read(mount.path("dir/a.txt")).unwrap_err().kind(), ErrorKind::NotFound);
+    assert!(!mount.list("dir").contains("a.txt"));
+
+    assert_eq!(fs::remove_dir(mount.path("dir")).unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
+    fs::remove_dir(mount.path("dir/sub"))?;
+    fs::remove_file(mount.path("dir/b.txt"))?;
+    fs::remove_dir(mount.path("dir"))?;
+    assert!(!mount.list("").contains("dir"));
+    Ok(())
+}
+
+#[test]
+fn test_rename() -> std::io::Result<()> {
+    let mount = Mount::new();
+    fs::create_dir(mount.path("from"))?;
+    fs::create_dir(mount.path("to"))?;
+    fs::write(mount.path("from/file.txt"), b"moved")?;
+    fs::write(mount.path("to/existing.txt"), b"replaced")?;
+
+    fs::rename(mount.path("from/file.txt"), mount.path("to/existing.txt"))?;
+    assert_eq!(fs::read(mount.path("to/existing.txt"))?, b"moved");
+    assert!(mount.list("from").is_empty());
+
+    fs::rename(mount.path("to"), mount.path("from/to"))?;
+    assert_eq!(fs::read(mount.path("from/to/existing.txt"))?, b"moved");
+    Ok(())
+}
+
+#[test]
+fn test_contents_survive_remount() -> std::io::Result<()> {
+    let mut mount = Mount::new();
+    fs::create_dir(mount.path("dir"))?;
+    let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write(mount.path("dir/data.bin"), &data)?;
+
+    mount.remount();
+    assert_eq!(mount.list("dir"), HashSet::from(["data.bin".to_string()]));
+    assert_eq!(fs::read(mount.path("dir/data.bin"))?, data);
+    Ok(())
+}
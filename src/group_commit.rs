@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use dashmap::DashMap;
+use libc::c_int;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+use crate::block::BlockCache;
+use crate::error::TimeFSError;
+use crate::fs::TimeFS;
+use crate::inode::INode;
+use crate::options::MetadataCompression;
+use crate::Result;
+
+/// Coalesces the fsyncs arriving within `window` of the first one into a single commit, which
+/// writes out every dirty block and the inodes of all files synced, then wakes everyone waiting.
+///
+/// Each caller gets the durability a plain fsync of its file would give, at the cost of waiting
+/// up to `window` for others to join.
+pub(crate) struct GroupCommit {
+    window: Duration,
+    block_cache: Arc<BlockCache>,
+    inodes: Arc<DashMap<u64, INode>>,
+    dirty_inodes: Arc<Mutex<HashSet<u64>>>,
+    inode_dir: PathBuf,
+    compression: Option<MetadataCompression>,
+    /// The batch still taking fsyncs, taken once its window is over.
+    open: Mutex<Option<Batch>>,
+    /// Number of commits so far.
+    commits: AtomicU64,
+}
+
+struct Batch {
+    inodes: HashSet<u64>,
+    /// Set to the outcome of the commit, an errno on failure.
+    done: watch::Receiver<Option<std::result::Result<(), c_int>>>,
+}
+
+impl GroupCommit {
+    pub fn new(
+        window: Duration,
+        block_cache: Arc<BlockCache>,
+        inodes: Arc<DashMap<u64, INode>>,
+        dirty_inodes: Arc<Mutex<HashSet<u64>>>,
+        inode_dir: PathBuf,
+        compression: Option<MetadataCompression>,
+    ) -> Self {
+        Self { window, block_cache, inodes, dirty_inodes, inode_dir, compression, open: Mutex::new(None), commits: AtomicU64::new(0) }
+    }
+
+    /// Returns once the contents and inode of `ino` are on disk, along with those of every file
+    /// synced in the same window.
+    pub async fn sync(self: &Arc<Self>, ino: u64) -> Result<()> {
+        let mut done = {
+            let mut open = self.open.lock();
+            let batch = open.get_or_insert_with(|| self.start_batch());
+            batch.inodes.insert(ino);
+            batch.done.clone()
+        };
+        let outcome = *done.wait_for(Option::is_some).await
+            .map_err(|_| TimeFSError::Io(std::io::Error::from_raw_os_error(libc::EIO)))?;
+        match outcome {
+            Some(Err(errno)) => Err(TimeFSError::Io(std::io::Error::from_raw_os_error(errno))),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn commits(&self) -> u64 {
+        self.commits.load(Ordering::SeqCst)
+    }
+
+    /// Opens a batch and commits it once the window is over, in a task of its own so a caller
+    /// giving up doesn't leave the others waiting.
+    fn start_batch(self: &Arc<Self>) -> Batch {
+        let (sender, done) = watch::channel(None);
+        let group_commit = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(group_commit.window).await;
+            let Some(batch) = group_commit.open.lock().take() else {
+                return;
+            };
+            let outcome = group_commit.commit(batch.inodes).await.map_err(Into::into);
+            let _ = sender.send(Some(outcome));
+        });
+        Batch { inodes: HashSet::new(), done }
+    }
+
+    async fn commit(&self, inodes: HashSet<u64>) -> Result<()> {
+        self.block_cache.flush_dirty().await?;
+        for ino in inodes {
+            TimeFS::sync_dirty_inode(&self.inodes, &self.dirty_inodes, &self.inode_dir, self.compression, ino)?;
+        }
+        self.commits.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
@@ -1,19 +1,41 @@
 use crate::block::BlockRef;
-use crate::{from_bin_file, write_to_bin_file, AutoSave, Result};
-use fuser::FileAttr;
-use serde::{Deserialize, Serialize, Serializer};
-use std::collections::HashMap;
-use std::path::Path;
+use crate::{from_bin_file, sync_file, write_to_bin_file_as, Result};
+use crate::options::MetadataCompression;
+use fuser::{FileAttr, FileType};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use crate::error::TimeFSError;
+use crate::fs::BLOCK_SIZE;
+use crate::file_attr::stat_blocks;
+
+/// A historical state of a file, sharing its blocks with the live data until they're rewritten.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Version {
+    pub(crate) timestamp: SystemTime,
+    pub(crate) size: u64,
+    pub(crate) blocks: Vec<BlockRef>,
+    /// Modification and change times of the file when the version was taken, given back to it
+    /// when it's restored.
+    pub(crate) mtime: SystemTime,
+    pub(crate) ctime: SystemTime,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum INodeType {
     File {
         blocks: Vec<BlockRef>,
         size: u64,
+        versions: Vec<Version>,
+        /// Block size of this file when overridden through the `user.timefs.blocksize` xattr.
+        block_size: Option<u32>,
     },
     Directory {
-        entries: HashMap<String, u64>,
+        entries: HashMap<String, ChildEntry>,
     }
 }
 
@@ -22,6 +44,8 @@ impl INodeType {
         INodeType::File {
             blocks: Vec::new(),
             size: 0,
+            versions: Vec::new(),
+            block_size: None,
         }
     }
     
@@ -31,12 +55,106 @@ impl INodeType {
         }
     }
 }
+/// The child a directory entry points at, along with its kind so that telling files from
+/// directories doesn't take loading the child.
+///
+/// Persisted as a single `u64`, the kind in the top byte above the inode id, so directories
+/// written when entries held bare ids still load. Their entries have no kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChildEntry {
+    pub(crate) id: u64,
+    pub(crate) kind: Option<FileType>,
+}
+
+/// Bits of a persisted entry below its kind, which inode ids never reach.
+const ENTRY_KIND_SHIFT: u32 = 56;
+/// Kinds in the order they are tagged with in persisted entries, from 1 as 0 means none.
+const ENTRY_KINDS: [FileType; 7] = [
+    FileType::RegularFile,
+    FileType::Directory,
+    FileType::Symlink,
+    FileType::NamedPipe,
+    FileType::CharDevice,
+    FileType::BlockDevice,
+    FileType::Socket,
+];
+
+impl ChildEntry {
+    pub fn new(id: u64, kind: FileType) -> Self {
+        Self { id, kind: Some(kind) }
+    }
+
+    fn to_bits(self) -> u64 {
+        let tag = self.kind.and_then(|kind| ENTRY_KINDS.iter().position(|&k| k == kind)).map_or(0, |i| i as u64 + 1);
+        tag << ENTRY_KIND_SHIFT | self.id
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        let tag = (bits >> ENTRY_KIND_SHIFT) as usize;
+        let kind = tag.checked_sub(1).and_then(|i| ENTRY_KINDS.get(i)).copied();
+        Self { id: bits & ((1 << ENTRY_KIND_SHIFT) - 1), kind }
+    }
+}
+
+impl Serialize for ChildEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.to_bits())
+    }
+}
+
+impl<'de> Deserialize<'de> for ChildEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(Self::from_bits)
+    }
+}
+
+/// A single change to a directory's entries, appended to its entry log instead of rewriting the whole inode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum EntryChange {
+    Add(String, ChildEntry),
+    Remove(String),
+}
+
+impl EntryChange {
+    fn name(&self) -> &str {
+        match self {
+            EntryChange::Add(name, _) | EntryChange::Remove(name) => name,
+        }
+    }
+}
+
+/// Entry logs shorter than this are never compacted, however small the directory.
+const ENTRY_LOG_COMPACT_MIN: usize = 64;
+
+/// Directories with this many entries keep them in buckets beside the inode, so that neither
+/// persisting the inode nor compacting its entry log rewrites all of them. They move back into
+/// the inode once shrunk to half of it.
+pub(crate) const EXTERNAL_ENTRIES_MIN: usize = 4096;
+/// Buckets the entries of a large directory are spread over by the hash of their name.
+const ENTRY_BUCKETS: u32 = 256;
+
+/// Inodes per subdirectory of the inode dir, matching how block files are spread out.
+const INODES_PER_SHARD: u64 = 1000;
+
  #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct INode {
     pub(crate) id: u64,
     pub(crate) parent: u64,
     pub(crate) data: INodeType,
     pub(crate) attr: FileAttr,
+    /// Bumped each time the inode id is reused, so NFS clients can detect stale handles.
+    pub(crate) generation: u64,
+    /// Never versioned automatically, inherited by children created in a directory with it set.
+    pub(crate) no_version: bool,
+    /// Entry changes not yet appended to the entry log.
+    #[serde(skip)]
+    entry_changes: Vec<EntryChange>,
+    /// Number of changes in the entry log on disk.
+    #[serde(skip)]
+    logged_entry_changes: usize,
+    /// Buckets of a large directory the entry log on disk touches, i.e. the ones compacting it rewrites.
+    #[serde(skip)]
+    logged_buckets: HashSet<u32>,
 }
 
 impl INode {
@@ -46,77 +164,812 @@ impl INode {
         data: INodeType,
         attr: FileAttr,
     ) -> Self {
-        Self { id, parent, data, attr }
+        Self {
+            id,
+            parent,
+            data,
+            attr,
+            generation: 0,
+            no_version: false,
+            entry_changes: Vec::new(),
+            logged_entry_changes: 0,
+            logged_buckets: HashSet::new(),
+        }
     }
     
-    pub fn with_file_size(id: u64, block_id: u64, parent: u64, attr: FileAttr, size: u64) -> Self {
-        let data = INodeType::File { 
-            blocks: BlockRef::alloc_blocks(block_id, size),
-            size,
-        };
-        Self::new(id, parent, data, attr)
+    /// Writes the inode, compressed when given a `compression`. Either kind of file is read back
+    /// by [`INode::from_file`].
+    pub fn write_to_file(&mut self, inode_dir: &Path, sync: bool, compression: Option<MetadataCompression>) -> Result<()> {
+        self.write_inode(inode_dir, sync, compression).map(|_| ())
     }
-    
-    pub fn with_directory_entries(id: u64, parent: u64, attr: FileAttr, entries: HashMap<String, u64>) -> Self {
-        let data = INodeType::Directory {
-            entries,
+
+    /// Writes the inode, returning how many directory entries had to be serialized.
+    ///
+    /// A large directory writes its entries into buckets once, when it outgrows the inode. After
+    /// that the buckets and the entry log hold them, and the inode is written without any.
+    /// Writing all entries replaces the entry log, so pending changes are included and cleared.
+    fn write_inode(&mut self, inode_dir: &Path, sync: bool, compression: Option<MetadataCompression>) -> Result<usize> {
+        let path = Self::inode_path(self.id, inode_dir);
+        std::fs::create_dir_all(Self::shard_dir(self.id, inode_dir))?;
+
+        let entries_dir = Self::entries_dir(self.id, inode_dir);
+        let external = entries_dir.exists();
+        let entries_len = match self.data {
+            INodeType::Directory { ref entries } => Some(entries.len()),
+            INodeType::File { .. } => None,
         };
-        Self::new(id, parent, data, attr)
+        match entries_len {
+            Some(len) if external && len >= EXTERNAL_ENTRIES_MIN / 2 => {
+                write_to_bin_file_as(&self.without_entries(), path.as_path(), sync, compression)?;
+                return Ok(0);
+            }
+            Some(len) if len >= EXTERNAL_ENTRIES_MIN => {
+                let written = self.write_buckets(inode_dir, 0..ENTRY_BUCKETS, sync, compression)?;
+                write_to_bin_file_as(&self.without_entries(), path.as_path(), sync, compression)?;
+                Self::remove_entry_log(self.id, inode_dir)?;
+                self.entries_written();
+                return Ok(written);
+            }
+            _ => {}
+        }
+
+        write_to_bin_file_as(self, path.as_path(), sync, compression)?;
+        // The full inode now includes every logged change, replaying them again would be wrong.
+        Self::remove_entry_log(self.id, inode_dir)?;
+        if external {
+            std::fs::remove_dir_all(&entries_dir)?;
+        }
+        self.entries_written();
+        Ok(entries_len.unwrap_or(0))
     }
-    
-    pub fn new_autosave(
-        id: u64,
-        parent: u64,
-        data: INodeType,
-        attr: FileAttr,
-        inode_dir: &Path,
-    ) -> AutoSave<Self> {
-        let val = Self::new(id, parent, data, attr);
-        let path = inode_dir.join(format!("inode_{}.bin", id));
-        AutoSave::new(val, path)
+
+    /// Forgets the entry log and the changes pending for it, once every entry has been written.
+    fn entries_written(&mut self) {
+        self.entry_changes.clear();
+        self.logged_entry_changes = 0;
+        self.logged_buckets.clear();
     }
-    
-    pub fn write_to_file(&self, inode_dir: &Path) -> Result<()> {
-        let path = inode_dir.join(format!("inode_{}.bin", self.id));
-        write_to_bin_file(self, path.as_path())?;
+
+    /// This inode without its entries, as written for a directory keeping them in buckets.
+    fn without_entries(&self) -> Self {
+        Self {
+            id: self.id,
+            parent: self.parent,
+            data: INodeType::empty_directory(),
+            attr: self.attr,
+            generation: self.generation,
+            no_version: self.no_version,
+            entry_changes: Vec::new(),
+            logged_entry_changes: 0,
+            logged_buckets: HashSet::new(),
+        }
+    }
+
+    /// Rewrites the given entry buckets of a directory, returning how many entries they hold.
+    fn write_buckets(&self, inode_dir: &Path, buckets: impl IntoIterator<Item = u32>, sync: bool, compression: Option<MetadataCompression>) -> Result<usize> {
+        let INodeType::Directory { ref entries } = self.data else {
+            return Err(TimeFSError::NotDirectory(self.id));
+        };
+        let mut contents = buckets.into_iter()
+            .map(|bucket| (bucket, HashMap::new()))
+            .collect::<HashMap<u32, HashMap<&str, ChildEntry>>>();
+        for (name, &entry) in entries {
+            if let Some(bucket) = contents.get_mut(&entry_bucket(name)) {
+                bucket.insert(name.as_str(), entry);
+            }
+        }
+
+        let entries_dir = Self::entries_dir(self.id, inode_dir);
+        std::fs::create_dir_all(&entries_dir)?;
+        let mut written = 0;
+        for (bucket, bucket_entries) in contents {
+            write_to_bin_file_as(&bucket_entries, &entries_dir.join(format!("{:03}.bin", bucket)), sync, compression)?;
+            written += bucket_entries.len();
+        }
+        Ok(written)
+    }
+
+    /// Reads the entries of a directory keeping them in buckets, if this one does.
+    fn load_buckets(&mut self, inode_dir: &Path) -> Result<()> {
+        let INodeType::Directory { ref mut entries } = self.data else {
+            return Ok(());
+        };
+        let dir = match std::fs::read_dir(Self::entries_dir(self.id, inode_dir)) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for file in dir {
+            let file = file?;
+            // Buckets left half written by a crash are still in their temporary file.
+            if file.path().extension().is_some_and(|ext| ext == "bin") {
+                entries.extend(from_bin_file::<HashMap<String, ChildEntry>>(&file.path())?);
+            }
+        }
         Ok(())
     }
     
     pub fn from_file(id: u64, inode_dir: &Path) -> Result<Self> {
-        let path = inode_dir.join(format!("inode_{}.bin", id));
-        Ok(from_bin_file(path.as_path())?)
+        let path = Self::inode_path(id, inode_dir);
+        let mut inode: Self = from_bin_file(path.as_path())?;
+        inode.load_buckets(inode_dir)?;
+        inode.replay_entry_log(inode_dir)?;
+        Ok(inode)
     }
-    
-    pub fn from_file_autosave(id: u64, inode_dir: &Path) -> Result<AutoSave<Self>> {
-        let path = inode_dir.join(format!("inode_{}.bin", id));
-        let val = Self::from_file(id, &path)?;
-        Ok(AutoSave::new(val, path))
+
+    /// Deletes the persisted inode `id` along with its entry log and buckets.
+    pub fn remove_file(id: u64, inode_dir: &Path) -> Result<()> {
+        for path in [Self::inode_path(id, inode_dir), Self::entry_log_path(id, inode_dir)] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        match std::fs::remove_dir_all(Self::entries_dir(id, inode_dir)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
     }
-    
-    pub fn is_file(&self) -> bool {
-        if let INodeType::File { .. } = self.data {
-            true
-        } else {
-            false
+
+    fn remove_entry_log(id: u64, inode_dir: &Path) -> Result<()> {
+        match std::fs::remove_file(Self::entry_log_path(id, inode_dir)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Directory holding inode `id`, inodes being spread over subdirectories like blocks are.
+    fn shard_dir(id: u64, inode_dir: &Path) -> PathBuf {
+        inode_dir.join(format!("{:03}", id / INODES_PER_SHARD))
+    }
+
+    pub fn inode_path(id: u64, inode_dir: &Path) -> PathBuf {
+        Self::shard_dir(id, inode_dir).join(format!("inode_{}.bin", id))
+    }
+
+    fn entry_log_path(id: u64, inode_dir: &Path) -> PathBuf {
+        Self::shard_dir(id, inode_dir).join(format!("inode_{}.log", id))
+    }
+
+    /// Directory holding the entry buckets of a large directory.
+    fn entries_dir(id: u64, inode_dir: &Path) -> PathBuf {
+        Self::shard_dir(id, inode_dir).join(format!("inode_{}.entries", id))
+    }
+
+    /// Parses the inode id out of an `inode_{id}.bin` or `inode_{id}.log` file name.
+    pub fn parse_file_name(name: &str) -> Option<u64> {
+        let id = name.strip_prefix("inode_")?;
+        id.strip_suffix(".bin").or_else(|| id.strip_suffix(".log"))?.parse().ok()
+    }
+
+    /// Moves inode files left directly in `inode_dir` by the flat layout into their shards.
+    /// Returns how many files were moved, so it's a no-op once a store has been migrated.
+    pub fn migrate_flat_layout(inode_dir: &Path) -> Result<usize> {
+        let mut moved = 0;
+        for entry in std::fs::read_dir(inode_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(id) = name.to_str().and_then(Self::parse_file_name) else {
+                continue;
+            };
+
+            let shard_dir = Self::shard_dir(id, inode_dir);
+            std::fs::create_dir_all(&shard_dir)?;
+            std::fs::rename(entry.path(), shard_dir.join(&name))?;
+            moved += 1;
+        }
+
+        if moved > 0 {
+            File::open(inode_dir)?.sync_all()?;
+        }
+        Ok(moved)
+    }
+
+    /// Whether inode files are still left directly in `inode_dir` by the flat layout, see
+    /// [`INode::migrate_flat_layout`].
+    pub fn has_flat_layout(inode_dir: &Path) -> Result<bool> {
+        let entries = match std::fs::read_dir(inode_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            if entry?.file_name().to_str().and_then(Self::parse_file_name).is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn replay_entry_log(&mut self, inode_dir: &Path) -> Result<()> {
+        let file = match File::open(Self::entry_log_path(self.id, inode_dir)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let external = Self::entries_dir(self.id, inode_dir).exists();
+        let mut reader = BufReader::new(file);
+        while !reader.fill_buf()?.is_empty() {
+            let change: EntryChange = bincode::deserialize_from(&mut reader)?;
+            if external {
+                self.logged_buckets.insert(entry_bucket(change.name()));
+            }
+            if let INodeType::Directory { ref mut entries } = self.data {
+                match change {
+                    EntryChange::Add(name, entry) => entries.insert(name, entry),
+                    EntryChange::Remove(name) => entries.remove(&name),
+                };
+            }
+            self.logged_entry_changes += 1;
+        }
+        Ok(())
+    }
+
+    /// Persists pending entry changes by appending them to the entry log, returning how many
+    /// directory entries had to be serialized. With `sync` the log is fsynced afterwards.
+    ///
+    /// Once the log outgrows the directory itself it is compacted, so that replaying it on load
+    /// stays cheap. What compacting rewrites is compressed when given a `compression`.
+    pub fn write_entry_changes(&mut self, inode_dir: &Path, sync: bool, compression: Option<MetadataCompression>) -> Result<usize> {
+        if self.entry_changes.is_empty() {
+            return Ok(0);
+        }
+
+        let entries_len = match self.data {
+            INodeType::Directory { ref entries } => entries.len(),
+            INodeType::File { .. } => return Err(TimeFSError::NotDirectory(self.id)),
+        };
+
+        let changes = std::mem::take(&mut self.entry_changes);
+        let external = Self::entries_dir(self.id, inode_dir).exists();
+        if external {
+            self.logged_buckets.extend(changes.iter().map(|change| entry_bucket(change.name())));
+        }
+        if self.logged_entry_changes + changes.len() > entries_len.max(ENTRY_LOG_COMPACT_MIN) {
+            return self.compact_entry_log(inode_dir, sync, compression);
+        }
+
+        std::fs::create_dir_all(Self::shard_dir(self.id, inode_dir))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::entry_log_path(self.id, inode_dir))?;
+        let mut writer = BufWriter::new(file);
+        for change in &changes {
+            bincode::serialize_into(&mut writer, change)?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if sync {
+            sync_file(&file)?;
+        }
+
+        self.logged_entry_changes += changes.len();
+        Ok(changes.len())
+    }
+
+    /// Folds the entry log into the inode, or for a large directory into the buckets it touched.
+    fn compact_entry_log(&mut self, inode_dir: &Path, sync: bool, compression: Option<MetadataCompression>) -> Result<usize> {
+        let buckets = std::mem::take(&mut self.logged_buckets);
+        self.logged_entry_changes = 0;
+        let large = matches!(self.data, INodeType::Directory { ref entries } if entries.len() >= EXTERNAL_ENTRIES_MIN / 2);
+        if !large || !Self::entries_dir(self.id, inode_dir).exists() {
+            return self.write_inode(inode_dir, sync, compression);
+        }
+
+        let written = self.write_buckets(inode_dir, buckets, sync, compression)?;
+        Self::remove_entry_log(self.id, inode_dir)?;
+        Ok(written)
+    }
+
+    /// Forgets pending entry changes without persisting them.
+    pub fn discard_entry_changes(&mut self) {
+        self.entry_changes.clear();
+    }
+
+    /// Adds a directory entry, recording the change so only it needs persisting.
+    pub fn add_entry(&mut self, name: impl AsRef<str>, entry: ChildEntry) -> Result<()> {
+        let name = name.as_ref();
+        match self.data {
+            INodeType::File { .. } => Err(TimeFSError::NotDirectory(self.id)),
+            INodeType::Directory { ref mut entries } => {
+                if entries.contains_key(name) {
+                    return Err(TimeFSError::NameExist(name.to_string()));
+                }
+
+                entries.insert(name.to_string(), entry);
+                self.entry_changes.push(EntryChange::Add(name.to_string(), entry));
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes a directory entry, returning the id it pointed to.
+    pub fn remove_entry(&mut self, name: impl AsRef<str>) -> Result<u64> {
+        let name = name.as_ref();
+        match self.data {
+            INodeType::File { .. } => Err(TimeFSError::NotDirectory(self.id)),
+            INodeType::Directory { ref mut entries } => {
+                let id = entries
+                    .remove(name)
+                    .ok_or(TimeFSError::NameNotFound(name.to_string()))?
+                    .id;
+                self.entry_changes.push(EntryChange::Remove(name.to_string()));
+                Ok(id)
+            }
         }
     }
     
     pub fn is_directory(&self) -> bool {
-        if let INodeType::Directory { .. } = self.data {
-            true
-        } else {
-            false
-        }
+        matches!(self.data, INodeType::Directory { .. })
     }
     
+    pub fn file_size(&self) -> u64 {
+        match self.data {
+            INodeType::File { size, .. } => size,
+            INodeType::Directory { .. } => 0,
+        }
+    }
+
+    /// Size of the blocks this file is split into, the file system default unless overridden.
+    pub fn block_size(&self) -> u32 {
+        match self.data {
+            INodeType::File { block_size, .. } => block_size.unwrap_or(BLOCK_SIZE),
+            INodeType::Directory { .. } => BLOCK_SIZE,
+        }
+    }
+
+    /// Overrides the block size of a file. Only allowed while the file holds no data, since
+    /// the block list would otherwise have to be rewritten.
+    pub fn set_block_size(&mut self, new_block_size: u32) -> Result<()> {
+        let id = self.id;
+        match self.data {
+            INodeType::File { ref blocks, ref versions, ref mut block_size, .. } => {
+                if !blocks.is_empty() || !versions.is_empty() {
+                    return Err(TimeFSError::Invalid(format!("inode {} already holds data", id)));
+                }
+                *block_size = Some(new_block_size);
+                self.attr.blksize = new_block_size;
+                Ok(())
+            }
+            INodeType::Directory { .. } => Err(TimeFSError::IsDirectory(id)),
+        }
+    }
+
+    /// Records a change of the contents, which is a change of the inode as well.
+    pub fn touch_mtime(&mut self) {
+        let now = SystemTime::now();
+        self.attr.mtime = now;
+        self.attr.ctime = now;
+    }
+
+    /// Records a change of the metadata only, such as its mode, owner, links or location.
+    pub fn touch_ctime(&mut self) {
+        self.attr.ctime = SystemTime::now();
+    }
+
+    /// Updates the logical size of a file along with the size reported in its attributes.
+    pub fn set_size(&mut self, new_size: u64) {
+        let block_size = self.block_size();
+        if let INodeType::File { ref mut size, .. } = self.data {
+            *size = new_size;
+            self.attr.size = new_size;
+            self.attr.blocks = stat_blocks(new_size.div_ceil(block_size as u64), block_size);
+        }
+    }
+
+    /// Every block held by this inode, both by its live data and by its versions.
+    pub fn held_blocks(&self) -> Vec<&BlockRef> {
+        match self.data {
+            INodeType::File { ref blocks, ref versions, .. } => blocks
+                .iter()
+                .chain(versions.iter().flat_map(|v| v.blocks.iter()))
+                .filter(|b| !b.is_hole())
+                .collect(),
+            INodeType::Directory { .. } => Vec::new(),
+        }
+    }
+
+    /// Ids of every block held by this inode, see [`INode::held_blocks`].
+    pub fn referenced_blocks(&self) -> Vec<u64> {
+        self.held_blocks().into_iter().map(BlockRef::id).collect()
+    }
+
+    /// Bytes of the blocks backing the live contents, what a file counts against its owner's quota.
+    pub fn allocated_bytes(&self) -> u64 {
+        match self.data {
+            INodeType::File { ref blocks, .. } => {
+                blocks.iter().filter(|b| !b.is_hole()).count() as u64 * self.block_size() as u64
+            }
+            INodeType::Directory { .. } => 0,
+        }
+    }
+
+    /// Records the current contents as a version taken at `timestamp`, or a nanosecond after the
+    /// newest version when the clock hasn't moved past it, as versions are told apart by timestamp.
+    pub fn record_version(&mut self, timestamp: SystemTime) -> Result<&Version> {
+        match self.data {
+            INodeType::File { ref blocks, size, ref mut versions, .. } => {
+                let timestamp = match versions.last() {
+                    Some(newest) if newest.timestamp >= timestamp => newest.timestamp + Duration::from_nanos(1),
+                    _ => timestamp,
+                };
+                versions.push(Version { timestamp, size, blocks: blocks.clone(), mtime: self.attr.mtime, ctime: self.attr.ctime });
+                Ok(versions.last().unwrap())
+            }
+            INodeType::Directory { .. } => Err(TimeFSError::IsDirectory(self.id)),
+        }
+    }
+
+    /// When the newest version was taken, `None` without any.
+    pub fn last_version_at(&self) -> Option<SystemTime> {
+        match self.data {
+            INodeType::File { ref versions, .. } => versions.last().map(|v| v.timestamp),
+            INodeType::Directory { .. } => None,
+        }
+    }
+
+    pub fn version_count(&self) -> usize {
+        match self.data {
+            INodeType::File { ref versions, .. } => versions.len(),
+            INodeType::Directory { .. } => 0,
+        }
+    }
+
+    /// Bytes of the blocks only versions hold, i.e. what dropping all of them would free.
+    pub fn history_bytes(&self) -> u64 {
+        let INodeType::File { ref blocks, ref versions, .. } = self.data else {
+            return 0;
+        };
+        let live = blocks.iter().map(|b| b.id()).collect::<HashSet<_>>();
+        let mut history = HashMap::new();
+        for block in versions.iter().flat_map(|v| v.blocks.iter()) {
+            if !block.is_hole() && !live.contains(&block.id()) {
+                history.insert(block.id(), block.size() as u64);
+            }
+        }
+        history.values().sum()
+    }
+
+    /// Drops the oldest version, returning it so the blocks it referenced can be released.
+    pub fn remove_oldest_version(&mut self) -> Option<Version> {
+        match self.data {
+            INodeType::File { ref mut versions, .. } if !versions.is_empty() => Some(versions.remove(0)),
+            _ => None,
+        }
+    }
+
+    pub fn get_version(&self, timestamp: SystemTime) -> Result<&Version> {
+        match self.data {
+            INodeType::File { ref versions, .. } => versions
+                .iter()
+                .find(|v| v.timestamp == timestamp)
+                .ok_or(TimeFSError::VersionNotFound(self.id, timestamp)),
+            INodeType::Directory { .. } => Err(TimeFSError::IsDirectory(self.id)),
+        }
+    }
+
+    /// Returns the logical byte ranges whose contents differ between the versions taken at `a` and `b`.
+    ///
+    /// Blocks are compared by id, so two versions sharing a block are known to be equal there
+    /// without reading any data. Adjacent changed blocks are merged into one range.
+    pub fn diff_versions(&self, a: SystemTime, b: SystemTime) -> Result<Vec<Range<u64>>> {
+        let a = self.get_version(a)?;
+        let b = self.get_version(b)?;
+
+        let block_size = self.block_size() as u64;
+        let end = a.size.max(b.size);
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+
+        for index in 0..a.blocks.len().max(b.blocks.len()) {
+            let changed = match (a.blocks.get(index), b.blocks.get(index)) {
+                (Some(x), Some(y)) => x.id() != y.id(),
+                (None, None) => false,
+                _ => true,
+            };
+            if !changed {
+                continue;
+            }
+
+            let start = index as u64 * block_size;
+            let range_end = (start + block_size).min(end);
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = range_end,
+                _ => ranges.push(start..range_end),
+            }
+        }
+
+        Ok(ranges)
+    }
+
+    /// Name of the entry matching `name` when case is ignored, preferring an exact match.
+    pub fn find_entry_ignore_case(&self, name: &str) -> Option<&str> {
+        let INodeType::Directory { ref entries } = self.data else {
+            return None;
+        };
+        if let Some((stored, _)) = entries.get_key_value(name) {
+            return Some(stored);
+        }
+        let folded = name.to_lowercase();
+        entries.keys().find(|stored| stored.to_lowercase() == folded).map(String::as_str)
+    }
+
     pub fn get_child_id(&self, name: impl AsRef<str>) -> Result<u64> {
+        self.get_child_entry(name).map(|entry| entry.id)
+    }
+
+    /// Id and kind of the child `name`, the kind being `None` only for entries from before kinds were kept.
+    pub fn get_child_entry(&self, name: impl AsRef<str>) -> Result<ChildEntry> {
         let name = name.as_ref();
-        
+
         match self.data {
             INodeType::File { .. } => Err(TimeFSError::NotDirectory(self.id)),
             INodeType::Directory {
                 ref entries,
-            } => entries.get(name).map(|e| *e).ok_or(TimeFSError::NameNotFound(name.to_string()))
+            } => entries.get(name).copied().ok_or(TimeFSError::NameNotFound(name.to_string()))
+        }
+    }
+}
+
+/// Bucket of a large directory holding the entry `name`, by a hash stable across builds.
+fn entry_bucket(name: &str) -> u32 {
+    crc32fast::hash(name.as_bytes()) % ENTRY_BUCKETS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::file_attr::FileAttrBuilder;
+
+    impl INode {
+        fn with_directory_entries(id: u64, parent: u64, attr: FileAttr, entries: HashMap<String, ChildEntry>) -> Self {
+            Self::new(id, parent, INodeType::Directory { entries }, attr)
+        }
+    }
+
+    fn file_with_blocks(ids: &[u64]) -> INode {
+        let attr = FileAttrBuilder::default().ino(2).build();
+        let mut inode = INode::new(2, 1, INodeType::empty_file(), attr);
+        if let INodeType::File { ref mut blocks, .. } = inode.data {
+            *blocks = ids.iter().map(|id| BlockRef::with_size(*id, BLOCK_SIZE)).collect();
+        }
+        inode.set_size(ids.len() as u64 * BLOCK_SIZE as u64);
+        inode
+    }
+
+    fn directory_with_entries(count: usize) -> INode {
+        let attr = FileAttrBuilder::default().ino(3).with_directory().build();
+        let entries = (0..count)
+            .map(|i| (format!("file_{}", i), ChildEntry::new(100 + i as u64, FileType::RegularFile)))
+            .collect();
+        INode::with_directory_entries(3, 1, attr, entries)
+    }
+
+    #[test]
+    fn test_inodes_are_sharded() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let inode_dir = temp_dir.path();
+
+        let attr = FileAttrBuilder::default().ino(1234).build();
+        INode::new(1234, 1, INodeType::empty_file(), attr).write_to_file(inode_dir, true, None)?;
+        assert_eq!(INode::inode_path(1234, inode_dir), inode_dir.join("001").join("inode_1234.bin"));
+        assert!(inode_dir.join("001").join("inode_1234.bin").exists());
+        assert_eq!(INode::from_file(1234, inode_dir)?.id, 1234);
+        assert_eq!(INode::migrate_flat_layout(inode_dir)?, 0, "nothing left to migrate");
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_write_clears_pending_entry_changes() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let inode_dir = temp_dir.path();
+
+        let mut inode = directory_with_entries(10);
+        inode.write_to_file(inode_dir, true, None)?;
+        inode.add_entry("logged", ChildEntry::new(500, FileType::RegularFile))?;
+        inode.write_entry_changes(inode_dir, true, None)?;
+        inode.add_entry("pending", ChildEntry::new(501, FileType::RegularFile))?;
+
+        // The full write takes in both changes, leaving nothing for the entry log.
+        inode.write_to_file(inode_dir, true, None)?;
+        assert_eq!(inode.logged_entry_changes, 0);
+        assert_eq!(inode.write_entry_changes(inode_dir, true, None)?, 0);
+        assert!(!INode::entry_log_path(inode.id, inode_dir).exists());
+        assert_eq!(INode::from_file(inode.id, inode_dir)?.get_child_id("pending")?, 501);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_changes_are_logged_as_delta() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let inode_dir = temp_dir.path();
+
+        let mut inode = directory_with_entries(10_000);
+        inode.write_to_file(inode_dir, true, None)?;
+
+        assert_eq!(inode.get_child_id("file_42")?, 142);
+        assert_eq!(inode.write_entry_changes(inode_dir, true, None)?, 0, "lookups shouldn't persist anything");
+
+        assert_eq!(inode.remove_entry("file_42")?, 142);
+        assert_eq!(inode.write_entry_changes(inode_dir, true, None)?, 1, "only the removed entry should be written");
+        inode.add_entry("new_file", ChildEntry::new(20_000, FileType::RegularFile))?;
+        assert_eq!(inode.write_entry_changes(inode_dir, true, None)?, 1);
+
+        let loaded = INode::from_file(3, inode_dir)?;
+        assert!(matches!(loaded.get_child_id("file_42"), Err(TimeFSError::NameNotFound(_))));
+        assert_eq!(loaded.get_child_id("file_43")?, 143);
+        assert_eq!(loaded.get_child_id("new_file")?, 20_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_log_compaction() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let inode_dir = temp_dir.path();
+
+        let mut inode = directory_with_entries(2);
+        inode.write_to_file(inode_dir, true, None)?;
+
+        for i in 0..ENTRY_LOG_COMPACT_MIN {
+            inode.add_entry(format!("extra_{}", i), ChildEntry::new(1000 + i as u64, FileType::RegularFile))?;
+            inode.write_entry_changes(inode_dir, true, None)?;
+        }
+        assert!(inode_dir.join("000").join("inode_3.log").exists());
+
+        for name in ["file_0", "file_1"] {
+            inode.remove_entry(name)?;
+            inode.write_entry_changes(inode_dir, true, None)?;
+        }
+        assert!(!inode_dir.join("000").join("inode_3.log").exists(), "log should be folded into the inode");
+
+        let loaded = INode::from_file(3, inode_dir)?;
+        assert!(matches!(loaded.get_child_id("file_0"), Err(TimeFSError::NameNotFound(_))));
+        assert!(matches!(loaded.get_child_id("file_1"), Err(TimeFSError::NameNotFound(_))));
+        assert_eq!(loaded.get_child_id("extra_0")?, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_child_entry_keeps_kind() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let inode_dir = temp_dir.path();
+
+        let mut inode = directory_with_entries(0);
+        inode.add_entry("dir", ChildEntry::new(7, FileType::Directory))?;
+        inode.write_to_file(inode_dir, false, None)?;
+        inode.add_entry("fifo", ChildEntry::new(8, FileType::NamedPipe))?;
+        inode.write_entry_changes(inode_dir, false, None)?;
+
+        let loaded = INode::from_file(3, inode_dir)?;
+        assert_eq!(loaded.get_child_entry("dir")?, ChildEntry::new(7, FileType::Directory));
+        assert_eq!(loaded.get_child_entry("fifo")?, ChildEntry::new(8, FileType::NamedPipe));
+        assert_eq!(loaded.get_child_id("fifo")?, 8);
+
+        // Entries persisted as bare ids load without a kind.
+        let legacy = bincode::serialize(&HashMap::from([("old".to_string(), 9u64)]))?;
+        let entries: HashMap<String, ChildEntry> = bincode::deserialize(&legacy)?;
+        assert_eq!(entries["old"], ChildEntry { id: 9, kind: None });
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_inode_round_trips() -> Result<()> {
+        let plain_dir = tempfile::tempdir()?;
+        let compressed_dir = tempfile::tempdir()?;
+        let mut inode = directory_with_entries(2000);
+        inode.write_to_file(plain_dir.path(), false, None)?;
+        inode.write_to_file(compressed_dir.path(), false, Some(MetadataCompression::default()))?;
+
+        let plain_len = std::fs::metadata(INode::inode_path(3, plain_dir.path()))?.len();
+        let compressed_len = std::fs::metadata(INode::inode_path(3, compressed_dir.path()))?.len();
+        assert!(compressed_len < plain_len / 2, "{} bytes compressed, {} plain", compressed_len, plain_len);
+
+        let loaded = INode::from_file(3, compressed_dir.path())?;
+        assert_eq!(loaded.get_child_id("file_1999")?, 2099);
+        let INodeType::Directory { ref entries } = loaded.data else { unreachable!() };
+        assert_eq!(entries.len(), 2000);
+
+        // Plain inodes written before keep loading next to compressed ones.
+        directory_with_entries(1).write_to_file(compressed_dir.path(), false, None)?;
+        assert_eq!(INode::from_file(3, compressed_dir.path())?.get_child_id("file_0")?, 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_directory_entries_are_bucketed() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let inode_dir = temp_dir.path();
+
+        let mut inode = directory_with_entries(100_000);
+        assert_eq!(inode.write_inode(inode_dir, false, None)?, 100_000, "entries are spilled once");
+        assert!(inode_dir.join("000").join("inode_3.entries").is_dir());
+        assert_eq!(inode.write_inode(inode_dir, false, None)?, 0, "attribute changes shouldn't rewrite entries");
+
+        assert_eq!(inode.remove_entry("file_42")?, 142);
+        assert_eq!(inode.write_entry_changes(inode_dir, false, None)?, 1);
+        inode.add_entry("new_file", ChildEntry::new(200_000, FileType::RegularFile))?;
+        assert_eq!(inode.write_entry_changes(inode_dir, false, None)?, 1);
+        assert_eq!(inode.write_inode(inode_dir, false, None)?, 0);
+
+        // Compacting the log only rewrites the buckets its two changes fell into.
+        let written = inode.compact_entry_log(inode_dir, false, None)?;
+        assert!(written < 2 * 100_000 / ENTRY_BUCKETS as usize * 2, "{} entries rewritten", written);
+        assert!(!inode_dir.join("000").join("inode_3.log").exists());
+
+        let loaded = INode::from_file(3, inode_dir)?;
+        let INodeType::Directory { ref entries } = loaded.data else { unreachable!() };
+        assert_eq!(entries.len(), 100_000);
+        assert!(matches!(loaded.get_child_id("file_42"), Err(TimeFSError::NameNotFound(_))));
+        assert_eq!(loaded.get_child_id("file_99999")?, 100_099);
+        assert_eq!(loaded.get_child_id("new_file")?, 200_000);
+
+        // Shrunk well below the threshold, the entries move back into the inode.
+        let mut small = directory_with_entries(10);
+        small.write_to_file(inode_dir, false, None)?;
+        assert!(!inode_dir.join("000").join("inode_3.entries").exists());
+        assert_eq!(INode::from_file(3, inode_dir)?.get_child_id("file_9")?, 109);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_versions_single_block() -> Result<()> {
+        let mut inode = file_with_blocks(&[1, 2, 3]);
+        let first = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let second = first + Duration::from_secs(60);
+
+        inode.record_version(first)?;
+        if let INodeType::File { ref mut blocks, .. } = inode.data {
+            blocks[1] = BlockRef::with_size(4, BLOCK_SIZE);
+        }
+        inode.record_version(second)?;
+
+        let block_size = BLOCK_SIZE as u64;
+        assert_eq!(inode.diff_versions(first, second)?, vec![block_size..2 * block_size]);
+        assert_eq!(inode.diff_versions(second, first)?, vec![block_size..2 * block_size]);
+        assert!(inode.diff_versions(first, first)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions_within_a_second_stay_distinct() -> Result<()> {
+        let mut inode = file_with_blocks(&[1]);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+
+        let first = inode.record_version(now)?.timestamp;
+        let second = inode.record_version(now + Duration::from_millis(500))?.timestamp;
+        // Taken when the clock hasn't moved, or even went back.
+        let third = inode.record_version(now + Duration::from_millis(500))?.timestamp;
+        let fourth = inode.record_version(now)?.timestamp;
+
+        assert_eq!((first, second), (now, now + Duration::from_millis(500)));
+        assert!(second < third && third < fourth);
+        assert_eq!(fourth.duration_since(first).unwrap(), Duration::from_nanos(500_000_002));
+        for timestamp in [first, second, third, fourth] {
+            assert_eq!(inode.get_version(timestamp)?.timestamp, timestamp);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_versions_grown_file() -> Result<()> {
+        let mut inode = file_with_blocks(&[1]);
+        let first = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let second = first + Duration::from_secs(60);
+
+        inode.record_version(first)?;
+        if let INodeType::File { ref mut blocks, .. } = inode.data {
+            blocks.push(BlockRef::with_size(2, 10));
         }
+        inode.set_size(BLOCK_SIZE as u64 + 10);
+        inode.record_version(second)?;
+
+        let block_size = BLOCK_SIZE as u64;
+        assert_eq!(inode.diff_versions(first, second)?, vec![block_size..block_size + 10]);
+        assert!(matches!(
+            inode.diff_versions(first, second + Duration::from_secs(1)),
+            Err(TimeFSError::VersionNotFound(2, _))
+        ));
+        Ok(())
     }
 }
@@ -0,0 +1,22 @@
+use std::ffi::OsStr;
+use std::io;
+use fuser::Notifier;
+
+/// Drops attributes, data and names the kernel caches, abstracted so tests can record what
+/// would be invalidated without a mounted session.
+pub(crate) trait Invalidator: Send + Sync {
+    /// Invalidates `len` bytes of cached data of `ino` from `offset`, all of it when `len` is 0,
+    /// and only the attributes when `offset` is negative.
+    fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()>;
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()>;
+}
+
+impl Invalidator for Notifier {
+    fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        Notifier::inval_inode(self, ino, offset, len)
+    }
+
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        Notifier::inval_entry(self, parent, name)
+    }
+}
@@ -0,0 +1,27 @@
+use fuser::KernelConfig;
+
+/// What TimeFS negotiates with the kernel in `init`, abstracted so tests can check what would
+/// be asked for without mounting.
+pub(crate) trait InitConfig {
+    /// Asks for `capabilities`, failing with the bits the kernel doesn't support.
+    fn add_capabilities(&mut self, capabilities: u32) -> Result<(), u32>;
+    /// Sets the largest write request, returning the previous value or, when the kernel can't
+    /// take it, the nearest value it can.
+    fn set_max_write(&mut self, value: u32) -> Result<u32, u32>;
+    /// Sets the readahead size, returning the previous value or the nearest one the kernel can take.
+    fn set_max_readahead(&mut self, value: u32) -> Result<u32, u32>;
+}
+
+impl InitConfig for KernelConfig {
+    fn add_capabilities(&mut self, capabilities: u32) -> Result<(), u32> {
+        KernelConfig::add_capabilities(self, capabilities)
+    }
+
+    fn set_max_write(&mut self, value: u32) -> Result<u32, u32> {
+        KernelConfig::set_max_write(self, value)
+    }
+
+    fn set_max_readahead(&mut self, value: u32) -> Result<u32, u32> {
+        KernelConfig::set_max_readahead(self, value)
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+/// A POSIX byte-range lock, covering `start..=end` as FUSE passes ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RangeLock {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    /// `F_RDLCK` or `F_WRLCK`, `F_UNLCK` only when asking to release a range.
+    pub(crate) typ: i32,
+    pub(crate) owner: u64,
+    pub(crate) pid: u32,
+}
+
+impl RangeLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts_with(&self, other: &RangeLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == libc::F_WRLCK || other.typ == libc::F_WRLCK)
+    }
+}
+
+/// Byte-range locks held on every inode, as taken through `fcntl`.
+///
+/// Locks of the same owner never conflict, and taking a lock replaces whatever the owner held
+/// on that range before, splitting its existing locks as needed.
+#[derive(Debug, Default)]
+pub(crate) struct LockTable {
+    locks: Mutex<HashMap<u64, Vec<RangeLock>>>,
+    /// Woken whenever a range is unlocked, so blocked lockers can retry.
+    released: Notify,
+}
+
+impl LockTable {
+    /// The first lock held by another owner that keeps `lock` from being taken.
+    pub fn conflict(&self, ino: u64, lock: &RangeLock) -> Option<RangeLock> {
+        let locks = self.locks.lock();
+        locks.get(&ino)?.iter().find(|held| held.conflicts_with(lock)).copied()
+    }
+
+    /// Takes or releases `lock` without waiting, returning the conflicting lock on failure.
+    pub fn try_lock(&self, ino: u64, lock: RangeLock) -> Result<(), RangeLock> {
+        let mut locks = self.locks.lock();
+        let held = locks.entry(ino).or_default();
+        if lock.typ != libc::F_UNLCK
+            && let Some(conflict) = held.iter().find(|held| held.conflicts_with(&lock))
+        {
+            return Err(*conflict);
+        }
+
+        let unlocked = Self::remove_range(held, lock.owner, lock.start, lock.end);
+        if lock.typ != libc::F_UNLCK {
+            held.push(lock);
+        }
+        if held.is_empty() {
+            locks.remove(&ino);
+        }
+        drop(locks);
+
+        if unlocked {
+            self.released.notify_waiters();
+        }
+        Ok(())
+    }
+
+    /// Takes `lock`, waiting for conflicting locks to be released first.
+    pub async fn lock(&self, ino: u64, lock: RangeLock) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Registers for the wakeup before trying, so a release in between isn't missed.
+            released.as_mut().enable();
+            if self.try_lock(ino, lock).is_ok() {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// Drops every lock `owner` holds on `ino`, as when it closes the file.
+    pub fn release_owner(&self, ino: u64, owner: u64) {
+        let unlocked = {
+            let mut locks = self.locks.lock();
+            let Some(held) = locks.get_mut(&ino) else {
+                return;
+            };
+            let unlocked = Self::remove_range(held, owner, 0, u64::MAX);
+            if held.is_empty() {
+                locks.remove(&ino);
+            }
+            unlocked
+        };
+        if unlocked {
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Cuts `start..=end` out of the locks of `owner`, returning whether anything was removed.
+    fn remove_range(held: &mut Vec<RangeLock>, owner: u64, start: u64, end: u64) -> bool {
+        let mut removed = false;
+        let mut kept = Vec::with_capacity(held.len());
+        for lock in held.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            removed = true;
+            if lock.start < start {
+                kept.push(RangeLock { end: start - 1, ..lock });
+            }
+            if lock.end > end {
+                kept.push(RangeLock { start: end + 1, ..lock });
+            }
+        }
+        *held = kept;
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn range_lock(owner: u64, start: u64, end: u64, typ: i32) -> RangeLock {
+        RangeLock { start, end, typ, owner, pid: owner as u32 }
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_lock_blocks_until_released() {
+        let table = Arc::new(LockTable::default());
+        let held = range_lock(1, 0, 99, libc::F_WRLCK);
+        table.try_lock(7, held).unwrap();
+
+        // Another owner sees the write lock, whether it wants to read or write.
+        let wanted = range_lock(2, 50, 149, libc::F_RDLCK);
+        assert_eq!(table.conflict(7, &wanted), Some(held));
+        assert_eq!(table.try_lock(7, wanted), Err(held));
+        assert_eq!(table.conflict(7, &range_lock(2, 100, 149, libc::F_WRLCK)), None);
+        assert_eq!(table.conflict(7, &range_lock(1, 0, 99, libc::F_WRLCK)), None, "owners don't conflict with themselves");
+
+        let waiter = tokio::spawn({
+            let table = table.clone();
+            async move { table.lock(7, wanted).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        // Releasing part of the range isn't enough, the waiter still overlaps the rest.
+        table.try_lock(7, range_lock(1, 0, 49, libc::F_UNLCK)).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        table.release_owner(7, 1);
+        tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        assert_eq!(table.conflict(7, &range_lock(3, 0, 99, libc::F_WRLCK)), Some(wanted));
+        assert_eq!(table.conflict(7, &range_lock(3, 0, 99, libc::F_RDLCK)), None);
+    }
+}
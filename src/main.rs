@@ -3,84 +3,323 @@ pub mod inode;
 pub mod superblock;
 pub mod file_handle;
 pub mod block;
+pub mod backend;
 pub mod error;
+pub mod snapshot;
+pub mod export;
+pub mod crypto;
+#[cfg(feature = "zstd")]
+pub mod compress;
+pub mod trash;
+pub mod fifo;
+pub mod control;
+pub mod lock;
+pub mod storage;
+pub mod group_commit;
+pub mod quota;
+pub mod dump;
+mod reply;
+mod invalidate;
+mod kernel_config;
 mod args;
 mod file_attr;
+mod options;
+#[cfg(all(test, feature = "fuse-tests"))]
+mod fuse_tests;
 
-use std::io::{BufReader, BufWriter};
-use std::ops::{Deref, DerefMut};
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use flate2::Compression;
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use clap::Parser;
+use log::{info, warn};
+use crate::args::Args;
+use crate::error::TimeFSError;
+use crate::storage::Storage;
+use crate::options::{CompressionAlgorithm, MetadataCompression};
 pub use crate::error::Result;
 
+/// Leads metadata files written compressed, so they can sit next to plain ones. Read as the
+/// start of a plain inode or superblock it would be an id or magic TimeFS never writes.
+const COMPRESSED_MARKER: [u8; 8] = *b"TIMEFSZ\xff";
+
+/// Reads a metadata file, compressed or not.
 pub(crate) fn from_bin_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
     let file = std::fs::File::open(path)?;
-    let reader = BufReader::new(file);
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf()?.starts_with(&COMPRESSED_MARKER) {
+        reader.consume(COMPRESSED_MARKER.len());
+        return from_bin_compressed(reader);
+    }
     Ok(bincode::deserialize_from(reader)?)
 }
 
-pub(crate) fn write_to_bin_file<T: Serialize>(val: &T, path: &Path) -> Result<()> {
-    let file = std::fs::File::create(path)?;
-    let writer = BufWriter::new(file);
-    bincode::serialize_into(writer, val)?;
-    Ok(())
+/// Serializes `val` into a temporary file that replaces `path` only once fully written. With
+/// `sync` the file and its directory are fsynced too, so the new contents survive a power failure.
+pub(crate) fn write_to_bin_file<T: Serialize>(val: &T, path: &Path, sync: bool) -> Result<()> {
+    write_to_bin_file_as(val, path, sync, None)
 }
 
-pub(crate) fn from_bin_compressed_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
-    let file = std::fs::File::open(path)?;
-    let reader = BufReader::new(file);
-    let reader = ZlibDecoder::new(reader);
-    Ok(bincode::deserialize_from(reader)?)
-}
+/// Suffix of the next temporary file metadata is written to before being renamed into place.
+static NEXT_TMP_FILE: AtomicU64 = AtomicU64::new(0);
 
-pub(crate) fn write_to_bin_compressed_file<T: Serialize>(val: &T, path: &Path) -> Result<()> {
-    let file = std::fs::File::create(path)?;
-    let writer = BufWriter::new(file);
-    let writer = ZlibEncoder::new(writer, Compression::best());
-    bincode::serialize_into(writer, val)?;
+/// Like [`write_to_bin_file`], compressing the file when given a `compression`.
+pub(crate) fn write_to_bin_file_as<T: Serialize>(val: &T, path: &Path, sync: bool, compression: Option<MetadataCompression>) -> Result<()> {
+    // Each write has a file of its own, so concurrent writes of the same file can't mix.
+    let tmp_path = path.with_extension(format!("tmp{}", NEXT_TMP_FILE.fetch_add(1, Ordering::Relaxed)));
+    let file = std::fs::File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    match compression {
+        Some(compression) => {
+            writer.write_all(&COMPRESSED_MARKER)?;
+            write_to_bin_compressed(val, &mut writer, compression)?;
+        }
+        None => bincode::serialize_into(&mut writer, val)?,
+    }
+
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    if sync {
+        sync_file(&file)?;
+    }
+    drop(file);
+
+    std::fs::rename(&tmp_path, path)?;
+    if sync {
+        sync_parent_dir(path)?;
+    }
     Ok(())
 }
 
-pub(crate) struct AutoSave<T>
-where T: Serialize {
-    inner: T,
-    path: PathBuf,
+#[cfg(not(test))]
+pub(crate) fn sync_file(file: &std::fs::File) -> std::io::Result<()> {
+    file.sync_all()
 }
 
-impl<'a, T> AutoSave<T> 
-where T: Serialize {
-    fn new(inner: T, path: impl AsRef<Path>) -> Self {
-        Self { inner, path: path.as_ref().to_path_buf() }
+/// Fsyncs the directory holding `path`, without which a rename into it may not survive a crash.
+#[cfg(not(test))]
+pub(crate) fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    fsync_parent_dir(path)
+}
+
+fn fsync_parent_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) => std::fs::File::open(dir)?.sync_all(),
+        None => Ok(()),
     }
 }
 
-impl<T> Drop for AutoSave<T>
-where T: Serialize {
-    fn drop(&mut self) {
-        write_to_bin_file(&self.inner, &self.path).expect("Failed to write to file");
+/// The fsyncs above, counted per thread so tests can tell what was made durable when without
+/// parallel tests seeing each other's.
+#[cfg(test)]
+mod counted_syncs {
+    use std::cell::Cell;
+    use std::path::Path;
+
+    thread_local! {
+        /// Metadata fsyncs issued by the current thread.
+        pub(crate) static FILE_SYNCS: Cell<usize> = const { Cell::new(0) };
+        /// Directory fsyncs issued by the current thread.
+        pub(crate) static DIR_SYNCS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub(crate) fn sync_file(file: &std::fs::File) -> std::io::Result<()> {
+        FILE_SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+        file.sync_all()
+    }
+
+    pub(crate) fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+        DIR_SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+        super::fsync_parent_dir(path)
     }
 }
 
-impl<T> Deref for AutoSave<T>
-where T: Serialize {
-    type Target = T;
-    
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+#[cfg(test)]
+pub(crate) use counted_syncs::{sync_file, sync_parent_dir, DIR_SYNCS, FILE_SYNCS};
+
+/// Header bytes naming the algorithm a compressed metadata file was written with.
+const HEADER_NONE: u8 = 0;
+const HEADER_ZLIB: u8 = 1;
+const HEADER_ZSTD: u8 = 2;
+/// First byte of every zlib stream at the default window size, i.e. files written before the header existed.
+const LEGACY_ZLIB: u8 = 0x78;
+
+pub(crate) fn from_bin_compressed<T: DeserializeOwned>(mut reader: impl Read) -> Result<T> {
+    let mut header = [0u8];
+    reader.read_exact(&mut header)?;
+    match header[0] {
+        HEADER_NONE => Ok(bincode::deserialize_from(reader)?),
+        HEADER_ZLIB => Ok(bincode::deserialize_from(ZlibDecoder::new(reader))?),
+        LEGACY_ZLIB => Ok(bincode::deserialize_from(ZlibDecoder::new((&header[..]).chain(reader)))?),
+        #[cfg(feature = "zstd")]
+        HEADER_ZSTD => Ok(bincode::deserialize_from(zstd::Decoder::new(reader)?)?),
+        #[cfg(not(feature = "zstd"))]
+        HEADER_ZSTD => Err(TimeFSError::UnsupportedCompression(HEADER_ZSTD)),
+        _ => Err(TimeFSError::UnsupportedCompression(header[0])),
     }
 }
 
-impl<T> DerefMut for AutoSave<T>
-where T: Serialize {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+pub(crate) fn write_to_bin_compressed<T: Serialize>(val: &T, mut writer: impl Write, compression: MetadataCompression) -> Result<()> {
+    match compression.algorithm {
+        CompressionAlgorithm::None => {
+            writer.write_all(&[HEADER_NONE])?;
+            bincode::serialize_into(&mut writer, val)?;
+            writer.flush()?;
+        }
+        CompressionAlgorithm::Zlib => {
+            writer.write_all(&[HEADER_ZLIB])?;
+            let level = compression.level.map_or(Compression::best(), |level| Compression::new(level as u32));
+            let mut writer = ZlibEncoder::new(writer, level);
+            bincode::serialize_into(&mut writer, val)?;
+            writer.finish()?.flush()?;
+        }
+        #[cfg(feature = "zstd")]
+        CompressionAlgorithm::Zstd => {
+            writer.write_all(&[HEADER_ZSTD])?;
+            let mut writer = zstd::Encoder::new(writer, compression.level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))?;
+            bincode::serialize_into(&mut writer, val)?;
+            writer.finish()?.flush()?;
+        }
     }
+    Ok(())
 }
 
 fn main() {
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+    #[cfg(not(feature = "tracing"))]
     env_logger::init();
+    let args = Args::parse();
+    let parsed = match args.validate() {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if args.check() {
+        println!("Arguments are valid");
+        return;
+    }
+    if args.dump() {
+        let summary = dump::dump(args.storage_path(), &mut std::io::stdout().lock()).expect("Failed to write the dump");
+        std::process::exit(if summary.unreadable == 0 { 0 } else { 1 });
+    }
+
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to build Tokio runtime");
+    let _guard = runtime.enter();
+
+    let storage = Storage::open_at(args.mount_path(), args.storage_path(), args.fs_options(&parsed))
+        .expect("Failed to open TimeFS storage");
+
+    if args.rebuild() {
+        storage.rebuild_index().expect("Failed to rebuild the inode index");
+        info!("Rebuilt the inode index");
+    }
+    // Without zstd, opening storage with the block compression training needs has failed already.
+    if args.train_dictionary() {
+        #[cfg(feature = "zstd")]
+        {
+            let id = runtime.block_on(storage.train_dictionary()).expect("Failed to train a compression dictionary");
+            info!("Compressing new blocks with dictionary {}", id);
+        }
+    }
+    if args.repair_blocks() {
+        let report = storage.repair_blocks().expect("Failed to repair blocks");
+        info!("Removed {} temporary block files, quarantined {} blocks: {:?}", report.removed_tmp_files, report.quarantined.len(), report.quarantined);
+    }
+    if args.scrub() {
+        let report = storage.scrub().expect("Failed to scrub blocks");
+        info!("Scrubbed {} blocks, {} corrupt: {:?}", report.good + report.corrupt, report.corrupt, report.corrupt_blocks);
+    }
+    if args.compact() {
+        let freed = runtime.block_on(storage.compact()).expect("Failed to compact files");
+        info!("Compaction freed {} blocks", freed);
+    }
+    if args.fsck() {
+        let report = storage.fsck(args.fsck_repair()).expect("Failed to check TimeFS");
+        match report.is_clean() {
+            true => info!("Fsck found no inconsistencies"),
+            false => warn!("Fsck found inconsistencies (repaired: {}): {:?}", report.repaired, report),
+        }
+    }
+    if let Some(path) = args.import() {
+        let file = std::fs::File::open(path).expect("Failed to open the stream to import");
+        runtime.block_on(storage.import(BufReader::new(file))).expect("Failed to import");
+        info!("Imported {:?}", path);
+    }
+    if let Some((path, since)) = args.export() {
+        let file = std::fs::File::create(path).expect("Failed to create the export file");
+        runtime.block_on(storage.export_since(since, BufWriter::new(file))).expect("Failed to export");
+        runtime.block_on(storage.close()).expect("Failed to shut down TimeFS");
+        info!("Exported to {:?}", path);
+        return;
+    }
+    storage.mount(&args.mount_options()).expect("Failed to serve TimeFS");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::superblock::SuperBlock;
+
+    #[test]
+    fn test_compressed_round_trip() -> Result<()> {
+        let mut super_block = SuperBlock::new();
+        for _ in 0..100 {
+            super_block.alloc_inode()?;
+        }
+        let expected = bincode::serialize(&super_block)?;
+
+        let compressions = [
+            MetadataCompression { algorithm: CompressionAlgorithm::None, level: None },
+            MetadataCompression { algorithm: CompressionAlgorithm::Zlib, level: None },
+            MetadataCompression { algorithm: CompressionAlgorithm::Zlib, level: Some(1) },
+            #[cfg(feature = "zstd")]
+            MetadataCompression { algorithm: CompressionAlgorithm::Zstd, level: None },
+            #[cfg(feature = "zstd")]
+            MetadataCompression { algorithm: CompressionAlgorithm::Zstd, level: Some(19) },
+        ];
+
+        for compression in compressions {
+            let mut buf = Vec::new();
+            write_to_bin_compressed(&super_block, &mut buf, compression)?;
+            let decoded: SuperBlock = from_bin_compressed(buf.as_slice())?;
+            assert_eq!(bincode::serialize(&decoded)?, expected, "{:?}", compression);
+        }
+
+        // Streams written before the header byte was introduced are plain zlib.
+        let mut legacy = ZlibEncoder::new(Vec::new(), Compression::best());
+        bincode::serialize_into(&mut legacy, &super_block)?;
+        let decoded: SuperBlock = from_bin_compressed(legacy.finish()?.as_slice())?;
+        assert_eq!(bincode::serialize(&decoded)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_writes_of_one_file_dont_mix() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("shared.bin");
+
+        std::thread::scope(|scope| {
+            let writers = (0..8u64)
+                .map(|writer| {
+                    let path = &path;
+                    scope.spawn(move || (0..50).try_for_each(|_| write_to_bin_file(&vec![writer; 1000], path, false)))
+                })
+                .collect::<Vec<_>>();
+            writers.into_iter().try_for_each(|writer| writer.join().unwrap())
+        })?;
+
+        // Whole contents of a single write, and no temporary file left behind.
+        let contents: Vec<u64> = from_bin_file(&path)?;
+        assert!(contents.len() == 1000 && contents.iter().all(|&v| v == contents[0]));
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+        Ok(())
+    }
 }
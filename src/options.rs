@@ -0,0 +1,290 @@
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use clap::ValueEnum;
+use fuser::FileAttr;
+use regex::Regex;
+
+/// How eagerly reads update a file's access time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum AtimePolicy {
+    /// Update atime on every read.
+    Strict,
+    /// Update atime only when it's not newer than mtime/ctime or is more than a day old.
+    #[default]
+    Relatime,
+    /// Never update atime.
+    Noatime,
+}
+
+impl AtimePolicy {
+    const RELATIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn should_update(&self, attr: &FileAttr, now: SystemTime) -> bool {
+        match self {
+            Self::Strict => true,
+            Self::Noatime => false,
+            Self::Relatime => {
+                attr.atime <= attr.mtime
+                    || attr.atime <= attr.ctime
+                    || now.duration_since(attr.atime).is_ok_and(|age| age >= Self::RELATIME_MAX_AGE)
+            }
+        }
+    }
+}
+
+/// Which blocks the block cache evicts once it's full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CachePolicy {
+    /// Admit new blocks by how often they're accessed and evict the least recently used, so a
+    /// scan can't push out blocks in frequent use.
+    #[default]
+    TinyLfu,
+    /// Admit every block and evict the least recently used, for workloads favoring recent blocks.
+    Lru,
+}
+
+/// What closing a file makes durable, trading the cost of `close` against what survives a crash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SyncOnClose {
+    /// Leave the data in the cache for the periodic flush, only `fsync` makes it durable.
+    #[default]
+    None,
+    /// Write the file's blocks out, but not its inode.
+    Data,
+    /// Write the file's blocks and inode out, as an `fsync` would.
+    All,
+}
+
+/// Algorithm compressed metadata, such as export streams, is written with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CompressionAlgorithm {
+    None,
+    #[default]
+    Zlib,
+    /// Zstandard, much faster than zlib at a similar ratio.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// Levels the algorithm accepts, `None` when it can't be tuned.
+    pub fn levels(&self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Self::None => None,
+            Self::Zlib => Some(0..=9),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Some(1..=22),
+        }
+    }
+}
+
+/// Compression of metadata files, the level falling back to the algorithm's default when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct MetadataCompression {
+    pub(crate) algorithm: CompressionAlgorithm,
+    pub(crate) level: Option<i32>,
+}
+
+/// Settings a TimeFS is mounted with, collected from the command line.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FsOptions {
+    /// Reject every mutating operation with `EROFS`.
+    pub(crate) read_only: bool,
+    /// Keep all data and metadata in memory only, for tests and scratch mounts.
+    pub(crate) in_memory: bool,
+    /// File holding the 32 byte master key blocks are encrypted with at rest.
+    pub(crate) key_file: Option<PathBuf>,
+    pub(crate) atime: AtimePolicy,
+    pub(crate) cache_policy: CachePolicy,
+    pub(crate) sync_on_close: SyncOnClose,
+    /// Worker threads flushing dirty blocks, one per CPU when unset.
+    pub(crate) flush_threads: Option<NonZeroUsize>,
+    /// Open file handles past which opening another fails with `EMFILE`, unlimited when unset.
+    pub(crate) max_open_files: Option<usize>,
+    /// Bytes past which files can't grow, failing with `EFBIG`, unlimited when unset.
+    pub(crate) max_file_size: Option<u64>,
+    /// Largest write request to ask the kernel for, its default when unset.
+    pub(crate) max_write: Option<u64>,
+    /// Readahead to ask the kernel for, its default when unset.
+    pub(crate) max_readahead: Option<u64>,
+    /// Move unlinked files and directories to the trash instead of freeing them.
+    pub(crate) trash: bool,
+    /// How long trashed inodes are kept, [`DEFAULT_TRASH_RETENTION`] when unset.
+    pub(crate) trash_retention: Option<Duration>,
+    /// Dirty bytes in the block cache that trigger flushing before the flush interval.
+    pub(crate) dirty_high_water: Option<usize>,
+    /// Age at which dirty blocks are flushed, [`DEFAULT_FLUSH_INTERVAL`] when unset. Zero leaves
+    /// them in the cache until they're evicted, synced or the filesystem is unmounted.
+    pub(crate) flush_interval: Option<Duration>,
+    /// File of `uid limit` lines capping the bytes each uid's files may take up.
+    pub(crate) quota_file: Option<PathBuf>,
+    /// File of inode ids or paths, one per line, whose blocks are read into the cache after mounting.
+    pub(crate) warm_cache: Option<PathBuf>,
+    /// Bytes of block storage after which the oldest trashed inodes are purged early.
+    pub(crate) storage_limit: Option<u64>,
+    /// Percentage of `storage_limit` past which writes are slowed down, [`DEFAULT_STORAGE_HIGH_WATER`] when unset.
+    pub(crate) storage_high_water: Option<u8>,
+    pub(crate) metadata_compression: MetadataCompression,
+    /// Write inode and superblock files with `metadata_compression` too, plain ones staying readable.
+    pub(crate) compress_metadata: bool,
+    /// Match names in directory lookups regardless of case, keeping the case they were created with.
+    pub(crate) case_insensitive: bool,
+    /// Skip fsyncing metadata files after writing them, faster but may lose metadata on power failure.
+    pub(crate) no_metadata_sync: bool,
+    /// Compress blocks with zstd before writing them, only possible for a filesystem without blocks yet.
+    pub(crate) block_compression: bool,
+    /// Read every block back after writing it and compare it with what was meant to be written.
+    pub(crate) verify_writes: bool,
+    /// Collect changed file inodes and write them out together this often, instead of on every change.
+    pub(crate) inode_flush_interval: Option<Duration>,
+    /// Coalesce fsyncs arriving within this long of each other into one commit of every dirty block.
+    pub(crate) fsync_batch_window: Option<Duration>,
+    /// How long the kernel may cache attributes, [`DEFAULT_TTL`] when unset.
+    pub(crate) attr_ttl: Option<Duration>,
+    /// How long the kernel may cache name lookups, [`DEFAULT_TTL`] when unset.
+    pub(crate) entry_ttl: Option<Duration>,
+    /// Record a version of a file before writes change it.
+    pub(crate) auto_version: bool,
+    /// Least time between two automatic versions of the same file.
+    pub(crate) min_version_interval: Option<Duration>,
+    /// Globs of files never versioned automatically, matched against their path below the mount
+    /// point and against their name.
+    pub(crate) version_exclude: Vec<Regex>,
+    /// Versions kept per file before the oldest are dropped, unlimited when unset.
+    pub(crate) max_versions: Option<u16>,
+    /// Bytes the history of a file may hold on its own before its oldest versions are dropped.
+    pub(crate) max_version_bytes: Option<u64>,
+    /// Hold the contents of new blocks until their file is flushed, or the next flush interval at
+    /// the latest, and give them ids only then, in a row in file order, so files written out of
+    /// order still read back sequentially.
+    pub(crate) delayed_allocation: bool,
+}
+
+pub(crate) const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub(crate) const DEFAULT_STORAGE_HIGH_WATER: u8 = 90;
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(1);
+/// Age at which dirty blocks are flushed when no flush interval is given.
+pub(crate) const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Parses a duration such as `90`, `250ms`, `30s`, `15m`, `12h` or `7d`, bare numbers being seconds.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = split_unit(s);
+    let secs = match unit {
+        "ms" => return Ok(Duration::from_millis(parse_value(value, s)?)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit {:?} in {:?}", unit, s)),
+    };
+    Ok(Duration::from_secs(parse_value(value, s)? * secs))
+}
+
+/// Parses a byte size such as `4096`, `512K`, `100M` or `2G`, using powers of 1024.
+pub(crate) fn parse_size(s: &str) -> Result<u64, String> {
+    let (value, unit) = split_unit(s);
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" => 10,
+        "M" | "MB" => 20,
+        "G" | "GB" => 30,
+        "T" | "TB" => 40,
+        _ => return Err(format!("unknown size unit {:?} in {:?}", unit, s)),
+    };
+    parse_value(value, s)?
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} is too large", s))
+}
+
+/// `path` made absolute, with symlinks resolved as far as it exists.
+pub(crate) fn resolve_path(path: &Path) -> std::io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("/"));
+    let rest = path.strip_prefix(existing).unwrap_or(Path::new(""));
+    Ok(existing.canonicalize()?.join(rest))
+}
+
+/// Whether `a` and `b` are the same directory or one lies inside the other.
+pub(crate) fn paths_overlap(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (a, b) = (resolve_path(a)?, resolve_path(b)?);
+    Ok(a.starts_with(&b) || b.starts_with(&a))
+}
+
+/// Compiles a shell-style glob into an anchored regex. `*`, `?` and `[...]` match within one
+/// path component, `**` matches across components.
+pub(crate) fn parse_glob(pattern: &str) -> Result<Regex, regex::Error> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re.push_str(".*");
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                let class = chars.by_ref().take_while(|&c| c != ']').collect::<String>();
+                re.push('[');
+                match class.strip_prefix('!') {
+                    Some(negated) => {
+                        re.push('^');
+                        re.push_str(negated);
+                    }
+                    None => re.push_str(&class),
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re)
+}
+
+fn split_unit(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+}
+
+fn parse_value(value: &str, s: &str) -> Result<u64, String> {
+    value.parse().map_err(|_| format!("invalid number in {:?}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("2ms"), Ok(Duration::from_millis(2)));
+        assert_eq!(parse_duration("7d"), Ok(DEFAULT_TRASH_RETENTION));
+        assert!(parse_duration("3w").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn test_parse_glob() -> Result<(), regex::Error> {
+        let tmp = parse_glob("*.tmp")?;
+        assert!(tmp.is_match("notes.tmp"));
+        assert!(!tmp.is_match("dir/notes.tmp"));
+        assert!(!tmp.is_match("notes.tmp.txt"));
+        assert!(parse_glob("**/.git/**")?.is_match("src/.git/HEAD"));
+        assert!(parse_glob("file?.txt")?.is_match("file1.txt"));
+        assert!(parse_glob("v[0-9].bin")?.is_match("v7.bin"));
+        assert!(!parse_glob("v[!0-9].bin")?.is_match("v7.bin"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("2gb"), Ok(2 << 30));
+        assert!(parse_size("10X").is_err());
+    }
+}
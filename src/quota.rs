@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::path::Path;
+use parking_lot::Mutex;
+use crate::error::TimeFSError;
+use crate::options::parse_size;
+
+/// Byte limits on what each uid may store, read from a quota file, along with what each uses.
+///
+/// A uid uses the allocated blocks of the files it owns. Charges are kept per inode, so that
+/// writing an inode out only replaces its own share, whoever owned it before.
+#[derive(Debug, Default)]
+pub(crate) struct Quotas {
+    limits: HashMap<u32, u64>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// Owner and bytes each inode is charged for.
+    by_inode: HashMap<u64, (u32, u64)>,
+    by_uid: HashMap<u32, u64>,
+}
+
+impl Usage {
+    fn release(&mut self, ino: u64) {
+        if let Some((uid, bytes)) = self.by_inode.remove(&ino)
+            && let Some(used) = self.by_uid.get_mut(&uid)
+        {
+            *used = used.saturating_sub(bytes);
+        }
+    }
+
+    fn charge(&mut self, ino: u64, uid: u32, bytes: u64) {
+        self.release(ino);
+        if bytes > 0 {
+            self.by_inode.insert(ino, (uid, bytes));
+            *self.by_uid.entry(uid).or_default() += bytes;
+        }
+    }
+}
+
+impl Quotas {
+    /// Reads a quota file of `uid limit` lines such as `1000 10G`, skipping blank lines and
+    /// those starting with `#`.
+    pub fn from_file(path: impl AsRef<Path>) -> crate::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> crate::Result<Self> {
+        let mut limits = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |reason: String| TimeFSError::Invalid(format!("quota file line {}: {}", number + 1, reason));
+            let mut fields = line.split_whitespace();
+            let (Some(uid), Some(limit), None) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(invalid(format!("expected `uid limit`, got {:?}", line)));
+            };
+            let uid = uid.parse().map_err(|_| invalid(format!("invalid uid {:?}", uid)))?;
+            limits.insert(uid, parse_size(limit).map_err(invalid)?);
+        }
+        Ok(Self { limits, usage: Mutex::default() })
+    }
+
+    /// Bytes the files of `uid` take up.
+    pub fn usage(&self, uid: u32) -> u64 {
+        self.usage.lock().by_uid.get(&uid).copied().unwrap_or(0)
+    }
+
+    /// Fails with `EDQUOT` when `bytes` more would take `uid` past its quota.
+    pub fn check(&self, uid: u32, bytes: u64) -> crate::Result<()> {
+        let Some(&limit) = self.limits.get(&uid) else {
+            return Ok(());
+        };
+        if self.usage(uid).saturating_add(bytes) > limit {
+            return Err(TimeFSError::QuotaExceeded(uid));
+        }
+        Ok(())
+    }
+
+    /// Charges `uid` for the `bytes` inode `ino` now takes up, instead of what it was charged before.
+    pub fn charge(&self, ino: u64, uid: u32, bytes: u64) {
+        self.usage.lock().charge(ino, uid, bytes);
+    }
+
+    /// Stops charging anyone for `ino`, once it's freed.
+    pub fn release(&self, ino: u64) {
+        self.usage.lock().release(ino);
+    }
+
+    /// Forgets all charges and charges every `(ino, uid, bytes)` in `inodes` afresh.
+    pub fn recompute(&self, inodes: impl IntoIterator<Item = (u64, u32, u64)>) {
+        let mut usage = Usage::default();
+        for (ino, uid, bytes) in inodes {
+            usage.charge(ino, uid, bytes);
+        }
+        *self.usage.lock() = usage;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quota_file() -> crate::Result<()> {
+        let quotas = Quotas::parse("# uid limit\n1000 10K\n\n  1001   2M\n")?;
+        assert_eq!(quotas.limits, HashMap::from([(1000, 10 * 1024), (1001, 2 * 1024 * 1024)]));
+
+        assert!(matches!(Quotas::parse("1000"), Err(TimeFSError::Invalid(_))));
+        assert!(matches!(Quotas::parse("root 1G"), Err(TimeFSError::Invalid(_))));
+        assert!(matches!(Quotas::parse("1000 lots"), Err(TimeFSError::Invalid(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_charges_move_with_their_inode() -> crate::Result<()> {
+        let quotas = Quotas::parse("1000 8K")?;
+        quotas.charge(5, 1000, 4096);
+        quotas.charge(5, 1000, 8192);
+        assert_eq!(quotas.usage(1000), 8192);
+        assert!(matches!(quotas.check(1000, 1), Err(TimeFSError::QuotaExceeded(1000))));
+        quotas.check(1001, u64::MAX)?;
+
+        // Handing the file to another owner moves its charge along.
+        quotas.charge(5, 1001, 8192);
+        assert_eq!((quotas.usage(1000), quotas.usage(1001)), (0, 8192));
+        quotas.release(5);
+        assert_eq!(quotas.usage(1001), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+use fuser::{FileAttr, ReplyAttr, ReplyEntry};
+use libc::c_int;
+
+/// Replies carrying attributes, abstracted so tests can capture what is handed to the kernel.
+pub(crate) trait AttrReply {
+    fn attr(self, ttl: &Duration, attr: &FileAttr);
+    fn error(self, err: c_int);
+}
+
+pub(crate) trait EntryReply {
+    fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64);
+    fn error(self, err: c_int);
+}
+
+impl AttrReply for ReplyAttr {
+    fn attr(self, ttl: &Duration, attr: &FileAttr) {
+        ReplyAttr::attr(self, ttl, attr)
+    }
+
+    fn error(self, err: c_int) {
+        ReplyAttr::error(self, err)
+    }
+}
+
+impl EntryReply for ReplyEntry {
+    fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
+        ReplyEntry::entry(self, ttl, attr, generation)
+    }
+
+    fn error(self, err: c_int) {
+        ReplyEntry::error(self, err)
+    }
+}
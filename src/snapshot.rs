@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::block::BlockRef;
+use crate::{from_bin_file, write_to_bin_file, Result};
+
+/// `ioctl` command on the root directory taking a snapshot, the input data is the snapshot name.
+pub(crate) const TIMEFS_IOC_SNAPSHOT: u32 = 0x5446_0001;
+
+/// An immutable, named record of every file's block list at one moment.
+///
+/// Snapshots hold a reference on each block they point to, so later writes copy
+/// those blocks instead of modifying them in place.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) name: String,
+    pub(crate) created_at: SystemTime,
+    pub(crate) files: HashMap<u64, Vec<BlockRef>>,
+}
+
+impl Snapshot {
+    pub fn new(name: impl AsRef<str>, files: HashMap<u64, Vec<BlockRef>>) -> Self {
+        Self {
+            name: name.as_ref().to_string(),
+            created_at: SystemTime::now(),
+            files,
+        }
+    }
+
+    pub fn write_to_file(&self, snapshots_dir: &Path, sync: bool) -> Result<()> {
+        let path = snapshots_dir.join(format!("{}.bin", self.name));
+        write_to_bin_file(self, path.as_path(), sync)
+    }
+
+    pub fn from_file(name: impl AsRef<str>, snapshots_dir: &Path) -> Result<Self> {
+        let path = snapshots_dir.join(format!("{}.bin", name.as_ref()));
+        from_bin_file(path.as_path())
+    }
+
+    /// Reads back every snapshot recorded in `snapshots_dir`.
+    pub fn load_all(snapshots_dir: &Path) -> Result<Vec<Self>> {
+        let entries = match std::fs::read_dir(snapshots_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "bin")
+                && let Some(name) = path.file_stem().and_then(|name| name.to_str())
+            {
+                snapshots.push(Self::from_file(name, snapshots_dir)?);
+            }
+        }
+        Ok(snapshots)
+    }
+
+    pub fn exists(name: impl AsRef<str>, snapshots_dir: &Path) -> bool {
+        snapshots_dir.join(format!("{}.bin", name.as_ref())).exists()
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use fuser::{MountOption, FUSE_ROOT_ID};
+use crate::block::{BlockRepairReport, ScrubReport};
+use crate::fs::{FsckReport, TimeFS};
+use crate::options::FsOptions;
+use crate::Result;
+
+/// The versioned block store of TimeFS, for programs embedding it as a library and for the
+/// binary, which maintains and mounts its store through here.
+///
+/// Without a mount, files live in one flat namespace and are addressed by the inode id
+/// [`Storage::create_file`] returns. Everything goes through the same code the FUSE handlers
+/// use, so a store written here can be mounted later and the other way around.
+pub(crate) struct Storage {
+    fs: TimeFS,
+}
+
+impl Storage {
+    /// Opens or creates the store kept under `storage_path`, to be mounted at `mount_path` by
+    /// [`Storage::mount`].
+    pub fn open_at(mount_path: impl AsRef<Path>, storage_path: impl AsRef<Path>, options: FsOptions) -> Result<Self> {
+        let fs = TimeFS::with_options(mount_path, storage_path, options)?;
+        Ok(Self { fs })
+    }
+
+    /// Serves the store over FUSE at the path it was opened at, until it's unmounted.
+    pub fn mount(self, options: &[MountOption]) -> Result<()> {
+        let mount_path = self.fs.mount_path().to_path_buf();
+        let notifier = self.fs.notifier_slot();
+        let mut session = fuser::Session::new(self.fs, &mount_path, options)?;
+        let _ = notifier.set(Box::new(session.notifier()));
+        session.run()?;
+        Ok(())
+    }
+
+    /// Streams every inode and the blocks written after `since`, see [`TimeFS::export_since`].
+    pub async fn export_since(&self, since: SystemTime, writer: impl Write) -> Result<()> {
+        self.fs.export_since(since, writer).await
+    }
+
+    /// Applies a stream written by [`Storage::export_since`], see [`TimeFS::import`].
+    pub async fn import(&self, reader: impl Read) -> Result<()> {
+        self.fs.import(reader).await
+    }
+
+    pub fn fsck(&self, repair: bool) -> Result<FsckReport> {
+        self.fs.fsck(repair)
+    }
+
+    pub fn rebuild_index(&self) -> Result<()> {
+        self.fs.rebuild_index()
+    }
+
+    pub fn repair_blocks(&self) -> Result<BlockRepairReport> {
+        self.fs.repair_blocks()
+    }
+
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        self.fs.scrub()
+    }
+
+    /// Compacts the block list of every file, returning the number of blocks freed.
+    pub async fn compact(&self) -> Result<usize> {
+        self.fs.compact_all().await
+    }
+
+    /// Trains a compression dictionary on the stored blocks, returning the id new blocks use.
+    #[cfg(feature = "zstd")]
+    pub async fn train_dictionary(&self) -> Result<u32> {
+        self.fs.train_block_dictionary().await
+    }
+
+    /// Writes out everything still held in memory, blocks before the metadata pointing at them.
+    pub async fn close(self) -> Result<()> {
+        self.fs.shutdown().await
+    }
+}
+
+/// Files and their versions, for programs embedding the store; the binary only maintains and
+/// mounts it.
+#[allow(dead_code)]
+impl Storage {
+    /// Opens or creates the store kept under `storage_path`.
+    pub fn open(storage_path: impl AsRef<Path>, options: FsOptions) -> Result<Self> {
+        Self::open_at(PathBuf::new(), storage_path, options)
+    }
+
+    /// Creates a store that only lives in memory.
+    pub fn in_memory() -> Result<Self> {
+        Self::open(PathBuf::new(), FsOptions { in_memory: true, ..FsOptions::default() })
+    }
+
+    /// Creates the file `name`, or returns the existing one by that name.
+    pub async fn create_file(&self, name: &str) -> Result<u64> {
+        let (attr, fh) = self.fs.create_file(FUSE_ROOT_ID, name, libc::O_RDWR)?;
+        self.fs.close_handle(fh).await?;
+        Ok(attr.ino)
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<u64> {
+        Ok(self.fs.lookup_attr(FUSE_ROOT_ID, name)?.ino)
+    }
+
+    pub fn remove_file(&self, name: &str) -> Result<()> {
+        self.fs.remove_entry(FUSE_ROOT_ID, name, false)
+    }
+
+    pub async fn write_at(&self, ino: u64, offset: u64, data: &[u8]) -> Result<u32> {
+        self.fs.write_at(ino, offset, data).await
+    }
+
+    pub async fn read_at(&self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        self.fs.read_at(ino, offset, size).await
+    }
+
+    /// Records the current contents of `ino` as a version, returning its timestamp.
+    pub async fn capture_version(&self, ino: u64) -> Result<SystemTime> {
+        self.fs.capture_version(ino).await
+    }
+
+    /// Timestamps of the versions of `ino`, oldest first.
+    pub fn list_versions(&self, ino: u64) -> Result<Vec<SystemTime>> {
+        self.fs.list_versions(ino)
+    }
+
+    pub async fn read_version(&self, ino: u64, timestamp: SystemTime, offset: u64, size: u32) -> Result<Vec<u8>> {
+        self.fs.read_version(ino, timestamp, offset, size).await
+    }
+
+    /// Rolls `ino` back to its version taken at `timestamp`, see [`TimeFS::restore_version`].
+    pub async fn restore_version(&self, ino: u64, timestamp: SystemTime, preserve_times: bool) -> Result<()> {
+        self.fs.restore_version(ino, timestamp, preserve_times).await
+    }
+
+    /// Records the block list of every file as a snapshot named `name`.
+    pub async fn snapshot(&self, name: &str) -> Result<()> {
+        self.fs.snapshot(name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_versions_read_back_without_mount() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let storage = Storage::open(temp_dir.path(), FsOptions::default())?;
+        let ino = storage.create_file("object").await?;
+        storage.write_at(ino, 0, b"first contents").await?;
+        let first = storage.capture_version(ino).await?;
+        storage.write_at(ino, 0, b"second").await?;
+        storage.snapshot("daily").await?;
+        storage.close().await?;
+
+        let storage = Storage::open(temp_dir.path(), FsOptions::default())?;
+        let ino = storage.lookup("object")?;
+        assert_eq!(storage.list_versions(ino)?, vec![first]);
+        assert_eq!(storage.read_version(ino, first, 0, 64).await?, b"first contents");
+        assert_eq!(storage.read_at(ino, 0, 64).await?, b"secondcontents");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_and_remove_in_memory() -> Result<()> {
+        let storage = Storage::in_memory()?;
+        let ino = storage.create_file("scratch").await?;
+        storage.write_at(ino, 0, b"original").await?;
+        let original = storage.capture_version(ino).await?;
+        storage.write_at(ino, 0, b"modified").await?;
+
+        storage.restore_version(ino, original, false).await?;
+        assert_eq!(storage.read_at(ino, 0, 64).await?, b"original");
+        storage.remove_file("scratch")?;
+        assert!(storage.lookup("scratch").is_err());
+        Ok(())
+    }
+}
@@ -1,29 +1,50 @@
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use fuser::FUSE_ROOT_ID;
 use serde::{Deserialize, Serialize};
-use crate::block::BlockRef;
+use crate::error::TimeFSError;
 use crate::fs::BLOCK_SIZE;
+use crate::{from_bin_file, write_to_bin_file_as};
+use crate::options::MetadataCompression;
+use log::warn;
+
+// TimeFS in hex
+pub(crate) const MAGIC: u64 = 0x54_69_6d_65_46_53;
+/// On-disk format version, bumped whenever the layout of persisted metadata changes.
+pub(crate) const FORMAT_VERSION: u32 = 4;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct SuperBlock {
     magic: u64,
+    version: u32,
     block_size: u32, 
     inode_count: u64,
     next_inode_id: u64,
     next_block_id: u64,
     root_dir_inode: u64,
-    create_at: u64,
+    create_at: SystemTime,
+    /// Set while mounted writable and cleared on a clean unmount. Found set on mounting, the
+    /// counters and free lists may predate ids handed out before a crash.
     dirty: bool,
+    /// Freed inode ids waiting to be reused, with the generation each was last used with.
+    free_inodes: Vec<(u64, u64)>,
+    /// Block ids no longer referenced by anything, handed out again before fresh ones.
+    /// Like the counters, both free lists are only up to date on disk after a clean unmount and
+    /// are rebuilt otherwise, see [`SuperBlock::reset_counters`].
+    free_blocks: Vec<u64>,
+    /// Loaded from the backup because the superblock itself was unreadable.
+    #[serde(skip)]
+    recovered: bool,
 }
 
 impl SuperBlock {
     pub fn new() -> Self {
         Self {
-            // TimeFS in hex
-            magic: 0x54_69_6d_65_46_53,
+            magic: MAGIC,
+            version: FORMAT_VERSION,
             // 4KB
             block_size: BLOCK_SIZE,
             inode_count: FUSE_ROOT_ID,
@@ -31,44 +52,321 @@ impl SuperBlock {
             next_block_id: 1,
             root_dir_inode: FUSE_ROOT_ID,
             dirty: false,
-            create_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap_or(Duration::from_secs(0))
-                .as_secs(),
+            free_inodes: Vec::new(),
+            free_blocks: Vec::new(),
+            recovered: false,
+            create_at: SystemTime::now(),
         }
     }
 
+    /// Loads the superblock at `path`, falling back to the backup of the one written before it
+    /// when it's unreadable, e.g. after a write cut short or a corrupted sector.
     pub fn from_file(path: impl AsRef<Path>) -> crate::Result<Self> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        Ok(bincode::deserialize_from(reader)?)
+        let path = path.as_ref();
+        let err = match Self::read_checked(path) {
+            Ok(sb) => return Ok(sb),
+            // Written by a newer TimeFS rather than damaged, going back would lose its changes.
+            Err(e @ TimeFSError::UnsupportedVersion(version)) if version > FORMAT_VERSION => return Err(e),
+            Err(e) => e,
+        };
+        let backup_path = Self::backup_path(path);
+        if !backup_path.exists() {
+            return Err(err);
+        }
+        let mut sb = Self::read_checked(&backup_path).map_err(|_| err)?;
+        warn!("Superblock {:?} is unreadable, recovered the previous one from {:?}", path, backup_path);
+        sb.recovered = true;
+        Ok(sb)
+    }
+
+    fn read_checked(path: &Path) -> crate::Result<Self> {
+        let sb: Self = from_bin_file(path)?;
+
+        if sb.magic != MAGIC {
+            return Err(TimeFSError::BadMagic(sb.magic));
+        }
+        if sb.version != FORMAT_VERSION {
+            return Err(TimeFSError::UnsupportedVersion(sb.version));
+        }
+
+        Ok(sb)
     }
     
-    pub fn get_next_inode_id(&mut self) -> u64 {
+    /// Hands out a never used inode id, failing once they run out rather than wrapping around
+    /// to the root or other live inodes.
+    pub fn get_next_inode_id(&mut self) -> crate::Result<u64> {
         let id = self.next_inode_id;
-        self.next_inode_id += 1;
-        id
+        if id <= FUSE_ROOT_ID {
+            return Err(TimeFSError::IdSpaceExhausted("inode"));
+        }
+        self.next_inode_id = id.checked_add(1).ok_or(TimeFSError::IdSpaceExhausted("inode"))?;
+        Ok(id)
     }
-    
-    pub fn get_next_block_id(&mut self) -> u64 {
+
+    /// Hands out a never used block id, failing once they run out rather than wrapping around
+    /// to 0, which marks holes.
+    pub fn get_next_block_id(&mut self) -> crate::Result<u64> {
         let id = self.next_block_id;
-        self.next_block_id += 1;
-        id
+        if id == 0 {
+            return Err(TimeFSError::IdSpaceExhausted("block"));
+        }
+        self.next_block_id = id.checked_add(1).ok_or(TimeFSError::IdSpaceExhausted("block"))?;
+        Ok(id)
     }
     
-    pub fn write_to_file(&self, path: impl AsRef<Path>) -> crate::Result<()> {
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, self)?;
-        Ok(())
+    /// Makes sure inode ids up to `inode_id` are never handed out again.
+    pub fn reserve_inode_ids_up_to(&mut self, inode_id: u64) {
+        self.next_inode_id = self.next_inode_id.max(inode_id.saturating_add(1));
+    }
+
+    /// Takes over the ids of an imported inode table, which are all in use from now on whether
+    /// they were freed here or never handed out.
+    pub fn adopt_inodes(&mut self, live_inodes: &HashSet<u64>) {
+        self.free_inodes.retain(|(id, _)| !live_inodes.contains(id));
+        if let Some(&max_inode_id) = live_inodes.iter().max() {
+            self.reserve_inode_ids_up_to(max_inode_id);
+        }
+        self.inode_count = live_inodes.len() as u64;
+    }
+
+    pub fn inode_count(&self) -> u64 {
+        self.inode_count
+    }
+
+    pub fn next_inode_id(&self) -> u64 {
+        self.next_inode_id
+    }
+
+    pub fn next_block_id(&self) -> u64 {
+        self.next_block_id
+    }
+
+    /// Replaces the counters with the ones implied by the inodes and blocks found on disk,
+    /// dropping freed inode ids that turn out to still be in use.
+    pub fn reset_counters(&mut self, live_inodes: &HashSet<u64>, max_block_id: u64) {
+        self.free_inodes.retain(|(id, _)| !live_inodes.contains(id));
+        let max_inode_id = live_inodes
+            .iter()
+            .chain(self.free_inodes.iter().map(|(id, _)| id))
+            .copied()
+            .max()
+            .unwrap_or(FUSE_ROOT_ID);
+
+        self.inode_count = live_inodes.len() as u64;
+        self.next_inode_id = max_inode_id + 1;
+        self.next_block_id = max_block_id + 1;
+        // Snapshots aren't part of the scan and may still point at freed blocks, so rather
+        // than risk handing those out again they're given up on.
+        self.free_blocks.clear();
+    }
+
+    /// Whether this is the backup of an older superblock, whose counters may be behind.
+    pub fn is_recovered(&self) -> bool {
+        self.recovered
+    }
+
+    /// Whether the filesystem was unmounted cleanly after it was last mounted writable.
+    pub fn is_clean(&self) -> bool {
+        !self.dirty
+    }
+
+    pub fn set_clean(&mut self, clean: bool) {
+        self.dirty = !clean;
+    }
+
+    /// Writes the superblock to `path`, keeping the one it replaces as a backup if it's readable.
+    /// With a `compression` it's written compressed.
+    pub fn write_to_file(&self, path: impl AsRef<Path>, sync: bool, compression: Option<MetadataCompression>) -> crate::Result<()> {
+        let path = path.as_ref();
+        if Self::read_checked(path).is_ok() {
+            let backup_path = Self::backup_path(path);
+            std::fs::copy(path, &backup_path)?;
+            if sync {
+                crate::sync_file(&File::open(&backup_path)?)?;
+            }
+        }
+        write_to_bin_file_as(self, path, sync, compression)
+    }
+
+    fn backup_path(path: &Path) -> PathBuf {
+        path.with_extension("bak")
     }
     
-    pub fn new_block(&mut self) -> BlockRef {
-        let id = self.get_next_block_id();
-        BlockRef::new(id)
+    /// Picks the id of a new block, reusing a freed one first.
+    pub fn alloc_block(&mut self) -> crate::Result<u64> {
+        match self.free_blocks.pop() {
+            Some(id) => Ok(id),
+            None => self.get_next_block_id(),
+        }
+    }
+
+    /// Hands out `count` never used block ids in a row, so the blocks of a file growing by that
+    /// much land next to each other in the same shard directories.
+    pub fn alloc_block_run(&mut self, count: u64) -> crate::Result<RangeInclusive<u64>> {
+        if count == 0 {
+            return Err(TimeFSError::Invalid("empty block run".to_string()));
+        }
+        let start = self.next_block_id;
+        let end = start.checked_add(count - 1).filter(|_| start != 0).ok_or(TimeFSError::IdSpaceExhausted("block"))?;
+        self.next_block_id = end.checked_add(1).ok_or(TimeFSError::IdSpaceExhausted("block"))?;
+        Ok(start..=end)
+    }
+
+    pub fn free_block(&mut self, id: u64) {
+        self.free_blocks.push(id);
     }
     
-    pub fn alloc_inode(&mut self) {
+    /// Picks the id and generation of a new inode, reusing a freed id with a bumped generation
+    /// so stale file handles to its previous inode can be told apart.
+    pub fn alloc_inode(&mut self) -> crate::Result<(u64, u64)> {
+        let allocated = match self.free_inodes.pop() {
+            Some((id, generation)) => (id, generation + 1),
+            None => (self.get_next_inode_id()?, 0),
+        };
         self.inode_count += 1;
+        Ok(allocated)
+    }
+
+    pub fn free_inode(&mut self, id: u64, generation: u64) {
+        self.inode_count = self.inode_count.saturating_sub(1);
+        self.free_inodes.push((id, generation));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_round_trip() -> crate::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("superblock.bin");
+
+        let mut sb = SuperBlock::new();
+        sb.get_next_inode_id()?;
+        sb.write_to_file(&path, true, None)?;
+
+        let loaded = SuperBlock::from_file(&path)?;
+        assert_eq!(loaded.version, FORMAT_VERSION);
+        assert_eq!(loaded.next_inode_id, sb.next_inode_id);
+        assert_eq!(loaded.create_at, sb.create_at, "creation time keeps its nanoseconds");
+        Ok(())
+    }
+
+    #[test]
+    fn test_freed_inode_ids_are_reused() -> crate::Result<()> {
+        let mut sb = SuperBlock::new();
+        let (id, generation) = sb.alloc_inode()?;
+        sb.free_inode(id, generation);
+
+        assert_eq!(sb.alloc_inode()?, (id, generation + 1));
+        assert_ne!(sb.alloc_inode()?.0, id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_freed_block_ids_are_reused() -> crate::Result<()> {
+        let mut sb = SuperBlock::new();
+        let first = sb.alloc_block()?;
+        let second = sb.alloc_block()?;
+        sb.free_block(first);
+
+        assert_eq!(sb.alloc_block()?, first);
+        assert_eq!(sb.alloc_block()?, second + 1);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_block_runs_skip_freed_ids() -> crate::Result<()> {
+        let mut sb = SuperBlock::new();
+        let first = sb.alloc_block()?;
+        sb.free_block(first);
+        assert_eq!(sb.alloc_block_run(3)?, 2..=4);
+        assert_eq!(sb.alloc_block()?, first);
+        assert_eq!(sb.alloc_block()?, 5);
+        assert!(matches!(sb.alloc_block_run(0), Err(TimeFSError::Invalid(_))));
+
+        sb.next_block_id = u64::MAX - 1;
+        assert!(matches!(sb.alloc_block_run(3), Err(TimeFSError::IdSpaceExhausted("block"))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_id_allocation_never_wraps() -> crate::Result<()> {
+        let mut sb = SuperBlock::new();
+        sb.next_inode_id = u64::MAX - 1;
+        sb.next_block_id = u64::MAX;
+
+        assert_eq!(sb.alloc_inode()?.0, u64::MAX - 1);
+        let count = sb.inode_count();
+        assert!(matches!(sb.alloc_inode(), Err(TimeFSError::IdSpaceExhausted("inode"))));
+        assert!(matches!(sb.alloc_inode(), Err(TimeFSError::IdSpaceExhausted("inode"))));
+        assert_eq!(sb.inode_count(), count);
+        assert!(matches!(sb.get_next_block_id(), Err(TimeFSError::IdSpaceExhausted("block"))));
+
+        // Freed ids are still handed out once fresh ones run out.
+        sb.free_inode(42, 3);
+        assert_eq!(sb.alloc_inode()?, (42, 4));
+
+        // A counter already past the end, e.g. from a corrupt superblock, never yields the root.
+        sb.next_inode_id = 0;
+        assert!(matches!(sb.alloc_inode(), Err(TimeFSError::IdSpaceExhausted("inode"))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_superblock_falls_back_to_backup() -> crate::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("superblock.bin");
+
+        let mut sb = SuperBlock::new();
+        sb.get_next_inode_id()?;
+        sb.write_to_file(&path, true, None)?;
+        sb.get_next_inode_id()?;
+        sb.write_to_file(&path, true, None)?;
+        assert!(!SuperBlock::from_file(&path)?.is_recovered());
+
+        // Torn mid-write, the previous superblock is all that's left.
+        let len = std::fs::metadata(&path)?.len();
+        std::fs::OpenOptions::new().write(true).open(&path)?.set_len(len / 2)?;
+        let recovered = SuperBlock::from_file(&path)?;
+        assert!(recovered.is_recovered());
+        assert_eq!(recovered.next_inode_id, sb.next_inode_id - 1);
+
+        // A corrupt superblock never overwrites the good backup.
+        std::fs::write(&path, b"garbage")?;
+        recovered.write_to_file(&path, true, None)?;
+        std::fs::write(&path, b"garbage")?;
+        assert_eq!(SuperBlock::from_file(&path)?.next_inode_id, sb.next_inode_id - 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_bad_magic() -> crate::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("superblock.bin");
+
+        let mut sb = SuperBlock::new();
+        sb.magic = 0xdead_beef;
+        sb.write_to_file(&path, true, None)?;
+
+        let result = SuperBlock::from_file(&path);
+        assert!(matches!(result, Err(TimeFSError::BadMagic(0xdead_beef))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_unknown_version() -> crate::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("superblock.bin");
+
+        let mut sb = SuperBlock::new();
+        sb.version = FORMAT_VERSION + 1;
+        sb.write_to_file(&path, true, None)?;
+
+        let result = SuperBlock::from_file(&path);
+        assert!(matches!(result, Err(TimeFSError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1));
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::{from_bin_file, write_to_bin_file, Result};
+
+/// Inode number of the virtual `.trash` directory listed in the root.
+pub(crate) const TRASH_DIR_INO: u64 = u64::MAX - 1;
+pub(crate) const TRASH_DIR_NAME: &str = ".trash";
+
+/// An unlinked inode kept in the trash, remembering where it was deleted from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TrashEntry {
+    pub(crate) ino: u64,
+    pub(crate) parent: u64,
+    pub(crate) name: String,
+    pub(crate) deleted_at: SystemTime,
+}
+
+impl TrashEntry {
+    pub fn new(ino: u64, parent: u64, name: impl AsRef<str>) -> Self {
+        Self {
+            ino,
+            parent,
+            name: name.as_ref().to_string(),
+            deleted_at: SystemTime::now(),
+        }
+    }
+
+    /// Name listed in `.trash`, suffixed with the inode so deleting the same name twice doesn't collide.
+    pub fn trash_name(&self) -> String {
+        format!("{}~{}", self.name, self.ino)
+    }
+}
+
+/// Index of trashed inodes, oldest deletion first.
+///
+/// Trashed inodes keep their blocks referenced, so their data stays readable until the entry
+/// is purged for being past the retention period or to bring storage back under its limit.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Trash {
+    entries: Vec<TrashEntry>,
+}
+
+impl Trash {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        from_bin_file(path)
+    }
+
+    pub fn write_to_file(&self, path: &Path, sync: bool) -> Result<()> {
+        write_to_bin_file(self, path, sync)
+    }
+
+    pub fn entries(&self) -> &[TrashEntry] {
+        &self.entries
+    }
+
+    pub fn push(&mut self, entry: TrashEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn find(&self, trash_name: &str) -> Option<&TrashEntry> {
+        self.entries.iter().find(|e| e.trash_name() == trash_name)
+    }
+
+    /// Removes the entry of `ino` from the trash, e.g. to restore it.
+    pub fn take(&mut self, ino: u64) -> Option<TrashEntry> {
+        let index = self.entries.iter().position(|e| e.ino == ino)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Removes every entry deleted at least `retention` before `now`.
+    pub fn take_expired(&mut self, now: SystemTime, retention: Duration) -> Vec<TrashEntry> {
+        let (expired, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|e| now.duration_since(e.deleted_at).is_ok_and(|age| age >= retention));
+        self.entries = kept;
+        expired
+    }
+
+    pub fn pop_oldest(&mut self) -> Option<TrashEntry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        Some(self.entries.remove(0))
+    }
+}